@@ -10,6 +10,7 @@ import logging
 import socket
 import math
 
+from artiq.coredevice.comm_kernel import TimeCorrelation
 
 logger = logging.getLogger(__name__)
 
@@ -100,7 +101,8 @@ def decode_message(data):
 
 
 DecodedDump = namedtuple(
-    "DecodedDump", "log_channel dds_onehot_sel messages")
+    "DecodedDump", "log_channel dds_onehot_sel messages time_correlation",
+    defaults=(None,))
 
 
 def decode_dump(data):
@@ -120,7 +122,18 @@ def decode_dump(data):
 
     logger.debug("analyzer dump has length %d", sent_bytes)
 
-    expected_len = sent_bytes + 15
+    # Dumps from older firmware have a shorter header, without the time
+    # correlation of the SNTP client.
+    header_len = 15 if len(data) == sent_bytes + 15 else 40
+    time_correlation = None
+    if header_len == 40 and len(data) >= header_len:
+        (has_time_correlation, wall_clock, rtio_counter,
+         round_trip) = struct.unpack(endian + "?QQQ", data[15:40])
+        if has_time_correlation:
+            time_correlation = TimeCorrelation(wall_clock, rtio_counter,
+                                               round_trip)
+
+    expected_len = sent_bytes + header_len
     if expected_len != len(data):
         raise ValueError("analyzer dump has incorrect length "
                          "(got {}, expected {})".format(
@@ -134,7 +147,7 @@ def decode_dump(data):
     if sent_bytes == 0:
         logger.warning("analyzer dump is empty")
 
-    position = 15
+    position = header_len
     messages = []
     for _ in range(sent_bytes//32):
         messages.append(decode_message(data[position:position+32]))
@@ -143,7 +156,25 @@ def decode_dump(data):
     if len(messages) == 1 and isinstance(messages[0], StoppedMessage):
         logger.warning("analyzer dump is empty aside from stop message")
 
-    return DecodedDump(log_channel, bool(dds_onehot_sel), messages)
+    return DecodedDump(log_channel, bool(dds_onehot_sel), messages,
+                       time_correlation)
+
+
+def upgrade_dump(data):
+    """Converts a dump from older firmware, whose header has no time
+    correlation, to the current format. Other dumps are returned as they
+    are."""
+    if data[0] == ord('E'):
+        endian = '>'
+    elif data[0] == ord('e'):
+        endian = '<'
+    else:
+        raise ValueError
+    sent_bytes = struct.unpack(endian + "I", data[1:5])[0]
+    if len(data) != 1 + 15 + sent_bytes:
+        return data
+    return (data[:16] + struct.pack(endian + "?QQQ", False, 0, 0, 0)
+            + data[16:])
 
 
 # simplified from sipyco broadcast Receiver
@@ -197,8 +228,9 @@ class AnalyzerProxyReceiver:
                     # 10x buffer size of firmware
                     raise ValueError
 
-                # The remaining header length is 11 bytes.
-                remaining_data = await self.reader.readexactly(payload_length + 11)
+                # The remaining header length is 36 bytes. The proxy upgrades
+                # dumps from older firmware to this header.
+                remaining_data = await self.reader.readexactly(payload_length + 36)
                 data = endian_byte + payload_length_word + remaining_data
                 self.receive_cb(data)
         except Exception:
@@ -0,0 +1,44 @@
+"""Authentication of session and management connections to the core device.
+
+When the ``auth_key`` config key of the core device is set, the core device
+answers the magic with a challenge (``A`` and a nonce) instead of the
+endianness byte. The host replies with the HMAC-SHA256 of the nonce keyed by
+the same key, and the core device closes the connection if it is wrong.
+"""
+
+import hmac
+import hashlib
+
+
+CHALLENGE = b"A"
+NONCE_LENGTH = 32
+
+
+def read_key(filename):
+    """Reads an authentication key from a file, or returns ``None`` if
+    ``filename`` is ``None``."""
+    if filename is None:
+        return None
+    with open(filename, "rb") as f:
+        return f.read()
+
+
+def answer(key, nonce):
+    return hmac.new(key, nonce, hashlib.sha256).digest()
+
+
+def handshake(read, write, key):
+    """Answers the challenge of the core device, if any, after the magic was
+    sent, and returns the endianness byte.
+
+    ``read`` and ``write`` read from and write to the connection."""
+    reply = read(1)
+    if reply != CHALLENGE:
+        return reply
+    if key is None:
+        raise IOError("Core device requires an authentication key")
+    write(answer(key, read(NONCE_LENGTH)))
+    try:
+        return read(1)
+    except ConnectionResetError:
+        raise IOError("Core device refused the authentication key") from None
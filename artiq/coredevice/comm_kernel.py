@@ -7,7 +7,7 @@ from enum import Enum
 from fractions import Fraction
 from collections import namedtuple
 
-from artiq.coredevice import exceptions
+from artiq.coredevice import exceptions, comm_auth
 from artiq import __version__ as software_version
 from sipyco.keepalive import create_connection
 
@@ -155,9 +155,25 @@ receivers = {
 }
 
 
+class TimeCorrelation(namedtuple("TimeCorrelation",
+                                 "wall_clock rtio_counter round_trip")):
+    """Wall-clock time at a value of the RTIO counter, measured by the SNTP
+    client of the core device (see the ``ntp_server`` config key).
+
+    ``wall_clock`` is in nanoseconds since the Unix epoch. ``rtio_counter``
+    and ``round_trip`` are in machine units, and the wall clock is exact
+    within half of ``round_trip``."""
+
+    def to_unix_ns(self, rtio_counter, ref_period):
+        """Convert a RTIO timestamp in machine units to nanoseconds since
+        the Unix epoch (UTC)."""
+        return (self.wall_clock
+                + round((rtio_counter - self.rtio_counter)*ref_period*1e9))
+
+
 class CommKernelDummy:
     def __init__(self):
-        pass
+        self.time_correlation = None
 
     def load(self, kernel_library):
         pass
@@ -185,12 +201,14 @@ def incompatible_versions(v1, v2):
 class CommKernel:
     warned_of_mismatch = False
 
-    def __init__(self, host, port=1381):
+    def __init__(self, host, port=1381, auth_key=None):
         self._read_type = None
         self.host = host
         self.port = port
+        self.auth_key = auth_key
         self.read_buffer = bytearray()
         self.write_buffer = bytearray()
+        self.time_correlation = None
 
 
     def open(self):
@@ -198,7 +216,8 @@ class CommKernel:
             return
         self.socket = create_connection(self.host, self.port)
         self.socket.sendall(b"ARTIQ coredev\n")
-        endian = self._read(1)
+        endian = comm_auth.handshake(self._read, self.socket.sendall,
+                                     self.auth_key)
         if endian == b"e":
             self.endian = "<"
         elif endian == b"E":
@@ -368,6 +387,16 @@ class CommKernel:
             finished_cleanly = self._read_bool()
             if not finished_cleanly:
                 logger.warning("Previous kernel did not cleanly finish")
+
+            # Added in ARTIQ-8, without a version field: the host software
+            # and the firmware must be upgraded together (see the release
+            # notes). Older firmware would leave this read waiting.
+            has_time_correlation = self._read_bool()
+            time_correlation = TimeCorrelation(self._read_int64(),
+                                               self._read_int64(),
+                                               self._read_int64())
+            if has_time_correlation:
+                self.time_correlation = time_correlation
         elif runtime_id == b"ARZQ":
             pass
         else:
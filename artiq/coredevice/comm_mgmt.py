@@ -1,9 +1,12 @@
 from enum import Enum
+from collections import namedtuple
 import logging
 import struct
 
 from sipyco.keepalive import create_connection
 
+from artiq.coredevice import comm_auth
+
 logger = logging.getLogger(__name__)
 
 
@@ -11,30 +14,81 @@ class Request(Enum):
     GetLog = 1
     ClearLog = 2
     PullLog = 7
+    PullLogRecords = 9
     SetLogFilter = 3
     SetUartLogFilter = 6
+    SetLogDirectives = 10
+    SetUartLogDirectives = 11
 
     ConfigRead = 12
     ConfigWrite = 13
     ConfigRemove = 14
     ConfigErase = 15
+    ConfigHealth = 21
+    ConfigList = 22
+    ConfigExport = 23
+    ConfigImport = 24
+    ConfigTransaction = 25
+
+    FlashKernelWrite = 26
+    FlashKernelList = 27
+    FlashKernelRemove = 28
+    FlashKernelCheck = 29
 
     Reboot = 5
 
     DebugAllocator = 8
 
+    FlashBegin = 16
+    FlashData = 17
+    FlashEnd = 18
+
+    GetBootStatus = 19
+    ConfirmBoot = 20
+
+    GetCrashDump = 30
+    ClearCrashDump = 31
+
+    GetNetworkStatus = 32
+
 
 class Reply(Enum):
     Success = 1
     Error = 6
     Unavailable = 4
+    ErrorMessage = 8
 
     LogContent = 2
+    LogRecord = 14
+    LogGap = 15
 
     ConfigData = 7
+    ConfigHealth = 11
+    ConfigList = 12
+
+    FlashKernelList = 13
 
     RebootImminent = 3
 
+    FlashProgress = 9
+
+    BootStatus = 10
+
+    CrashDump = 16
+
+    NetworkStatus = 17
+
+
+class FlashImage(Enum):
+    Firmware = 0
+    Gateware = 1
+
+
+class Ipv4Config(Enum):
+    Static = 0
+    Dhcp = 1
+    Fallback = 2
+
 
 class LogLevel(Enum):
     OFF = 0
@@ -45,17 +99,35 @@ class LogLevel(Enum):
     TRACE = 5
 
 
+# Core device log message; timestamp is in microseconds since startup.
+LogRecord = namedtuple("LogRecord", "sequence timestamp level target message")
+# Messages with sequence numbers from start (included) to end (excluded)
+# were lost on the core device.
+LogGap = namedtuple("LogGap", "start end")
+# DHCP lease of the core device; remaining is the remaining time in seconds,
+# or None for an infinite lease.
+DhcpLease = namedtuple("DhcpLease", "address server router dns_servers remaining")
+# IPv6 configuration of the core device, including the autoconfigured
+# addresses; router is None without a default route.
+Ipv6Status = namedtuple("Ipv6Status", "addresses router dns_servers")
+# Network configuration of the core device; lease is None without DHCP.
+NetworkStatus = namedtuple("NetworkStatus",
+                           "ipv4_config ipv4_address lease ipv6")
+
+
 class CommMgmt:
-    def __init__(self, host, port=1380):
+    def __init__(self, host, port=1380, auth_key=None):
         self.host = host
         self.port = port
+        self.auth_key = auth_key
 
     def open(self):
         if hasattr(self, "socket"):
             return
         self.socket = create_connection(self.host, self.port)
         self.socket.sendall(b"ARTIQ management\n")
-        endian = self._read(1)
+        endian = comm_auth.handshake(self._read, self.socket.sendall,
+                                     self.auth_key)
         if endian == b"e":
             self.endian = "<"
         elif endian == b"E":
@@ -87,6 +159,9 @@ class CommMgmt:
     def _write_int32(self, value):
         self._write(struct.pack(self.endian + "l", value))
 
+    def _write_int64(self, value):
+        self._write(struct.pack(self.endian + "Q", value))
+
     def _write_bytes(self, value):
         self._write_int32(len(value))
         self._write(value)
@@ -115,16 +190,37 @@ class CommMgmt:
             raise IOError("Incorrect reply from device: {} (expected {})".
                           format(header, ty))
 
+    def _read_expect_or_message(self, ty):
+        header = self._read_header()
+        if header == Reply.ErrorMessage:
+            raise IOError("Device reported an error: {}".format(self._read_string()))
+        elif header != ty:
+            raise IOError("Incorrect reply from device: {} (expected {})".
+                          format(header, ty))
+
+    def _read_int8(self):
+        (value, ) = struct.unpack("B", self._read(1))
+        return value
+
     def _read_int32(self):
         (value, ) = struct.unpack(self.endian + "l", self._read(4))
         return value
 
+    def _read_int64(self):
+        (value, ) = struct.unpack(self.endian + "Q", self._read(8))
+        return value
+
     def _read_bytes(self):
         return self._read(self._read_int32())
 
     def _read_string(self):
         return self._read_bytes().decode("utf-8")
 
+    def _read_optional_string(self):
+        if self._read_int8():
+            return self._read_string()
+        return None
+
     # External API
 
     def get_log(self):
@@ -141,6 +237,33 @@ class CommMgmt:
         self._read_expect(Reply.LogContent)
         return self._read_string()
 
+    def pull_log_records(self, start=0):
+        """Stream the core device log, from the message with sequence
+        number ``start`` onwards, including messages logged later.
+
+        Yields :class:`LogRecord` tuples, and :class:`LogGap` tuples for
+        messages that were lost, for example because they were overwritten
+        in the log buffer before being sent. Trace messages of the network
+        stack (``smoltcp`` targets) are skipped, as sending them would log
+        more of them. To resume after the connection is lost, pass the
+        sequence number following the last received record."""
+        self._write_header(Request.PullLogRecords)
+        self._write_int64(start)
+        while True:
+            ty = self._read_header()
+            if ty == Reply.LogRecord:
+                sequence = self._read_int64()
+                timestamp = self._read_int64()
+                level = LogLevel(self._read_int8())
+                target = self._read_string()
+                message = self._read_string()
+                yield LogRecord(sequence, timestamp, level, target, message)
+            elif ty == Reply.LogGap:
+                yield LogGap(self._read_int64(), self._read_int64())
+            else:
+                raise IOError("Incorrect reply from device: {} (expected {})".
+                              format(ty, Reply.LogRecord))
+
     def set_log_level(self, level):
         if level not in LogLevel.__members__:
             raise ValueError("invalid log level {}".format(level))
@@ -157,6 +280,21 @@ class CommMgmt:
         self._write_int8(getattr(LogLevel, level).value)
         self._read_expect(Reply.Success)
 
+    def set_log_filter(self, directives):
+        """Set the log levels of individual modules, such as
+        ``runtime::rtio_mgt=TRACE,runtime::session=DEBUG``, overriding the
+        log level for them. An empty string removes the filter."""
+        self._write_header(Request.SetLogDirectives)
+        self._write_string(directives)
+        self._read_expect_or_message(Reply.Success)
+
+    def set_uart_log_filter(self, directives):
+        """Same as :meth:`set_log_filter`, for the messages printed
+        to the UART."""
+        self._write_header(Request.SetUartLogDirectives)
+        self._write_string(directives)
+        self._read_expect_or_message(Reply.Success)
+
     def config_read(self, key):
         self._write_header(Request.ConfigRead)
         self._write_string(key)
@@ -175,6 +313,8 @@ class CommMgmt:
         ty = self._read_header()
         if ty == Reply.Error:
             raise IOError("Device failed to write config. More information may be available in the log.")
+        elif ty == Reply.ErrorMessage:
+            raise IOError("Device rejected config: {}".format(self._read_string()))
         elif ty != Reply.Success:
             raise IOError("Incorrect reply from device: {} (expected {})".
                           format(ty, Reply.Success))
@@ -188,9 +328,187 @@ class CommMgmt:
         self._write_header(Request.ConfigErase)
         self._read_expect(Reply.Success)
 
+    def config_health(self):
+        """Check the records in the core device configuration storage.
+
+        Returns a dictionary with the number of intact records, the offsets
+        of damaged records (which the device skips), and the free and
+        reclaimable space in bytes."""
+        self._write_header(Request.ConfigHealth)
+        self._read_expect_or_message(Reply.ConfigHealth)
+        records = self._read_int32()
+        damaged = [self._read_int32() for _ in range(self._read_int32())]
+        free = self._read_int32()
+        reclaimable = self._read_int32()
+        return {
+            "records": records,
+            "damaged": damaged,
+            "free": free,
+            "reclaimable": reclaimable
+        }
+
+    def config_list(self, values=False):
+        """List the keys in the core device configuration.
+
+        Returns a list of (key, length, value) tuples in the order the keys
+        were last written. The value is ``None`` unless ``values`` is set."""
+        self._write_header(Request.ConfigList)
+        self._write_int8(values)
+        self._read_expect_or_message(Reply.ConfigList)
+        entries = []
+        for _ in range(self._read_int32()):
+            key = self._read_string()
+            length = self._read_int32()
+            value = self._read_bytes() if self._read_int8() else None
+            entries.append((key, length, value))
+        return entries
+
+    def config_transaction(self, changes):
+        """Write and remove several keys at once. ``changes`` is a list of
+        (key, value) pairs, where a value of ``None`` removes the key.
+        If the core device loses power while writing, none of the changes
+        take effect."""
+        self._write_header(Request.ConfigTransaction)
+        self._write_int32(len(changes))
+        for key, value in changes:
+            if value is None:
+                self._write_int8(1)
+                self._write_string(key)
+            else:
+                self._write_int8(0)
+                self._write_string(key)
+                self._write_bytes(value)
+        self._read_expect_or_message(Reply.Success)
+
+    def config_export(self):
+        """Return a backup of the core device configuration, for use with
+        :meth:`config_import`."""
+        self._write_header(Request.ConfigExport)
+        self._read_expect_or_message(Reply.ConfigData)
+        return self._read_bytes()
+
+    def config_import(self, backup):
+        """Replace the core device configuration with a backup made by
+        :meth:`config_export`. The device checks the backup before writing
+        it, and keeps the current configuration if it is invalid."""
+        self._write_header(Request.ConfigImport)
+        self._write_bytes(backup)
+        self._read_expect_or_message(Reply.Success)
+
+    def flash_kernel_write(self, name, kernel):
+        """Store a kernel (ELF or TAR) in the flash kernel partition,
+        replacing any kernel called ``name``. The session loads the
+        ``startup_kernel`` and ``idle_kernel`` kernels from there."""
+        self._write_header(Request.FlashKernelWrite)
+        self._write_string(name)
+        self._write_bytes(kernel)
+        self._read_expect_or_message(Reply.Success)
+
+    def flash_kernel_list(self):
+        """List the kernels in the flash kernel partition.
+
+        Returns a list of (name, length) tuples, and the number of bytes
+        left for new kernels."""
+        self._write_header(Request.FlashKernelList)
+        self._read_expect_or_message(Reply.FlashKernelList)
+        kernels = []
+        for _ in range(self._read_int32()):
+            name = self._read_string()
+            length = self._read_int32()
+            kernels.append((name, length))
+        return kernels, self._read_int32()
+
+    def flash_kernel_remove(self, name):
+        self._write_header(Request.FlashKernelRemove)
+        self._write_string(name)
+        self._read_expect_or_message(Reply.Success)
+
+    def flash_kernel_check(self, kernel):
+        """Check that a kernel (ELF or TAR) can be loaded by the core
+        device, without storing it. Raises ``IOError`` describing the
+        problem otherwise."""
+        self._write_header(Request.FlashKernelCheck)
+        self._write_bytes(kernel)
+        self._read_expect_or_message(Reply.Success)
+
     def reboot(self):
         self._write_header(Request.Reboot)
         self._read_expect(Reply.RebootImminent)
 
     def debug_allocator(self):
         self._write_header(Request.DebugAllocator)
+
+    def flash(self, image, data, chunk_size=4096, progress=None):
+        """Write a firmware (.fbi) or slave FPGA gateware image, header
+        included, to the core device flash. The device verifies the image
+        after writing it; the new image is used after the next reboot."""
+        self._write_header(Request.FlashBegin)
+        self._write_int8(image.value)
+        self._write_int32(len(data))
+        self._read_expect_or_message(Reply.Success)
+
+        for offset in range(0, len(data), chunk_size):
+            self._write_header(Request.FlashData)
+            self._write_bytes(data[offset:offset + chunk_size])
+            self._read_expect_or_message(Reply.FlashProgress)
+            written = self._read_int32()
+            total = self._read_int32()
+            if progress is not None:
+                progress(written, total)
+
+        self._write_header(Request.FlashEnd)
+        self._read_expect_or_message(Reply.Success)
+
+    def boot_status(self):
+        """Return the active firmware slot ("A" or "B"), whether it is
+        booting on trial, and the number of trial boots so far."""
+        self._write_header(Request.GetBootStatus)
+        self._read_expect(Reply.BootStatus)
+        slot = "AB"[self._read_int8()]
+        trial = bool(self._read_int8())
+        attempts = self._read_int8()
+        return slot, trial, attempts
+
+    def confirm_boot(self):
+        self._write_header(Request.ConfirmBoot)
+        self._read_expect(Reply.Success)
+
+    def get_crash_dump(self):
+        """Return the report of the last crash of the runtime, or ``None``
+        if there is none."""
+        self._write_header(Request.GetCrashDump)
+        header = self._read_header()
+        if header == Reply.Unavailable:
+            return None
+        if header != Reply.CrashDump:
+            raise IOError("Incorrect reply from device: {} (expected {})".
+                          format(header, Reply.CrashDump))
+        return self._read_string()
+
+    def clear_crash_dump(self):
+        self._write_header(Request.ClearCrashDump)
+        self._read_expect(Reply.Success)
+
+    def network_status(self):
+        """Return the network configuration of the core device, as a
+        :class:`NetworkStatus`."""
+        self._write_header(Request.GetNetworkStatus)
+        self._read_expect(Reply.NetworkStatus)
+        ipv4_config = Ipv4Config(self._read_int8())
+        ipv4_address = self._read_optional_string()
+        lease = None
+        if self._read_int8():
+            address = self._read_string()
+            server = self._read_string()
+            router = self._read_optional_string()
+            dns_servers = [self._read_string()
+                           for _ in range(self._read_int32())]
+            (remaining, ) = struct.unpack(self.endian + "L", self._read(4))
+            if remaining == 0xffffffff:
+                remaining = None
+            lease = DhcpLease(address, server, router, dns_servers, remaining)
+        addresses = [self._read_string() for _ in range(self._read_int32())]
+        router = self._read_optional_string()
+        dns_servers = [self._read_string() for _ in range(self._read_int32())]
+        ipv6 = Ipv6Status(addresses, router, dns_servers)
+        return NetworkStatus(ipv4_config, ipv4_address, lease, ipv6)
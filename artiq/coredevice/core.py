@@ -16,6 +16,7 @@ from artiq.compiler.embedding import Stitcher
 from artiq.compiler.targets import RV32IMATarget, RV32GTarget, CortexA9Target
 
 from artiq.coredevice.comm_kernel import CommKernel, CommKernelDummy
+from artiq.coredevice.comm_auth import read_key
 # Import for side effects (creating the exception classes).
 from artiq.coredevice import exceptions
 
@@ -82,6 +83,9 @@ class Core:
         (optional).
     :param analyze_at_run_end: automatically trigger the core device analyzer
         proxy after the Experiment's run stage finishes.
+    :param auth_key_file: file containing the key to answer the
+        authentication challenge of the core device with, if its
+        ``auth_key`` config key is set (optional).
     """
 
     kernel_invariants = {
@@ -92,7 +96,8 @@ class Core:
                  host, ref_period,
                  analyzer_proxy=None, analyze_at_run_end=False,
                  ref_multiplier=8,
-                 target="rv32g", satellite_cpu_targets={}):
+                 target="rv32g", satellite_cpu_targets={},
+                 auth_key_file=None):
         self.ref_period = ref_period
         self.ref_multiplier = ref_multiplier
         self.satellite_cpu_targets = satellite_cpu_targets
@@ -101,7 +106,7 @@ class Core:
         if host is None:
             self.comm = CommKernelDummy()
         else:
-            self.comm = CommKernel(host)
+            self.comm = CommKernel(host, auth_key=read_key(auth_key_file))
         self.analyzer_proxy_name = analyzer_proxy
         self.analyze_at_run_end = analyze_at_run_end
 
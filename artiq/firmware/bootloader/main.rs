@@ -8,10 +8,10 @@ extern crate smoltcp;
 extern crate board_misoc;
 extern crate riscv;
 
-use core::{ptr, slice, convert::TryFrom};
+use core::{cmp, fmt, ptr, slice, convert::TryFrom};
 use crc::crc32;
 use byteorder::{ByteOrder, LittleEndian};
-use board_misoc::{ident, cache, sdram, config, boot, mem as board_mem};
+use board_misoc::{ident, cache, sdram, config, boot, boot_control, flash_image, mem as board_mem};
 #[cfg(has_slave_fpga_cfg)]
 use board_misoc::slave_fpga;
 #[cfg(has_ethmac)]
@@ -128,11 +128,11 @@ fn load_slave_fpga() {
     let magic = LittleEndian::read_u32(&header[0..]);
     let length = LittleEndian::read_u32(&header[4..]) as usize;
     println!("  magic: 0x{:08x}, length: 0x{:08x}", magic, length);
-    if magic != 0x5352544d {
+    if magic != flash_image::GATEWARE_MAGIC {
         println!("  ...Error: bad magic");
         return
     }
-    if length > 0x220000 {
+    if length > flash_image::GATEWARE_MAX_SIZE {
         println!("  ...Error: too long (corrupted?)");
         return
     }
@@ -154,25 +154,42 @@ fn load_slave_fpga() {
     println!("  ...done");
 }
 
-fn flash_boot() {
-    const FIRMWARE: *mut u8 = board_mem::FLASH_BOOT_ADDRESS as *mut u8;
-    const MAIN_RAM: *mut u8 = board_mem::MAIN_RAM_BASE as *mut u8;
+fn check_signature(firmware: &[u8], signature: Option<&[u8]>) -> bool {
+    match flash_image::check_signature(firmware, signature) {
+        Ok(flash_image::Signature::NotRequired) => true,
+        Ok(flash_image::Signature::Verified) => {
+            println!("Firmware signature verified");
+            true
+        }
+        Ok(flash_image::Signature::Allowed(e)) => {
+            println!("Starting firmware although {}, as allowed by `allow_unsigned_firmware`", e);
+            true
+        }
+        Err(e) => {
+            println!("Not starting firmware: {}", e);
+            false
+        }
+    }
+}
 
-    println!("Booting from flash...");
+// Copies the firmware in `slot` to SDRAM and checks it; returns whether it can be started.
+fn load_firmware(slot: boot_control::Slot) -> bool {
+    let firmware = slot.address() as *mut u8;
+    const MAIN_RAM: *mut u8 = board_mem::MAIN_RAM_BASE as *mut u8;
 
-    let header = unsafe { slice::from_raw_parts(FIRMWARE, 8) };
+    let header = unsafe { slice::from_raw_parts(firmware, 8) };
     let length = LittleEndian::read_u32(&header[0..]) as usize;
     let expected_crc = LittleEndian::read_u32(&header[4..]);
 
     if length == 0 || length == 0xffffffff {
-        println!("No firmware present");
-        return
-    } else if length > 4 * 1024 * 1024 {
-        println!("Firmware too large (is it corrupted?)");
-        return
+        println!("No firmware present in slot {}", slot);
+        return false
+    } else if length > flash_image::FIRMWARE_MAX_SIZE {
+        println!("Firmware in slot {} too large (is it corrupted?)", slot);
+        return false
     }
 
-    let firmware_in_flash = unsafe { slice::from_raw_parts(FIRMWARE.offset(8), length) };
+    let firmware_in_flash = unsafe { slice::from_raw_parts(firmware.offset(8), length) };
     let actual_crc_flash = crc32::checksum_ieee(firmware_in_flash);
 
     if actual_crc_flash == expected_crc {
@@ -181,15 +198,50 @@ fn flash_boot() {
 
         let actual_crc_sdram = crc32::checksum_ieee(firmware_in_sdram);
         if actual_crc_sdram == expected_crc {
-            println!("Starting firmware.");
-            unsafe { boot::jump(MAIN_RAM as usize) }
+            let signature = unsafe { flash_image::signature_after(firmware.offset(8), length) };
+            return check_signature(firmware_in_sdram, signature)
         } else {
             println!("Firmware CRC failed in SDRAM (actual {:08x}, expected {:08x})",
                      actual_crc_sdram, expected_crc);
         }
     } else {
-        println!("Firmware CRC failed in flash (actual {:08x}, expected {:08x})",
-                 actual_crc_flash, expected_crc);
+        println!("Firmware CRC failed in flash, slot {} (actual {:08x}, expected {:08x})",
+                 slot, actual_crc_flash, expected_crc);
+    }
+    false
+}
+
+fn start_firmware() -> ! {
+    println!("Starting firmware.");
+    unsafe { boot::jump(board_mem::MAIN_RAM_BASE) }
+}
+
+fn flash_boot() {
+    println!("Booting from flash...");
+
+    let mut state = boot_control::read();
+    if state.trial {
+        if state.attempts >= boot_control::MAX_TRIAL_ATTEMPTS {
+            println!("Firmware in slot {} not confirmed after {} attempts, reverting to slot {}",
+                     state.active, state.attempts, state.active.other());
+            state = boot_control::State::confirmed(state.active.other());
+        } else {
+            state.attempts += 1;
+            println!("Trying firmware in slot {} (attempt {} of {})",
+                     state.active, state.attempts, boot_control::MAX_TRIAL_ATTEMPTS);
+        }
+        boot_control::write(state);
+    }
+
+    if load_firmware(state.active) {
+        start_firmware()
+    }
+
+    let fallback = state.active.other();
+    println!("Falling back to firmware in slot {}", fallback);
+    if load_firmware(fallback) {
+        boot_control::write(boot_control::State::confirmed(fallback));
+        start_firmware()
     }
 }
 
@@ -198,8 +250,8 @@ enum NetConnState {
     WaitCommand,
     FirmwareLength(usize, u8),
     FirmwareDownload(usize, usize),
-    FirmwareWaitO,
-    FirmwareWaitK,
+    FirmwareWaitO(usize),
+    FirmwareWaitK(usize),
     #[cfg(has_slave_fpga_cfg)]
     GatewareLength(usize, u8),
     #[cfg(has_slave_fpga_cfg)]
@@ -207,13 +259,37 @@ enum NetConnState {
     #[cfg(has_slave_fpga_cfg)]
     GatewareWaitO,
     #[cfg(has_slave_fpga_cfg)]
-    GatewareWaitK
+    GatewareWaitK,
+    InstallImage,
+    InstallLength(flash_image::Kind, usize, u8),
+    InstallDownload,
+    InstallWaitO,
+    InstallWaitK
+}
+
+// A status line for the client; it must fit into the socket transmit buffer.
+#[cfg(has_ethmac)]
+struct NetReply {
+    data: [u8; 128],
+    length: usize
+}
+
+#[cfg(has_ethmac)]
+impl fmt::Write for NetReply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let size = cmp::min(s.len(), self.data.len() - self.length);
+        self.data[self.length..self.length + size].copy_from_slice(&s.as_bytes()[..size]);
+        self.length += size;
+        Ok(())
+    }
 }
 
 #[cfg(has_ethmac)]
 struct NetConn {
     state: NetConnState,
-    firmware_downloaded: bool
+    firmware_length: Option<usize>,
+    installer: Option<flash_image::Writer>,
+    reply: NetReply
 }
 
 #[cfg(has_ethmac)]
@@ -221,13 +297,31 @@ impl NetConn {
     pub fn new() -> NetConn {
         NetConn {
             state: NetConnState::WaitCommand,
-            firmware_downloaded: false
+            firmware_length: None,
+            installer: None,
+            reply: NetReply { data: [0; 128], length: 0 }
         }
     }
 
     pub fn reset(&mut self) {
         self.state = NetConnState::WaitCommand;
-        self.firmware_downloaded = false;
+        self.firmware_length = None;
+        self.installer = None;
+    }
+
+    // Prints a message, and also sends it to the client.
+    fn report(&mut self, args: fmt::Arguments) {
+        use core::fmt::Write;
+
+        println!("{}", args);
+        let _ = self.reply.write_fmt(args);
+        let _ = self.reply.write_str("\n");
+    }
+
+    pub fn take_reply(&mut self) -> Option<&[u8]> {
+        let length = self.reply.length;
+        self.reply.length = 0;
+        if length > 0 { Some(&self.reply.data[..length]) } else { None }
     }
 
     // buf must contain at least one byte
@@ -247,9 +341,23 @@ impl NetConn {
                         self.state = NetConnState::GatewareLength(0, 0);
                         Ok(1)
                     }
+                    b'I' => {
+                        println!("Received install command");
+                        self.state = NetConnState::InstallImage;
+                        Ok(1)
+                    }
                     b'B' => {
-                        if self.firmware_downloaded {
+                        if let Some(firmware_length) = self.firmware_length {
                             println!("Received boot command");
+                            let firmware = unsafe {
+                                slice::from_raw_parts(board_mem::MAIN_RAM_BASE as *const u8,
+                                                      firmware_length)
+                            };
+                            let (firmware, signature) = flash_image::split_signature(firmware);
+                            if !check_signature(firmware, signature) {
+                                println!("Refusing to boot downloaded firmware");
+                                return Err(())
+                            }
                             boot_callback();
                             self.state = NetConnState::WaitCommand;
                             Ok(1)
@@ -289,27 +397,27 @@ impl NetConn {
 
                 let recv_bytes = recv_bytes + length;
                 if recv_bytes == firmware_length {
-                    self.state = NetConnState::FirmwareWaitO;
+                    self.state = NetConnState::FirmwareWaitO(firmware_length);
                     Ok(length)
                 } else {
                     self.state = NetConnState::FirmwareDownload(firmware_length, recv_bytes);
                     Ok(length)
                 }
             },
-            NetConnState::FirmwareWaitO => {
+            NetConnState::FirmwareWaitO(firmware_length) => {
                 if buf[0] == b'O' {
-                    self.state = NetConnState::FirmwareWaitK;
+                    self.state = NetConnState::FirmwareWaitK(firmware_length);
                     Ok(1)
                 } else {
                     println!("End-of-firmware confirmation failed");
                     Err(())
                 }
             },
-            NetConnState::FirmwareWaitK => {
+            NetConnState::FirmwareWaitK(firmware_length) => {
                 if buf[0] == b'K' {
                     println!("Firmware successfully downloaded");
                     self.state = NetConnState::WaitCommand;
-                    self.firmware_downloaded = true;
+                    self.firmware_length = Some(firmware_length);
                     Ok(1)
                 } else {
                     println!("End-of-firmware confirmation failed");
@@ -381,6 +489,92 @@ impl NetConn {
                     Err(())
                 }
             }
+
+            NetConnState::InstallImage => {
+                let kind = match buf[0] {
+                    b'F' => flash_image::Kind::Firmware,
+                    b'G' => flash_image::Kind::Gateware,
+                    ty => {
+                        self.report(format_args!("ERROR unknown image type 0x{:02x}", ty));
+                        return Err(())
+                    }
+                };
+                self.state = NetConnState::InstallLength(kind, 0, 0);
+                Ok(1)
+            },
+            NetConnState::InstallLength(kind, image_length, recv_bytes) => {
+                let image_length = (image_length << 8) | (buf[0] as usize);
+                let recv_bytes = recv_bytes + 1;
+                if recv_bytes == 4 {
+                    match flash_image::Writer::new(kind, image_length) {
+                        Ok(writer) => {
+                            println!("Installing {} ({} bytes)", kind, image_length);
+                            self.installer = Some(writer);
+                            self.state = NetConnState::InstallDownload;
+                        }
+                        Err(e) => {
+                            self.report(format_args!("ERROR cannot install {}: {}", kind, e));
+                            return Err(())
+                        }
+                    }
+                } else {
+                    self.state = NetConnState::InstallLength(kind, image_length, recv_bytes);
+                }
+                Ok(1)
+            },
+            NetConnState::InstallDownload => {
+                let result = {
+                    let writer = self.installer.as_mut().unwrap();
+                    let length = cmp::min(writer.length() - writer.written(), buf.len());
+                    writer.write(&buf[..length])
+                        .map(|()| (length, writer.written() == writer.length()))
+                };
+                match result {
+                    Ok((length, done)) => {
+                        if done {
+                            self.state = NetConnState::InstallWaitO;
+                        }
+                        Ok(length)
+                    }
+                    Err(e) => {
+                        self.report(format_args!("ERROR {}", e));
+                        Err(())
+                    }
+                }
+            },
+            NetConnState::InstallWaitO => {
+                if buf[0] == b'O' {
+                    self.state = NetConnState::InstallWaitK;
+                    Ok(1)
+                } else {
+                    println!("End-of-image confirmation failed");
+                    Err(())
+                }
+            },
+            NetConnState::InstallWaitK => {
+                if buf[0] != b'K' {
+                    println!("End-of-image confirmation failed");
+                    return Err(())
+                }
+                self.state = NetConnState::WaitCommand;
+                let writer = self.installer.take().unwrap();
+                let (kind, slot) = (writer.kind(), writer.slot());
+                match (writer.finish(), slot) {
+                    (Ok(()), Some(slot)) => {
+                        self.report(format_args!("OK {} installed to slot {}, reboot to try it",
+                                                 kind, slot));
+                        Ok(1)
+                    }
+                    (Ok(()), None) => {
+                        self.report(format_args!("OK {} installed, reboot to use it", kind));
+                        Ok(1)
+                    }
+                    (Err(e), _) => {
+                        self.report(format_args!("ERROR {}", e));
+                        Err(())
+                    }
+                }
+            }
         }
     }
 
@@ -462,9 +656,13 @@ fn network_boot() {
                     }
 
                     if socket.may_recv() {
-                        if socket.recv(|data| {
+                        let failed = socket.recv(|data| {
                                     (data.len(), net_conn.input(data, || { boot_time = Some(timestamp + 20); }).is_err())
-                                }).unwrap() {
+                                }).unwrap();
+                        if let Some(reply) = net_conn.take_reply() {
+                            let _ = socket.send_slice(reply);
+                        }
+                        if failed {
                             net_conn.reset();
                             socket.close();
                         }
@@ -0,0 +1,132 @@
+use core::slice;
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+use cache;
+use spiflash;
+use crash_dump;
+
+pub use boot_state::{Slot, State};
+
+// Two firmware slots follow each other from FLASH_BOOT_ADDRESS. Boot-control
+// records are kept in two sectors: the one following the second slot, and the
+// one following the crash report sector. Records carry a sequence number, and
+// the valid record with the newest one is in effect. Records are appended to
+// the sector holding it; when that sector is full, the other sector is erased
+// and written to instead. The sector holding the record in effect is never
+// erased, so a power failure loses at most the record being written.
+pub const SLOT_SIZE: usize = 0x200000;
+
+/// Number of boots a new firmware gets to confirm itself before the
+/// bootloader reverts to the other slot.
+pub const MAX_TRIAL_ATTEMPTS: u8 = 3;
+
+const SECTORS: [usize; 2] = [
+    ::mem::FLASH_BOOT_ADDRESS + 2 * SLOT_SIZE,
+    crash_dump::END_ADDRESS
+];
+const SIZE: usize = spiflash::SECTOR_SIZE;
+
+const MAGIC:       u32   = 0x4c544342; // "BCTL"
+const RECORD_SIZE: usize = 16;
+
+impl Slot {
+    pub fn address(&self) -> usize {
+        match self {
+            &Slot::A => ::mem::FLASH_BOOT_ADDRESS,
+            &Slot::B => ::mem::FLASH_BOOT_ADDRESS + SLOT_SIZE
+        }
+    }
+}
+
+impl State {
+    fn encode(&self, sequence: u32) -> [u8; RECORD_SIZE] {
+        let mut record = [0; RECORD_SIZE];
+        LittleEndian::write_u32(&mut record[0..], MAGIC);
+        record[4] = match self.active { Slot::A => 0, Slot::B => 1 };
+        record[5] = self.trial as u8;
+        record[6] = self.attempts;
+        LittleEndian::write_u32(&mut record[8..], sequence);
+        let crc = crc32::checksum_ieee(&record[..12]);
+        LittleEndian::write_u32(&mut record[12..], crc);
+        record
+    }
+
+    fn decode(record: &[u8]) -> Option<(State, u32)> {
+        if LittleEndian::read_u32(&record[0..]) != MAGIC ||
+                LittleEndian::read_u32(&record[12..]) != crc32::checksum_ieee(&record[..12]) {
+            return None
+        }
+        let active = match record[4] {
+            0 => Slot::A,
+            1 => Slot::B,
+            _ => return None
+        };
+        let state = State { active: active, trial: record[5] != 0, attempts: record[6] };
+        Some((state, LittleEndian::read_u32(&record[8..])))
+    }
+}
+
+fn data(index: usize) -> &'static [u8] {
+    unsafe { slice::from_raw_parts(SECTORS[index] as *const u8, SIZE) }
+}
+
+fn is_erased(record: &[u8]) -> bool {
+    record.iter().all(|&byte| byte == 0xff)
+}
+
+// Returns the newest valid record, with its sequence number and the index of
+// the sector holding it.
+fn newest() -> Option<(State, u32, usize)> {
+    let mut newest: Option<(State, u32, usize)> = None;
+    for index in 0..SECTORS.len() {
+        for record in data(index).chunks(RECORD_SIZE).take_while(|record| !is_erased(record)) {
+            if record.len() < RECORD_SIZE { break }
+            // Skip records torn by a power failure.
+            if let Some((state, sequence)) = State::decode(record) {
+                if newest.map(|(_, newest_sequence, _)| sequence >= newest_sequence)
+                         .unwrap_or(true) {
+                    newest = Some((state, sequence, index))
+                }
+            }
+        }
+    }
+    newest
+}
+
+/// Returns the boot-control state, or slot A confirmed if none was recorded,
+/// which matches a board flashed over JTAG.
+pub fn read() -> State {
+    newest().map(|(state, _, _)| state).unwrap_or(State::confirmed(Slot::A))
+}
+
+pub fn write(state: State) {
+    let (sequence, index) = match newest() {
+        Some((_, sequence, index)) => (sequence.wrapping_add(1), index),
+        None => (0, 0)
+    };
+    let free = data(index).chunks(RECORD_SIZE)
+        .position(|record| record.len() == RECORD_SIZE && is_erased(record));
+    let (index, offset) = match free {
+        Some(position) => (index, position * RECORD_SIZE),
+        None => {
+            // Switch to the other sector, keeping the record in effect until
+            // the new one is written.
+            let index = 1 - index;
+            unsafe { spiflash::erase_sector(SECTORS[index]) };
+            (index, 0)
+        }
+    };
+    unsafe { spiflash::write(SECTORS[index] + offset, &state.encode(sequence)) };
+    cache::flush_l2_cache();
+}
+
+/// Marks the active slot as good. Returns it if it was on trial.
+pub fn confirm() -> Option<Slot> {
+    let state = read();
+    if state.trial {
+        write(State::confirmed(state.active));
+        Some(state.active)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,57 @@
+use core::fmt;
+
+// The boot-control state, without its storage in flash, so that it can be
+// tested on the host (see `libproto_artiq/tests/boot_state.rs`).
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B
+}
+
+impl Slot {
+    pub fn other(&self) -> Slot {
+        match self {
+            &Slot::A => Slot::B,
+            &Slot::B => Slot::A
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Slot::A => write!(f, "A"),
+            &Slot::B => write!(f, "B")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct State {
+    pub active:   Slot,
+    /// The active slot has not yet been confirmed to boot.
+    pub trial:    bool,
+    pub attempts: u8
+}
+
+impl State {
+    pub fn confirmed(slot: Slot) -> State {
+        State { active: slot, trial: false, attempts: 0 }
+    }
+
+    pub fn trial(slot: Slot) -> State {
+        State { active: slot, trial: true, attempts: 0 }
+    }
+
+    /// The slot that new firmware is written to. While the active slot is on
+    /// trial, the other slot holds the only firmware known to boot, so the
+    /// trial slot is written over again.
+    pub fn update_slot(&self) -> Slot {
+        if self.trial {
+            self.active
+        } else {
+            self.active.other()
+        }
+    }
+}
@@ -1,11 +1,30 @@
 extern crate build_misoc;
 extern crate cc;
 
+use std::env;
+use std::fs;
 use std::path::Path;
 
 fn main() {
     build_misoc::cfg();
 
+    // Firmware must be signed with the Ed25519 key whose 32-byte raw public key
+    // is in this file; without it, signatures are not checked.
+    println!("cargo:rerun-if-env-changed=ARTIQ_FIRMWARE_PUBLIC_KEY");
+    let public_key = match env::var("ARTIQ_FIRMWARE_PUBLIC_KEY") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            let key = fs::read(&path).expect("cannot read firmware public key");
+            assert!(key.len() == 32, "firmware public key must be 32 raw bytes");
+            format!("Some({:?})", key)
+        }
+        Err(_) => String::from("None")
+    };
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("public_key.rs"),
+              format!("const FIRMWARE_PUBLIC_KEY: Option<[u8; 32]> = {};\n", public_key))
+        .unwrap();
+
     let vectors_path = "riscv32/vectors.S";
 
     println!("cargo:rerun-if-changed={}", vectors_path);
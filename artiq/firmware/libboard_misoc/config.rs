@@ -7,6 +7,9 @@ pub enum Error {
     Truncated { offset: usize },
     InvalidSize { offset: usize, size: usize },
     MissingSeparator { offset: usize },
+    ChecksumMismatch { offset: usize },
+    InvalidBackup(&'static str),
+    VerifyFailed,
     Utf8Error(str::Utf8Error),
     NoFlash,
     KeyNotFound
@@ -25,6 +28,12 @@ impl fmt::Display for Error {
                 write!(f, "invalid record size {} at offset {}", size, offset),
             &Error::MissingSeparator { offset } =>
                 write!(f, "missing separator at offset {}", offset),
+            &Error::ChecksumMismatch { offset } =>
+                write!(f, "checksum mismatch at offset {}", offset),
+            &Error::InvalidBackup(reason) =>
+                write!(f, "invalid backup: {}", reason),
+            &Error::VerifyFailed =>
+                write!(f, "flash read-back failed"),
             &Error::Utf8Error(err) =>
                 write!(f, "{}", err),
             &Error::NoFlash =>
@@ -35,13 +44,34 @@ impl fmt::Display for Error {
     }
 }
 
+/// Summary of the state of the store, see `health`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Health {
+    /// Intact records, including overwritten and removed ones.
+    pub records:     usize,
+    /// Damaged records, which are skipped when reading.
+    pub damaged:     usize,
+    /// Bytes available for new records.
+    pub free:        usize,
+    /// Bytes that compacting the store would make available.
+    pub reclaimable: usize
+}
+
+/// A change made as part of a `transaction`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<'a> {
+    Write(&'a str, &'a [u8]),
+    Remove(&'a str)
+}
+
 #[cfg(has_spiflash)]
 mod imp {
-    use core::str;
+    use core::{str, slice};
     use byteorder::{ByteOrder, BigEndian};
+    use crc::crc32;
     use cache;
     use spiflash;
-    use super::Error;
+    use super::{Error, Health, Change};
     use core::fmt;
     use core::fmt::Write;
 
@@ -74,12 +104,96 @@ mod imp {
         }
     }
 
-    // One flash sector immediately before the firmware.
-    const ADDR: usize = ::mem::FLASH_BOOT_ADDRESS - spiflash::SECTOR_SIZE;
+    // The store alternates between two flash sectors: the one immediately
+    // before the firmware, where it has always been, and the one following
+    // the boot-control sector. Each sector starts with a header carrying a
+    // sequence number, and the valid sector with the newest one is in effect.
+    // Compaction copies the live records into the other sector and writes its
+    // header last, so there is always one complete copy of the store in flash,
+    // and every compaction erases the sector that was not in use.
+    //
+    // A sector 0 without a header, as written by older firmware or by
+    // `artiq_flash storage`, is read as the oldest possible copy.
+    const SECTORS: [usize; 2] = [
+        ::mem::FLASH_BOOT_ADDRESS - spiflash::SECTOR_SIZE,
+        ::mem::FLASH_BOOT_ADDRESS + 2 * ::boot_control::SLOT_SIZE + spiflash::SECTOR_SIZE
+    ];
     const SIZE: usize = spiflash::SECTOR_SIZE;
 
+    const MAGIC:       u32   = 0x41434647; // "ACFG"
+    const HEADER_SIZE: usize = 12;
+
+    #[derive(Clone, Copy)]
+    struct Sector {
+        index:    usize,
+        sequence: u32,
+        legacy:   bool
+    }
+
+    impl Sector {
+        fn raw(index: usize) -> &'static [u8] {
+            unsafe { slice::from_raw_parts(SECTORS[index] as *const u8, SIZE) }
+        }
+
+        fn read_header(index: usize) -> Option<u32> {
+            let header = &Sector::raw(index)[..HEADER_SIZE];
+            if BigEndian::read_u32(&header[0..]) == MAGIC &&
+                    BigEndian::read_u32(&header[8..]) == crc32::checksum_ieee(&header[..8]) {
+                Some(BigEndian::read_u32(&header[4..]))
+            } else {
+                None
+            }
+        }
+
+        unsafe fn write_header(index: usize, sequence: u32) {
+            let mut header = [0; HEADER_SIZE];
+            BigEndian::write_u32(&mut header[0..], MAGIC);
+            BigEndian::write_u32(&mut header[4..], sequence);
+            let crc = crc32::checksum_ieee(&header[..8]);
+            BigEndian::write_u32(&mut header[8..], crc);
+            spiflash::write(SECTORS[index], &header);
+            cache::flush_l2_cache();
+        }
+
+        fn active() -> Option<Sector> {
+            let mut active: Option<Sector> = None;
+            for index in 0..SECTORS.len() {
+                if let Some(sequence) = Sector::read_header(index) {
+                    let newer = match active {
+                        None => true,
+                        // Sequence numbers wrap around.
+                        Some(other) => sequence.wrapping_sub(other.sequence) as i32 > 0
+                    };
+                    if newer {
+                        active = Some(Sector { index: index, sequence: sequence, legacy: false })
+                    }
+                }
+            }
+            if active.is_none() && BigEndian::read_u32(Sector::raw(0)) != !0 {
+                active = Some(Sector { index: 0, sequence: 0, legacy: true })
+            }
+            active
+        }
+
+        // Returns the index and sequence number of the sector that takes over
+        // from `active`.
+        fn next(active: Option<Sector>) -> (usize, u32) {
+            match active {
+                Some(sector) => ((sector.index + 1) % SECTORS.len(), sector.sequence.wrapping_add(1)),
+                None => (0, 1)
+            }
+        }
+
+        fn data(&self) -> &'static [u8] {
+            if self.legacy {
+                Sector::raw(self.index)
+            } else {
+                &Sector::raw(self.index)[HEADER_SIZE..]
+            }
+        }
+    }
+
     mod lock {
-        use core::slice;
         use core::sync::atomic::{AtomicUsize, Ordering};
         use super::Error;
 
@@ -95,10 +209,6 @@ mod imp {
                     Ok(Lock)
                 }
             }
-
-            pub fn data(&self) -> &'static [u8] {
-                unsafe { slice::from_raw_parts(super::ADDR as *const u8, super::SIZE) }
-            }
         }
 
         impl Drop for Lock {
@@ -110,15 +220,100 @@ mod imp {
 
     use self::lock::Lock;
 
+    impl Lock {
+        fn active(&self) -> Option<Sector> {
+            Sector::active()
+        }
+
+        fn data(&self) -> &'static [u8] {
+            self.active().map(|sector| sector.data()).unwrap_or(&[])
+        }
+    }
+
+    // Records start with their size, including the size field itself. Records
+    // written by this firmware have the top bit of the size set and end with
+    // a CRC32 of the rest of the record; those written by older firmware or by
+    // artiq_mkfs have neither.
+    //
+    // A checksummed record can also hold a group of records written by
+    // a transaction, in the format without a checksum. Since the group is
+    // covered by one checksum, it is either intact or skipped as a whole.
+    const CHECKSUM_FLAG: u32   = 0x80000000;
+    const GROUP_FLAG:    u32   = 0x40000000;
+    const CHECKSUM_SIZE: usize = 4;
+
+    enum Record<'a> {
+        Entry(&'a [u8], &'a [u8]),
+        Group(&'a [u8])
+    }
+
+    fn parse_record(data: &[u8], offset: usize, checksummed_only: bool)
+            -> Option<Result<(usize, Record), Error>> {
+        if data.len() < 4 {
+            if data.iter().all(|&x| x == 0xff) {
+                // erased flash with no room for another record
+                return None
+            }
+            return Some(Err(Error::Truncated { offset: offset }))
+        }
+
+        let size_field = BigEndian::read_u32(data);
+        if size_field == !0 /* all ones; erased flash */ {
+            return None
+        }
+        let checksummed = size_field & CHECKSUM_FLAG != 0;
+        if checksummed_only && !checksummed {
+            return Some(Err(Error::InvalidSize { offset: offset, size: size_field as usize }))
+        }
+        let group = checksummed && size_field & GROUP_FLAG != 0;
+        let record_size = (size_field & !(CHECKSUM_FLAG | GROUP_FLAG)) as usize;
+        let trailer_size = if checksummed { CHECKSUM_SIZE } else { 0 };
+        if record_size < 4 + trailer_size || record_size > data.len() {
+            return Some(Err(Error::InvalidSize { offset: offset, size: record_size }))
+        }
+
+        let record_body = &data[4..record_size - trailer_size];
+        if checksummed {
+            let expected = BigEndian::read_u32(&data[record_size - CHECKSUM_SIZE..]);
+            let actual = crc32::checksum_ieee(&data[..record_size - CHECKSUM_SIZE]);
+            if actual != expected {
+                return Some(Err(Error::ChecksumMismatch { offset: offset }))
+            }
+        }
+        if group {
+            return Some(Ok((record_size, Record::Group(record_body))))
+        }
+        match record_body.iter().position(|&x| x == 0) {
+            None => Some(Err(Error::MissingSeparator { offset: offset })),
+            Some(pos) => {
+                let (key, zero_and_value) = record_body.split_at(pos);
+                Some(Ok((record_size, Record::Entry(key, &zero_and_value[1..]))))
+            }
+        }
+    }
+
+    fn is_erased(data: &[u8]) -> bool {
+        data.iter().all(|&x| x == 0xff)
+    }
+
+    /// Iterates over the records, yielding an error for each damaged one,
+    /// such as a record torn by a power failure. The following records are
+    /// then located again by looking for the next one with a valid checksum.
+    ///
+    /// The records of a group are yielded one by one.
     #[derive(Clone)]
     struct Iter<'a> {
-        data:   &'a [u8],
-        offset: usize
+        data:     &'a [u8],
+        offset:   usize,
+        resync:   bool,
+        // remaining records of the current group, and its offset
+        group:    &'a [u8],
+        group_at: usize
     }
 
     impl<'a> Iter<'a> {
         fn new(data: &'a [u8]) -> Iter<'a> {
-            Iter { data: data, offset: 0 }
+            Iter { data: data, offset: 0, resync: false, group: &[], group_at: 0 }
         }
     }
 
@@ -126,30 +321,56 @@ mod imp {
         type Item = Result<(&'a [u8], &'a [u8]), Error>;
 
         fn next(&mut self) -> Option<Self::Item> {
-            let data = &self.data[self.offset..];
-
-            if data.len() < 4 {
-                // error!("offset {}: truncated record", self.offset);
-                return Some(Err(Error::Truncated { offset: self.offset }))
+            if !self.group.is_empty() {
+                return match parse_record(self.group, self.group_at, false) {
+                    Some(Ok((record_size, Record::Entry(key, value)))) => {
+                        self.group = &self.group[record_size..];
+                        Some(Ok((key, value)))
+                    }
+                    result => {
+                        // A group is only written by us and its checksum is valid,
+                        // so this is not expected; drop the rest of the group.
+                        let size = self.group.len();
+                        self.group = &[];
+                        match result {
+                            Some(Err(err)) => Some(Err(err)),
+                            _ => Some(Err(Error::InvalidSize { offset: self.group_at, size: size }))
+                        }
+                    }
+                }
             }
 
-            let record_size = BigEndian::read_u32(data) as usize;
-            if record_size == !0 /* all ones; erased flash */ {
-                return None
-            } else if record_size < 4 || record_size > data.len() {
-                return Some(Err(Error::InvalidSize { offset: self.offset, size: record_size }))
+            if self.resync {
+                loop {
+                    let data = &self.data[self.offset..];
+                    if is_erased(data) {
+                        return None
+                    }
+                    if let Some(Ok(_)) = parse_record(data, self.offset, true) {
+                        break
+                    }
+                    self.offset += 1;
+                }
+                self.resync = false;
             }
 
-            let record_body = &data[4..record_size];
-            match record_body.iter().position(|&x| x == 0) {
-                None => {
-                    return Some(Err(Error::MissingSeparator { offset: self.offset }))
+            match parse_record(&self.data[self.offset..], self.offset, false) {
+                None => None,
+                Some(Ok((record_size, Record::Entry(key, value)))) => {
+                    self.offset += record_size;
+                    Some(Ok((key, value)))
                 }
-                Some(pos) => {
+                Some(Ok((record_size, Record::Group(records)))) => {
+                    self.group = records;
+                    self.group_at = self.offset;
                     self.offset += record_size;
-
-                    let (key, zero_and_value) = record_body.split_at(pos);
-                    Some(Ok((key, &zero_and_value[1..])))
+                    self.next()
+                }
+                Some(Err(err)) => {
+                    // The size of a damaged record cannot be trusted.
+                    self.offset += 1;
+                    self.resync = true;
+                    Some(Err(err))
                 }
             }
         }
@@ -161,7 +382,11 @@ mod imp {
             let mut value = &[][..];
             let mut found = false;
             while let Some(result) = iter.next() {
-                let (record_key, record_value) = result?;
+                let (record_key, record_value) = match result {
+                    Ok(record) => record,
+                    // Skip damaged records; see health().
+                    Err(_) => continue
+                };
                 if key.as_bytes() == record_key {
                     found = !record_value.is_empty();
                     // last write wins
@@ -178,50 +403,138 @@ mod imp {
         })
     }
 
-    unsafe fn append_at(data: &[u8], mut offset: usize,
-                        key: &[u8], value: &[u8]) -> Result<usize, Error> {
-        let record_size = 4 + key.len() + 1 + value.len();
-        if offset + record_size > data.len() {
-            return Err(Error::SpaceExhausted)
+    /// Calls `f` with each key and its current value, in the order they were
+    /// last written. Removed keys are left out.
+    pub fn list<F: FnMut(&[u8], &[u8])>(f: F) -> Result<(), Error> {
+        let lock = Lock::take()?;
+        live_records(lock.data(), f);
+        Ok(())
+    }
+
+    // Calls `f` with the records that compaction would keep.
+    fn live_records<'a, F: FnMut(&'a [u8], &'a [u8])>(data: &'a [u8], mut f: F) {
+        let mut iter = Iter::new(data);
+        while let Some(result) = iter.next() {
+            let (key, value) = match result {
+                Ok(record) => record,
+                Err(_) => continue
+            };
+            let overwritten = iter.clone().any(|next_result| {
+                next_result.map(|(next_key, _)| key == next_key).unwrap_or(false)
+            });
+            if !value.is_empty() && !overwritten {
+                f(key, value)
+            }
         }
+    }
 
+    fn record_size(key: &[u8], value: &[u8]) -> usize {
+        4 + key.len() + 1 + value.len() + CHECKSUM_SIZE
+    }
+
+    // Calls `emit` with the successive parts of a checksummed record.
+    fn encode_record<F: FnMut(&[u8])>(key: &[u8], value: &[u8], mut emit: F) {
         let mut record_size_bytes = [0u8; 4];
-        BigEndian::write_u32(&mut record_size_bytes[..], record_size as u32);
+        BigEndian::write_u32(&mut record_size_bytes[..],
+                             record_size(key, value) as u32 | CHECKSUM_FLAG);
+
+        let mut crc = crc32::update(0, &crc32::IEEE_TABLE, &record_size_bytes[..]);
+        crc = crc32::update(crc, &crc32::IEEE_TABLE, key);
+        crc = crc32::update(crc, &crc32::IEEE_TABLE, &[0]);
+        crc = crc32::update(crc, &crc32::IEEE_TABLE, value);
+        let mut crc_bytes = [0u8; CHECKSUM_SIZE];
+        BigEndian::write_u32(&mut crc_bytes[..], crc);
+
+        emit(&record_size_bytes[..]);
+        emit(key);
+        emit(&[0]);
+        emit(value);
+        emit(&crc_bytes[..]);
+    }
+
+    fn change_size(change: &Change) -> usize {
+        match change {
+            &Change::Write(key, value) => 4 + key.len() + 1 + value.len(),
+            &Change::Remove(key) => 4 + key.len() + 1
+        }
+    }
 
+    fn group_size(changes: &[Change]) -> usize {
+        4 + changes.iter().map(change_size).sum::<usize>() + CHECKSUM_SIZE
+    }
+
+    // Calls `emit` with the successive parts of a group record.
+    fn encode_group<F: FnMut(&[u8])>(changes: &[Change], mut emit: F) {
+        let mut crc = 0;
         {
-            let mut write = |payload| {
-                spiflash::write(data.as_ptr().offset(offset as isize) as usize, payload);
-                offset += payload.len();
+            let mut emit = |part: &[u8]| {
+                crc = crc32::update(crc, &crc32::IEEE_TABLE, part);
+                emit(part)
             };
 
-            write(&record_size_bytes[..]);
-            write(key);
-            write(&[0]);
-            write(value);
-            cache::flush_l2_cache();
+            let mut size_bytes = [0u8; 4];
+            BigEndian::write_u32(&mut size_bytes[..],
+                                 group_size(changes) as u32 | CHECKSUM_FLAG | GROUP_FLAG);
+            emit(&size_bytes[..]);
+            for change in changes {
+                let (key, value) = match change {
+                    &Change::Write(key, value) => (key, value),
+                    &Change::Remove(key) => (key, &[][..])
+                };
+                BigEndian::write_u32(&mut size_bytes[..], change_size(change) as u32);
+                emit(&size_bytes[..]);
+                emit(key.as_bytes());
+                emit(&[0]);
+                emit(value);
+            }
+        }
+        let mut crc_bytes = [0u8; CHECKSUM_SIZE];
+        BigEndian::write_u32(&mut crc_bytes[..], crc);
+        emit(&crc_bytes[..]);
+    }
+
+    unsafe fn append_at(data: &[u8], mut offset: usize,
+                        key: &[u8], value: &[u8]) -> Result<usize, Error> {
+        if offset + record_size(key, value) > data.len() {
+            return Err(Error::SpaceExhausted)
         }
 
+        encode_record(key, value, |payload| {
+            spiflash::write(data.as_ptr().offset(offset as isize) as usize, payload);
+            offset += payload.len();
+        });
+        cache::flush_l2_cache();
+
         Ok(offset)
     }
 
     fn compact() -> Result<(), Error> {
         let lock = Lock::take()?;
+        let active = lock.active();
         let data = lock.data();
 
+        // The old records cannot be read from flash while it is being written.
         static mut OLD_DATA: [u8; SIZE] = [0; SIZE];
         let old_data = unsafe {
-            OLD_DATA.copy_from_slice(data);
-            &OLD_DATA[..]
+            OLD_DATA[..data.len()].copy_from_slice(data);
+            &OLD_DATA[..data.len()]
         };
 
-        unsafe { spiflash::erase_sector(data.as_ptr() as usize) };
+        let (index, sequence) = Sector::next(active);
+        unsafe { spiflash::erase_sector(SECTORS[index]) };
+        cache::flush_l2_cache();
+        let data = &Sector::raw(index)[HEADER_SIZE..];
 
         // This is worst-case quadratic, but we're limited by a small SPI flash sector size,
         // so it does not really matter.
         let mut offset = 0;
         let mut iter = Iter::new(old_data);
+        // Damaged records are left behind.
         'iter: while let Some(result) = iter.next() {
-            let (key, value) = result?;
+            let (key, value) = match result {
+                Ok(record) => record,
+                Err(_) => continue
+            };
             if value.is_empty() {
                 // This is a removed entry, ignore it.
                 continue
@@ -229,8 +542,7 @@ mod imp {
 
             let mut next_iter = iter.clone();
             while let Some(next_result) = next_iter.next() {
-                let (next_key, _) = next_result?;
-                if key == next_key {
+                if next_result.map(|(next_key, _)| key == next_key).unwrap_or(false) {
                     // There's another entry that overwrites this one, ignore this one.
                     continue 'iter
                 }
@@ -238,22 +550,47 @@ mod imp {
             offset = unsafe { append_at(data, offset, key, value)? };
         }
 
+        // Until this point, the old sector remains in effect.
+        unsafe { Sector::write_header(index, sequence) };
+
         Ok(())
     }
 
+    fn free_offset(data: &[u8]) -> usize {
+        let mut iter = Iter::new(data);
+        while let Some(_) = iter.next() {}
+        iter.offset
+    }
+
     fn append(key: &str, value: &[u8]) -> Result<(), Error> {
         let lock = Lock::take()?;
-        let data = lock.data();
+        let data = match lock.active() {
+            Some(sector) => sector.data(),
+            // Nothing to append to yet; compaction sets up an empty sector.
+            None => return Err(Error::SpaceExhausted)
+        };
 
-        let free_offset = {
-            let mut iter = Iter::new(data);
-            while let Some(result) = iter.next() {
-                let _ = result?;
-            }
-            iter.offset
+        unsafe { append_at(data, free_offset(data), key.as_bytes(), value)? };
+
+        Ok(())
+    }
+
+    fn append_group(changes: &[Change]) -> Result<(), Error> {
+        let lock = Lock::take()?;
+        let data = match lock.active() {
+            Some(sector) => sector.data(),
+            None => return Err(Error::SpaceExhausted)
         };
 
-        unsafe { append_at(data, free_offset, key.as_bytes(), value)? };
+        let mut offset = free_offset(data);
+        if offset + group_size(changes) > data.len() {
+            return Err(Error::SpaceExhausted)
+        }
+        encode_group(changes, |part| {
+            unsafe { spiflash::write(data.as_ptr().offset(offset as isize) as usize, part) };
+            offset += part.len();
+        });
+        cache::flush_l2_cache();
 
         Ok(())
     }
@@ -268,6 +605,18 @@ mod imp {
         }
     }
 
+    /// Makes all of `changes` at once. If the write is interrupted, for example
+    /// by a power failure, none of them take effect.
+    pub fn transaction(changes: &[Change]) -> Result<(), Error> {
+        match append_group(changes) {
+            Err(Error::SpaceExhausted) => {
+                compact()?;
+                append_group(changes)
+            }
+            res => res
+        }
+    }
+
     pub fn write_int(key: &str, value: u32) -> Result<(), Error> {
         let mut buf = [0; 16];
         let mut wrapper = FmtWrapper::new(&mut buf);
@@ -279,11 +628,141 @@ mod imp {
         write(key, &[])
     }
 
-    pub fn erase() -> Result<(), Error> {
+    // A backup starts with a header (magic, version, length of the records),
+    // followed by the live records in the checksummed format, and ends with
+    // a CRC32 of everything before it.
+    const BACKUP_MAGIC:       u32   = 0x41434642; // "ACFB"
+    const BACKUP_VERSION:     u32   = 1;
+    const BACKUP_HEADER_SIZE: usize = 12;
+
+    /// Calls `f` with the successive parts of a backup of the store.
+    pub fn export<F: FnMut(&[u8])>(mut f: F) -> Result<(), Error> {
+        let lock = Lock::take()?;
+        let data = lock.data();
+
+        let mut length = 0;
+        live_records(data, |key, value| length += record_size(key, value));
+
+        let mut header = [0u8; BACKUP_HEADER_SIZE];
+        BigEndian::write_u32(&mut header[0..], BACKUP_MAGIC);
+        BigEndian::write_u32(&mut header[4..], BACKUP_VERSION);
+        BigEndian::write_u32(&mut header[8..], length as u32);
+
+        let mut crc = 0;
+        {
+            let mut emit = |part: &[u8]| {
+                crc = crc32::update(crc, &crc32::IEEE_TABLE, part);
+                f(part)
+            };
+            emit(&header[..]);
+            live_records(data, |key, value| encode_record(key, value, &mut emit));
+        }
+        let mut crc_bytes = [0u8; CHECKSUM_SIZE];
+        BigEndian::write_u32(&mut crc_bytes[..], crc);
+        f(&crc_bytes[..]);
+
+        Ok(())
+    }
+
+    // Returns the records in a backup, if it is intact and fits into a sector.
+    fn check_backup(backup: &[u8]) -> Result<&[u8], Error> {
+        if backup.len() < BACKUP_HEADER_SIZE + CHECKSUM_SIZE {
+            return Err(Error::InvalidBackup("truncated"))
+        }
+        let (contents, crc) = backup.split_at(backup.len() - CHECKSUM_SIZE);
+        if BigEndian::read_u32(&contents[0..]) != BACKUP_MAGIC {
+            return Err(Error::InvalidBackup("bad magic"))
+        }
+        if BigEndian::read_u32(crc) != crc32::checksum_ieee(contents) {
+            return Err(Error::InvalidBackup("checksum mismatch"))
+        }
+        if BigEndian::read_u32(&contents[4..]) != BACKUP_VERSION {
+            return Err(Error::InvalidBackup("unsupported version"))
+        }
+        let records = &contents[BACKUP_HEADER_SIZE..];
+        if BigEndian::read_u32(&contents[8..]) as usize != records.len() {
+            return Err(Error::InvalidBackup("length mismatch"))
+        }
+        if records.len() > SIZE - HEADER_SIZE {
+            return Err(Error::SpaceExhausted)
+        }
+
+        let mut iter = Iter::new(records);
+        while let Some(result) = iter.next() {
+            result.map_err(|_| Error::InvalidBackup("damaged record"))?;
+        }
+        if iter.offset != records.len() {
+            return Err(Error::InvalidBackup("trailing data"))
+        }
+        Ok(records)
+    }
+
+    /// Replaces the contents of the store with those of a backup made by
+    /// `export`. The backup is checked first, and the current contents stay
+    /// in effect until the new ones are completely written.
+    pub fn import(backup: &[u8]) -> Result<(), Error> {
+        let records = check_backup(backup)?;
+
+        let lock = Lock::take()?;
+        let (index, sequence) = Sector::next(lock.active());
+        unsafe {
+            spiflash::erase_sector(SECTORS[index]);
+            spiflash::write(SECTORS[index] + HEADER_SIZE, records);
+        }
+        cache::flush_l2_cache();
+        if &Sector::raw(index)[HEADER_SIZE..HEADER_SIZE + records.len()] != records {
+            return Err(Error::VerifyFailed)
+        }
+        unsafe { Sector::write_header(index, sequence) };
+
+        Ok(())
+    }
+
+    /// Checks every record, calling `damaged` with the offset of each damaged one.
+    pub fn health<F: FnMut(usize)>(mut damaged: F) -> Result<Health, Error> {
         let lock = Lock::take()?;
         let data = lock.data();
+        let capacity = match lock.active() {
+            Some(_) => data.len(),
+            None => SIZE - HEADER_SIZE
+        };
 
-        unsafe { spiflash::erase_sector(data.as_ptr() as usize) };
+        let mut health = Health::default();
+        let mut live = 0;
+        let mut iter = Iter::new(data);
+        while let Some(result) = iter.next() {
+            match result {
+                Ok((key, value)) => {
+                    health.records += 1;
+                    let overwritten = iter.clone().any(|next_result| {
+                        next_result.map(|(next_key, _)| key == next_key).unwrap_or(false)
+                    });
+                    if !value.is_empty() && !overwritten {
+                        // The size this record takes up once compacted.
+                        live += record_size(key, value);
+                    }
+                }
+                Err(Error::Truncated { offset }) |
+                Err(Error::InvalidSize { offset, .. }) |
+                Err(Error::MissingSeparator { offset }) |
+                Err(Error::ChecksumMismatch { offset }) => {
+                    health.damaged += 1;
+                    damaged(offset)
+                }
+                Err(err) => return Err(err)
+            }
+        }
+        health.free = capacity - iter.offset;
+        health.reclaimable = iter.offset.saturating_sub(live);
+        Ok(health)
+    }
+
+    pub fn erase() -> Result<(), Error> {
+        let _lock = Lock::take()?;
+
+        for &address in SECTORS.iter() {
+            unsafe { spiflash::erase_sector(address) };
+        }
         cache::flush_l2_cache();
 
         Ok(())
@@ -292,7 +771,7 @@ mod imp {
 
 #[cfg(not(has_spiflash))]
 mod imp {
-    use super::Error;
+    use super::{Error, Health, Change};
 
     pub fn read<F: FnOnce(Result<&[u8], Error>) -> R, R>(_key: &str, f: F) -> R {
         f(Err(Error::NoFlash))
@@ -302,6 +781,10 @@ mod imp {
         f(Err(Error::NoFlash))
     }
 
+    pub fn list<F: FnMut(&[u8], &[u8])>(_f: F) -> Result<(), Error> {
+        Err(Error::NoFlash)
+    }
+
     pub fn write(_key: &str, _value: &[u8]) -> Result<(), Error> {
         Err(Error::NoFlash)
     }
@@ -310,6 +793,22 @@ mod imp {
         Err(Error::NoFlash)
     }
 
+    pub fn transaction(_changes: &[Change]) -> Result<(), Error> {
+        Err(Error::NoFlash)
+    }
+
+    pub fn health<F: FnMut(usize)>(_damaged: F) -> Result<Health, Error> {
+        Err(Error::NoFlash)
+    }
+
+    pub fn export<F: FnMut(&[u8])>(_f: F) -> Result<(), Error> {
+        Err(Error::NoFlash)
+    }
+
+    pub fn import(_backup: &[u8]) -> Result<(), Error> {
+        Err(Error::NoFlash)
+    }
+
     pub fn erase() -> Result<(), Error> {
         Err(Error::NoFlash)
     }
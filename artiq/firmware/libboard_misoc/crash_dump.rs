@@ -0,0 +1,93 @@
+use core::{cmp, slice};
+use byteorder::{ByteOrder, BigEndian};
+use crc::crc32;
+use cache;
+use spiflash;
+use kernel_store;
+
+// The report of the last crash of the runtime is kept in the flash sector
+// following the flash kernel partition, so that it survives the restart, and
+// can be read after a power cycle. The report is written before its header,
+// so that a report cut short by a reset is not mistaken for a valid one.
+//
+// The header holds a magic, the length of the report, its CRC32, and a word
+// that is cleared once the report has been logged after the restart.
+const ADDR: usize = kernel_store::END_ADDRESS;
+const SIZE: usize = spiflash::SECTOR_SIZE;
+
+/// First flash address after the crash report sector.
+pub const END_ADDRESS: usize = ADDR + SIZE;
+
+const MAGIC:           u32   = 0x41435244; // "ACRD"
+const HEADER_SIZE:     usize = 16;
+const REPORTED_OFFSET: usize = 12;
+
+/// Longest report that fits in the flash sector.
+pub const MAX_SIZE: usize = SIZE - HEADER_SIZE;
+
+fn raw() -> &'static [u8] {
+    unsafe { slice::from_raw_parts(ADDR as *const u8, SIZE) }
+}
+
+fn header() -> Option<(&'static [u8], bool)> {
+    let raw = raw();
+    if BigEndian::read_u32(&raw[0..]) != MAGIC {
+        return None
+    }
+    let length = BigEndian::read_u32(&raw[4..]) as usize;
+    if length > MAX_SIZE {
+        return None
+    }
+    let report = &raw[HEADER_SIZE..HEADER_SIZE + length];
+    if crc32::checksum_ieee(report) != BigEndian::read_u32(&raw[8..]) {
+        return None
+    }
+    let reported = BigEndian::read_u32(&raw[REPORTED_OFFSET..]) == 0;
+    Some((report, reported))
+}
+
+/// Calls `f` with the stored crash report, if there is one.
+pub fn read<F: FnOnce(Option<&[u8]>) -> R, R>(f: F) -> R {
+    f(header().map(|(report, _)| report))
+}
+
+/// Returns whether a stored crash report has not been logged yet.
+pub fn unreported() -> bool {
+    header().map_or(false, |(_, reported)| !reported)
+}
+
+/// Notes that the stored crash report has been logged.
+pub fn mark_reported() {
+    if header().is_some() {
+        unsafe {
+            spiflash::write(ADDR + REPORTED_OFFSET, &[0; 4]);
+            cache::flush_l2_cache();
+        }
+    }
+}
+
+/// Replaces the stored crash report, truncating it to `MAX_SIZE` bytes.
+///
+/// This is meant to be called from the panic handler; `report` must be in
+/// RAM, as the flash cannot be read while it is written.
+pub fn write(report: &[u8]) {
+    let report = &report[..cmp::min(report.len(), MAX_SIZE)];
+    let mut header = [0xff; HEADER_SIZE];
+    BigEndian::write_u32(&mut header[0..], MAGIC);
+    BigEndian::write_u32(&mut header[4..], report.len() as u32);
+    BigEndian::write_u32(&mut header[8..], crc32::checksum_ieee(report));
+    unsafe {
+        spiflash::erase_sector(ADDR);
+        spiflash::write(ADDR + HEADER_SIZE, report);
+        spiflash::write(ADDR, &header);
+        cache::flush_l2_cache();
+    }
+}
+
+/// Removes the stored crash report.
+pub fn clear() {
+    unsafe {
+        spiflash::erase_sector(ADDR);
+        cache::flush_l2_cache();
+    }
+}
@@ -0,0 +1,364 @@
+use core::{cmp, fmt, slice};
+use core::sync::atomic::{AtomicBool, Ordering};
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+use cache;
+use spiflash;
+use config;
+use boot_control::{self, Slot};
+
+include!(concat!(env!("OUT_DIR"), "/public_key.rs"));
+
+// Both images start with an 8-byte header, as produced by `mscimg -f`
+// (firmware: length, CRC32) or artiq_flash (gateware: magic, length).
+pub const HEADER_SIZE: usize = 8;
+
+// Signed firmware is followed by a trailer outside of the length and CRC in
+// its header: the magic, then an Ed25519 signature of the firmware.
+pub const SIGNATURE_MAGIC: u32   = 0x47495341; // "ASIG"
+pub const SIGNATURE_SIZE:  usize = 4 + 64;
+
+pub const FIRMWARE_MAX_SIZE: usize = boot_control::SLOT_SIZE - HEADER_SIZE - SIGNATURE_SIZE;
+
+pub const GATEWARE_MAGIC:    u32   = 0x5352544d;
+pub const GATEWARE_MAX_SIZE: usize = 0x220000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Firmware,
+    Gateware
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Kind::Firmware => write!(f, "firmware"),
+            &Kind::Gateware => write!(f, "gateware")
+        }
+    }
+}
+
+impl Kind {
+    fn address(&self, slot: Option<Slot>) -> Option<usize> {
+        match self {
+            &Kind::Firmware => slot.map(|slot| slot.address()),
+            #[cfg(has_slave_fpga_cfg)]
+            &Kind::Gateware => Some(::csr::CONFIG_SLAVE_FPGA_GATEWARE as usize),
+            #[cfg(not(has_slave_fpga_cfg))]
+            &Kind::Gateware => None
+        }
+    }
+
+    // Largest amount of data following the header.
+    fn max_size(&self) -> usize {
+        match self {
+            &Kind::Firmware => FIRMWARE_MAX_SIZE + SIGNATURE_SIZE,
+            &Kind::Gateware => GATEWARE_MAX_SIZE
+        }
+    }
+}
+
+/// Splits the signature trailer, if there is one, off the end of `data`.
+pub fn split_signature(data: &[u8]) -> (&[u8], Option<&[u8]>) {
+    if data.len() >= SIGNATURE_SIZE {
+        let (firmware, trailer) = data.split_at(data.len() - SIGNATURE_SIZE);
+        if LittleEndian::read_u32(trailer) == SIGNATURE_MAGIC {
+            return (firmware, Some(&trailer[4..]))
+        }
+    }
+    (data, None)
+}
+
+/// Returns the signature following a firmware of `length` bytes at `firmware`, if there is one.
+pub unsafe fn signature_after(firmware: *const u8, length: usize) -> Option<&'static [u8]> {
+    let trailer = slice::from_raw_parts(firmware.offset(length as isize), SIGNATURE_SIZE);
+    if LittleEndian::read_u32(trailer) == SIGNATURE_MAGIC {
+        Some(&trailer[4..])
+    } else {
+        None
+    }
+}
+
+/// Returns whether the bootloader only starts firmware signed with the key
+/// given at build time.
+pub fn signature_required() -> bool {
+    FIRMWARE_PUBLIC_KEY.is_some()
+}
+
+/// How firmware passed the signature check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signature {
+    NotRequired,
+    Verified,
+    /// Without a valid signature, allowed by the `allow_unsigned_firmware`
+    /// config key.
+    Allowed(Error)
+}
+
+/// Checks the signature of `firmware`, which the bootloader does before
+/// starting it.
+pub fn check_signature(firmware: &[u8], signature: Option<&[u8]>) -> Result<Signature, Error> {
+    use ed25519_compact::{PublicKey, Signature as Ed25519Signature};
+
+    let public_key = match FIRMWARE_PUBLIC_KEY {
+        Some(key) => PublicKey::new(key),
+        None => return Ok(Signature::NotRequired)
+    };
+    let error = match signature {
+        Some(signature) => {
+            let valid = Ed25519Signature::from_slice(signature)
+                .and_then(|signature| public_key.verify(firmware, &signature))
+                .is_ok();
+            if valid {
+                return Ok(Signature::Verified)
+            }
+            Error::InvalidSignature
+        }
+        None => Error::Unsigned
+    };
+    if config::read_str("allow_unsigned_firmware", |r| r == Ok("1")) {
+        Ok(Signature::Allowed(error))
+    } else {
+        Err(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    AlreadyLocked,
+    Unsupported(Kind),
+    InvalidLength { length: usize, max: usize },
+    LengthMismatch { header: usize, expected: usize },
+    BadMagic(u32),
+    Overrun { length: usize },
+    Incomplete { written: usize, length: usize },
+    CrcMismatch { actual: u32, expected: u32 },
+    VerifyFailed { actual: u32, expected: u32 },
+    HeaderVerifyFailed,
+    Unsigned,
+    InvalidSignature
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::AlreadyLocked =>
+                write!(f, "another image is being written"),
+            &Error::Unsupported(kind) =>
+                write!(f, "{} images are not supported on this board", kind),
+            &Error::InvalidLength { length, max } =>
+                write!(f, "invalid image length {} (maximum {})", length, max),
+            &Error::LengthMismatch { header, expected } =>
+                write!(f, "image header announces {} payload bytes, expected {}",
+                       header, expected),
+            &Error::BadMagic(magic) =>
+                write!(f, "bad magic 0x{:08x}", magic),
+            &Error::Overrun { length } =>
+                write!(f, "received data past the end of the {}-byte image", length),
+            &Error::Incomplete { written, length } =>
+                write!(f, "image incomplete ({} of {} bytes)", written, length),
+            &Error::CrcMismatch { actual, expected } =>
+                write!(f, "image CRC mismatch (actual {:08x}, expected {:08x})",
+                       actual, expected),
+            &Error::VerifyFailed { actual, expected } =>
+                write!(f, "flash read-back CRC mismatch (actual {:08x}, expected {:08x})",
+                       actual, expected),
+            &Error::HeaderVerifyFailed =>
+                write!(f, "flash read-back of image header failed"),
+            &Error::Unsigned =>
+                write!(f, "firmware is not signed"),
+            &Error::InvalidSignature =>
+                write!(f, "firmware signature is invalid")
+        }
+    }
+}
+
+static LOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Streams a boot image, header included, into its place in flash.
+///
+/// The payload is written first and the header last, once the payload has
+/// been read back and checked; until then the header stays erased, which the
+/// bootloader treats as "no image present" rather than loading a partial one.
+///
+/// Firmware goes to the slot that is not active, or to the active slot if it
+/// is still on trial, and is booted on trial once finished; the last confirmed
+/// firmware stays in place as the fallback. It may carry a signature trailer.
+pub struct Writer {
+    kind:        Kind,
+    slot:        Option<Slot>,
+    address:     usize,
+    length:      usize,
+    written:     usize,
+    erased_to:   usize,
+    header:      [u8; HEADER_SIZE],
+    payload_end: usize,
+    payload_crc: u32,
+    crc:         u32
+}
+
+impl Writer {
+    /// `length` is the size of the whole image, header included.
+    pub fn new(kind: Kind, length: usize) -> Result<Writer, Error> {
+        let slot = match kind {
+            Kind::Firmware => Some(boot_control::read().update_slot()),
+            Kind::Gateware => None
+        };
+        let address = kind.address(slot).ok_or(Error::Unsupported(kind))?;
+        if length <= HEADER_SIZE || length - HEADER_SIZE > kind.max_size() {
+            return Err(Error::InvalidLength { length: length, max: HEADER_SIZE + kind.max_size() })
+        }
+        if LOCKED.swap(true, Ordering::SeqCst) {
+            return Err(Error::AlreadyLocked)
+        }
+        debug_assert!(address % spiflash::SECTOR_SIZE == 0);
+
+        Ok(Writer {
+            kind:        kind,
+            slot:        slot,
+            address:     address,
+            length:      length,
+            written:     0,
+            erased_to:   address,
+            header:      [0; HEADER_SIZE],
+            payload_end: length,
+            payload_crc: 0,
+            crc:         0
+        })
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    /// The firmware slot being written, if any.
+    pub fn slot(&self) -> Option<Slot> {
+        self.slot
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    fn check_header(&mut self) -> Result<(), Error> {
+        let data_length = self.length - HEADER_SIZE;
+        let header_length = match self.kind {
+            Kind::Firmware => LittleEndian::read_u32(&self.header[0..]),
+            Kind::Gateware => {
+                let magic = LittleEndian::read_u32(&self.header[0..]);
+                if magic != GATEWARE_MAGIC {
+                    return Err(Error::BadMagic(magic))
+                }
+                LittleEndian::read_u32(&self.header[4..])
+            }
+        } as usize;
+        let signed = self.kind == Kind::Firmware &&
+                     header_length.checked_add(SIGNATURE_SIZE) == Some(data_length);
+        if header_length != data_length && !signed {
+            return Err(Error::LengthMismatch { header: header_length, expected: data_length })
+        }
+        self.payload_end = HEADER_SIZE + header_length;
+        Ok(())
+    }
+
+    pub fn write(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        if self.written + data.len() > self.length {
+            return Err(Error::Overrun { length: self.length })
+        }
+
+        if self.written < HEADER_SIZE {
+            let size = cmp::min(HEADER_SIZE - self.written, data.len());
+            self.header[self.written..self.written + size].copy_from_slice(&data[..size]);
+            self.written += size;
+            data = &data[size..];
+            if self.written == HEADER_SIZE {
+                self.check_header()?;
+            }
+        }
+        if data.is_empty() {
+            return Ok(())
+        }
+
+        let address = self.address + self.written;
+        unsafe {
+            while self.erased_to < address + data.len() {
+                spiflash::erase_sector(self.erased_to);
+                self.erased_to += spiflash::SECTOR_SIZE;
+            }
+            spiflash::write(address, data);
+        }
+        if self.written < self.payload_end {
+            let payload = &data[..cmp::min(self.payload_end - self.written, data.len())];
+            self.payload_crc = crc32::update(self.payload_crc, &crc32::IEEE_TABLE, payload);
+        }
+        self.crc = crc32::update(self.crc, &crc32::IEEE_TABLE, data);
+        self.written += data.len();
+        Ok(())
+    }
+
+    /// Checks the payload and writes the header, making the image bootable.
+    /// A firmware slot is then selected for a trial boot.
+    pub fn finish(mut self) -> Result<(), Error> {
+        if self.written != self.length {
+            return Err(Error::Incomplete { written: self.written, length: self.length })
+        }
+
+        // Do not leave a stale signature trailer behind unsigned firmware.
+        if self.kind == Kind::Firmware {
+            let end = self.address + self.payload_end + SIGNATURE_SIZE;
+            while self.erased_to < end {
+                unsafe { spiflash::erase_sector(self.erased_to) };
+                self.erased_to += spiflash::SECTOR_SIZE;
+            }
+        }
+
+        if self.kind == Kind::Firmware {
+            let expected_crc = LittleEndian::read_u32(&self.header[4..]);
+            if self.payload_crc != expected_crc {
+                return Err(Error::CrcMismatch { actual: self.payload_crc, expected: expected_crc })
+            }
+        }
+
+        cache::flush_l2_cache();
+        let data = unsafe {
+            slice::from_raw_parts((self.address + HEADER_SIZE) as *const u8,
+                                  self.length - HEADER_SIZE)
+        };
+        let flash_crc = crc32::checksum_ieee(data);
+        if flash_crc != self.crc {
+            return Err(Error::VerifyFailed { actual: flash_crc, expected: self.crc })
+        }
+
+        // Refuse firmware that the bootloader would not start, rather than
+        // replacing the fallback with it; the header is left erased.
+        if self.kind == Kind::Firmware {
+            let firmware = &data[..self.payload_end - HEADER_SIZE];
+            let signature = unsafe {
+                signature_after(firmware.as_ptr(), firmware.len())
+            };
+            check_signature(firmware, signature)?;
+        }
+
+        unsafe { spiflash::write(self.address, &self.header) };
+        cache::flush_l2_cache();
+        let header = unsafe { slice::from_raw_parts(self.address as *const u8, HEADER_SIZE) };
+        if header != &self.header[..] {
+            return Err(Error::HeaderVerifyFailed)
+        }
+
+        if let Some(slot) = self.slot {
+            boot_control::write(boot_control::State::trial(slot));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        LOCKED.store(false, Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,384 @@
+use core::{cmp, fmt, str, slice};
+use core::sync::atomic::{AtomicBool, Ordering};
+use byteorder::{ByteOrder, BigEndian};
+use crc::crc32;
+use cache;
+use spiflash;
+use boot_control;
+
+// Flash kernels (such as the startup and idle kernels) are kept in their own
+// partition following the second config sector, as they can be much larger
+// than the config store. The partition is split in two halves that are used
+// the same way as the config sectors: each half starts with a header carrying
+// a sequence number, and the valid half with the newest one is in effect.
+//
+// Kernels are appended to the half in effect. When it fills up, the kernels
+// that remain in use are copied into the other half, whose header is written
+// last, so that a power failure never loses kernels that were stored before.
+const ADDR:      usize = ::mem::FLASH_BOOT_ADDRESS + 2 * boot_control::SLOT_SIZE +
+                         2 * spiflash::SECTOR_SIZE;
+const HALF_SIZE: usize = 4 * spiflash::SECTOR_SIZE;
+const HALVES:    [usize; 2] = [ADDR, ADDR + HALF_SIZE];
+
+/// First flash address after the partition.
+pub const END_ADDRESS: usize = ADDR + 2 * HALF_SIZE;
+
+const MAGIC:       u32   = 0x414b524e; // "AKRN"
+const HEADER_SIZE: usize = 12;
+
+// Each entry starts with the length of its name and of its data, a CRC32 of
+// both, and a word that is cleared when the entry is removed or replaced.
+// The name and data follow, padded to a multiple of 4 bytes. The entry header
+// is written after the name and data, so that an entry is only seen once
+// complete.
+const ENTRY_HEADER_SIZE: usize = 16;
+const REMOVED_OFFSET:    usize = 12;
+
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// Largest kernel that fits in the partition, with a name of maximal length.
+pub const MAX_KERNEL_SIZE: usize = HALF_SIZE - HEADER_SIZE - ENTRY_HEADER_SIZE - MAX_NAME_LENGTH;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    AlreadyLocked,
+    SpaceExhausted,
+    InvalidName,
+    VerifyFailed,
+    KernelNotFound
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::AlreadyLocked =>
+                write!(f, "attempt at reentrant access"),
+            &Error::SpaceExhausted =>
+                write!(f, "space exhausted"),
+            &Error::InvalidName =>
+                write!(f, "kernel names must have 1 to {} bytes", MAX_NAME_LENGTH),
+            &Error::VerifyFailed =>
+                write!(f, "flash read-back failed"),
+            &Error::KernelNotFound =>
+                write!(f, "kernel not found")
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Half {
+    index:    usize,
+    sequence: u32
+}
+
+impl Half {
+    fn raw(index: usize) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(HALVES[index] as *const u8, HALF_SIZE) }
+    }
+
+    fn read_header(index: usize) -> Option<u32> {
+        let header = &Half::raw(index)[..HEADER_SIZE];
+        if BigEndian::read_u32(&header[0..]) == MAGIC &&
+                BigEndian::read_u32(&header[8..]) == crc32::checksum_ieee(&header[..8]) {
+            Some(BigEndian::read_u32(&header[4..]))
+        } else {
+            None
+        }
+    }
+
+    unsafe fn write_header(index: usize, sequence: u32) {
+        let mut header = [0; HEADER_SIZE];
+        BigEndian::write_u32(&mut header[0..], MAGIC);
+        BigEndian::write_u32(&mut header[4..], sequence);
+        let crc = crc32::checksum_ieee(&header[..8]);
+        BigEndian::write_u32(&mut header[8..], crc);
+        spiflash::write(HALVES[index], &header);
+        cache::flush_l2_cache();
+    }
+
+    fn active() -> Option<Half> {
+        let mut active: Option<Half> = None;
+        for index in 0..HALVES.len() {
+            if let Some(sequence) = Half::read_header(index) {
+                let newer = match active {
+                    None => true,
+                    // Sequence numbers wrap around.
+                    Some(other) => sequence.wrapping_sub(other.sequence) as i32 > 0
+                };
+                if newer {
+                    active = Some(Half { index: index, sequence: sequence })
+                }
+            }
+        }
+        active
+    }
+
+    // Returns the index and sequence number of the half that takes over
+    // from `active`.
+    fn next(active: Option<Half>) -> (usize, u32) {
+        match active {
+            Some(half) => ((half.index + 1) % HALVES.len(), half.sequence.wrapping_add(1)),
+            None => (0, 1)
+        }
+    }
+
+    fn entries(&self) -> Iter {
+        Iter { data: Half::raw(self.index), offset: HEADER_SIZE, damaged: false }
+    }
+}
+
+static LOCKED: AtomicBool = AtomicBool::new(false);
+
+struct Lock;
+
+impl Lock {
+    fn take() -> Result<Lock, Error> {
+        if LOCKED.swap(true, Ordering::SeqCst) {
+            Err(Error::AlreadyLocked)
+        } else {
+            Ok(Lock)
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        LOCKED.store(false, Ordering::SeqCst)
+    }
+}
+
+fn entry_size(name_length: usize, data_length: usize) -> usize {
+    (ENTRY_HEADER_SIZE + name_length + data_length + 3) & !3
+}
+
+struct Entry {
+    offset:  usize,
+    size:    usize,
+    name:    &'static str,
+    data:    &'static [u8],
+    // The entry as stored, without padding.
+    raw:     &'static [u8],
+    removed: bool
+}
+
+// Iterates over the entries of a half, stopping at the free space or at the
+// first damaged entry, such as one whose write was interrupted.
+struct Iter {
+    data:    &'static [u8],
+    offset:  usize,
+    damaged: bool
+}
+
+impl Iterator for Iter {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let data = &self.data[self.offset..];
+        if data.len() < ENTRY_HEADER_SIZE || BigEndian::read_u32(&data[0..]) == !0 {
+            return None
+        }
+        let name_length = BigEndian::read_u32(&data[0..]) as usize;
+        let data_length = BigEndian::read_u32(&data[4..]) as usize;
+        if name_length > MAX_NAME_LENGTH || data_length > MAX_KERNEL_SIZE ||
+                entry_size(name_length, data_length) > data.len() {
+            self.damaged = true;
+            return None
+        }
+        let raw = &data[..ENTRY_HEADER_SIZE + name_length + data_length];
+        let contents = &raw[ENTRY_HEADER_SIZE..];
+        let name = match str::from_utf8(&contents[..name_length]) {
+            Ok(name) if BigEndian::read_u32(&data[8..]) == crc32::checksum_ieee(contents) => name,
+            _ => {
+                self.damaged = true;
+                return None
+            }
+        };
+
+        let entry = Entry {
+            offset:  self.offset,
+            size:    entry_size(name_length, data_length),
+            name:    name,
+            data:    &contents[name_length..],
+            raw:     raw,
+            removed: BigEndian::read_u32(&data[REMOVED_OFFSET..]) != !0
+        };
+        self.offset += entry.size;
+        Some(entry)
+    }
+}
+
+fn live_entries(half: Option<Half>) -> impl Iterator<Item=Entry> {
+    half.map(|half| half.entries()).into_iter().flat_map(|iter| iter)
+        .filter(|entry| !entry.removed)
+}
+
+/// Calls `f` with the data of the kernel called `name`, or an error.
+///
+/// The kernels cannot be changed until `f` returns.
+pub fn read<F: FnOnce(Result<&[u8], Error>) -> R, R>(name: &str, f: F) -> R {
+    let _lock = match Lock::take() {
+        Ok(lock) => lock,
+        Err(err) => return f(Err(err))
+    };
+    let result = live_entries(Half::active())
+        .filter(|entry| entry.name == name)
+        .last()
+        .map(|entry| entry.data)
+        .ok_or(Error::KernelNotFound);
+    f(result)
+}
+
+/// Calls `f` with the name and length of each stored kernel, and returns
+/// the space left for new kernels, in bytes.
+pub fn list<F: FnMut(&str, usize)>(mut f: F) -> Result<usize, Error> {
+    let _lock = Lock::take()?;
+    let mut used = HEADER_SIZE;
+    for entry in live_entries(Half::active()) {
+        f(entry.name, entry.data.len());
+        used += entry.size;
+    }
+    Ok((HALF_SIZE - used).saturating_sub(ENTRY_HEADER_SIZE))
+}
+
+fn is_erased(address: usize, length: usize) -> bool {
+    let data = unsafe { slice::from_raw_parts(address as *const u8, length) };
+    data.iter().all(|&byte| byte == 0xff)
+}
+
+// Writes an entry whose contents are in RAM.
+unsafe fn write_entry(address: usize, name: &str, data: &[u8]) {
+    spiflash::write(address + ENTRY_HEADER_SIZE, name.as_bytes());
+    spiflash::write(address + ENTRY_HEADER_SIZE + name.len(), data);
+
+    let crc = crc32::checksum_ieee(name.as_bytes());
+    let crc = crc32::update(crc, &crc32::IEEE_TABLE, data);
+    let mut header = [0; REMOVED_OFFSET];
+    BigEndian::write_u32(&mut header[0..], name.len() as u32);
+    BigEndian::write_u32(&mut header[4..], data.len() as u32);
+    BigEndian::write_u32(&mut header[8..], crc);
+    spiflash::write(address, &header);
+}
+
+// Copies an entry from the other half. The flash cannot be read while it
+// is being written, so the entry goes through RAM a page at a time.
+unsafe fn copy_entry(address: usize, entry: &Entry) {
+    let source = &entry.raw[ENTRY_HEADER_SIZE..];
+    let mut buffer = [0; spiflash::PAGE_SIZE];
+    let mut offset = 0;
+    while offset < source.len() {
+        let size = cmp::min(buffer.len(), source.len() - offset);
+        buffer[..size].copy_from_slice(&source[offset..offset + size]);
+        spiflash::write(address + ENTRY_HEADER_SIZE + offset, &buffer[..size]);
+        offset += size;
+    }
+    let mut header = [0; REMOVED_OFFSET];
+    header.copy_from_slice(&entry.raw[..REMOVED_OFFSET]);
+    spiflash::write(address, &header);
+}
+
+unsafe fn mark_removed(half: Half, name: &str, except: Option<usize>) {
+    for entry in live_entries(Some(half)) {
+        if entry.name == name && Some(entry.offset) != except {
+            spiflash::write(HALVES[half.index] + entry.offset + REMOVED_OFFSET, &[0; 4]);
+        }
+    }
+    cache::flush_l2_cache();
+}
+
+// Copies the kernels in use, except `name`, into the other half, followed
+// by `name` with the new `data` if there is one. The old half remains in
+// effect until this is finished.
+fn compact(active: Option<Half>, name: &str, data: Option<&[u8]>) -> Result<(), Error> {
+    let (index, sequence) = Half::next(active);
+    let mut used = HEADER_SIZE;
+    let mut count = 0;
+    for entry in live_entries(active) {
+        if entry.name != name {
+            used += entry.size;
+            count += 1;
+        }
+    }
+    if let Some(data) = data {
+        used += entry_size(name.len(), data.len());
+        count += 1;
+    }
+    if used > HALF_SIZE {
+        return Err(Error::SpaceExhausted)
+    }
+
+    unsafe {
+        for sector in 0..HALF_SIZE / spiflash::SECTOR_SIZE {
+            spiflash::erase_sector(HALVES[index] + sector * spiflash::SECTOR_SIZE);
+        }
+        let mut offset = HEADER_SIZE;
+        for entry in live_entries(active) {
+            if entry.name != name {
+                copy_entry(HALVES[index] + offset, &entry);
+                offset += entry.size;
+            }
+        }
+        if let Some(data) = data {
+            write_entry(HALVES[index] + offset, name, data);
+        }
+        cache::flush_l2_cache();
+    }
+
+    let mut iter = Half { index: index, sequence: sequence }.entries();
+    if iter.by_ref().count() != count || iter.damaged {
+        return Err(Error::VerifyFailed)
+    }
+    unsafe { Half::write_header(index, sequence) };
+    Ok(())
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        Err(Error::InvalidName)
+    } else {
+        Ok(())
+    }
+}
+
+/// Stores a kernel called `name`, replacing any kernel with that name.
+pub fn write(name: &str, data: &[u8]) -> Result<(), Error> {
+    check_name(name)?;
+    if data.len() > MAX_KERNEL_SIZE {
+        return Err(Error::SpaceExhausted)
+    }
+    let _lock = Lock::take()?;
+    let active = Half::active();
+    let size = entry_size(name.len(), data.len());
+
+    if let Some(half) = active {
+        let mut iter = half.entries();
+        while let Some(_) = iter.next() {}
+        let offset = iter.offset;
+        // An interrupted write leaves data behind the last complete entry,
+        // which is only cleared by compacting.
+        if !iter.damaged && offset + size <= HALF_SIZE &&
+                is_erased(HALVES[half.index] + offset, size) {
+            unsafe {
+                write_entry(HALVES[half.index] + offset, name, data);
+                cache::flush_l2_cache();
+            }
+            match half.entries().find(|entry| entry.offset == offset) {
+                Some(ref entry) if entry.name == name && entry.data == data => (),
+                _ => return Err(Error::VerifyFailed)
+            }
+            unsafe { mark_removed(half, name, Some(offset)) };
+            return Ok(())
+        }
+    }
+    compact(active, name, Some(data))
+}
+
+/// Removes the kernel called `name`.
+pub fn remove(name: &str) -> Result<(), Error> {
+    let _lock = Lock::take()?;
+    let half = Half::active().ok_or(Error::KernelNotFound)?;
+    if !live_entries(Some(half)).any(|entry| entry.name == name) {
+        return Err(Error::KernelNotFound)
+    }
+    unsafe { mark_removed(half, name, None) };
+    Ok(())
+}
@@ -2,6 +2,8 @@
 #![feature(asm)]
 
 extern crate byteorder;
+extern crate crc;
+extern crate ed25519_compact;
 #[cfg(feature = "log")]
 extern crate log;
 #[cfg(feature = "smoltcp")]
@@ -28,6 +30,16 @@ pub mod clock;
 pub mod uart;
 #[cfg(has_spiflash)]
 pub mod spiflash;
+#[cfg(has_spiflash)]
+pub mod flash_image;
+#[cfg(has_spiflash)]
+mod boot_state;
+#[cfg(has_spiflash)]
+pub mod boot_control;
+#[cfg(has_spiflash)]
+pub mod kernel_store;
+#[cfg(has_spiflash)]
+pub mod crash_dump;
 pub mod config;
 #[cfg(feature = "uart_console")]
 #[macro_use]
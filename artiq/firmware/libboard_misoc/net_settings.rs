@@ -1,8 +1,9 @@
-use core::fmt;
+use core::{fmt, str};
 use core::fmt::{Display, Formatter};
 use core::str::FromStr;
 
-use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv4Cidr,
+                    Ipv6Address, Ipv6Cidr};
 
 use config;
 #[cfg(soc_platform = "kasli")]
@@ -38,6 +39,59 @@ impl Display for Ipv4AddrConfig {
     }
 }
 
+/// Parses the address of a server, such as `192.168.1.10`, `192.168.1.10:514`
+/// or `[fd00::10]:514`, with `default_port` if no port is given.
+pub fn parse_endpoint(s: &str, default_port: u16) -> Option<IpEndpoint> {
+    match IpEndpoint::from_str(s) {
+        Ok(endpoint) if endpoint.port == 0 => Some(IpEndpoint::new(endpoint.addr, default_port)),
+        Ok(endpoint) => Some(endpoint),
+        Err(()) => IpAddress::from_str(s).ok()
+            .map(|addr| IpEndpoint::new(addr, default_port))
+    }
+}
+
+/// Longest host name, which is a single DNS label.
+pub const HOSTNAME_MAX_LEN: usize = 63;
+
+#[cfg(soc_platform = "kc705")]
+const HOSTNAME_PREFIX: &str = "kc705";
+#[cfg(not(soc_platform = "kc705"))]
+const HOSTNAME_PREFIX: &str = "kasli";
+
+/// Parses a host name, such as `kasli-lab1`, optionally followed by the
+/// `.local` domain.
+pub fn parse_hostname(s: &str) -> Option<&str> {
+    let s = s.trim_end_matches('.');
+    let s = if s.len() > 6 && s.as_bytes()[s.len() - 6..].eq_ignore_ascii_case(b".local") {
+        &s[..s.len() - 6]
+    } else {
+        s
+    };
+    let valid = s.len() >= 1 && s.len() <= HOSTNAME_MAX_LEN &&
+        s.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-') &&
+        !s.starts_with('-') && !s.ends_with('-');
+    if valid { Some(s) } else { None }
+}
+
+/// Calls `f` with the host name of the device, from the `hostname` config
+/// key, or made from `hardware_addr`, such as `kasli-020000000021`.
+pub fn with_hostname<F: FnOnce(&str) -> R, R>(hardware_addr: EthernetAddress, f: F) -> R {
+    config::read_str("hostname", |r| {
+        if let Some(hostname) = r.ok().and_then(parse_hostname) {
+            return f(hostname)
+        }
+        let mut buffer = [0; HOSTNAME_MAX_LEN];
+        let prefix = HOSTNAME_PREFIX.as_bytes();
+        buffer[..prefix.len()].copy_from_slice(prefix);
+        buffer[prefix.len()] = b'-';
+        let digits = b"0123456789abcdef";
+        for (i, byte) in hardware_addr.0.iter().enumerate() {
+            buffer[prefix.len() + 1 + 2 * i] = digits[(byte >> 4) as usize];
+            buffer[prefix.len() + 2 + 2 * i] = digits[(byte & 0xf) as usize];
+        }
+        f(str::from_utf8(&buffer[..prefix.len() + 13]).unwrap())
+    })
+}
 
 pub struct NetAddresses {
     pub hardware_addr: EthernetAddress,
@@ -2,9 +2,12 @@
 #![no_std]
 
 extern crate cslice;
+#[cfg(feature = "unwind")]
 extern crate unwind;
+#[cfg(feature = "libc")]
 extern crate libc;
 
 pub mod dwarf;
+#[cfg(feature = "unwind")]
 pub mod eh_rust;
 pub mod eh_artiq;
@@ -0,0 +1,95 @@
+use core::fmt;
+use core::str::FromStr;
+use alloc::vec::Vec;
+use alloc::string::String;
+use log::LevelFilter;
+
+/// Log levels for individual targets, written as `env_logger` directives,
+/// such as `runtime::rtio_mgt=trace,runtime::session=debug`.
+///
+/// A directive applies to the targets starting with its name, and the
+/// longest matching name wins. A directive without a name sets the level
+/// of the other targets; a name without a level enables all its messages.
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    default:    Option<LevelFilter>,
+    directives: Vec<(String, LevelFilter)>
+}
+
+#[derive(Debug)]
+pub struct ParseLogFilterError(String);
+
+impl fmt::Display for ParseLogFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid log filter directive {:?}", self.0)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = ParseLogFilterError;
+
+    fn from_str(s: &str) -> Result<LogFilter, ParseLogFilterError> {
+        let mut filter = LogFilter::default();
+        for directive in s.split(',').map(|directive| directive.trim()) {
+            if directive.is_empty() {
+                continue
+            }
+            let invalid = || ParseLogFilterError(String::from(directive));
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            match parts.next() {
+                Some(level) => {
+                    if name.is_empty() {
+                        return Err(invalid())
+                    }
+                    let level = level.trim().parse().map_err(|_| invalid())?;
+                    filter.directives.push((String::from(name), level))
+                }
+                None => match name.parse() {
+                    Ok(level) => filter.default = Some(level),
+                    Err(_) => filter.directives.push((String::from(name), LevelFilter::Trace))
+                }
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut separator = "";
+        if let Some(level) = self.default {
+            write!(f, "{}", level)?;
+            separator = ",";
+        }
+        for &(ref name, level) in self.directives.iter() {
+            write!(f, "{}{}={}", separator, name, level)?;
+            separator = ",";
+        }
+        Ok(())
+    }
+}
+
+impl LogFilter {
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.directives.is_empty()
+    }
+
+    /// Returns the level for `target`, if any directive applies to it.
+    pub fn level(&self, target: &str) -> Option<LevelFilter> {
+        self.directives.iter()
+            .filter(|&&(ref name, _)| target.starts_with(name.as_str()))
+            .max_by_key(|&&(ref name, _)| name.len())
+            .map(|&(_, level)| level)
+            .or(self.default)
+    }
+
+    /// Returns the most verbose level of the directives.
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives.iter()
+            .map(|&(_, level)| level)
+            .chain(self.default)
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
+}
@@ -1,26 +1,31 @@
 #![no_std]
 
+extern crate alloc;
 extern crate log;
-extern crate log_buffer;
+#[cfg(feature = "board_misoc")]
 #[macro_use]
 extern crate board_misoc;
 
 use core::cell::{Cell, RefCell, RefMut};
-use core::fmt::Write;
 use log::{Log, LevelFilter};
-use log_buffer::LogBuffer;
+#[cfg(feature = "board_misoc")]
 use board_misoc::clock;
 
+mod record_buffer;
+mod filter;
+
+use record_buffer::RecordBuffer;
+pub use record_buffer::{LogRecord, Records};
+pub use filter::{LogFilter, ParseLogFilterError};
+
 pub struct LogBufferRef<'a> {
-    buffer:        RefMut<'a, LogBuffer<&'static mut [u8]>>,
-    old_log_level: LevelFilter
+    buffer:        RefMut<'a, RecordBuffer>,
+    next_sequence: u64
 }
 
 impl<'a> LogBufferRef<'a> {
-    fn new(buffer: RefMut<'a, LogBuffer<&'static mut [u8]>>) -> LogBufferRef<'a> {
-        let old_log_level = log::max_level();
-        log::set_max_level(LevelFilter::Off);
-        LogBufferRef { buffer, old_log_level }
+    fn new(buffer: RefMut<'a, RecordBuffer>, next_sequence: u64) -> LogBufferRef<'a> {
+        LogBufferRef { buffer, next_sequence }
     }
 
     pub fn is_empty(&self) -> bool {
@@ -31,20 +36,28 @@ impl<'a> LogBufferRef<'a> {
         self.buffer.clear()
     }
 
-    pub fn extract(&mut self) -> &str {
-        self.buffer.extract()
+    /// Returns the records in the buffer, oldest first.
+    ///
+    /// Every message gets the next sequence number, so a jump between
+    /// consecutive records means that the messages in between were lost,
+    /// either overwritten by newer ones or dropped while the buffer was in use.
+    pub fn records(&self) -> Records {
+        self.buffer.records()
     }
-}
 
-impl<'a> Drop for LogBufferRef<'a> {
-    fn drop(&mut self) {
-        log::set_max_level(self.old_log_level)
+    /// Returns the sequence number that the next message will get.
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
     }
 }
 
 pub struct BufferLogger {
-    buffer:      RefCell<LogBuffer<&'static mut [u8]>>,
-    uart_filter: Cell<LevelFilter>
+    buffer:         RefCell<RecordBuffer>,
+    next_sequence:  Cell<u64>,
+    buffer_filter:  Cell<LevelFilter>,
+    buffer_targets: RefCell<LogFilter>,
+    uart_filter:    Cell<LevelFilter>,
+    uart_targets:   RefCell<LogFilter>
 }
 
 static mut LOGGER: *const BufferLogger = 0 as *const _;
@@ -52,8 +65,12 @@ static mut LOGGER: *const BufferLogger = 0 as *const _;
 impl BufferLogger {
     pub fn new(buffer: &'static mut [u8]) -> BufferLogger {
         BufferLogger {
-            buffer: RefCell::new(LogBuffer::new(buffer)),
+            buffer: RefCell::new(RecordBuffer::new(buffer)),
+            next_sequence: Cell::new(0),
+            buffer_filter: Cell::new(LevelFilter::Info),
+            buffer_targets: RefCell::new(LogFilter::default()),
             uart_filter: Cell::new(LevelFilter::Info),
+            uart_targets: RefCell::new(LogFilter::default()),
         }
     }
 
@@ -63,7 +80,7 @@ impl BufferLogger {
             log::set_logger(&*LOGGER)
                 .expect("global logger can only be initialized once");
         }
-        log::set_max_level(LevelFilter::Info);
+        self.update_max_level();
         f();
     }
 
@@ -72,19 +89,78 @@ impl BufferLogger {
     }
 
     pub fn buffer<'a>(&'a self) -> Result<LogBufferRef<'a>, ()> {
+        let next_sequence = self.next_sequence.get();
         self.buffer
             .try_borrow_mut()
-            .map(LogBufferRef::new)
+            .map(|buffer| LogBufferRef::new(buffer, next_sequence))
             .map_err(|_| ())
     }
 
+    // Messages are only formatted when at least one of the filters lets
+    // them through.
+    fn update_max_level(&self) {
+        let max_level = self.buffer_filter.get()
+            .max(self.buffer_targets.borrow().max_level())
+            .max(self.uart_filter.get())
+            .max(self.uart_targets.borrow().max_level());
+        log::set_max_level(max_level)
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        self.buffer_filter.get()
+    }
+
+    pub fn set_log_level(&self, max_level: LevelFilter) {
+        self.buffer_filter.set(max_level);
+        self.update_max_level()
+    }
+
+    /// Sets the levels of individual targets in the log buffer, overriding
+    /// the log level.
+    pub fn set_log_filter(&self, filter: LogFilter) {
+        *self.buffer_targets.borrow_mut() = filter;
+        self.update_max_level()
+    }
+
     pub fn uart_log_level(&self) -> LevelFilter {
         self.uart_filter.get()
     }
 
     pub fn set_uart_log_level(&self, max_level: LevelFilter) {
-        self.uart_filter.set(max_level)
+        self.uart_filter.set(max_level);
+        self.update_max_level()
     }
+
+    /// Sets the levels of individual targets on the UART, overriding the
+    /// UART log level.
+    pub fn set_uart_log_filter(&self, filter: LogFilter) {
+        *self.uart_targets.borrow_mut() = filter;
+        self.update_max_level()
+    }
+}
+
+// Without the board, as when tested on the host, messages have no timestamp
+// and are not printed.
+#[cfg(feature = "board_misoc")]
+fn timestamp() -> u64 {
+    clock::get_us()
+}
+
+#[cfg(not(feature = "board_misoc"))]
+fn timestamp() -> u64 {
+    0
+}
+
+#[cfg(feature = "board_misoc")]
+fn uart_log(timestamp: u64, record: &log::Record) {
+    let seconds = timestamp / 1_000_000;
+    let micros  = timestamp % 1_000_000;
+    println!("[{:6}.{:06}s] {:>5}({}): {}", seconds, micros,
+             record.level(), record.target(), record.args());
+}
+
+#[cfg(not(feature = "board_misoc"))]
+fn uart_log(_timestamp: u64, _record: &log::Record) {
 }
 
 // required for impl Log
@@ -97,18 +173,24 @@ impl Log for BufferLogger {
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
-            let timestamp = clock::get_us();
-            let seconds   = timestamp / 1_000_000;
-            let micros    = timestamp % 1_000_000;
-
-            if let Ok(mut buffer) = self.buffer.try_borrow_mut() {
-                writeln!(buffer, "[{:6}.{:06}s] {:>5}({}): {}", seconds, micros,
-                         record.level(), record.target(), record.args()).unwrap();
+            let timestamp = timestamp();
+
+            let buffer_filter = self.buffer_targets.borrow().level(record.target())
+                .unwrap_or(self.buffer_filter.get());
+            if record.level() <= buffer_filter {
+                // Messages logged while the buffer is in use are dropped, but
+                // still take a sequence number, so that readers see the gap.
+                let sequence = self.next_sequence.get();
+                self.next_sequence.set(sequence + 1);
+                if let Ok(mut buffer) = self.buffer.try_borrow_mut() {
+                    buffer.push(sequence, timestamp, record.level(), record.target(), record.args());
+                }
             }
 
-            if record.level() <= self.uart_filter.get() {
-                println!("[{:6}.{:06}s] {:>5}({}): {}", seconds, micros,
-                         record.level(), record.target(), record.args());
+            let uart_filter = self.uart_targets.borrow().level(record.target())
+                .unwrap_or(self.uart_filter.get());
+            if record.level() <= uart_filter {
+                uart_log(timestamp, record);
             }
         }
     }
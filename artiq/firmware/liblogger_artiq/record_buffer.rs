@@ -0,0 +1,204 @@
+use core::{fmt, str};
+use core::fmt::Write;
+use log::Level;
+
+// Records are stored one after another in a ring, each with this header:
+//   length     u32  (header included; a zero length marks the end of the ring)
+//   sequence   u64
+//   timestamp  u64
+//   level      u8
+//   target     u8   (length of the target; the message fills the rest)
+// A record never wraps around the end of the storage; when there is no room
+// left before the end, the next record starts at offset 0, evicting the
+// oldest records.
+const HEADER_SIZE: usize = 22;
+
+// Longer messages are truncated to fit records of this size, header included.
+const MAX_RECORD_SIZE: usize = 1024;
+
+/// A log message, as kept in the log buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct LogRecord<'a> {
+    pub sequence:  u64,
+    /// Microseconds since startup.
+    pub timestamp: u64,
+    pub level:     Level,
+    pub target:    &'a str,
+    pub message:   &'a str
+}
+
+impl<'a> fmt::Display for LogRecord<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:6}.{:06}s] {:>5}({}): {}",
+               self.timestamp / 1_000_000, self.timestamp % 1_000_000,
+               self.level, self.target, self.message)
+    }
+}
+
+// Formats into a slice, dropping whatever does not fit.
+struct SliceWriter<'a> {
+    data:   &'a mut [u8],
+    length: usize
+}
+
+impl<'a> fmt::Write for SliceWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut count = s.len().min(self.data.len() - self.length);
+        while !s.is_char_boundary(count) {
+            count -= 1
+        }
+        self.data[self.length..self.length + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.length += count;
+        Ok(())
+    }
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[..4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn level_from_u8(level: u8) -> Level {
+    match level {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace
+    }
+}
+
+pub struct RecordBuffer {
+    storage: &'static mut [u8],
+    start:   usize,
+    end:     usize,
+    count:   usize
+}
+
+impl RecordBuffer {
+    pub fn new(storage: &'static mut [u8]) -> RecordBuffer {
+        assert!(storage.len() >= 2 * MAX_RECORD_SIZE);
+        RecordBuffer { storage: storage, start: 0, end: 0, count: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.end = 0;
+        self.count = 0;
+    }
+
+    fn record_at(&self, offset: usize) -> LogRecord {
+        let data = &self.storage[offset..offset + read_u32(&self.storage[offset..]) as usize];
+        let target_end = HEADER_SIZE + data[21] as usize;
+        // Both were copied from a &str and cut at a character boundary.
+        let target = unsafe { str::from_utf8_unchecked(&data[HEADER_SIZE..target_end]) };
+        let message = unsafe { str::from_utf8_unchecked(&data[target_end..]) };
+        LogRecord {
+            sequence:  read_u64(&data[4..]),
+            timestamp: read_u64(&data[12..]),
+            level:     level_from_u8(data[20]),
+            target:    target,
+            message:   message
+        }
+    }
+
+    // Returns the offset of the record following the one at `offset`.
+    fn next_offset(&self, offset: usize) -> usize {
+        let next = offset + read_u32(&self.storage[offset..]) as usize;
+        if next + HEADER_SIZE > self.storage.len() || read_u32(&self.storage[next..]) == 0 {
+            0
+        } else {
+            next
+        }
+    }
+
+    // Returns the offset at which `size` bytes are free, evicting old records
+    // as needed.
+    fn reserve(&mut self, size: usize) -> usize {
+        loop {
+            if self.count == 0 {
+                self.clear();
+                return 0
+            }
+            if self.end > self.start {
+                if self.storage.len() - self.end >= size {
+                    return self.end
+                }
+                if self.storage.len() - self.end >= 4 {
+                    self.storage[self.end..self.end + 4].copy_from_slice(&[0; 4]);
+                }
+                self.end = 0;
+            } else {
+                if self.start - self.end >= size {
+                    return self.end
+                }
+                self.start = self.next_offset(self.start);
+                self.count -= 1;
+            }
+        }
+    }
+
+    pub fn push(&mut self, sequence: u64, timestamp: u64, level: Level, target: &str,
+                args: &fmt::Arguments) {
+        let offset = self.reserve(MAX_RECORD_SIZE);
+        let record = &mut self.storage[offset..offset + MAX_RECORD_SIZE];
+
+        let target_length = {
+            let mut writer = SliceWriter { data: &mut record[HEADER_SIZE..HEADER_SIZE + 255], length: 0 };
+            let _ = writer.write_str(target);
+            writer.length
+        };
+        let message_length = {
+            let mut writer = SliceWriter { data: &mut record[HEADER_SIZE + target_length..], length: 0 };
+            let _ = writer.write_fmt(*args);
+            writer.length
+        };
+        let length = HEADER_SIZE + target_length + message_length;
+        record[0..4].copy_from_slice(&(length as u32).to_le_bytes());
+        record[4..12].copy_from_slice(&sequence.to_le_bytes());
+        record[12..20].copy_from_slice(&timestamp.to_le_bytes());
+        record[20] = level as u8;
+        record[21] = target_length as u8;
+
+        self.end = offset + length;
+        self.count += 1;
+    }
+
+    /// Returns the records, oldest first.
+    pub fn records(&self) -> Records {
+        Records { buffer: self, offset: self.start, remaining: self.count }
+    }
+}
+
+pub struct Records<'a> {
+    buffer:    &'a RecordBuffer,
+    offset:    usize,
+    remaining: usize
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = LogRecord<'a>;
+
+    fn next(&mut self) -> Option<LogRecord<'a>> {
+        if self.remaining == 0 {
+            return None
+        }
+        let record = self.buffer.record_at(self.offset);
+        self.remaining -= 1;
+        if self.remaining > 0 {
+            self.offset = self.buffer.next_offset(self.offset);
+        }
+        Some(record)
+    }
+}
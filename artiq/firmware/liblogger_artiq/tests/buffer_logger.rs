@@ -0,0 +1,41 @@
+#[macro_use]
+extern crate log;
+extern crate logger_artiq;
+
+use logger_artiq::BufferLogger;
+
+fn sequences(logger: &BufferLogger) -> Vec<u64> {
+    let buffer = logger.buffer().unwrap();
+    let sequences = buffer.records().map(|record| record.sequence).collect();
+    sequences
+}
+
+// The logger is global, so everything is tested in a single test.
+#[test]
+fn sequence_numbers() {
+    let storage = Box::leak(vec![0; 4096].into_boxed_slice());
+    let logger: &'static BufferLogger = Box::leak(Box::new(BufferLogger::new(storage)));
+    logger.register(|| ());
+
+    info!("first");
+    debug!("filtered out, without a sequence number");
+    info!("second");
+    assert_eq!(sequences(logger), [0, 1]);
+
+    {
+        let buffer = logger.buffer().unwrap();
+        assert_eq!(buffer.next_sequence(), 2);
+        warn!("dropped while the buffer is in use");
+        assert!(logger.buffer().is_err());
+    }
+    info!("third");
+
+    let buffer = logger.buffer().unwrap();
+    let records: Vec<(u64, String)> = buffer.records()
+        .map(|record| (record.sequence, String::from(record.message)))
+        .collect();
+    assert_eq!(records, [(0, String::from("first")),
+                         (1, String::from("second")),
+                         (3, String::from("third"))]);
+    assert_eq!(buffer.next_sequence(), 4);
+}
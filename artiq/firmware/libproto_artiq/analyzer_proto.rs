@@ -1,4 +1,5 @@
 use io::{Write, ProtoWrite, Error as IoError};
+use session_proto::{TimeCorrelation, write_time_correlation};
 
 #[derive(Debug)]
 pub struct Header {
@@ -6,7 +7,8 @@ pub struct Header {
     pub total_byte_count: u64,
     pub overflow_occurred: bool,
     pub log_channel: u8,
-    pub dds_onehot_sel: bool
+    pub dds_onehot_sel: bool,
+    pub time_correlation: Option<TimeCorrelation>
 }
 
 impl Header {
@@ -18,6 +20,7 @@ impl Header {
         writer.write_u8(self.overflow_occurred as u8)?;
         writer.write_u8(self.log_channel)?;
         writer.write_u8(self.dds_onehot_sel as u8)?;
+        write_time_correlation(writer, self.time_correlation)?;
         Ok(())
     }
 }
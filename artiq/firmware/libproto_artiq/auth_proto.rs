@@ -0,0 +1,47 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use io::{Read, Write, Error as IoError};
+
+// Optional authentication of session and management connections with a key
+// shared by the core device and the hosts. After the magic, instead of the
+// endianness byte, the core device sends `A` and a nonce; the host answers
+// with the HMAC-SHA256 of the nonce keyed by the shared key, and the core
+// device sends the endianness byte only if the answer is correct.
+pub const CHALLENGE: u8 = b'A';
+pub const NONCE_LEN: usize = 32;
+pub const MAC_LEN:   usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8]) -> HmacSha256 {
+    // HMAC takes keys of any length.
+    HmacSha256::new_from_slice(key).unwrap()
+}
+
+/// HMAC-SHA256 (RFC 2104).
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; MAC_LEN] {
+    let mut mac = hmac(key);
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+pub fn write_challenge<W>(writer: &mut W, nonce: &[u8; NONCE_LEN]) -> Result<(), IoError<W::WriteError>>
+    where W: Write + ?Sized
+{
+    writer.write_all(&[CHALLENGE])?;
+    writer.write_all(nonce)
+}
+
+/// Reads the answer of the host to a challenge, and returns whether it is
+/// correct.
+pub fn read_answer<R>(reader: &mut R, key: &[u8], nonce: &[u8; NONCE_LEN]) -> Result<bool, IoError<R::ReadError>>
+    where R: Read + ?Sized
+{
+    let mut answer = [0; MAC_LEN];
+    reader.read_exact(&mut answer)?;
+    // Compared in constant time, so as not to reveal how much of the answer
+    // is correct.
+    let mut mac = hmac(key);
+    mac.update(nonce);
+    Ok(mac.verify_slice(&answer).is_ok())
+}
@@ -0,0 +1,171 @@
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, BigEndian};
+
+// Messages of the DHCP client of the runtime (RFC 2131), which sends the host
+// name of the device (option 12). Addresses are in network byte order.
+pub const CLIENT_PORT: u16 = 68;
+pub const SERVER_PORT: u16 = 67;
+
+pub const DHCP_DISCOVER: u8 = 1;
+pub const DHCP_OFFER:    u8 = 2;
+pub const DHCP_REQUEST:  u8 = 3;
+pub const DHCP_ACK:      u8 = 5;
+pub const DHCP_NAK:      u8 = 6;
+
+pub const MAX_DNS_SERVERS: usize = 3;
+
+// Largest reply we ask for, which is the smallest allowed.
+const MAX_MESSAGE_SIZE: u16 = 576;
+const MAGIC_COOKIE:     u32 = 0x6382_5363;
+// Length of the fixed part of a message, up to and including the cookie.
+const HEADER_LEN:       usize = 240;
+
+const OPTION_PAD:            u8 = 0;
+const OPTION_SUBNET_MASK:    u8 = 1;
+const OPTION_ROUTER:         u8 = 3;
+const OPTION_DNS_SERVERS:    u8 = 6;
+const OPTION_HOSTNAME:       u8 = 12;
+const OPTION_REQUESTED_IP:   u8 = 50;
+const OPTION_LEASE_TIME:     u8 = 51;
+const OPTION_MESSAGE_TYPE:   u8 = 53;
+const OPTION_SERVER_ID:      u8 = 54;
+const OPTION_PARAMETERS:     u8 = 55;
+const OPTION_MAX_SIZE:       u8 = 57;
+const OPTION_RENEWAL_TIME:   u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_CLIENT_ID:      u8 = 61;
+const OPTION_END:            u8 = 255;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request<'a> {
+    pub message_type:  u8,
+    pub xid:           u32,
+    pub hardware_addr: [u8; 6],
+    /// Address of the client while renewing a lease, or all zeros.
+    pub client_addr:   [u8; 4],
+    /// Address offered by a server, and the server, when requesting an offer.
+    pub requested:     Option<([u8; 4], [u8; 4])>,
+    pub hostname:      &'a [u8]
+}
+
+impl<'a> Request<'a> {
+    pub fn write(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN + 64);
+        packet.resize(HEADER_LEN, 0);
+        packet[0] = 1; // BOOTREQUEST
+        packet[1] = 1; // Ethernet
+        packet[2] = 6;
+        BigEndian::write_u32(&mut packet[4..], self.xid);
+        // Without an address, ask for broadcast replies, as unicast replies to
+        // the offered address would be dropped.
+        if self.client_addr == [0; 4] {
+            BigEndian::write_u16(&mut packet[10..], 0x8000);
+        }
+        packet[12..16].copy_from_slice(&self.client_addr);
+        packet[28..34].copy_from_slice(&self.hardware_addr);
+        BigEndian::write_u32(&mut packet[236..], MAGIC_COOKIE);
+
+        packet.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, self.message_type]);
+        packet.extend_from_slice(&[OPTION_CLIENT_ID, 7, 1]);
+        packet.extend_from_slice(&self.hardware_addr);
+        if let Some((address, server)) = self.requested {
+            packet.extend_from_slice(&[OPTION_REQUESTED_IP, 4]);
+            packet.extend_from_slice(&address);
+            packet.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+            packet.extend_from_slice(&server);
+        }
+        if !self.hostname.is_empty() {
+            let hostname = &self.hostname[..self.hostname.len().min(255)];
+            packet.extend_from_slice(&[OPTION_HOSTNAME, hostname.len() as u8]);
+            packet.extend_from_slice(hostname);
+        }
+        packet.extend_from_slice(&[OPTION_MAX_SIZE, 2]);
+        packet.extend_from_slice(&MAX_MESSAGE_SIZE.to_be_bytes());
+        packet.extend_from_slice(&[OPTION_PARAMETERS, 6, OPTION_SUBNET_MASK, OPTION_ROUTER,
+                                   OPTION_DNS_SERVERS, OPTION_LEASE_TIME, OPTION_RENEWAL_TIME,
+                                   OPTION_REBINDING_TIME]);
+        packet.push(OPTION_END);
+        packet
+    }
+}
+
+/// The fields of a reply that the client uses. Times are in seconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub message_type:   u8,
+    pub your_addr:      [u8; 4],
+    pub server:         Option<[u8; 4]>,
+    pub subnet_mask:    Option<[u8; 4]>,
+    pub router:         Option<[u8; 4]>,
+    pub dns_servers:    [Option<[u8; 4]>; MAX_DNS_SERVERS],
+    pub lease_time:     Option<u32>,
+    pub renewal_time:   Option<u32>,
+    pub rebinding_time: Option<u32>
+}
+
+fn address(data: &[u8]) -> Option<[u8; 4]> {
+    if data.len() >= 4 {
+        let mut address = [0; 4];
+        address.copy_from_slice(&data[..4]);
+        Some(address)
+    } else {
+        None
+    }
+}
+
+fn time(data: &[u8]) -> Option<u32> {
+    if data.len() >= 4 { Some(BigEndian::read_u32(data)) } else { None }
+}
+
+/// Reads a reply to the request `xid` of the client with `hardware_addr`.
+/// Returns `None` for other packets, and for malformed ones.
+pub fn read_reply(hardware_addr: &[u8; 6], xid: u32, packet: &[u8]) -> Option<Reply> {
+    if packet.len() < HEADER_LEN || packet[0] != 2 || BigEndian::read_u32(&packet[4..]) != xid ||
+            &packet[28..34] != hardware_addr ||
+            BigEndian::read_u32(&packet[236..]) != MAGIC_COOKIE {
+        return None
+    }
+    let mut reply = Reply {
+        message_type:   0,
+        your_addr:      address(&packet[16..20])?,
+        server:         None,
+        subnet_mask:    None,
+        router:         None,
+        dns_servers:    [None; MAX_DNS_SERVERS],
+        lease_time:     None,
+        renewal_time:   None,
+        rebinding_time: None
+    };
+
+    let mut options = &packet[HEADER_LEN..];
+    loop {
+        let kind = match options.first() {
+            Some(&kind) if kind != OPTION_END => kind,
+            _ => break
+        };
+        if kind == OPTION_PAD {
+            options = &options[1..];
+            continue
+        }
+        let length = *options.get(1)? as usize;
+        let data = options.get(2..2 + length)?;
+        options = &options[2 + length..];
+
+        match kind {
+            OPTION_MESSAGE_TYPE if length >= 1 => reply.message_type = data[0],
+            OPTION_SERVER_ID => reply.server = address(data),
+            OPTION_SUBNET_MASK => reply.subnet_mask = address(data),
+            OPTION_ROUTER => reply.router = address(data),
+            OPTION_DNS_SERVERS => {
+                for (server, data) in reply.dns_servers.iter_mut().zip(data.chunks_exact(4)) {
+                    *server = address(data)
+                }
+            }
+            OPTION_LEASE_TIME => reply.lease_time = time(data),
+            OPTION_RENEWAL_TIME => reply.renewal_time = time(data),
+            OPTION_REBINDING_TIME => reply.rebinding_time = time(data),
+            _ => ()
+        }
+    }
+    Some(reply)
+}
@@ -4,6 +4,10 @@ use io::{Read, ProtoRead, Write, ProtoWrite, Error as IoError};
 pub enum Error<T> {
     #[fail(display = "unknown packet {:#02x}", _0)]
     UnknownPacket(u8),
+    #[fail(display = "invalid payload status {}", _0)]
+    InvalidPayloadStatus(u8),
+    #[fail(display = "payload length {} exceeds maximum of {}", _0, _1)]
+    PayloadTooLong(u16, usize),
     #[fail(display = "{}", _0)]
     Io(#[cause] IoError<T>)
 }
@@ -29,18 +33,6 @@ pub enum PayloadStatus {
     FirstAndLast = 3,
 }
 
-impl From<u8> for PayloadStatus {
-    fn from(value: u8) -> PayloadStatus {
-        match value {
-            0 => PayloadStatus::Middle,
-            1 => PayloadStatus::First,
-            2 => PayloadStatus::Last,
-            3 => PayloadStatus::FirstAndLast,
-            _ => unreachable!(),
-        }
-    }
-}
-
 impl PayloadStatus {
     pub fn is_first(self) -> bool {
         self == PayloadStatus::First || self == PayloadStatus::FirstAndLast
@@ -58,6 +50,28 @@ impl PayloadStatus {
             (false, false) => PayloadStatus::Middle
         }
     }
+
+    fn read_from<R>(reader: &mut R) -> Result<PayloadStatus, Error<R::ReadError>>
+        where R: Read + ?Sized
+    {
+        Ok(match reader.read_u8()? {
+            0 => PayloadStatus::Middle,
+            1 => PayloadStatus::First,
+            2 => PayloadStatus::Last,
+            3 => PayloadStatus::FirstAndLast,
+            status => return Err(Error::InvalidPayloadStatus(status))
+        })
+    }
+}
+
+fn read_payload_length<R>(reader: &mut R, max_size: usize) -> Result<u16, Error<R::ReadError>>
+    where R: Read + ?Sized
+{
+    let length = reader.read_u16()?;
+    if length as usize > max_size {
+        return Err(Error::PayloadTooLong(length, max_size))
+    }
+    Ok(length)
 }
 
 #[derive(PartialEq, Debug)]
@@ -271,7 +285,7 @@ impl Packet {
             },
             0xa3 => {
                 let last = reader.read_bool()?;
-                let length = reader.read_u16()?;
+                let length = read_payload_length(reader, SAT_PAYLOAD_MAX_SIZE)?;
                 let mut data: [u8; SAT_PAYLOAD_MAX_SIZE] = [0; SAT_PAYLOAD_MAX_SIZE];
                 reader.read_exact(&mut data[0..length as usize])?;
                 Packet::AnalyzerData {
@@ -285,15 +299,15 @@ impl Packet {
                 let source = reader.read_u8()?;
                 let destination = reader.read_u8()?;
                 let id = reader.read_u32()?;
-                let status = reader.read_u8()?;
-                let length = reader.read_u16()?;
+                let status = PayloadStatus::read_from(reader)?;
+                let length = read_payload_length(reader, MASTER_PAYLOAD_MAX_SIZE)?;
                 let mut trace: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
                 reader.read_exact(&mut trace[0..length as usize])?;
                 Packet::DmaAddTraceRequest {
                     source: source,
                     destination: destination,
                     id: id,
-                    status: PayloadStatus::from(status),
+                    status: status,
                     length: length as u16,
                     trace: trace,
                 }
@@ -335,14 +349,14 @@ impl Packet {
             0xc0 => { 
                 let destination = reader.read_u8()?;
                 let id = reader.read_u32()?;
-                let status = reader.read_u8()?;
-                let length = reader.read_u16()?;
+                let status = PayloadStatus::read_from(reader)?;
+                let length = read_payload_length(reader, MASTER_PAYLOAD_MAX_SIZE)?;
                 let mut data: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
                 reader.read_exact(&mut data[0..length as usize])?;
                 Packet::SubkernelAddDataRequest {
                     destination: destination,
                     id: id,
-                    status: PayloadStatus::from(status),
+                    status: status,
                     length: length as u16,
                     data: data,
                 }
@@ -371,7 +385,7 @@ impl Packet {
             },
             0xca => {
                 let last = reader.read_bool()?;
-                let length = reader.read_u16()?;
+                let length = read_payload_length(reader, SAT_PAYLOAD_MAX_SIZE)?;
                 let mut data: [u8; SAT_PAYLOAD_MAX_SIZE] = [0; SAT_PAYLOAD_MAX_SIZE];
                 reader.read_exact(&mut data[0..length as usize])?;
                 Packet::SubkernelException {
@@ -384,15 +398,15 @@ impl Packet {
                 let source = reader.read_u8()?;
                 let destination = reader.read_u8()?;
                 let id = reader.read_u32()?;
-                let status = reader.read_u8()?;
-                let length = reader.read_u16()?;
+                let status = PayloadStatus::read_from(reader)?;
+                let length = read_payload_length(reader, MASTER_PAYLOAD_MAX_SIZE)?;
                 let mut data: [u8; MASTER_PAYLOAD_MAX_SIZE] = [0; MASTER_PAYLOAD_MAX_SIZE];
                 reader.read_exact(&mut data[0..length as usize])?;
                 Packet::SubkernelMessage {
                     source: source,
                     destination: destination,
                     id: id,
-                    status: PayloadStatus::from(status),
+                    status: status,
                     length: length as u16,
                     data: data,
                 }
@@ -0,0 +1,24 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use io::Cursor;
+use proto_artiq::drtioaux_proto::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = Cursor::new(data);
+    if let Ok(packet) = Packet::read_from(&mut reader) {
+        // Anything that decodes must survive an encode/decode round trip.
+        // (The bytes may differ, since any non-zero byte decodes as `true`.)
+        let mut buffer = [0; 1024];
+        let length = {
+            let mut writer = Cursor::new(&mut buffer[..]);
+            packet.write_to(&mut writer).unwrap();
+            writer.position()
+        };
+        assert!(length <= reader.position());
+
+        let mut reader = Cursor::new(&buffer[..length]);
+        assert_eq!(Packet::read_from(&mut reader).unwrap(), packet);
+        assert_eq!(reader.position(), length);
+    }
+});
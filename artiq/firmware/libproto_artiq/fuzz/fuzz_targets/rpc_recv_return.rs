@@ -0,0 +1,51 @@
+#![no_main]
+#![feature(never_type)]
+
+use core::cell::RefCell;
+use libfuzzer_sys::fuzz_target;
+use io::{Cursor, Error};
+use proto_artiq::rpc_proto;
+
+// Tags come from the compiler and are trusted; the value comes from the host.
+const TAGS: &[&[u8]] = &[
+    b"n", b"b", b"i", b"I", b"f", b"s", b"B", b"A",
+    b"t\x02ib", b"t\x03Ifs", b"t\x02t\x02bIs",
+    b"ri", b"rI", b"rf",
+];
+
+// The layout of lists and arrays assumes 32-bit pointers, so they can only be
+// exercised on a 32-bit target (e.g. `cargo fuzz run --target i686-unknown-linux-gnu`).
+#[cfg(target_pointer_width = "32")]
+const LIST_TAGS: &[&[u8]] = &[
+    b"lb", b"li", b"lf", b"ls", b"llI", b"lt\x02is",
+    b"a\x01i", b"a\x02f", b"a\x03b",
+];
+#[cfg(not(target_pointer_width = "32"))]
+const LIST_TAGS: &[&[u8]] = &[];
+
+// Refuse allocations that no fuzzer input could fill.
+const MAX_ALLOCATION: usize = 1 << 20;
+
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return
+    }
+    let index = data[0] as usize % (TAGS.len() + LIST_TAGS.len());
+    let tag = if index < TAGS.len() { TAGS[index] } else { LIST_TAGS[index - TAGS.len()] };
+    let mut reader = Cursor::new(&data[1..]);
+
+    let allocations = RefCell::new(Vec::new());
+    let alloc = |size: usize| -> Result<*mut (), Error<!>> {
+        if size > MAX_ALLOCATION {
+            return Err(Error::UnexpectedEnd)
+        }
+        // u64 storage keeps every allocation suitably aligned for any payload type.
+        let mut storage = vec![0u64; (size + 7) / 8];
+        let ptr = storage.as_mut_ptr() as *mut ();
+        allocations.borrow_mut().push(storage);
+        Ok(ptr)
+    };
+
+    let mut slot = [0u64; 64];
+    let _ = rpc_proto::recv_return(&mut reader, tag, slot.as_mut_ptr() as *mut (), &alloc);
+});
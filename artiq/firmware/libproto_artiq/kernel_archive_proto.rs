@@ -0,0 +1,105 @@
+use core::str;
+
+// Flash kernels for systems with subkernels are TAR archives made by
+// artiq_compile, holding `main.elf` and one `<subkernel id> <destination>.elf`
+// file per subkernel. The runtime reads them with tar-no-std, which trusts
+// the archive it is given: a partial block, a malformed size or a file name
+// that is not UTF-8 makes it panic, and it silently stops at the first entry
+// that is not a regular file. `check` rules all of these out beforehand.
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Fail, Debug, PartialEq)]
+pub enum Error {
+    #[fail(display = "archive is not made of 512-byte blocks")]
+    PartialBlock,
+    #[fail(display = "archive is truncated at offset {}", _0)]
+    Truncated(usize),
+    #[fail(display = "archive header at offset {} has a wrong checksum", _0)]
+    WrongChecksum(usize),
+    #[fail(display = "archive header at offset {} has an invalid size", _0)]
+    InvalidSize(usize),
+    #[fail(display = "archive header at offset {} has an invalid file name", _0)]
+    InvalidName(usize),
+    #[fail(display = "archive entry at offset {} is not a regular file", _0)]
+    NotAFile(usize),
+}
+
+/// Returns whether `data` starts with a ustar header.
+pub fn is_archive(data: &[u8]) -> bool {
+    data.len() >= BLOCK_SIZE && &data[257..262] == b"ustar"
+}
+
+// Numeric header fields are zero-padded octal numbers, terminated by NULs or
+// spaces.
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let end = field.iter().position(|&c| c == 0 || c == b' ').unwrap_or(field.len());
+    if end == 0 || field[end..].iter().any(|&c| c != 0 && c != b' ') {
+        return None
+    }
+    let digits = str::from_utf8(&field[..end]).ok()?;
+    usize::from_str_radix(digits, 8).ok()
+}
+
+fn checksum_valid(header: &[u8]) -> bool {
+    let expected = match parse_octal(&header[148..156]) {
+        Some(checksum) => checksum,
+        None => return false
+    };
+    // The checksum is computed with its own field filled with spaces.
+    let actual = header.iter().enumerate()
+        .map(|(index, &byte)| (if index >= 148 && index < 156 { b' ' } else { byte }) as usize)
+        .sum::<usize>();
+    actual == expected
+}
+
+fn name_valid(header: &[u8]) -> bool {
+    let name = &header[0..100];
+    let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    end != 0 && str::from_utf8(&name[..end]).is_ok()
+}
+
+/// Checks the structure of a TAR archive: its headers, and that the files
+/// they describe are complete.
+pub fn check(data: &[u8]) -> Result<(), Error> {
+    if data.len() % BLOCK_SIZE != 0 {
+        return Err(Error::PartialBlock)
+    }
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = &data[offset..offset + BLOCK_SIZE];
+        // The archive ends with zero blocks.
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(())
+        }
+        if !checksum_valid(header) {
+            return Err(Error::WrongChecksum(offset))
+        }
+        let size = parse_octal(&header[124..136]).ok_or(Error::InvalidSize(offset))?;
+        if !name_valid(header) {
+            return Err(Error::InvalidName(offset))
+        }
+        match header[156] {
+            b'0' | 0 => (),
+            _ => return Err(Error::NotAFile(offset))
+        }
+
+        let start = offset + BLOCK_SIZE;
+        if size > data.len() - start {
+            return Err(Error::Truncated(offset))
+        }
+        offset = start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    }
+    Ok(())
+}
+
+/// Parses a subkernel file name, "<subkernel id> <destination>.elf", into
+/// the subkernel id and destination.
+pub fn parse_subkernel_filename(name: &str) -> Option<(u32, u32)> {
+    let mut parts = name.strip_suffix(".elf")?.split_whitespace();
+    let id = parts.next()?.parse().ok()?;
+    let destination = parts.next()?.parse().ok()?;
+    match parts.next() {
+        None => Some((id, destination)),
+        Some(_) => None
+    }
+}
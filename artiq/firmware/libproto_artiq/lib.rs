@@ -11,6 +11,8 @@ extern crate cslice;
 extern crate log;
 
 extern crate byteorder;
+extern crate sha2;
+extern crate hmac;
 extern crate io;
 extern crate dyld;
 extern crate eh;
@@ -28,4 +30,8 @@ pub mod analyzer_proto;
 pub mod moninj_proto;
 #[cfg(feature = "alloc")]
 pub mod session_proto;
+#[cfg(feature = "alloc")]
+pub mod dhcp_proto;
 pub mod rpc_proto;
+pub mod auth_proto;
+pub mod kernel_archive_proto;
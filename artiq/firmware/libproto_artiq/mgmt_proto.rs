@@ -13,6 +13,10 @@ pub enum Error<T> {
     UnknownPacket(u8),
     #[fail(display = "unknown log level {}", _0)]
     UnknownLogLevel(u8),
+    #[fail(display = "unknown flash image {}", _0)]
+    UnknownFlashImage(u8),
+    #[fail(display = "unknown config change {}", _0)]
+    UnknownConfigChange(u8),
     #[fail(display = "invalid UTF-8: {}", _0)]
     Utf8(Utf8Error),
     #[fail(display = "{}", _0)]
@@ -48,36 +52,133 @@ pub fn read_magic<R>(reader: &mut R) -> Result<(), Error<R::ReadError>>
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashImage {
+    Firmware,
+    Gateware,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigEntry<'a> {
+    pub key:    &'a str,
+    pub length: u32,
+    pub value:  Option<&'a [u8]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashKernelEntry<'a> {
+    pub name:   &'a str,
+    pub length: u32,
+}
+
+/// How the IPv4 address of the device is assigned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv4Config {
+    Static,
+    Dhcp,
+    /// DHCP did not answer in time, and the `ip_fallback` address is used
+    /// until it does.
+    Fallback,
+}
+
+/// A DHCP lease, with the addresses in text form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhcpLease<'a> {
+    pub address:     &'a str,
+    pub server:      &'a str,
+    pub router:      Option<&'a str>,
+    pub dns_servers: &'a [&'a str],
+    /// Remaining time, in seconds.
+    pub remaining:   u32,
+}
+
+/// The IPv6 configuration of the device, with the addresses in text form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Status<'a> {
+    /// Link-local, static and autoconfigured addresses.
+    pub addresses:   &'a [&'a str],
+    pub router:      Option<&'a str>,
+    pub dns_servers: &'a [&'a str],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigChange {
+    Write  { key: String, value: Vec<u8> },
+    Remove { key: String },
+}
+
 #[derive(Debug)]
 pub enum Request {
     GetLog,
     ClearLog,
     PullLog,
+    PullLogRecords { from: u64 },
     #[cfg(feature = "log")]
     SetLogFilter(log::LevelFilter),
     #[cfg(feature = "log")]
     SetUartLogFilter(log::LevelFilter),
+    SetLogDirectives(String),
+    SetUartLogDirectives(String),
 
     ConfigRead   { key: String },
     ConfigWrite  { key: String, value: Vec<u8> },
     ConfigRemove { key: String },
     ConfigErase,
+    ConfigHealth,
+    ConfigList { values: bool },
+    ConfigExport,
+    ConfigImport { backup: Vec<u8> },
+    ConfigTransaction(Vec<ConfigChange>),
+
+    FlashKernelWrite  { name: String, data: Vec<u8> },
+    FlashKernelList,
+    FlashKernelRemove { name: String },
+    FlashKernelCheck  { data: Vec<u8> },
 
     Reboot,
 
     DebugAllocator,
+
+    FlashBegin { image: FlashImage, length: u32 },
+    FlashData(Vec<u8>),
+    FlashEnd,
+
+    GetBootStatus,
+    ConfirmBoot,
+
+    GetCrashDump,
+    ClearCrashDump,
+
+    GetNetworkStatus,
 }
 
 pub enum Reply<'a> {
     Success,
     Error,
     Unavailable,
+    ErrorMessage(&'a str),
 
     LogContent(&'a str),
+    #[cfg(feature = "log")]
+    LogRecord { sequence: u64, timestamp: u64, level: log::Level, target: &'a str, message: &'a str },
+    LogGap { from: u64, to: u64 },
 
     ConfigData(&'a [u8]),
+    ConfigHealth { records: u32, damaged: &'a [u32], free: u32, reclaimable: u32 },
+    ConfigList(&'a [ConfigEntry<'a>]),
+
+    FlashKernelList { kernels: &'a [FlashKernelEntry<'a>], free: u32 },
 
     RebootImminent,
+
+    FlashProgress { written: u32, length: u32 },
+
+    BootStatus { slot: u8, trial: bool, attempts: u8 },
+
+    CrashDump(&'a str),
+
+    NetworkStatus { ipv4_config: Ipv4Config, ipv4_address: Option<&'a str>,
+                    lease: Option<DhcpLease<'a>>, ipv6: Ipv6Status<'a> },
 }
 
 impl Request {
@@ -102,10 +203,15 @@ impl Request {
             1  => Request::GetLog,
             2  => Request::ClearLog,
             7  => Request::PullLog,
+            9  => Request::PullLogRecords {
+                from: reader.read_u64()?
+            },
             #[cfg(feature = "log")]
             3 => Request::SetLogFilter(read_log_level_filter(reader)?),
             #[cfg(feature = "log")]
             6 => Request::SetUartLogFilter(read_log_level_filter(reader)?),
+            10 => Request::SetLogDirectives(reader.read_string()?),
+            11 => Request::SetUartLogDirectives(reader.read_string()?),
 
             12 => Request::ConfigRead {
                 key: reader.read_string()?
@@ -118,16 +224,84 @@ impl Request {
                 key: reader.read_string()?
             },
             15 => Request::ConfigErase,
+            21 => Request::ConfigHealth,
+            22 => Request::ConfigList {
+                values: reader.read_bool()?
+            },
+            23 => Request::ConfigExport,
+            24 => Request::ConfigImport {
+                backup: reader.read_bytes()?
+            },
+            25 => {
+                let count = reader.read_u32()?;
+                let mut changes = Vec::new();
+                for _ in 0..count {
+                    changes.push(match reader.read_u8()? {
+                        0 => ConfigChange::Write {
+                            key:   reader.read_string()?,
+                            value: reader.read_bytes()?
+                        },
+                        1 => ConfigChange::Remove {
+                            key: reader.read_string()?
+                        },
+                        ty => return Err(Error::UnknownConfigChange(ty))
+                    })
+                }
+                Request::ConfigTransaction(changes)
+            }
+
+            26 => Request::FlashKernelWrite {
+                name: reader.read_string()?,
+                data: reader.read_bytes()?
+            },
+            27 => Request::FlashKernelList,
+            28 => Request::FlashKernelRemove {
+                name: reader.read_string()?
+            },
+            29 => Request::FlashKernelCheck {
+                data: reader.read_bytes()?
+            },
 
             5 => Request::Reboot,
 
             8 => Request::DebugAllocator,
 
+            16 => Request::FlashBegin {
+                image: match reader.read_u8()? {
+                    0 => FlashImage::Firmware,
+                    1 => FlashImage::Gateware,
+                    ty => return Err(Error::UnknownFlashImage(ty))
+                },
+                length: reader.read_u32()?
+            },
+            17 => Request::FlashData(reader.read_bytes()?),
+            18 => Request::FlashEnd,
+
+            19 => Request::GetBootStatus,
+            20 => Request::ConfirmBoot,
+
+            30 => Request::GetCrashDump,
+            31 => Request::ClearCrashDump,
+
+            32 => Request::GetNetworkStatus,
+
             ty => return Err(Error::UnknownPacket(ty))
         })
     }
 }
 
+fn write_option_string<W>(writer: &mut W, value: Option<&str>) -> Result<(), IoError<W::WriteError>>
+    where W: Write + ?Sized
+{
+    match value {
+        Some(value) => {
+            writer.write_bool(true)?;
+            writer.write_string(value)
+        }
+        None => writer.write_bool(false)
+    }
+}
+
 impl<'a> Reply<'a> {
     pub fn write_to<W>(&self, writer: &mut W) -> Result<(), IoError<W::WriteError>>
         where W: Write + ?Sized
@@ -143,20 +317,124 @@ impl<'a> Reply<'a> {
             Reply::Unavailable => {
                 writer.write_u8(4)?;
             }
+            Reply::ErrorMessage(message) => {
+                writer.write_u8(8)?;
+                writer.write_string(message)?;
+            }
 
             Reply::LogContent(ref log) => {
                 writer.write_u8(2)?;
                 writer.write_string(log)?;
             }
+            #[cfg(feature = "log")]
+            Reply::LogRecord { sequence, timestamp, level, target, message } => {
+                writer.write_u8(14)?;
+                writer.write_u64(sequence)?;
+                writer.write_u64(timestamp)?;
+                writer.write_u8(level as u8)?;
+                writer.write_string(target)?;
+                writer.write_string(message)?;
+            }
+            Reply::LogGap { from, to } => {
+                writer.write_u8(15)?;
+                writer.write_u64(from)?;
+                writer.write_u64(to)?;
+            }
 
             Reply::ConfigData(ref bytes) => {
                 writer.write_u8(7)?;
                 writer.write_bytes(bytes)?;
             },
+            Reply::ConfigHealth { records, damaged, free, reclaimable } => {
+                writer.write_u8(11)?;
+                writer.write_u32(records)?;
+                writer.write_u32(damaged.len() as u32)?;
+                for &offset in damaged {
+                    writer.write_u32(offset)?;
+                }
+                writer.write_u32(free)?;
+                writer.write_u32(reclaimable)?;
+            },
+            Reply::ConfigList(entries) => {
+                writer.write_u8(12)?;
+                writer.write_u32(entries.len() as u32)?;
+                for entry in entries {
+                    writer.write_string(entry.key)?;
+                    writer.write_u32(entry.length)?;
+                    match entry.value {
+                        Some(value) => {
+                            writer.write_bool(true)?;
+                            writer.write_bytes(value)?;
+                        }
+                        None => writer.write_bool(false)?
+                    }
+                }
+            },
+
+            Reply::FlashKernelList { kernels, free } => {
+                writer.write_u8(13)?;
+                writer.write_u32(kernels.len() as u32)?;
+                for kernel in kernels {
+                    writer.write_string(kernel.name)?;
+                    writer.write_u32(kernel.length)?;
+                }
+                writer.write_u32(free)?;
+            },
 
             Reply::RebootImminent => {
                 writer.write_u8(3)?;
             }
+
+            Reply::FlashProgress { written, length } => {
+                writer.write_u8(9)?;
+                writer.write_u32(written)?;
+                writer.write_u32(length)?;
+            }
+
+            Reply::BootStatus { slot, trial, attempts } => {
+                writer.write_u8(10)?;
+                writer.write_u8(slot)?;
+                writer.write_bool(trial)?;
+                writer.write_u8(attempts)?;
+            }
+
+            Reply::CrashDump(report) => {
+                writer.write_u8(16)?;
+                writer.write_string(report)?;
+            }
+
+            Reply::NetworkStatus { ipv4_config, ipv4_address, lease, ipv6 } => {
+                writer.write_u8(17)?;
+                writer.write_u8(match ipv4_config {
+                    Ipv4Config::Static   => 0,
+                    Ipv4Config::Dhcp     => 1,
+                    Ipv4Config::Fallback => 2
+                })?;
+                write_option_string(writer, ipv4_address)?;
+                match lease {
+                    Some(lease) => {
+                        writer.write_bool(true)?;
+                        writer.write_string(lease.address)?;
+                        writer.write_string(lease.server)?;
+                        write_option_string(writer, lease.router)?;
+                        writer.write_u32(lease.dns_servers.len() as u32)?;
+                        for server in lease.dns_servers {
+                            writer.write_string(server)?;
+                        }
+                        writer.write_u32(lease.remaining)?;
+                    }
+                    None => writer.write_bool(false)?
+                }
+                writer.write_u32(ipv6.addresses.len() as u32)?;
+                for address in ipv6.addresses {
+                    writer.write_string(address)?;
+                }
+                write_option_string(writer, ipv6.router)?;
+                writer.write_u32(ipv6.dns_servers.len() as u32)?;
+                for server in ipv6.dns_servers {
+                    writer.write_string(server)?;
+                }
+            }
         }
         Ok(())
     }
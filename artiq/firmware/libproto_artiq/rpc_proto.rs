@@ -147,11 +147,13 @@ unsafe fn recv_value<R, E>(reader: &mut R, tag: Tag, data: &mut *mut (),
                 // need to take into account any extra padding required.
                 // (Note: On RISC-V, there will never actually be any types with
                 // alignment larger than 8 bytes, so storage_offset == 0 always.)
+                // (Saturating arithmetic makes a bogus length from the host fail
+                // the allocation rather than wrap around to a short buffer.)
                 let list_size = 4 + 4;
                 let storage_offset = round_up(list_size, tag.alignment());
-                let storage_size = tag.size() * length;
+                let storage_size = tag.size().saturating_mul(length);
 
-                let allocation = alloc(storage_offset + storage_size)? as *mut u8;
+                let allocation = alloc(storage_offset.saturating_add(storage_size))? as *mut u8;
                 *ptr_to_list = allocation as *mut List;
                 let storage = allocation.offset(storage_offset as isize) as *mut ();
 
@@ -167,13 +169,13 @@ unsafe fn recv_value<R, E>(reader: &mut R, tag: Tag, data: &mut *mut (),
                 let mut total_len: usize = 1;
                 for _ in 0..num_dims {
                     let len = reader.read_u32()? as usize;
-                    total_len *= len;
+                    total_len = total_len.saturating_mul(len);
                     consume_value!(usize, |ptr| *ptr = len )
                 }
 
                 // Allocate backing storage for elements; deserialize them.
                 let elt_tag = it.clone().next().expect("truncated tag");
-                *buffer = alloc(elt_tag.size() * total_len)?;
+                *buffer = alloc(elt_tag.size().saturating_mul(total_len))?;
                 recv_elements(reader, elt_tag, total_len, *buffer, alloc)
             })
         }
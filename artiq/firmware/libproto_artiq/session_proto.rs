@@ -61,6 +61,39 @@ fn read_sync<R>(reader: &mut R) -> Result<(), IoError<R::ReadError>>
     Ok(())
 }
 
+/// Wall-clock time at a value of the RTIO counter, measured by the SNTP client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeCorrelation {
+    /// Nanoseconds since the Unix epoch.
+    pub wall_clock:   u64,
+    pub rtio_counter: u64,
+    /// Round-trip time of the measurement, in RTIO machine units. The wall
+    /// clock is exact within half of it.
+    pub round_trip:   u64
+}
+
+// Written as a flag followed by the fields, which are zero without a
+// correlation, so that the length does not depend on it.
+pub(crate) fn write_time_correlation<W>(writer: &mut W, correlation: Option<TimeCorrelation>)
+        -> Result<(), IoError<W::WriteError>>
+    where W: Write + ?Sized
+{
+    let correlation = match correlation {
+        Some(correlation) => {
+            writer.write_bool(true)?;
+            correlation
+        }
+        None => {
+            writer.write_bool(false)?;
+            TimeCorrelation { wall_clock: 0, rtio_counter: 0, round_trip: 0 }
+        }
+    };
+    writer.write_u64(correlation.wall_clock)?;
+    writer.write_u64(correlation.rtio_counter)?;
+    writer.write_u64(correlation.round_trip)?;
+    Ok(())
+}
+
 fn write_sync<W>(writer: &mut W) -> Result<(), IoError<W::WriteError>>
     where W: Write + ?Sized
 {
@@ -92,7 +125,8 @@ pub enum Request {
 pub enum Reply<'a> {
     SystemInfo {
         ident: &'a str,
-        finished_cleanly: bool
+        finished_cleanly: bool,
+        time_correlation: Option<TimeCorrelation>
     },
 
     LoadCompleted,
@@ -168,11 +202,12 @@ impl<'a> Reply<'a> {
     {
         write_sync(writer)?;
         match *self {
-            Reply::SystemInfo { ident, finished_cleanly } => {
+            Reply::SystemInfo { ident, finished_cleanly, time_correlation } => {
                 writer.write_u8(2)?;
                 writer.write(b"AROR")?;
                 writer.write_string(ident)?;
                 writer.write_u8(finished_cleanly as u8)?;
+                write_time_correlation(writer, time_correlation)?;
             },
 
             Reply::LoadCompleted => {
@@ -0,0 +1,60 @@
+extern crate io;
+extern crate proto_artiq;
+
+use io::{Cursor, ProtoRead};
+use proto_artiq::analyzer_proto::Header;
+use proto_artiq::session_proto::TimeCorrelation;
+
+fn write_header(header: &Header) -> Cursor<Vec<u8>> {
+    let mut bytes = Vec::new();
+    header.write_to(&mut bytes).unwrap();
+    Cursor::new(bytes)
+}
+
+// artiq.coredevice.comm_analyzer.decode_dump tells the header formats apart
+// by their length.
+#[test]
+fn header() {
+    let mut reader = write_header(&Header {
+        sent_bytes: 64,
+        total_byte_count: 96,
+        overflow_occurred: false,
+        log_channel: 7,
+        dds_onehot_sel: true,
+        time_correlation: None
+    });
+    assert_eq!(reader.read_u32().unwrap(), 64);
+    assert_eq!(reader.read_u64().unwrap(), 96);
+    assert_eq!(reader.read_bool().unwrap(), false);
+    assert_eq!(reader.read_u8().unwrap(), 7);
+    assert_eq!(reader.read_bool().unwrap(), true);
+    assert_eq!(reader.read_bool().unwrap(), false);
+    assert_eq!(reader.read_u64().unwrap(), 0);
+    assert_eq!(reader.read_u64().unwrap(), 0);
+    assert_eq!(reader.read_u64().unwrap(), 0);
+    assert_eq!(reader.position(), 40);
+    assert_eq!(reader.get_ref().len(), 40);
+}
+
+#[test]
+fn header_time_correlation() {
+    let mut reader = write_header(&Header {
+        sent_bytes: 0,
+        total_byte_count: 0,
+        overflow_occurred: true,
+        log_channel: 0,
+        dds_onehot_sel: true,
+        time_correlation: Some(TimeCorrelation {
+            wall_clock:   1_700_000_000_000_000_000,
+            rtio_counter: 125_000_000,
+            round_trip:   400_000
+        })
+    });
+    let mut fields = [0; 15];
+    reader.read_exact(&mut fields).unwrap();
+    assert_eq!(reader.read_bool().unwrap(), true);
+    assert_eq!(reader.read_u64().unwrap(), 1_700_000_000_000_000_000);
+    assert_eq!(reader.read_u64().unwrap(), 125_000_000);
+    assert_eq!(reader.read_u64().unwrap(), 400_000);
+    assert_eq!(reader.position(), reader.get_ref().len());
+}
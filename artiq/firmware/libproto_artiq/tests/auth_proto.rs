@@ -0,0 +1,47 @@
+extern crate io;
+extern crate proto_artiq;
+
+use io::{Cursor, Error as IoError};
+use proto_artiq::auth_proto::{self, CHALLENGE, NONCE_LEN};
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[test]
+fn hmac_sha256_vectors() {
+    // RFC 4231, test cases 1, 2 and 6.
+    assert_eq!(hex(&auth_proto::hmac_sha256(&[0x0b; 20], b"Hi There")),
+               "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+    assert_eq!(hex(&auth_proto::hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+               "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    assert_eq!(hex(&auth_proto::hmac_sha256(&[0xaa; 131],
+                   b"Test Using Larger Than Block-Size Key - Hash Key First")),
+               "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+}
+
+#[test]
+fn challenge() {
+    let nonce = [0x5a; NONCE_LEN];
+    let mut writer = Cursor::new(Vec::new());
+    auth_proto::write_challenge(&mut writer, &nonce).unwrap();
+    let bytes = writer.into_inner();
+    assert_eq!(bytes[0], CHALLENGE);
+    assert_eq!(&bytes[1..], &nonce[..]);
+}
+
+#[test]
+fn answer() {
+    let nonce = [0x5a; NONCE_LEN];
+    let answer = auth_proto::hmac_sha256(b"secret", &nonce);
+    let mut reader = Cursor::new(&answer[..]);
+    assert_eq!(auth_proto::read_answer(&mut reader, b"secret", &nonce).unwrap(), true);
+    let mut reader = Cursor::new(&answer[..]);
+    assert_eq!(auth_proto::read_answer(&mut reader, b"other", &nonce).unwrap(), false);
+
+    let mut reader = Cursor::new(&answer[..16]);
+    match auth_proto::read_answer(&mut reader, b"secret", &nonce) {
+        Err(IoError::UnexpectedEnd) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
@@ -0,0 +1,32 @@
+// libboard_misoc does not build on the host; its boot state has no
+// dependencies, and is tested here.
+extern crate core;
+
+#[path = "../../libboard_misoc/boot_state.rs"]
+mod boot_state;
+
+use boot_state::{Slot, State};
+
+#[test]
+fn update_slot() {
+    assert_eq!(State::confirmed(Slot::A).update_slot(), Slot::B);
+    assert_eq!(State::confirmed(Slot::B).update_slot(), Slot::A);
+}
+
+#[test]
+fn flash_twice_without_confirm() {
+    // The first update goes to the other slot, and is put on trial.
+    let state = State::confirmed(Slot::A);
+    let slot = state.update_slot();
+    assert_eq!(slot, Slot::B);
+
+    // The second one replaces it, before and after booting it, rather than
+    // the firmware known to boot.
+    let state = State::trial(slot);
+    assert_eq!(state.update_slot(), Slot::B);
+    let state = State { attempts: 1, ..state };
+    assert_eq!(state.update_slot(), Slot::B);
+
+    // Once confirmed, the next update goes to the other slot again.
+    assert_eq!(State::confirmed(Slot::B).update_slot(), Slot::A);
+}
@@ -0,0 +1,191 @@
+extern crate proto_artiq;
+
+use proto_artiq::dhcp_proto::{self, Reply, Request, DHCP_ACK, DHCP_DISCOVER, DHCP_OFFER,
+                              DHCP_REQUEST};
+
+const HARDWARE_ADDR: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x21];
+const XID: u32 = 0x1234_5678;
+
+// Returns the options of a message, as (kind, data) pairs.
+fn options(packet: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut options = Vec::new();
+    let mut rest = &packet[240..];
+    while rest[0] != 255 {
+        let length = rest[1] as usize;
+        options.push((rest[0], rest[2..2 + length].to_vec()));
+        rest = &rest[2 + length..];
+    }
+    assert_eq!(rest, [255]);
+    options
+}
+
+fn option(packet: &[u8], kind: u8) -> Option<Vec<u8>> {
+    options(packet).into_iter().find(|&(k, _)| k == kind).map(|(_, data)| data)
+}
+
+fn reply_header(xid: u32, your_addr: [u8; 4]) -> Vec<u8> {
+    let mut packet = vec![0; 240];
+    packet[0] = 2;
+    packet[1] = 1;
+    packet[2] = 6;
+    packet[4..8].copy_from_slice(&xid.to_be_bytes());
+    packet[16..20].copy_from_slice(&your_addr);
+    packet[28..34].copy_from_slice(&HARDWARE_ADDR);
+    packet[236..240].copy_from_slice(&[0x63, 0x82, 0x53, 0x63]);
+    packet
+}
+
+#[test]
+fn discover() {
+    let packet = Request {
+        message_type:  DHCP_DISCOVER,
+        xid:           XID,
+        hardware_addr: HARDWARE_ADDR,
+        client_addr:   [0; 4],
+        requested:     None,
+        hostname:      b"kasli-020000000021"
+    }.write();
+    assert_eq!(&packet[..4], [1, 1, 6, 0]);
+    assert_eq!(&packet[4..8], XID.to_be_bytes());
+    // Broadcast flag, without an address.
+    assert_eq!(&packet[10..12], [0x80, 0x00]);
+    assert_eq!(&packet[12..16], [0; 4]);
+    assert_eq!(&packet[28..34], HARDWARE_ADDR);
+    assert_eq!(&packet[236..240], [0x63, 0x82, 0x53, 0x63]);
+
+    assert_eq!(option(&packet, 53), Some(vec![DHCP_DISCOVER]));
+    assert_eq!(option(&packet, 61), Some(vec![1, 0x02, 0x00, 0x00, 0x00, 0x00, 0x21]));
+    assert_eq!(option(&packet, 12), Some(b"kasli-020000000021".to_vec()));
+    assert_eq!(option(&packet, 57), Some(vec![0x02, 0x40]));
+    assert_eq!(option(&packet, 55), Some(vec![1, 3, 6, 51, 58, 59]));
+    assert_eq!(option(&packet, 50), None);
+    assert_eq!(option(&packet, 54), None);
+}
+
+#[test]
+fn request() {
+    let packet = Request {
+        message_type:  DHCP_REQUEST,
+        xid:           XID,
+        hardware_addr: HARDWARE_ADDR,
+        client_addr:   [0; 4],
+        requested:     Some(([192, 168, 1, 70], [192, 168, 1, 1])),
+        hostname:      b"kasli"
+    }.write();
+    assert_eq!(option(&packet, 53), Some(vec![DHCP_REQUEST]));
+    assert_eq!(option(&packet, 50), Some(vec![192, 168, 1, 70]));
+    assert_eq!(option(&packet, 54), Some(vec![192, 168, 1, 1]));
+    assert_eq!(option(&packet, 12), Some(b"kasli".to_vec()));
+}
+
+#[test]
+fn renew() {
+    let packet = Request {
+        message_type:  DHCP_REQUEST,
+        xid:           XID,
+        hardware_addr: HARDWARE_ADDR,
+        client_addr:   [192, 168, 1, 70],
+        requested:     None,
+        hostname:      b""
+    }.write();
+    // Unicast replies to the leased address.
+    assert_eq!(&packet[10..12], [0, 0]);
+    assert_eq!(&packet[12..16], [192, 168, 1, 70]);
+    // An empty option is invalid.
+    assert_eq!(option(&packet, 12), None);
+}
+
+#[test]
+fn ack() {
+    let mut packet = reply_header(XID, [192, 168, 1, 70]);
+    packet.extend_from_slice(&[53, 1, DHCP_ACK]);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&[54, 4, 192, 168, 1, 1]);
+    packet.extend_from_slice(&[1, 4, 255, 255, 255, 0]);
+    packet.extend_from_slice(&[3, 8, 192, 168, 1, 1, 192, 168, 1, 2]);
+    packet.extend_from_slice(&[6, 16, 8, 8, 8, 8, 8, 8, 4, 4, 1, 1, 1, 1, 9, 9, 9, 9]);
+    packet.extend_from_slice(&[51, 4, 0, 0, 0x0e, 0x10]);
+    packet.extend_from_slice(&[58, 4, 0, 0, 0x07, 0x08]);
+    packet.extend_from_slice(&[59, 4, 0, 0, 0x0c, 0x4e]);
+    packet.extend_from_slice(&[12, 3, b'f', b'o', b'o']);
+    packet.push(255);
+    // Ignored after the end option.
+    packet.extend_from_slice(&[53, 1, DHCP_OFFER]);
+
+    assert_eq!(dhcp_proto::read_reply(&HARDWARE_ADDR, XID, &packet), Some(Reply {
+        message_type:   DHCP_ACK,
+        your_addr:      [192, 168, 1, 70],
+        server:         Some([192, 168, 1, 1]),
+        subnet_mask:    Some([255, 255, 255, 0]),
+        router:         Some([192, 168, 1, 1]),
+        dns_servers:    [Some([8, 8, 8, 8]), Some([8, 8, 4, 4]), Some([1, 1, 1, 1])],
+        lease_time:     Some(3600),
+        renewal_time:   Some(1800),
+        rebinding_time: Some(3150)
+    }));
+}
+
+#[test]
+fn minimal_offer() {
+    let mut packet = reply_header(XID, [10, 0, 0, 5]);
+    packet.extend_from_slice(&[53, 1, DHCP_OFFER]);
+    // Without an end option.
+    assert_eq!(dhcp_proto::read_reply(&HARDWARE_ADDR, XID, &packet), Some(Reply {
+        message_type:   DHCP_OFFER,
+        your_addr:      [10, 0, 0, 5],
+        server:         None,
+        subnet_mask:    None,
+        router:         None,
+        dns_servers:    [None; 3],
+        lease_time:     None,
+        renewal_time:   None,
+        rebinding_time: None
+    }));
+}
+
+#[test]
+fn short_options() {
+    let mut packet = reply_header(XID, [10, 0, 0, 5]);
+    packet.extend_from_slice(&[53, 1, DHCP_OFFER]);
+    packet.extend_from_slice(&[54, 2, 10, 0]);
+    packet.extend_from_slice(&[51, 3, 0, 0, 1]);
+    packet.push(255);
+    let reply = dhcp_proto::read_reply(&HARDWARE_ADDR, XID, &packet).unwrap();
+    assert_eq!(reply.server, None);
+    assert_eq!(reply.lease_time, None);
+}
+
+#[test]
+fn other_packets() {
+    let mut packet = reply_header(XID, [10, 0, 0, 5]);
+    packet.extend_from_slice(&[53, 1, DHCP_OFFER, 255]);
+    assert!(dhcp_proto::read_reply(&HARDWARE_ADDR, XID, &packet).is_some());
+
+    // Another transaction, or another client.
+    assert_eq!(dhcp_proto::read_reply(&HARDWARE_ADDR, XID + 1, &packet), None);
+    assert_eq!(dhcp_proto::read_reply(&[0x02, 0, 0, 0, 0, 0x22], XID, &packet), None);
+
+    // A request, as sent by other clients.
+    let mut request = packet.clone();
+    request[0] = 1;
+    assert_eq!(dhcp_proto::read_reply(&HARDWARE_ADDR, XID, &request), None);
+
+    // BOOTP, without the cookie.
+    let mut bootp = packet.clone();
+    bootp[236] = 0;
+    assert_eq!(dhcp_proto::read_reply(&HARDWARE_ADDR, XID, &bootp), None);
+
+    assert_eq!(dhcp_proto::read_reply(&HARDWARE_ADDR, XID, &packet[..239]), None);
+}
+
+#[test]
+fn truncated_option() {
+    let mut packet = reply_header(XID, [10, 0, 0, 5]);
+    packet.extend_from_slice(&[53, 1, DHCP_OFFER]);
+    packet.extend_from_slice(&[6, 8, 8, 8, 8, 8]);
+    assert_eq!(dhcp_proto::read_reply(&HARDWARE_ADDR, XID, &packet), None);
+
+    let mut packet = reply_header(XID, [10, 0, 0, 5]);
+    packet.extend_from_slice(&[53, 1, DHCP_OFFER, 54]);
+    assert_eq!(dhcp_proto::read_reply(&HARDWARE_ADDR, XID, &packet), None);
+}
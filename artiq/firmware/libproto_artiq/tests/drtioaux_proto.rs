@@ -0,0 +1,268 @@
+extern crate io;
+extern crate proto_artiq;
+
+use io::Cursor;
+use proto_artiq::drtioaux_proto::{Packet, PayloadStatus, Error,
+                                  SAT_PAYLOAD_MAX_SIZE, MASTER_PAYLOAD_MAX_SIZE};
+
+// Size of a DRTIO aux packet buffer, including the trailing CRC.
+const AUX_BUFFER_SIZE: usize = 1024;
+
+fn sat_payload(length: u16) -> [u8; SAT_PAYLOAD_MAX_SIZE] {
+    let mut data = [0; SAT_PAYLOAD_MAX_SIZE];
+    for (i, byte) in data[..length as usize].iter_mut().enumerate() {
+        *byte = i as u8 ^ 0xa5;
+    }
+    data
+}
+
+fn master_payload(length: u16) -> [u8; MASTER_PAYLOAD_MAX_SIZE] {
+    let mut data = [0; MASTER_PAYLOAD_MAX_SIZE];
+    for (i, byte) in data[..length as usize].iter_mut().enumerate() {
+        *byte = i as u8 ^ 0x5a;
+    }
+    data
+}
+
+// Adding a packet to drtioaux_proto makes this match non-exhaustive, which is
+// a reminder to add it to `all_packets` below as well.
+fn variant_index(packet: &Packet) -> usize {
+    match *packet {
+        Packet::EchoRequest => 0,
+        Packet::EchoReply => 1,
+        Packet::ResetRequest => 2,
+        Packet::ResetAck => 3,
+        Packet::TSCAck => 4,
+
+        Packet::DestinationStatusRequest { .. } => 5,
+        Packet::DestinationDownReply => 6,
+        Packet::DestinationOkReply => 7,
+        Packet::DestinationSequenceErrorReply { .. } => 8,
+        Packet::DestinationCollisionReply { .. } => 9,
+        Packet::DestinationBusyReply { .. } => 10,
+
+        Packet::RoutingSetPath { .. } => 11,
+        Packet::RoutingSetRank { .. } => 12,
+        Packet::RoutingAck => 13,
+
+        Packet::MonitorRequest { .. } => 14,
+        Packet::MonitorReply { .. } => 15,
+        Packet::InjectionRequest { .. } => 16,
+        Packet::InjectionStatusRequest { .. } => 17,
+        Packet::InjectionStatusReply { .. } => 18,
+
+        Packet::I2cStartRequest { .. } => 19,
+        Packet::I2cRestartRequest { .. } => 20,
+        Packet::I2cStopRequest { .. } => 21,
+        Packet::I2cWriteRequest { .. } => 22,
+        Packet::I2cWriteReply { .. } => 23,
+        Packet::I2cReadRequest { .. } => 24,
+        Packet::I2cReadReply { .. } => 25,
+        Packet::I2cBasicReply { .. } => 26,
+        Packet::I2cSwitchSelectRequest { .. } => 27,
+
+        Packet::SpiSetConfigRequest { .. } => 28,
+        Packet::SpiWriteRequest { .. } => 29,
+        Packet::SpiReadRequest { .. } => 30,
+        Packet::SpiReadReply { .. } => 31,
+        Packet::SpiBasicReply { .. } => 32,
+
+        Packet::AnalyzerHeaderRequest { .. } => 33,
+        Packet::AnalyzerHeader { .. } => 34,
+        Packet::AnalyzerDataRequest { .. } => 35,
+        Packet::AnalyzerData { .. } => 36,
+
+        Packet::DmaAddTraceRequest { .. } => 37,
+        Packet::DmaAddTraceReply { .. } => 38,
+        Packet::DmaRemoveTraceRequest { .. } => 39,
+        Packet::DmaRemoveTraceReply { .. } => 40,
+        Packet::DmaPlaybackRequest { .. } => 41,
+        Packet::DmaPlaybackReply { .. } => 42,
+        Packet::DmaPlaybackStatus { .. } => 43,
+
+        Packet::SubkernelAddDataRequest { .. } => 44,
+        Packet::SubkernelAddDataReply { .. } => 45,
+        Packet::SubkernelLoadRunRequest { .. } => 46,
+        Packet::SubkernelLoadRunReply { .. } => 47,
+        Packet::SubkernelFinished { .. } => 48,
+        Packet::SubkernelExceptionRequest { .. } => 49,
+        Packet::SubkernelException { .. } => 50,
+        Packet::SubkernelMessage { .. } => 51,
+        Packet::SubkernelMessageAck { .. } => 52,
+    }
+}
+
+const VARIANT_COUNT: usize = 53;
+
+fn all_packets() -> Vec<Packet> {
+    let mut hops = [0; 32];
+    for (i, hop) in hops.iter_mut().enumerate() {
+        *hop = i as u8;
+    }
+
+    vec![
+        Packet::EchoRequest,
+        Packet::EchoReply,
+        Packet::ResetRequest,
+        Packet::ResetAck,
+        Packet::TSCAck,
+
+        Packet::DestinationStatusRequest { destination: 3 },
+        Packet::DestinationDownReply,
+        Packet::DestinationOkReply,
+        Packet::DestinationSequenceErrorReply { channel: 0x1234 },
+        Packet::DestinationCollisionReply { channel: 0xfffe },
+        Packet::DestinationBusyReply { channel: 1 },
+
+        Packet::RoutingSetPath { destination: 7, hops: hops },
+        Packet::RoutingSetRank { rank: 2 },
+        Packet::RoutingAck,
+
+        Packet::MonitorRequest { destination: 1, channel: 0x0102, probe: 3 },
+        Packet::MonitorReply { value: 0x0123_4567_89ab_cdef },
+        Packet::InjectionRequest { destination: 1, channel: 0x0203, overrd: 1, value: 1 },
+        Packet::InjectionStatusRequest { destination: 2, channel: 0x0304, overrd: 2 },
+        Packet::InjectionStatusReply { value: 0xff },
+
+        Packet::I2cStartRequest { destination: 0, busno: 1 },
+        Packet::I2cRestartRequest { destination: 0, busno: 2 },
+        Packet::I2cStopRequest { destination: 0, busno: 3 },
+        Packet::I2cWriteRequest { destination: 1, busno: 0, data: 0xe8 },
+        Packet::I2cWriteReply { succeeded: true, ack: false },
+        Packet::I2cReadRequest { destination: 1, busno: 0, ack: true },
+        Packet::I2cReadReply { succeeded: false, data: 0x42 },
+        Packet::I2cBasicReply { succeeded: true },
+        Packet::I2cSwitchSelectRequest { destination: 4, busno: 0, address: 0xe0, mask: 0x80 },
+
+        Packet::SpiSetConfigRequest { destination: 2, busno: 1, flags: 0x08, length: 32, div: 16, cs: 3 },
+        Packet::SpiWriteRequest { destination: 2, busno: 1, data: 0xdead_beef },
+        Packet::SpiReadRequest { destination: 2, busno: 1 },
+        Packet::SpiReadReply { succeeded: true, data: 0xcafe_babe },
+        Packet::SpiBasicReply { succeeded: false },
+
+        Packet::AnalyzerHeaderRequest { destination: 5 },
+        Packet::AnalyzerHeader { sent_bytes: 4096, total_byte_count: 1 << 40, overflow_occurred: true },
+        Packet::AnalyzerDataRequest { destination: 5 },
+        Packet::AnalyzerData { last: false, length: SAT_PAYLOAD_MAX_SIZE as u16,
+                               data: sat_payload(SAT_PAYLOAD_MAX_SIZE as u16) },
+
+        Packet::DmaAddTraceRequest { source: 0, destination: 1, id: 42, status: PayloadStatus::First,
+                                     length: MASTER_PAYLOAD_MAX_SIZE as u16,
+                                     trace: master_payload(MASTER_PAYLOAD_MAX_SIZE as u16) },
+        Packet::DmaAddTraceReply { source: 1, destination: 0, id: 42, succeeded: true },
+        Packet::DmaRemoveTraceRequest { source: 0, destination: 1, id: 42 },
+        Packet::DmaRemoveTraceReply { destination: 0, succeeded: false },
+        Packet::DmaPlaybackRequest { source: 0, destination: 1, id: 42, timestamp: 0x1000_0000_0000 },
+        Packet::DmaPlaybackReply { destination: 0, succeeded: true },
+        Packet::DmaPlaybackStatus { source: 1, destination: 0, id: 42, error: 2,
+                                    channel: 0x0001_0002, timestamp: 123456789 },
+
+        Packet::SubkernelAddDataRequest { destination: 1, id: 7, status: PayloadStatus::Middle,
+                                          length: 100, data: master_payload(100) },
+        Packet::SubkernelAddDataReply { succeeded: true },
+        Packet::SubkernelLoadRunRequest { source: 0, destination: 1, id: 7, run: true },
+        Packet::SubkernelLoadRunReply { destination: 0, succeeded: true },
+        Packet::SubkernelFinished { destination: 0, id: 7, with_exception: true, exception_src: 1 },
+        Packet::SubkernelExceptionRequest { destination: 1 },
+        Packet::SubkernelException { last: true, length: 0, data: sat_payload(0) },
+        Packet::SubkernelMessage { source: 1, destination: 0, id: 7, status: PayloadStatus::FirstAndLast,
+                                   length: 17, data: master_payload(17) },
+        Packet::SubkernelMessageAck { destination: 1 },
+    ]
+}
+
+fn encode(packet: &Packet) -> ([u8; AUX_BUFFER_SIZE], usize) {
+    let mut buffer = [0; AUX_BUFFER_SIZE];
+    let length = {
+        let mut writer = Cursor::new(&mut buffer[..]);
+        packet.write_to(&mut writer).unwrap();
+        writer.position()
+    };
+    (buffer, length)
+}
+
+#[test]
+fn all_variants_covered() {
+    let mut covered = [false; VARIANT_COUNT];
+    for packet in all_packets().iter() {
+        covered[variant_index(packet)] = true;
+    }
+    for (index, &covered) in covered.iter().enumerate() {
+        assert!(covered, "packet variant #{} has no round-trip sample", index);
+    }
+}
+
+#[test]
+fn round_trip() {
+    for packet in all_packets() {
+        let (buffer, length) = encode(&packet);
+        // The gateware appends a 4-byte CRC to each packet.
+        assert!(length <= AUX_BUFFER_SIZE - 4, "{:?} does not fit in an aux packet", packet);
+
+        let mut reader = Cursor::new(&buffer[..length]);
+        let decoded = Packet::read_from(&mut reader).unwrap();
+        assert_eq!(decoded, packet);
+        assert_eq!(reader.position(), length, "{:?} left trailing bytes", packet);
+    }
+}
+
+#[test]
+fn packet_ids_unique() {
+    let packets = all_packets();
+    for (i, a) in packets.iter().enumerate() {
+        for b in packets[i + 1..].iter() {
+            assert_ne!(encode(a).0[0], encode(b).0[0], "{:?} and {:?} share an ID", a, b);
+        }
+    }
+}
+
+#[test]
+fn truncated_packets_rejected() {
+    for packet in all_packets() {
+        let (buffer, length) = encode(&packet);
+        for truncated in 0..length {
+            let mut reader = Cursor::new(&buffer[..truncated]);
+            match Packet::read_from(&mut reader) {
+                Err(Error::Io(_)) => (),
+                result => panic!("{:?} truncated to {} bytes decoded as {:?}", packet, truncated, result)
+            }
+        }
+    }
+}
+
+#[test]
+fn unknown_packet_rejected() {
+    let mut reader = Cursor::new(&[0xff][..]);
+    match Packet::read_from(&mut reader) {
+        Err(Error::UnknownPacket(0xff)) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn invalid_payload_status_rejected() {
+    let packet = Packet::SubkernelMessage { source: 1, destination: 0, id: 7, status: PayloadStatus::Last,
+                                            length: 1, data: master_payload(1) };
+    let (mut buffer, length) = encode(&packet);
+    // ID, source, destination, message ID, then status.
+    buffer[1 + 1 + 1 + 4] = 4;
+    let mut reader = Cursor::new(&buffer[..length]);
+    match Packet::read_from(&mut reader) {
+        Err(Error::InvalidPayloadStatus(4)) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn oversized_payload_rejected() {
+    let mut buffer = [0; AUX_BUFFER_SIZE];
+    buffer[0] = 0xa3;
+    buffer[1] = 1;
+    let length = SAT_PAYLOAD_MAX_SIZE as u16 + 1;
+    buffer[2..4].copy_from_slice(&length.to_ne_bytes());
+    let mut reader = Cursor::new(&buffer[..]);
+    match Packet::read_from(&mut reader) {
+        Err(Error::PayloadTooLong(len, SAT_PAYLOAD_MAX_SIZE)) if len == length => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
@@ -0,0 +1,119 @@
+extern crate proto_artiq;
+
+use proto_artiq::kernel_archive_proto::{self, Error, BLOCK_SIZE};
+
+const ELF: &[u8] = b"\x7fELF\x01\x01\x01\x00";
+
+fn set_checksum(header: &mut [u8]) {
+    for byte in header[148..156].iter_mut() {
+        *byte = b' ';
+    }
+    let checksum = header.iter().map(|&byte| byte as usize).sum::<usize>();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+}
+
+fn header(name: &[u8], size: &str, typeflag: u8) -> Vec<u8> {
+    let mut header = vec![0; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name);
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..124 + size.len()].copy_from_slice(size.as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    set_checksum(&mut header);
+    header
+}
+
+fn entry(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut entry = header(name.as_bytes(), &format!("{:011o}\0", data.len()), b'0');
+    entry.extend_from_slice(data);
+    let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+    entry.extend(std::iter::repeat(0).take(padding));
+    entry
+}
+
+fn archive(entries: &[Vec<u8>]) -> Vec<u8> {
+    let mut archive = entries.concat();
+    archive.extend_from_slice(&[0; 2 * BLOCK_SIZE]);
+    archive
+}
+
+#[test]
+fn valid() {
+    let data = archive(&[entry("main.elf", ELF), entry("1 2.elf", &[0x7f; 700])]);
+    assert!(kernel_archive_proto::is_archive(&data));
+    assert_eq!(kernel_archive_proto::check(&data), Ok(()));
+    assert!(!kernel_archive_proto::is_archive(ELF));
+}
+
+#[test]
+fn without_end_blocks() {
+    let data = entry("main.elf", ELF);
+    assert_eq!(kernel_archive_proto::check(&data), Ok(()));
+}
+
+#[test]
+fn partial_block() {
+    let mut data = archive(&[entry("main.elf", ELF)]);
+    data.extend_from_slice(&[0; 10]);
+    assert_eq!(kernel_archive_proto::check(&data), Err(Error::PartialBlock));
+}
+
+#[test]
+fn wrong_checksum() {
+    let mut data = archive(&[entry("main.elf", ELF), entry("1 2.elf", ELF)]);
+    data[BLOCK_SIZE * 2] = b'3';
+    assert_eq!(kernel_archive_proto::check(&data), Err(Error::WrongChecksum(BLOCK_SIZE * 2)));
+
+    let mut data = archive(&[entry("main.elf", ELF)]);
+    data[148..156].copy_from_slice(b"12x4\0   ");
+    assert_eq!(kernel_archive_proto::check(&data), Err(Error::WrongChecksum(0)));
+}
+
+#[test]
+fn invalid_size() {
+    for size in &["", " 0000010\0", "0000001x010\0", "00000010\00010", "99999999999\0"] {
+        let mut data = header(b"main.elf", size, b'0');
+        data.extend_from_slice(&[0; BLOCK_SIZE]);
+        assert_eq!(kernel_archive_proto::check(&data), Err(Error::InvalidSize(0)), "{:?}", size);
+    }
+}
+
+#[test]
+fn truncated() {
+    let mut data = entry("main.elf", ELF);
+    data.extend(header(b"1 2.elf", "00000010000\0", b'0'));
+    data.extend_from_slice(&[0; 3 * BLOCK_SIZE]);
+    assert_eq!(kernel_archive_proto::check(&data), Err(Error::Truncated(BLOCK_SIZE * 2)));
+}
+
+#[test]
+fn invalid_name() {
+    for name in &[&b""[..], &b"main\xff.elf"[..]] {
+        let mut data = header(name, "00000000000\0", b'0');
+        data.extend_from_slice(&[0; BLOCK_SIZE]);
+        assert_eq!(kernel_archive_proto::check(&data), Err(Error::InvalidName(0)));
+    }
+}
+
+#[test]
+fn not_a_file() {
+    let mut data = entry("main.elf", ELF);
+    data.extend(header(b"subkernels/", "00000000000\0", b'5'));
+    data.extend_from_slice(&[0; 2 * BLOCK_SIZE]);
+    assert_eq!(kernel_archive_proto::check(&data), Err(Error::NotAFile(BLOCK_SIZE * 2)));
+}
+
+#[test]
+fn subkernel_filename() {
+    assert_eq!(kernel_archive_proto::parse_subkernel_filename("1 2.elf"), Some((1, 2)));
+    assert_eq!(kernel_archive_proto::parse_subkernel_filename("12  0.elf"), Some((12, 0)));
+    assert_eq!(kernel_archive_proto::parse_subkernel_filename("1 2"), None);
+    assert_eq!(kernel_archive_proto::parse_subkernel_filename("1.elf"), None);
+    assert_eq!(kernel_archive_proto::parse_subkernel_filename("1 2 3.elf"), None);
+    assert_eq!(kernel_archive_proto::parse_subkernel_filename("a 2.elf"), None);
+    assert_eq!(kernel_archive_proto::parse_subkernel_filename("1 -2.elf"), None);
+}
@@ -0,0 +1,527 @@
+extern crate io;
+extern crate log;
+extern crate proto_artiq;
+
+use io::{Cursor, ProtoRead, ProtoWrite};
+use proto_artiq::mgmt_proto::{self, Request, Reply, Error, FlashImage, ConfigEntry, ConfigChange,
+                              FlashKernelEntry, Ipv4Config, DhcpLease, Ipv6Status};
+
+fn read_request(bytes: &[u8]) -> Request {
+    let mut reader = Cursor::new(bytes);
+    let request = Request::read_from(&mut reader).unwrap();
+    assert_eq!(reader.position(), bytes.len(), "{:?} left trailing bytes", request);
+    request
+}
+
+fn write_reply(reply: &Reply) -> Cursor<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reply.write_to(&mut bytes).unwrap();
+    Cursor::new(bytes)
+}
+
+fn assert_consumed(reader: &Cursor<Vec<u8>>) {
+    assert_eq!(reader.position(), reader.get_ref().len());
+}
+
+#[test]
+fn magic() {
+    let mut reader = Cursor::new(&b"ARTIQ management\n"[..]);
+    mgmt_proto::read_magic(&mut reader).unwrap();
+
+    let mut reader = Cursor::new(&b"ARTIQ managemenT\n"[..]);
+    match mgmt_proto::read_magic(&mut reader) {
+        Err(Error::WrongMagic) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn request_log() {
+    match read_request(&[1]) {
+        Request::GetLog => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+    match read_request(&[2]) {
+        Request::ClearLog => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+    match read_request(&[7]) {
+        Request::PullLog => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    let mut bytes = Vec::new();
+    bytes.write_u8(9).unwrap();
+    bytes.write_u64(1 << 40).unwrap();
+    match read_request(&bytes) {
+        Request::PullLogRecords { from } => assert_eq!(from, 1 << 40),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_log_filter() {
+    let levels = [
+        log::LevelFilter::Off, log::LevelFilter::Error, log::LevelFilter::Warn,
+        log::LevelFilter::Info, log::LevelFilter::Debug, log::LevelFilter::Trace
+    ];
+    for (code, &level) in levels.iter().enumerate() {
+        match read_request(&[3, code as u8]) {
+            Request::SetLogFilter(filter) => assert_eq!(filter, level),
+            request => panic!("unexpected request {:?}", request)
+        }
+        match read_request(&[6, code as u8]) {
+            Request::SetUartLogFilter(filter) => assert_eq!(filter, level),
+            request => panic!("unexpected request {:?}", request)
+        }
+    }
+
+    let mut reader = Cursor::new(&[3u8, 6][..]);
+    match Request::read_from(&mut reader) {
+        Err(Error::UnknownLogLevel(6)) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn request_log_directives() {
+    let mut bytes = Vec::new();
+    bytes.write_u8(10).unwrap();
+    bytes.write_string("runtime::rtio_mgt=trace").unwrap();
+    match read_request(&bytes) {
+        Request::SetLogDirectives(filter) => assert_eq!(filter, "runtime::rtio_mgt=trace"),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    let mut bytes = Vec::new();
+    bytes.write_u8(11).unwrap();
+    bytes.write_string("").unwrap();
+    match read_request(&bytes) {
+        Request::SetUartLogDirectives(filter) => assert_eq!(filter, ""),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_config() {
+    let mut bytes = Vec::new();
+    bytes.write_u8(12).unwrap();
+    bytes.write_string("rtio_clock").unwrap();
+    match read_request(&bytes) {
+        Request::ConfigRead { key } => assert_eq!(key, "rtio_clock"),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    let mut bytes = Vec::new();
+    bytes.write_u8(13).unwrap();
+    bytes.write_string("ip").unwrap();
+    bytes.write_bytes(b"192.168.1.70").unwrap();
+    match read_request(&bytes) {
+        Request::ConfigWrite { key, value } => {
+            assert_eq!(key, "ip");
+            assert_eq!(value, b"192.168.1.70");
+        }
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    let mut bytes = Vec::new();
+    bytes.write_u8(14).unwrap();
+    bytes.write_string("startup_kernel").unwrap();
+    match read_request(&bytes) {
+        Request::ConfigRemove { key } => assert_eq!(key, "startup_kernel"),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    match read_request(&[15]) {
+        Request::ConfigErase => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    match read_request(&[21]) {
+        Request::ConfigHealth => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    match read_request(&[22, 1]) {
+        Request::ConfigList { values } => assert!(values),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    match read_request(&[23]) {
+        Request::ConfigExport => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    let mut bytes = Vec::new();
+    bytes.write_u8(24).unwrap();
+    bytes.write_bytes(b"ACFB\x00\x00\x00\x01").unwrap();
+    match read_request(&bytes) {
+        Request::ConfigImport { backup } => assert_eq!(backup, b"ACFB\x00\x00\x00\x01"),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_config_transaction() {
+    let mut bytes = Vec::new();
+    bytes.write_u8(25).unwrap();
+    bytes.write_u32(2).unwrap();
+    bytes.write_u8(0).unwrap();
+    bytes.write_string("ip").unwrap();
+    bytes.write_bytes(b"192.168.1.70").unwrap();
+    bytes.write_u8(1).unwrap();
+    bytes.write_string("ip6").unwrap();
+    match read_request(&bytes) {
+        Request::ConfigTransaction(changes) => assert_eq!(changes, vec![
+            ConfigChange::Write { key: "ip".to_owned(), value: b"192.168.1.70".to_vec() },
+            ConfigChange::Remove { key: "ip6".to_owned() },
+        ]),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    let mut reader = Cursor::new(&[25u8, 0, 0, 0, 1, 2][..]);
+    match Request::read_from(&mut reader) {
+        Err(Error::UnknownConfigChange(2)) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn request_config_invalid_key() {
+    let mut bytes = Vec::new();
+    bytes.write_u8(12).unwrap();
+    bytes.write_bytes(b"\xff\xfe").unwrap();
+    let mut reader = Cursor::new(bytes);
+    match Request::read_from(&mut reader) {
+        Err(Error::Utf8(_)) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn request_flash_kernel() {
+    let mut bytes = Vec::new();
+    bytes.write_u8(26).unwrap();
+    bytes.write_string("startup_kernel").unwrap();
+    bytes.write_bytes(b"\x7fELF").unwrap();
+    match read_request(&bytes) {
+        Request::FlashKernelWrite { name, data } => {
+            assert_eq!(name, "startup_kernel");
+            assert_eq!(data, b"\x7fELF");
+        }
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    match read_request(&[27]) {
+        Request::FlashKernelList => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    let mut bytes = Vec::new();
+    bytes.write_u8(28).unwrap();
+    bytes.write_string("idle_kernel").unwrap();
+    match read_request(&bytes) {
+        Request::FlashKernelRemove { name } => assert_eq!(name, "idle_kernel"),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    let mut bytes = Vec::new();
+    bytes.write_u8(29).unwrap();
+    bytes.write_bytes(b"\x7fELF").unwrap();
+    match read_request(&bytes) {
+        Request::FlashKernelCheck { data } => assert_eq!(data, b"\x7fELF"),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_misc() {
+    match read_request(&[5]) {
+        Request::Reboot => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+    match read_request(&[8]) {
+        Request::DebugAllocator => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_flash() {
+    for &(code, image) in [(0, FlashImage::Firmware), (1, FlashImage::Gateware)].iter() {
+        let mut bytes = Vec::new();
+        bytes.write_u8(16).unwrap();
+        bytes.write_u8(code).unwrap();
+        bytes.write_u32(0x12_3456).unwrap();
+        match read_request(&bytes) {
+            Request::FlashBegin { image: decoded, length } => {
+                assert_eq!(decoded, image);
+                assert_eq!(length, 0x12_3456);
+            }
+            request => panic!("unexpected request {:?}", request)
+        }
+    }
+
+    let mut bytes = Vec::new();
+    bytes.write_u8(17).unwrap();
+    bytes.write_bytes(b"\x00\x01\x02\xff").unwrap();
+    match read_request(&bytes) {
+        Request::FlashData(data) => assert_eq!(data, b"\x00\x01\x02\xff"),
+        request => panic!("unexpected request {:?}", request)
+    }
+
+    match read_request(&[18]) {
+        Request::FlashEnd => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_flash_unknown_image() {
+    let mut reader = Cursor::new(&[16u8, 2, 0, 0, 0, 0][..]);
+    match Request::read_from(&mut reader) {
+        Err(Error::UnknownFlashImage(2)) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn request_boot() {
+    match read_request(&[19]) {
+        Request::GetBootStatus => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+    match read_request(&[20]) {
+        Request::ConfirmBoot => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_crash_dump() {
+    match read_request(&[30]) {
+        Request::GetCrashDump => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+    match read_request(&[31]) {
+        Request::ClearCrashDump => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_network_status() {
+    match read_request(&[32]) {
+        Request::GetNetworkStatus => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_unknown() {
+    let mut reader = Cursor::new(&[0xffu8][..]);
+    match Request::read_from(&mut reader) {
+        Err(Error::UnknownPacket(0xff)) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn reply_status() {
+    for &(ref reply, code) in [(Reply::Success, 1), (Reply::Error, 6),
+                           (Reply::Unavailable, 4), (Reply::RebootImminent, 3)].iter() {
+        let mut reader = write_reply(reply);
+        assert_eq!(reader.read_u8().unwrap(), code);
+        assert_consumed(&reader);
+    }
+}
+
+#[test]
+fn reply_log_content() {
+    let mut reader = write_reply(&Reply::LogContent("[     0.000000s]  INFO(runtime): booting\n"));
+    assert_eq!(reader.read_u8().unwrap(), 2);
+    assert_eq!(reader.read_string().unwrap(), "[     0.000000s]  INFO(runtime): booting\n");
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_log_record() {
+    let mut reader = write_reply(&Reply::LogRecord {
+        sequence:  42,
+        timestamp: 1_500_000,
+        level:     log::Level::Warn,
+        target:    "runtime::rtio_mgt",
+        message:   "link down"
+    });
+    assert_eq!(reader.read_u8().unwrap(), 14);
+    assert_eq!(reader.read_u64().unwrap(), 42);
+    assert_eq!(reader.read_u64().unwrap(), 1_500_000);
+    assert_eq!(reader.read_u8().unwrap(), 2);
+    assert_eq!(reader.read_string().unwrap(), "runtime::rtio_mgt");
+    assert_eq!(reader.read_string().unwrap(), "link down");
+    assert_consumed(&reader);
+
+    let mut reader = write_reply(&Reply::LogGap { from: 10, to: 17 });
+    assert_eq!(reader.read_u8().unwrap(), 15);
+    assert_eq!(reader.read_u64().unwrap(), 10);
+    assert_eq!(reader.read_u64().unwrap(), 17);
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_config_data() {
+    let mut reader = write_reply(&Reply::ConfigData(b"ext0_synth0_10to125"));
+    assert_eq!(reader.read_u8().unwrap(), 7);
+    assert_eq!(reader.read_bytes().unwrap(), b"ext0_synth0_10to125");
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_config_health() {
+    let mut reader = write_reply(&Reply::ConfigHealth {
+        records: 5, damaged: &[120, 4000], free: 60000, reclaimable: 312
+    });
+    assert_eq!(reader.read_u8().unwrap(), 11);
+    assert_eq!(reader.read_u32().unwrap(), 5);
+    assert_eq!(reader.read_u32().unwrap(), 2);
+    assert_eq!(reader.read_u32().unwrap(), 120);
+    assert_eq!(reader.read_u32().unwrap(), 4000);
+    assert_eq!(reader.read_u32().unwrap(), 60000);
+    assert_eq!(reader.read_u32().unwrap(), 312);
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_config_list() {
+    let entries = [
+        ConfigEntry { key: "mac", length: 17, value: Some(b"02:00:00:00:00:01") },
+        ConfigEntry { key: "idle_kernel", length: 4096, value: None },
+    ];
+    let mut reader = write_reply(&Reply::ConfigList(&entries));
+    assert_eq!(reader.read_u8().unwrap(), 12);
+    assert_eq!(reader.read_u32().unwrap(), 2);
+    assert_eq!(reader.read_string().unwrap(), "mac");
+    assert_eq!(reader.read_u32().unwrap(), 17);
+    assert_eq!(reader.read_bool().unwrap(), true);
+    assert_eq!(reader.read_bytes().unwrap(), b"02:00:00:00:00:01");
+    assert_eq!(reader.read_string().unwrap(), "idle_kernel");
+    assert_eq!(reader.read_u32().unwrap(), 4096);
+    assert_eq!(reader.read_bool().unwrap(), false);
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_flash_kernel_list() {
+    let kernels = [
+        FlashKernelEntry { name: "startup_kernel", length: 180224 },
+        FlashKernelEntry { name: "idle_kernel", length: 4096 },
+    ];
+    let mut reader = write_reply(&Reply::FlashKernelList { kernels: &kernels, free: 65536 });
+    assert_eq!(reader.read_u8().unwrap(), 13);
+    assert_eq!(reader.read_u32().unwrap(), 2);
+    assert_eq!(reader.read_string().unwrap(), "startup_kernel");
+    assert_eq!(reader.read_u32().unwrap(), 180224);
+    assert_eq!(reader.read_string().unwrap(), "idle_kernel");
+    assert_eq!(reader.read_u32().unwrap(), 4096);
+    assert_eq!(reader.read_u32().unwrap(), 65536);
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_error_message() {
+    let mut reader = write_reply(&Reply::ErrorMessage("image CRC mismatch"));
+    assert_eq!(reader.read_u8().unwrap(), 8);
+    assert_eq!(reader.read_string().unwrap(), "image CRC mismatch");
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_flash_progress() {
+    let mut reader = write_reply(&Reply::FlashProgress { written: 65536, length: 1048584 });
+    assert_eq!(reader.read_u8().unwrap(), 9);
+    assert_eq!(reader.read_u32().unwrap(), 65536);
+    assert_eq!(reader.read_u32().unwrap(), 1048584);
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_boot_status() {
+    let mut reader = write_reply(&Reply::BootStatus { slot: 1, trial: true, attempts: 2 });
+    assert_eq!(reader.read_u8().unwrap(), 10);
+    assert_eq!(reader.read_u8().unwrap(), 1);
+    assert_eq!(reader.read_bool().unwrap(), true);
+    assert_eq!(reader.read_u8().unwrap(), 2);
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_crash_dump() {
+    let mut reader = write_reply(&Reply::CrashDump("panic at runtime/main.rs:1:1: test\n"));
+    assert_eq!(reader.read_u8().unwrap(), 16);
+    assert_eq!(reader.read_string().unwrap(), "panic at runtime/main.rs:1:1: test\n");
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_network_status() {
+    const NO_IPV6: Ipv6Status = Ipv6Status { addresses: &[], router: None, dns_servers: &[] };
+
+    let mut reader = write_reply(&Reply::NetworkStatus {
+        ipv4_config: Ipv4Config::Static, ipv4_address: Some("192.168.1.70/24"), lease: None,
+        ipv6: NO_IPV6
+    });
+    assert_eq!(reader.read_u8().unwrap(), 17);
+    assert_eq!(reader.read_u8().unwrap(), 0);
+    assert_eq!(reader.read_bool().unwrap(), true);
+    assert_eq!(reader.read_string().unwrap(), "192.168.1.70/24");
+    assert_eq!(reader.read_bool().unwrap(), false);
+    assert_eq!(reader.read_u32().unwrap(), 0);
+    assert_eq!(reader.read_bool().unwrap(), false);
+    assert_eq!(reader.read_u32().unwrap(), 0);
+    assert_consumed(&reader);
+
+    let lease = DhcpLease {
+        address: "10.0.0.12/16", server: "10.0.0.1", router: None,
+        dns_servers: &["10.0.0.1", "10.0.0.2"], remaining: 3600
+    };
+    let mut reader = write_reply(&Reply::NetworkStatus {
+        ipv4_config: Ipv4Config::Dhcp, ipv4_address: Some("10.0.0.12/16"), lease: Some(lease),
+        ipv6: NO_IPV6
+    });
+    assert_eq!(reader.read_u8().unwrap(), 17);
+    assert_eq!(reader.read_u8().unwrap(), 1);
+    assert_eq!(reader.read_bool().unwrap(), true);
+    assert_eq!(reader.read_string().unwrap(), "10.0.0.12/16");
+    assert_eq!(reader.read_bool().unwrap(), true);
+    assert_eq!(reader.read_string().unwrap(), "10.0.0.12/16");
+    assert_eq!(reader.read_string().unwrap(), "10.0.0.1");
+    assert_eq!(reader.read_bool().unwrap(), false);
+    assert_eq!(reader.read_u32().unwrap(), 2);
+    assert_eq!(reader.read_string().unwrap(), "10.0.0.1");
+    assert_eq!(reader.read_string().unwrap(), "10.0.0.2");
+    assert_eq!(reader.read_u32().unwrap(), 3600);
+    assert_eq!(reader.read_u32().unwrap(), 0);
+    assert_eq!(reader.read_bool().unwrap(), false);
+    assert_eq!(reader.read_u32().unwrap(), 0);
+    assert_consumed(&reader);
+
+    let ipv6 = Ipv6Status {
+        addresses: &["fe80::ff:fe00:21/10", "2001:db8:1::ff:fe00:21/64"],
+        router: Some("fe80::1"), dns_servers: &["2001:db8::53"]
+    };
+    let mut reader = write_reply(&Reply::NetworkStatus {
+        ipv4_config: Ipv4Config::Fallback, ipv4_address: None, lease: None, ipv6: ipv6
+    });
+    assert_eq!(reader.read_u8().unwrap(), 17);
+    assert_eq!(reader.read_u8().unwrap(), 2);
+    assert_eq!(reader.read_bool().unwrap(), false);
+    assert_eq!(reader.read_bool().unwrap(), false);
+    assert_eq!(reader.read_u32().unwrap(), 2);
+    assert_eq!(reader.read_string().unwrap(), "fe80::ff:fe00:21/10");
+    assert_eq!(reader.read_string().unwrap(), "2001:db8:1::ff:fe00:21/64");
+    assert_eq!(reader.read_bool().unwrap(), true);
+    assert_eq!(reader.read_string().unwrap(), "fe80::1");
+    assert_eq!(reader.read_u32().unwrap(), 1);
+    assert_eq!(reader.read_string().unwrap(), "2001:db8::53");
+    assert_consumed(&reader);
+}
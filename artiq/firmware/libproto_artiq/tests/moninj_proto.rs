@@ -0,0 +1,127 @@
+extern crate io;
+extern crate proto_artiq;
+
+use io::{Cursor, ProtoRead, ProtoWrite};
+use proto_artiq::moninj_proto::{self, HostMessage, DeviceMessage, Error};
+
+fn read_message(bytes: &[u8]) -> HostMessage {
+    let mut reader = Cursor::new(bytes);
+    let message = HostMessage::read_from(&mut reader).unwrap();
+    assert_eq!(reader.position(), bytes.len(), "{:?} left trailing bytes", message);
+    message
+}
+
+fn write_message(message: &DeviceMessage) -> Cursor<Vec<u8>> {
+    let mut bytes = Vec::new();
+    message.write_to(&mut bytes).unwrap();
+    Cursor::new(bytes)
+}
+
+#[test]
+fn magic() {
+    let mut reader = Cursor::new(&b"ARTIQ moninj\n"[..]);
+    moninj_proto::read_magic(&mut reader).unwrap();
+
+    let mut reader = Cursor::new(&b"ARTIQ coredev\n"[..]);
+    match moninj_proto::read_magic(&mut reader) {
+        Err(Error::WrongMagic) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn monitor_probe() {
+    for &enable in [false, true].iter() {
+        let mut bytes = Vec::new();
+        bytes.write_u8(0).unwrap();
+        bytes.write_bool(enable).unwrap();
+        bytes.write_u32(0x0001_0203).unwrap();
+        bytes.write_u8(1).unwrap();
+        match read_message(&bytes) {
+            HostMessage::MonitorProbe { enable: e, channel, probe } =>
+                assert_eq!((e, channel, probe), (enable, 0x0001_0203, 1)),
+            message => panic!("unexpected message {:?}", message)
+        }
+    }
+}
+
+#[test]
+fn inject() {
+    let mut bytes = Vec::new();
+    bytes.write_u8(1).unwrap();
+    bytes.write_u32(0x0002_0000).unwrap();
+    bytes.write_u8(1).unwrap();
+    bytes.write_u8(0).unwrap();
+    match read_message(&bytes) {
+        HostMessage::Inject { channel, overrd, value } =>
+            assert_eq!((channel, overrd, value), (0x0002_0000, 1, 0)),
+        message => panic!("unexpected message {:?}", message)
+    }
+}
+
+#[test]
+fn get_injection_status() {
+    let mut bytes = Vec::new();
+    bytes.write_u8(2).unwrap();
+    bytes.write_u32(5).unwrap();
+    bytes.write_u8(2).unwrap();
+    match read_message(&bytes) {
+        HostMessage::GetInjectionStatus { channel, overrd } =>
+            assert_eq!((channel, overrd), (5, 2)),
+        message => panic!("unexpected message {:?}", message)
+    }
+}
+
+#[test]
+fn monitor_injection() {
+    let mut bytes = Vec::new();
+    bytes.write_u8(3).unwrap();
+    bytes.write_bool(true).unwrap();
+    bytes.write_u32(6).unwrap();
+    bytes.write_u8(0).unwrap();
+    match read_message(&bytes) {
+        HostMessage::MonitorInjection { enable, channel, overrd } =>
+            assert_eq!((enable, channel, overrd), (true, 6, 0)),
+        message => panic!("unexpected message {:?}", message)
+    }
+}
+
+#[test]
+fn unknown_message() {
+    let mut reader = Cursor::new(&[4u8][..]);
+    match HostMessage::read_from(&mut reader) {
+        Err(Error::UnknownPacket(4)) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn truncated_message() {
+    let mut reader = Cursor::new(&[0u8, 1, 0x12, 0x34][..]);
+    match HostMessage::read_from(&mut reader) {
+        Err(Error::Io(_)) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn monitor_status() {
+    let mut reader = write_message(&DeviceMessage::MonitorStatus {
+        channel: 0x0003_0004, probe: 2, value: 0xffff_0000_1234_5678 });
+    assert_eq!(reader.read_u8().unwrap(), 0);
+    assert_eq!(reader.read_u32().unwrap(), 0x0003_0004);
+    assert_eq!(reader.read_u8().unwrap(), 2);
+    assert_eq!(reader.read_u64().unwrap(), 0xffff_0000_1234_5678);
+    assert_eq!(reader.position(), reader.get_ref().len());
+}
+
+#[test]
+fn injection_status() {
+    let mut reader = write_message(&DeviceMessage::InjectionStatus {
+        channel: 9, overrd: 1, value: 1 });
+    assert_eq!(reader.read_u8().unwrap(), 1);
+    assert_eq!(reader.read_u32().unwrap(), 9);
+    assert_eq!(reader.read_u8().unwrap(), 1);
+    assert_eq!(reader.read_u8().unwrap(), 1);
+    assert_eq!(reader.position(), reader.get_ref().len());
+}
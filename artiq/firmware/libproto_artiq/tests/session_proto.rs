@@ -0,0 +1,310 @@
+extern crate cslice;
+extern crate eh;
+extern crate io;
+extern crate proto_artiq;
+
+use cslice::CSlice;
+use eh::eh_artiq::{Exception, StackPointerBacktrace};
+use io::{Cursor, ProtoRead, ProtoWrite};
+use proto_artiq::session_proto::{self, Request, Reply, Error, DeviceMap, TimeCorrelation};
+
+const SYNC: [u8; 4] = [0x5a; 4];
+
+// Encodes requests the way artiq.coredevice.comm_kernel does.
+fn request(ty: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&SYNC);
+    bytes.write_u8(ty).unwrap();
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+fn read_request(bytes: &[u8]) -> Request {
+    let mut reader = Cursor::new(bytes);
+    let request = Request::read_from(&mut reader).unwrap();
+    assert_eq!(reader.position(), bytes.len(), "{:?} left trailing bytes", request);
+    request
+}
+
+fn write_reply(reply: &Reply) -> Cursor<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reply.write_to(&mut bytes).unwrap();
+    let mut reader = Cursor::new(bytes);
+    let mut sync = [0; 4];
+    reader.read_exact(&mut sync).unwrap();
+    assert_eq!(sync, SYNC);
+    reader
+}
+
+fn assert_consumed(reader: &Cursor<Vec<u8>>) {
+    assert_eq!(reader.position(), reader.get_ref().len());
+}
+
+fn cslice(s: &str) -> CSlice<u8> {
+    unsafe { CSlice::new(s.as_ptr(), s.len()) }
+}
+
+#[test]
+fn magic() {
+    let mut reader = Cursor::new(&b"ARTIQ coredev\n"[..]);
+    session_proto::read_magic(&mut reader).unwrap();
+
+    let mut reader = Cursor::new(&b"ARTIQ moninj\n\n"[..]);
+    match session_proto::read_magic(&mut reader) {
+        Err(Error::WrongMagic) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn request_system_info() {
+    match read_request(&request(3, &[])) {
+        Request::SystemInfo => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_load_kernel() {
+    let mut body = Vec::new();
+    body.write_bytes(b"\x7fELF kernel").unwrap();
+    match read_request(&request(5, &body)) {
+        Request::LoadKernel(kernel) => assert_eq!(kernel, b"\x7fELF kernel"),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_run_kernel() {
+    match read_request(&request(6, &[])) {
+        Request::RunKernel => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_rpc_reply() {
+    let mut body = Vec::new();
+    body.write_bytes(b"i").unwrap();
+    match read_request(&request(7, &body)) {
+        Request::RpcReply { tag } => assert_eq!(tag, b"i"),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_rpc_exception() {
+    let mut body = Vec::new();
+    body.write_u32(11).unwrap();
+    body.write_u32(0x1000).unwrap();
+    body.write_i64(-1).unwrap();
+    body.write_i64(2).unwrap();
+    body.write_i64(i64::MAX).unwrap();
+    body.write_u32(0x2000).unwrap();
+    body.write_u32(42).unwrap();
+    body.write_u32(7).unwrap();
+    body.write_u32(0x3000).unwrap();
+    match read_request(&request(8, &body)) {
+        Request::RpcException { id, message, param, file, line, column, function } => {
+            assert_eq!((id, message, file, line, column, function),
+                       (11, 0x1000, 0x2000, 42, 7, 0x3000));
+            assert_eq!(param, [-1, 2, i64::MAX]);
+        }
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_upload_subkernel() {
+    let mut body = Vec::new();
+    body.write_u32(5).unwrap();
+    body.write_u8(2).unwrap();
+    body.write_bytes(b"subkernel").unwrap();
+    match read_request(&request(9, &body)) {
+        Request::UploadSubkernel { id, destination, kernel } => {
+            assert_eq!((id, destination), (5, 2));
+            assert_eq!(kernel, b"subkernel");
+        }
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_resynchronizes() {
+    let mut bytes = vec![0x00, 0x5a, 0x5a, 0x13];
+    bytes.extend_from_slice(&request(6, &[]));
+    match read_request(&bytes) {
+        Request::RunKernel => (),
+        request => panic!("unexpected request {:?}", request)
+    }
+}
+
+#[test]
+fn request_unknown() {
+    let mut reader = Cursor::new(request(0xee, &[]));
+    match Request::read_from(&mut reader) {
+        Err(Error::UnknownPacket(0xee)) => (),
+        result => panic!("unexpected result {:?}", result)
+    }
+}
+
+#[test]
+fn reply_system_info() {
+    let mut reader = write_reply(&Reply::SystemInfo {
+        ident: "8.0+test",
+        finished_cleanly: true,
+        time_correlation: None
+    });
+    assert_eq!(reader.read_u8().unwrap(), 2);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic).unwrap();
+    assert_eq!(&magic, b"AROR");
+    assert_eq!(reader.read_string().unwrap(), "8.0+test");
+    assert_eq!(reader.read_bool().unwrap(), true);
+    assert_eq!(reader.read_bool().unwrap(), false);
+    assert_eq!(reader.read_u64().unwrap(), 0);
+    assert_eq!(reader.read_u64().unwrap(), 0);
+    assert_eq!(reader.read_u64().unwrap(), 0);
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_system_info_time_correlation() {
+    let mut reader = write_reply(&Reply::SystemInfo {
+        ident: "8.0+test",
+        finished_cleanly: false,
+        time_correlation: Some(TimeCorrelation {
+            wall_clock:   1_700_000_000_123_456_789,
+            rtio_counter: 42_000_000_000,
+            round_trip:   350_000
+        })
+    });
+    assert_eq!(reader.read_u8().unwrap(), 2);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic).unwrap();
+    assert_eq!(reader.read_string().unwrap(), "8.0+test");
+    assert_eq!(reader.read_bool().unwrap(), false);
+    assert_eq!(reader.read_bool().unwrap(), true);
+    assert_eq!(reader.read_u64().unwrap(), 1_700_000_000_123_456_789);
+    assert_eq!(reader.read_u64().unwrap(), 42_000_000_000);
+    assert_eq!(reader.read_u64().unwrap(), 350_000);
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_load() {
+    let mut reader = write_reply(&Reply::LoadCompleted);
+    assert_eq!(reader.read_u8().unwrap(), 5);
+    assert_consumed(&reader);
+
+    let mut reader = write_reply(&Reply::LoadFailed("no such symbol"));
+    assert_eq!(reader.read_u8().unwrap(), 6);
+    assert_eq!(reader.read_string().unwrap(), "no such symbol");
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_kernel_finished() {
+    let mut reader = write_reply(&Reply::KernelFinished { async_errors: 0b101 });
+    assert_eq!(reader.read_u8().unwrap(), 7);
+    assert_eq!(reader.read_u8().unwrap(), 0b101);
+    assert_consumed(&reader);
+
+    let mut reader = write_reply(&Reply::KernelStartupFailed);
+    assert_eq!(reader.read_u8().unwrap(), 8);
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_kernel_exception() {
+    let mut device_map = DeviceMap::new();
+    device_map.insert(0x12, String::from("ttl0"));
+    session_proto::set_device_map(device_map);
+
+    let exceptions = [
+        Some(Exception {
+            id:       3,
+            file:     cslice("repository/experiment.py"),
+            line:     10,
+            column:   4,
+            function: cslice("run"),
+            message:  cslice("underflow on {rtio_channel_info:0}"),
+            param:    [0x12, -5, 6]
+        }),
+        Some(Exception {
+            id:       4,
+            // host-side string keys
+            file:     unsafe { CSlice::new(0x100 as *const u8, usize::MAX) },
+            line:     0,
+            column:   0,
+            function: unsafe { CSlice::new(0x200 as *const u8, usize::MAX) },
+            message:  unsafe { CSlice::new(0x300 as *const u8, usize::MAX) },
+            param:    [0, 0, 0]
+        }),
+    ];
+    let stack_pointers = [
+        StackPointerBacktrace { stack_pointer: 0x4000, initial_backtrace_size: 1, current_backtrace_size: 2 },
+        StackPointerBacktrace { stack_pointer: 0x3000, initial_backtrace_size: 2, current_backtrace_size: 2 },
+    ];
+    let backtrace = [(0x4500_1000, 0x4000), (0x4500_2000, 0x3000)];
+
+    let mut reader = write_reply(&Reply::KernelException {
+        exceptions: &exceptions,
+        stack_pointers: &stack_pointers,
+        backtrace: &backtrace,
+        async_errors: 1
+    });
+    assert_eq!(reader.read_u8().unwrap(), 9);
+    assert_eq!(reader.read_u32().unwrap(), 2);
+
+    assert_eq!(reader.read_u32().unwrap(), 3);
+    assert_eq!(reader.read_string().unwrap(), "underflow on 0x0012:ttl0");
+    assert_eq!(reader.read_u64().unwrap(), 0x12);
+    assert_eq!(reader.read_u64().unwrap() as i64, -5);
+    assert_eq!(reader.read_u64().unwrap(), 6);
+    assert_eq!(reader.read_string().unwrap(), "repository/experiment.py");
+    assert_eq!(reader.read_u32().unwrap(), 10);
+    assert_eq!(reader.read_u32().unwrap(), 4);
+    assert_eq!(reader.read_string().unwrap(), "run");
+
+    assert_eq!(reader.read_u32().unwrap(), 4);
+    assert_eq!((reader.read_u32().unwrap(), reader.read_u32().unwrap()), (u32::MAX, 0x300));
+    for _ in 0..3 {
+        assert_eq!(reader.read_u64().unwrap(), 0);
+    }
+    assert_eq!((reader.read_u32().unwrap(), reader.read_u32().unwrap()), (u32::MAX, 0x100));
+    assert_eq!(reader.read_u32().unwrap(), 0);
+    assert_eq!(reader.read_u32().unwrap(), 0);
+    assert_eq!((reader.read_u32().unwrap(), reader.read_u32().unwrap()), (u32::MAX, 0x200));
+
+    for sp in stack_pointers.iter() {
+        assert_eq!(reader.read_u32().unwrap() as usize, sp.stack_pointer);
+        assert_eq!(reader.read_u32().unwrap() as usize, sp.initial_backtrace_size);
+        assert_eq!(reader.read_u32().unwrap() as usize, sp.current_backtrace_size);
+    }
+
+    assert_eq!(reader.read_u32().unwrap(), 2);
+    for &(addr, sp) in backtrace.iter() {
+        assert_eq!(reader.read_u32().unwrap() as usize, addr);
+        assert_eq!(reader.read_u32().unwrap() as usize, sp);
+    }
+    assert_eq!(reader.read_u8().unwrap(), 1);
+    assert_consumed(&reader);
+}
+
+#[test]
+fn reply_rpc_request() {
+    for &async in [false, true].iter() {
+        let mut reader = write_reply(&Reply::RpcRequest { async: async });
+        assert_eq!(reader.read_u8().unwrap(), 10);
+        assert_eq!(reader.read_bool().unwrap(), async);
+        assert_consumed(&reader);
+    }
+}
+
+#[test]
+fn reply_clock_failure() {
+    let mut reader = write_reply(&Reply::ClockFailure);
+    assert_eq!(reader.read_u8().unwrap(), 15);
+    assert_consumed(&reader);
+}
@@ -6,6 +6,8 @@ use sched::{Io, Mutex, TcpListener, TcpStream, Error as SchedError};
 use analyzer_proto::*;
 use urc::Urc;
 use board_artiq::drtio_routing;
+use sntp;
+use net_allow;
 use core::cell::RefCell;
 
 const BUFFER_SIZE: usize = 512 * 1024;
@@ -105,7 +107,8 @@ fn worker(stream: &mut TcpStream, _io: &Io, _aux_mutex: &Mutex,
             sent_bytes: local_sent_bytes + remote.sent_bytes,
             overflow_occurred: local_overflow_occurred | remote.overflow_occurred,
             log_channel: csr::CONFIG_RTIO_LOG_CHANNEL as u8,
-            dds_onehot_sel: true 
+            dds_onehot_sel: true,
+            time_correlation: sntp::latest()
         }, remote.data),
         Err(e) => {
             error!("Error getting remote analyzer data: {}", e);
@@ -114,7 +117,8 @@ fn worker(stream: &mut TcpStream, _io: &Io, _aux_mutex: &Mutex,
                 sent_bytes: local_sent_bytes,
                 overflow_occurred: true,
                 log_channel: csr::CONFIG_RTIO_LOG_CHANNEL as u8,
-                dds_onehot_sel: true 
+                dds_onehot_sel: true,
+                time_correlation: sntp::latest()
             },
             Vec::new())
         }
@@ -126,7 +130,8 @@ fn worker(stream: &mut TcpStream, _io: &Io, _aux_mutex: &Mutex,
         sent_bytes: local_sent_bytes,
         overflow_occurred: local_overflow_occurred,
         log_channel: csr::CONFIG_RTIO_LOG_CHANNEL as u8,
-        dds_onehot_sel: true  // kept for backward compatibility of analyzer dumps
+        dds_onehot_sel: true, // kept for backward compatibility of analyzer dumps
+        time_correlation: sntp::latest()
     };
     debug!("{:?}", header);
 
@@ -153,7 +158,15 @@ pub fn thread(io: Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &M
     loop {
         arm();
 
-        let mut stream = listener.accept().expect("analyzer: cannot accept");
+        // Refused connections must not rearm the analyzer, which would
+        // clear the buffer.
+        let mut stream = loop {
+            let stream = listener.accept().expect("analyzer: cannot accept");
+            if net_allow::allowed(&stream) {
+                break stream
+            }
+            stream.abort().expect("analyzer: cannot abort");
+        };
         info!("connection from {}", stream.remote_endpoint());
 
         disarm();
@@ -0,0 +1,87 @@
+use alloc::vec::Vec;
+use io::Error as IoError;
+
+use board_misoc::{clock, config, net_settings};
+use auth_proto;
+use sched::{TcpStream, Error as SchedError};
+
+// When the `auth_key` config key is set, hosts connecting to the session and
+// management ports have to prove that they know its value; see auth_proto.
+// Without it, the handshake is unchanged and any host is accepted.
+
+static mut NONCE_COUNTER: u64 = 0;
+// The boot count and MAC address, set by `startup`.
+static mut NONCE_PREFIX: Option<[u8; 10]> = None;
+
+fn key() -> Option<Vec<u8>> {
+    config::read("auth_key", |r| r.ok().map(|key| key.to_vec()))
+}
+
+// Counts the boots in which authentication was used, in the `auth_boot_count`
+// config key.
+fn count_boot() -> Result<u32, config::Error> {
+    let count = config::read_str("auth_boot_count", |r| r.ok().and_then(|s| s.parse().ok()))
+        .map(|count: u32| count.wrapping_add(1))
+        .unwrap_or(0);
+    config::write_int("auth_boot_count", count)?;
+    Ok(count)
+}
+
+/// Counts this boot if a key is configured; called at startup, and again
+/// when the key is written. Without a stored boot count, the nonces could
+/// repeat those of an earlier boot, so hosts are then refused.
+pub fn startup() {
+    if unsafe { NONCE_PREFIX.is_some() } || key().is_none() {
+        return
+    }
+    match count_boot() {
+        Ok(count) => {
+            let mut prefix = [0; 10];
+            prefix[..4].copy_from_slice(&count.to_be_bytes());
+            prefix[4..].copy_from_slice(&net_settings::get_adresses().hardware_addr.0);
+            unsafe { NONCE_PREFIX = Some(prefix) }
+        }
+        Err(err) =>
+            error!("cannot store the boot count, refusing authenticated connections: {}", err)
+    }
+}
+
+// The nonces only have to never repeat and to be unpredictable to someone who
+// does not know the key, which a MAC of a counter achieves. The time and the
+// counter start over at each boot, and the same key may be shared by several
+// core devices, so the boot count and the MAC address are included as well.
+fn nonce(key: &[u8], prefix: &[u8; 10]) -> [u8; auth_proto::NONCE_LEN] {
+    let counter = unsafe {
+        NONCE_COUNTER += 1;
+        NONCE_COUNTER
+    };
+    let mut message = [0; 26];
+    message[..10].copy_from_slice(prefix);
+    message[10..18].copy_from_slice(&clock::get_us().to_be_bytes());
+    message[18..].copy_from_slice(&counter.to_be_bytes());
+    auth_proto::hmac_sha256(key, &message)
+}
+
+/// Challenges the host after it sent the magic, if a key is configured, and
+/// returns whether it may proceed.
+pub fn authenticate(stream: &mut TcpStream) -> Result<bool, IoError<SchedError>> {
+    let key = match key() {
+        Some(key) => key,
+        None => return Ok(true)
+    };
+    let prefix = match unsafe { NONCE_PREFIX } {
+        Some(prefix) => prefix,
+        None => {
+            warn!("refusing {}, as the boot count could not be stored",
+                  stream.remote_endpoint());
+            return Ok(false)
+        }
+    };
+    let nonce = nonce(&key, &prefix);
+    auth_proto::write_challenge(stream, &nonce)?;
+    let correct = auth_proto::read_answer(stream, &key, &nonce)?;
+    if !correct {
+        warn!("wrong authentication key from {}", stream.remote_endpoint());
+    }
+    Ok(correct)
+}
@@ -0,0 +1,239 @@
+use core::str::{self, FromStr};
+use alloc::string::String;
+use log::LevelFilter;
+use smoltcp::wire::{EthernetAddress, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr};
+
+use io::{Cursor, ProtoRead};
+use board_misoc::{flash_image, net_settings::{self, Ipv4AddrConfig}};
+use board_artiq::drtio_routing;
+use logger_artiq::LogFilter;
+use rtio_clocking;
+use syslog;
+use sntp;
+use mdns;
+use net_allow;
+
+// Values of the config keys read by the firmware are checked when they are
+// written through the management interface, rather than being found invalid
+// (and silently replaced by a default) at the next boot. Keys that are not
+// listed here are written without checking.
+struct Key {
+    name:  &'static str,
+    check: fn(&str) -> Result<(), String>
+}
+
+static KEYS: &'static [Key] = &[
+    Key { name: "mac",                     check: check_mac },
+    Key { name: "ip",                      check: check_ip },
+    Key { name: "ip6",                     check: check_ip6 },
+    Key { name: "ipv4_default_route",      check: check_ipv4_route },
+    Key { name: "ipv6_default_route",      check: check_ipv6_route },
+    Key { name: "ip6_autoconf",            check: check_flag },
+    Key { name: "net_allow",               check: check_net_allow },
+    Key { name: "mgmt_allow",              check: check_net_allow },
+    Key { name: "ip_fallback",             check: check_ip_fallback },
+    Key { name: "dhcp_timeout",            check: check_dhcp_timeout },
+    Key { name: "hostname",                check: check_hostname },
+    Key { name: "mdns_instance",           check: check_mdns_instance },
+    Key { name: "rtio_clock",              check: check_rtio_clock },
+    Key { name: "log_level",               check: check_log_level },
+    Key { name: "uart_log_level",          check: check_log_level },
+    Key { name: "log_filter",              check: check_log_filter },
+    Key { name: "uart_log_filter",         check: check_log_filter },
+    Key { name: "syslog_server",           check: check_syslog_server },
+    Key { name: "syslog_level",            check: check_log_level },
+    Key { name: "ntp_server",              check: check_ntp_server },
+    Key { name: "boot_confirm",            check: check_boot_confirm },
+    Key { name: "net_trace",               check: check_flag },
+    Key { name: "panic_reset",             check: check_flag },
+    Key { name: "no_flash_boot",           check: check_flag },
+    Key { name: "allow_unsigned_firmware", check: check_allow_unsigned_firmware },
+];
+
+// Keys with binary values.
+struct BinaryKey {
+    name:  &'static str,
+    check: fn(&[u8]) -> Result<(), String>
+}
+
+static BINARY_KEYS: &'static [BinaryKey] = &[
+    BinaryKey { name: "routing_table", check: check_routing_table },
+    BinaryKey { name: "device_map",    check: check_device_map },
+    BinaryKey { name: "auth_key",      check: check_auth_key },
+];
+
+fn parse<T: FromStr>(value: &str, expected: &str) -> Result<(), String> {
+    value.parse::<T>().map(|_| ()).map_err(|_| format!("expected {}", expected))
+}
+
+fn check_mac(value: &str) -> Result<(), String> {
+    parse::<EthernetAddress>(value, "a MAC address such as 02:00:00:00:00:01")
+}
+
+fn check_ip(value: &str) -> Result<(), String> {
+    parse::<Ipv4AddrConfig>(value,
+        "an IPv4 address with optional prefix length, such as 192.168.1.70/24, or use_dhcp")
+}
+
+fn check_ip6(value: &str) -> Result<(), String> {
+    parse::<Ipv6Cidr>(value, "an IPv6 address with prefix length, such as fd00::70/64")
+}
+
+fn check_ipv4_route(value: &str) -> Result<(), String> {
+    parse::<Ipv4Address>(value, "an IPv4 address")
+}
+
+fn check_ipv6_route(value: &str) -> Result<(), String> {
+    parse::<Ipv6Address>(value, "an IPv6 address")
+}
+
+fn check_net_allow(value: &str) -> Result<(), String> {
+    match net_allow::parse(value) {
+        Some(_) => Ok(()),
+        None => Err(String::from("expected networks separated by commas, such as 10.0.0.0/16, fd00::/8"))
+    }
+}
+
+fn check_ip_fallback(value: &str) -> Result<(), String> {
+    parse::<Ipv4Cidr>(value, "an IPv4 address with prefix length, such as 192.168.1.70/24")
+}
+
+fn check_dhcp_timeout(value: &str) -> Result<(), String> {
+    parse::<u64>(value, "a number of seconds")
+}
+
+fn check_hostname(value: &str) -> Result<(), String> {
+    match net_settings::parse_hostname(value) {
+        Some(_) => Ok(()),
+        None => Err(format!("expected up to {} letters, digits and hyphens, such as kasli-lab1",
+                            net_settings::HOSTNAME_MAX_LEN))
+    }
+}
+
+fn check_mdns_instance(value: &str) -> Result<(), String> {
+    match mdns::parse_instance(value) {
+        Some(_) => Ok(()),
+        None => Err(format!("expected up to {} bytes of text without control characters",
+                            mdns::INSTANCE_MAX_LEN))
+    }
+}
+
+fn check_rtio_clock(value: &str) -> Result<(), String> {
+    match value {
+        "i" | "e" => Ok(()),
+        _ if rtio_clocking::parse_rtio_clock(value).is_some() => Ok(()),
+        _ => Err(String::from("expected one of int_125, int_100, ext0_bypass, \
+                               ext0_synth0_10to125, ext0_synth0_80to125, \
+                               ext0_synth0_100to125, ext0_synth0_125to125"))
+    }
+}
+
+fn check_log_level(value: &str) -> Result<(), String> {
+    parse::<LevelFilter>(value, "one of OFF, ERROR, WARN, INFO, DEBUG, TRACE")
+}
+
+fn check_log_filter(value: &str) -> Result<(), String> {
+    value.parse::<LogFilter>().map(|_| ()).map_err(|err| format!("{}", err))
+}
+
+fn check_syslog_server(value: &str) -> Result<(), String> {
+    match syslog::parse_server(value) {
+        Some(_) => Ok(()),
+        None => Err(String::from("expected an IP address with optional port, \
+                                  such as 192.168.1.10 or [fd00::10]:514"))
+    }
+}
+
+fn check_ntp_server(value: &str) -> Result<(), String> {
+    match sntp::parse_server(value) {
+        Some(_) => Ok(()),
+        None => Err(String::from("expected an IP address with optional port, \
+                                  such as 192.168.1.10 or [fd00::10]:123"))
+    }
+}
+
+fn check_boot_confirm(value: &str) -> Result<(), String> {
+    match value {
+        "auto" | "manual" => Ok(()),
+        _ => Err(String::from("expected auto or manual"))
+    }
+}
+
+fn check_flag(value: &str) -> Result<(), String> {
+    match value {
+        "0" | "1" => Ok(()),
+        _ => Err(String::from("expected 0 or 1"))
+    }
+}
+
+fn check_allow_unsigned_firmware(value: &str) -> Result<(), String> {
+    // Otherwise, anyone who can reach the management interface could disable
+    // the signature requirement, and then install their own firmware.
+    if flash_image::signature_required() {
+        return Err(String::from("firmware signatures are enforced, so this key can only \
+                                 be set in the config image written with artiq_flash"))
+    }
+    check_flag(value)
+}
+
+fn check_routing_table(value: &[u8]) -> Result<(), String> {
+    let expected = drtio_routing::DEST_COUNT * drtio_routing::MAX_HOPS;
+    // Without DRTIO routing, the table is not used.
+    if expected != 0 && value.len() != expected {
+        return Err(format!("expected {} bytes, as made by artiq_route, got {}",
+                           expected, value.len()))
+    }
+    Ok(())
+}
+
+fn check_device_map(value: &[u8]) -> Result<(), String> {
+    let mut reader = Cursor::new(value);
+    let count = reader.read_u32().map_err(|_| String::from("truncated header"))?;
+    for index in 0..count {
+        let truncated = || format!("entry {} of {} is truncated", index, count);
+        let _channel = reader.read_u32().map_err(|_| truncated())?;
+        let length = reader.read_u32().map_err(|_| truncated())? as usize;
+        // Check the length before reading, as it may be anything.
+        let start = reader.position();
+        if length > value.len() - start {
+            return Err(truncated())
+        }
+        str::from_utf8(&value[start..start + length])
+            .map_err(|_| format!("name in entry {} is not valid UTF-8", index))?;
+        reader.set_position(start + length);
+    }
+    if reader.position() != value.len() {
+        return Err(format!("{} bytes of trailing data", value.len() - reader.position()))
+    }
+    Ok(())
+}
+
+fn check_auth_key(value: &[u8]) -> Result<(), String> {
+    // Short keys could be found by trying them all against a recorded
+    // challenge and answer.
+    if value.len() < 16 {
+        return Err(format!("expected at least 16 bytes, such as from /dev/urandom, got {}",
+                           value.len()))
+    }
+    Ok(())
+}
+
+/// Checks a value about to be written to the config key `key`, returning
+/// a description of the problem if it is not valid.
+///
+/// Empty values remove the key, and are always accepted.
+pub fn check(key: &str, value: &[u8]) -> Result<(), String> {
+    if value.is_empty() {
+        return Ok(())
+    }
+    let result = if let Some(entry) = KEYS.iter().find(|entry| entry.name == key) {
+        str::from_utf8(value)
+            .map_err(|_| String::from("not valid UTF-8"))
+            .and_then(entry.check)
+    } else if let Some(entry) = BINARY_KEYS.iter().find(|entry| entry.name == key) {
+        (entry.check)(value)
+    } else {
+        Ok(())
+    };
+    result.map_err(|reason| format!("invalid value for {}: {}", key, reason))
+}
@@ -0,0 +1,41 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use sched::Io;
+
+// Subsystems that read config keys once at startup subscribe to them here,
+// so that changes made through the management interface are applied without
+// a reboot. Handlers run in the thread that made the change, after the change
+// has been written to flash, and read the new values from the config store.
+struct Subscriber {
+    keys:    &'static [&'static str],
+    handler: Box<dyn Fn(&Io)>
+}
+
+static mut SUBSCRIBERS: Vec<Subscriber> = Vec::new();
+
+/// Calls `handler` every time one of `keys` is written or removed.
+///
+/// Subscriptions are made at startup, before any thread is spawned.
+pub fn subscribe<F: Fn(&Io) + 'static>(keys: &'static [&'static str], handler: F) {
+    unsafe {
+        SUBSCRIBERS.push(Subscriber { keys: keys, handler: Box::new(handler) })
+    }
+}
+
+/// Calls the handler of each subscriber to any of `keys`, once.
+pub fn notify(io: &Io, keys: &[&str]) {
+    for subscriber in unsafe { SUBSCRIBERS.iter() } {
+        if subscriber.keys.iter().any(|key| keys.contains(key)) {
+            (subscriber.handler)(io)
+        }
+    }
+}
+
+/// Calls the handler of every subscriber, after changes to arbitrary keys
+/// such as erasing or importing the whole config store.
+pub fn notify_all(io: &Io) {
+    for subscriber in unsafe { SUBSCRIBERS.iter() } {
+        (subscriber.handler)(io)
+    }
+}
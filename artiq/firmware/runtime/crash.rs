@@ -0,0 +1,121 @@
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use alloc::string::String;
+
+use board_misoc::{csr, crash_dump};
+use logger_artiq::BufferLogger;
+use TrapFrame;
+
+// When the runtime panics, a report is written to flash before restarting or
+// halting, so that it can be read even if the UART was not being watched. It
+// is logged once at the next startup, and served through the management
+// interface until it is cleared or replaced by the next one.
+const REPORT_SIZE: usize = 16384;
+// Number of log records at the end of the report.
+const LOG_TAIL: usize = 32;
+
+struct Trap {
+    frame:  TrapFrame,
+    mcause: usize,
+    mepc:   usize,
+    mtval:  usize
+}
+
+static mut TRAP: Option<Trap> = None;
+static mut SAVING: bool = false;
+static mut REPORT: [u8; REPORT_SIZE] = [0; REPORT_SIZE];
+
+// Formats into the report buffer, dropping whatever does not fit.
+struct ReportWriter {
+    data:   &'static mut [u8],
+    length: usize
+}
+
+impl fmt::Write for ReportWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut count = s.len().min(self.data.len() - self.length);
+        while !s.is_char_boundary(count) {
+            count -= 1
+        }
+        self.data[self.length..self.length + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.length += count;
+        Ok(())
+    }
+}
+
+/// Notes the state of the CPU when an exception is taken, for the report
+/// of the panic that follows.
+pub fn record_trap(frame: &TrapFrame, mcause: usize, mepc: usize, mtval: usize) {
+    unsafe {
+        TRAP = Some(Trap { frame: *frame, mcause: mcause, mepc: mepc, mtval: mtval })
+    }
+}
+
+fn write_report(writer: &mut ReportWriter, info: &PanicInfo) -> fmt::Result {
+    if let Some(location) = info.location() {
+        write!(writer, "panic at {}:{}:{}", location.file(), location.line(), location.column())?;
+    } else {
+        write!(writer, "panic at unknown location")?;
+    }
+    if let Some(message) = info.message() {
+        writeln!(writer, ": {}", message)?;
+    } else {
+        writeln!(writer, "")?;
+    }
+
+    if let Some(trap) = unsafe { TRAP.as_ref() } {
+        writeln!(writer, "mcause 0x{:08x}, mepc 0x{:08x}, mtval 0x{:08x}",
+                 trap.mcause, trap.mepc, trap.mtval)?;
+        writeln!(writer, "trap frame: {:x?}", trap.frame)?;
+    }
+
+    writeln!(writer, "backtrace for software version {}:", csr::CONFIG_IDENTIFIER_STR)?;
+    let _ = unwind_backtrace::backtrace(|ip| {
+        // As in the panic handler, show the call instruction.
+        let _ = writeln!(writer, "{:#08x}", ip - 4);
+    });
+
+    writeln!(writer, "log tail:")?;
+    BufferLogger::with(|logger| {
+        // The buffer is unavailable if the panic happened while it was in use.
+        if let Ok(buffer) = logger.buffer() {
+            let count = buffer.records().count();
+            for record in buffer.records().skip(count.saturating_sub(LOG_TAIL)) {
+                writeln!(writer, "{}", record)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Writes the report of a panic to flash.
+pub fn save(info: &PanicInfo) {
+    unsafe {
+        // Do not try again if writing the report panics.
+        if SAVING {
+            return
+        }
+        SAVING = true;
+
+        let mut writer = ReportWriter { data: &mut REPORT[..], length: 0 };
+        let _ = write_report(&mut writer, info);
+        crash_dump::write(&REPORT[..writer.length]);
+    }
+}
+
+/// Logs the report of the previous crash, if it has not been logged yet.
+pub fn log_previous() {
+    if !crash_dump::unreported() {
+        return
+    }
+    crash_dump::read(|report| {
+        if let Some(report) = report {
+            error!("the runtime crashed before the last restart, with this report \
+                    (use `artiq_coremgmt crash show` to read it again):");
+            for line in String::from_utf8_lossy(report).lines() {
+                error!("{}", line);
+            }
+        }
+    });
+    crash_dump::mark_reported();
+}
@@ -1,9 +1,31 @@
-use board_misoc::clock;
-use sched;
-use sched::Dhcpv4Socket;
 use core::fmt::{Display, Formatter};
-use smoltcp::socket::{Dhcpv4Config, Dhcpv4Event};
-use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, BigEndian};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpEndpoint, Ipv4Address, Ipv4Cidr};
+
+use board_misoc::{clock, config, net_settings};
+use sched::{Io, UdpSocket, Error as SchedError};
+use dhcp_proto::{self, Reply, Request, CLIENT_PORT, SERVER_PORT, DHCP_DISCOVER, DHCP_OFFER,
+                 DHCP_REQUEST, DHCP_ACK, DHCP_NAK, MAX_DNS_SERVERS};
+
+// The DHCP client (RFC 2131) runs while the `ip` config key is unset or
+// `use_dhcp`. It sends the host name of the device (option 12), so that the
+// DHCP server can register it in DNS, and the lease it obtains is reported
+// through the management interface. Its messages are in `dhcp_proto`.
+//
+// If no lease is obtained within `dhcp_timeout` seconds, the address in the
+// `ip_fallback` config key, if any, is used until the DHCP server answers.
+const PACKET_SIZE:          usize = 1500;
+const DEFAULT_TIMEOUT_S:    u64 = 30;
+// Replies to the first discover are often lost, as the Ethernet link is
+// not quite up yet, so it is repeated quickly.
+const FIRST_RETRY_MS:       u64 = 1_000;
+const MIN_RETRY_MS:         u64 = 4_000;
+const MAX_RETRY_MS:         u64 = 64_000;
+// Number of requests sent for an offer before discovering again.
+const REQUEST_ATTEMPTS:     u32 = 4;
+// Shortest interval between requests when renewing or rebinding.
+const MIN_RENEW_RETRY_MS:   u64 = 60_000;
 
 struct OptionalIpAddressDisplay<'a> (&'a Option<Ipv4Address>);
 
@@ -16,75 +38,285 @@ impl<'a> Display for OptionalIpAddressDisplay<'a> {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lease {
+    pub address:     Ipv4Cidr,
+    pub server:      Ipv4Address,
+    pub router:      Option<Ipv4Address>,
+    pub dns_servers: [Option<Ipv4Address>; MAX_DNS_SERVERS],
+    // Times in milliseconds since startup, or `None` for infinite leases.
+    renew_at:        Option<u64>,
+    rebind_at:       Option<u64>,
+    pub expires_at:  Option<u64>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Waiting,
+    Bound(Lease),
+    Fallback(Ipv4Cidr)
+}
+
+static mut STATUS: Status = Status::Waiting;
+
+/// Returns the state of the DHCP client; only meaningful while it runs.
+pub fn status() -> Status {
+    unsafe { STATUS }
+}
+
+fn set_status(status: Status) {
+    unsafe { STATUS = status }
+}
+
+struct Settings {
+    hardware_addr: EthernetAddress,
+    hostname:      Vec<u8>,
+    fallback:      Option<Ipv4Cidr>,
+    fallback_route: Option<Ipv4Address>,
+    timeout_ms:    u64
+}
+
+fn settings() -> Settings {
+    let net_addresses = net_settings::get_adresses();
+    let hostname = net_settings::with_hostname(net_addresses.hardware_addr,
+                                               |hostname| hostname.as_bytes().to_vec());
+    let fallback = match config::read_str("ip_fallback", |r| r.map(|s| s.parse::<Ipv4Cidr>())) {
+        Ok(Ok(fallback)) => Some(fallback),
+        Ok(Err(_)) => {
+            warn!("ignoring invalid `ip_fallback` config key");
+            None
+        }
+        Err(_) => None
+    };
+    let timeout_s = match config::read_str("dhcp_timeout", |r| r.map(|s| s.parse::<u64>())) {
+        Ok(Ok(timeout)) => timeout,
+        _ => DEFAULT_TIMEOUT_S
+    };
+    Settings {
+        hardware_addr:  net_addresses.hardware_addr,
+        hostname:       hostname,
+        fallback:       fallback,
+        fallback_route: net_addresses.ipv4_default_route,
+        timeout_ms:     timeout_s.saturating_mul(1000)
+    }
+}
+
+fn write_request(settings: &Settings, xid: u32, message_type: u8, client_addr: Ipv4Address,
+                 requested: Option<(Ipv4Address, Ipv4Address)>) -> Vec<u8> {
+    Request {
+        message_type:  message_type,
+        xid:           xid,
+        hardware_addr: settings.hardware_addr.0,
+        client_addr:   client_addr.0,
+        requested:     requested.map(|(address, server)| (address.0, server.0)),
+        hostname:      &settings.hostname
+    }.write()
+}
+
+fn lease(reply: &Reply, server: Ipv4Address, now: u64) -> Option<Lease> {
+    let prefix_len = IpAddress::Ipv4(Ipv4Address(reply.subnet_mask?)).prefix_len()?;
+    let your_addr = Ipv4Address(reply.your_addr);
+    if !your_addr.is_unicast() {
+        return None
+    }
+    // Times are in seconds; all ones is an infinite lease.
+    let lease_time = reply.lease_time.filter(|&time| time != 0xffff_ffff);
+    let at = |time: u32| now + time as u64 * 1000;
+    let mut dns_servers = [None; MAX_DNS_SERVERS];
+    for (dns_server, address) in dns_servers.iter_mut().zip(reply.dns_servers.iter()) {
+        *dns_server = address.map(Ipv4Address)
+    }
+    Some(Lease {
+        address:     Ipv4Cidr::new(your_addr, prefix_len),
+        server:      server,
+        router:      reply.router.map(Ipv4Address),
+        dns_servers: dns_servers,
+        renew_at:    lease_time.map(|time| at(reply.renewal_time.unwrap_or(time / 2))),
+        rebind_at:   lease_time.map(|time| at(reply.rebinding_time.unwrap_or(time / 8 * 7))),
+        expires_at:  lease_time.map(at)
+    })
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Discovering { attempt: u32 },
+    Requesting { address: Ipv4Address, server: Ipv4Address, attempt: u32 },
+    Bound(Lease)
+}
+
+fn configure(io: &Io, address: &Ipv4Cidr, router: Option<Ipv4Address>) {
+    io.set_ipv4_address(address);
+    match router {
+        Some(route) => { io.set_ipv4_default_route(route).unwrap(); }
+        None => { io.remove_ipv4_default_route(); }
+    }
+}
+
+fn deconfigure(io: &Io) {
+    io.set_ipv4_address(&Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0));
+    io.remove_ipv4_default_route();
+}
 
-pub fn dhcp_thread(io: sched::Io) {
-    let mut socket = Dhcpv4Socket::new(&io);
-    let mut last_config: Option<Dhcpv4Config> = None;
-    let mut done_reset = false;
-    let start_time = clock::get_ms();
+fn retry_interval(attempt: u32) -> u64 {
+    match attempt {
+        0 => FIRST_RETRY_MS,
+        _ => (MIN_RETRY_MS << (attempt - 1).min(4)).min(MAX_RETRY_MS)
+    }
+}
+
+fn run(io: &Io, settings: &Settings) -> Result<(), SchedError> {
+    let socket = UdpSocket::new(io, 2048);
+    socket.bind(CLIENT_PORT)?;
+    let broadcast = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::BROADCAST), SERVER_PORT);
 
+    let mut xid = clock::get_us() as u32 ^ BigEndian::read_u32(&settings.hardware_addr.0[2..]);
+    let mut state = State::Discovering { attempt: 0 };
+    let mut unconfigured_since = clock::get_ms();
+    let mut packet = vec![0; PACKET_SIZE];
     loop {
-        // A significant amount of the time our first discover isn't received
-        // by the server. This is likely to be because the ethernet device isn't quite
-        // ready at the point that it is sent. The following makes recovery from
-        // that faster.
-        if !done_reset && last_config.is_none() && start_time + 1000 < clock::get_ms() {
-            info!("Didn't get initial config in first second. Assuming packet loss, trying a reset.");
-            socket.reset();
-            done_reset = true;
+        let now = clock::get_ms();
+        if let Some(fallback) = settings.fallback {
+            if status() == Status::Waiting && now - unconfigured_since >= settings.timeout_ms {
+                warn!("no DHCP lease after {} s, using fallback address {}",
+                      settings.timeout_ms / 1000, fallback);
+                configure(io, &fallback, settings.fallback_route);
+                set_status(Status::Fallback(fallback));
+            }
         }
-        if let Some(event) = socket.poll() {
-            match event {
-                Dhcpv4Event::Configured(config) => {
-                    // Only compare the ip address in the config with previous config because we
-                    // ignore the rest of the config i.e. we don't do any DNS or require a default
-                    // gateway.
-                    let changed = if let Some(last_config) = last_config {
-                        let mut changed = false;
-                        if config.address != last_config.address {
-                            info!("IP address changed {} -> {}", last_config.address, config.address);
-                            changed = true;
-                        }
-                        if config.router != last_config.router {
-                            info!("Default route changed {} -> {}",
-                                OptionalIpAddressDisplay(&last_config.router),
-                                OptionalIpAddressDisplay(&config.router),
-                            );
-                            changed = true;
-                        }
-                        changed
-                    } else {
-                        info!("Acquired DHCP config IP address: None -> {} default route: None -> {}",
-                            config.address,
-                            OptionalIpAddressDisplay(&config.router),
-                        );
-                        true
-                    };
-                    if changed {
-                        last_config = Some(config);
-                        io.set_ipv4_address(&config.address);
-                        match config.router {
-                            Some(route) => { io.set_ipv4_default_route(route).unwrap(); }
-                            None => { io.remove_ipv4_default_route(); }
+
+        // Send a message for the current state, and find out when to send
+        // the next one.
+        let deadline = match state {
+            State::Discovering { attempt } => {
+                let request = write_request(settings, xid, DHCP_DISCOVER,
+                                            Ipv4Address::UNSPECIFIED, None);
+                socket.send_to(&request, broadcast)?;
+                now + retry_interval(attempt)
+            }
+            State::Requesting { address, server, attempt } => {
+                let request = write_request(settings, xid, DHCP_REQUEST,
+                                            Ipv4Address::UNSPECIFIED, Some((address, server)));
+                socket.send_to(&request, broadcast)?;
+                now + retry_interval(attempt + 1)
+            }
+            State::Bound(lease) => {
+                match (lease.renew_at, lease.rebind_at, lease.expires_at) {
+                    (Some(renew_at), Some(rebind_at), Some(expires_at)) if now >= renew_at => {
+                        if now >= expires_at {
+                            info!("DHCP lease of {} expired", lease.address);
+                            deconfigure(io);
+                            set_status(Status::Waiting);
+                            unconfigured_since = now;
+                            state = State::Discovering { attempt: 0 };
+                            continue
                         }
+                        // Renew with the server that gave the lease, then
+                        // with any server.
+                        let (destination, until) = if now < rebind_at {
+                            (IpEndpoint::new(IpAddress::Ipv4(lease.server), SERVER_PORT), rebind_at)
+                        } else {
+                            (broadcast, expires_at)
+                        };
+                        let request = write_request(settings, xid, DHCP_REQUEST,
+                                                    lease.address.address(), None);
+                        socket.send_to(&request, destination)?;
+                        now + ((until - now) / 2).max(MIN_RENEW_RETRY_MS).min(until - now)
                     }
+                    (Some(renew_at), ..) => renew_at,
+                    _ => now + MAX_RETRY_MS
+                }
+            }
+        };
+
+        // Wait for a reply, and follow it.
+        let reply = loop {
+            let now = clock::get_ms();
+            if now >= deadline {
+                break None
+            }
+            socket.set_timeout(Some(deadline - now));
+            let length = match socket.recv_from(&mut packet) {
+                Ok((length, _)) => length,
+                Err(SchedError::TimedOut) => break None,
+                Err(err) => return Err(err)
+            };
+            if let Some(reply) = dhcp_proto::read_reply(&settings.hardware_addr.0, xid,
+                                                        &packet[..length]) {
+                break Some(reply)
+            }
+        };
+        let now = clock::get_ms();
+        let replied = reply.is_some();
+        state = match (state, reply.as_ref()) {
+            (State::Discovering { .. }, Some(reply)) if reply.message_type == DHCP_OFFER => {
+                match reply.server {
+                    Some(server) => State::Requesting {
+                        address: Ipv4Address(reply.your_addr), server: Ipv4Address(server), attempt: 0
+                    },
+                    None => State::Discovering { attempt: 0 }
                 }
-                Dhcpv4Event::Deconfigured => {
-                    if let Some(config) = last_config {
-                        info!("Lost DHCP config IP address {} -> None; default route {} -> None",
-                            config.address,
-                            OptionalIpAddressDisplay(&config.router),
-                        );
-                        io.set_ipv4_address(&Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0));
-                        io.remove_ipv4_default_route();
-                        last_config = None;
+            }
+            (State::Discovering { attempt }, _) =>
+                State::Discovering { attempt: attempt + 1 },
+
+            (State::Requesting { server, .. }, Some(reply)) |
+            (State::Bound(Lease { server, .. }), Some(reply))
+                    if reply.message_type == DHCP_ACK => {
+                match lease(reply, reply.server.map(Ipv4Address).unwrap_or(server), now) {
+                    Some(lease) => {
+                        if let Status::Bound(previous) = status() {
+                            if previous.address != lease.address ||
+                                    previous.router != lease.router {
+                                info!("DHCP lease changed: address {}, default route {}",
+                                      lease.address, OptionalIpAddressDisplay(&lease.router));
+                            }
+                        } else {
+                            info!("acquired DHCP lease from {}: address {}, default route {}",
+                                  lease.server, lease.address,
+                                  OptionalIpAddressDisplay(&lease.router));
+                        }
+                        configure(io, &lease.address, lease.router);
+                        set_status(Status::Bound(lease));
+                        State::Bound(lease)
                     }
-                    // We always get one of these events at the start, ignore that one
+                    None => {
+                        warn!("ignoring DHCP lease without a valid address and subnet mask");
+                        State::Discovering { attempt: 0 }
+                    }
+                }
+            }
+            (State::Requesting { .. }, Some(reply)) |
+            (State::Bound(_), Some(reply)) if reply.message_type == DHCP_NAK => {
+                if let Status::Bound(lease) = status() {
+                    info!("DHCP server refused the lease of {}", lease.address);
+                    deconfigure(io);
+                    set_status(Status::Waiting);
+                    unconfigured_since = now;
                 }
+                State::Discovering { attempt: 0 }
             }
+            (State::Requesting { attempt, .. }, _) if attempt + 1 >= REQUEST_ATTEMPTS =>
+                State::Discovering { attempt: 0 },
+            (State::Requesting { address, server, attempt }, _) =>
+                State::Requesting { address: address, server: server, attempt: attempt + 1 },
+            (State::Bound(lease), _) => State::Bound(lease)
+        };
+        // Request an offer in the same transaction, and start a new one
+        // for the other exchanges.
+        if replied && !matches!(state, State::Requesting { .. }) {
+            xid = xid.wrapping_add(1)
         }
-        // We want to poll after every poll of the interface. So we need to
-        // do a minimal yield here.
-        io.relinquish().unwrap();
     }
 }
+
+pub fn dhcp_thread(io: Io) {
+    let settings = settings();
+    set_status(Status::Waiting);
+    match run(&io, &settings) {
+        // Interrupted when the IPv4 address is set statically.
+        Err(SchedError::Interrupted) => (),
+        Err(err) => error!("DHCP client failed: {}", err),
+        Ok(()) => unreachable!()
+    }
+    set_status(Status::Waiting);
+}
@@ -7,7 +7,10 @@ use board_misoc::net_settings::{Ipv4AddrConfig, NetAddresses};
 const IPV4_INDEX: usize = 0;
 const IPV6_LL_INDEX: usize = 1;
 const IPV6_INDEX: usize = 2;
-const IP_ADDRESS_STORAGE_SIZE: usize = 3;
+const IPV6_AUTOCONF_INDEX: usize = 3;
+/// Number of IPv6 addresses that can be autoconfigured.
+pub const IPV6_AUTOCONF_COUNT: usize = 2;
+const IP_ADDRESS_STORAGE_SIZE: usize = IPV6_AUTOCONF_INDEX + IPV6_AUTOCONF_COUNT;
 
 pub trait InterfaceBuilderEx {
     fn init_ip_addrs(self, net_addresses: &NetAddresses) -> Self;
@@ -32,6 +35,7 @@ impl<'a, DeviceT: for<'d> Device<'d>> InterfaceBuilderEx for InterfaceBuilder<'a
 pub trait InterfaceEx {
     fn update_ipv4_addr(&mut self, addr: &Ipv4Cidr);
     fn update_ipv6_addr(&mut self, addr: &Ipv6Cidr);
+    fn update_ipv6_autoconf_addrs(&mut self, addrs: &[Option<Ipv6Cidr>]);
 }
 
 impl<'a, DeviceT: for<'d> Device<'d>> InterfaceEx for Interface<'a, DeviceT> {
@@ -41,4 +45,14 @@ impl<'a, DeviceT: for<'d> Device<'d>> InterfaceEx for Interface<'a, DeviceT> {
     fn update_ipv6_addr(&mut self, addr: &Ipv6Cidr) {
         self.update_ip_addrs(|storage| storage[IPV6_INDEX] = IpCidr::Ipv6(*addr))
     }
+    fn update_ipv6_autoconf_addrs(&mut self, addrs: &[Option<Ipv6Cidr>]) {
+        self.update_ip_addrs(|storage| {
+            for i in 0..IPV6_AUTOCONF_COUNT {
+                storage[IPV6_AUTOCONF_INDEX + i] = match addrs.get(i) {
+                    Some(&Some(addr)) => IpCidr::Ipv6(addr),
+                    _ => IpCidr::new(IpAddress::Ipv4(Ipv4Address::UNSPECIFIED), 32)
+                }
+            }
+        })
+    }
 }
@@ -0,0 +1,124 @@
+use alloc::vec::Vec;
+use alloc::string::String;
+
+use dyld::elf;
+use board_artiq::drtio_routing;
+use kernel_archive_proto;
+use tar_no_std::TarArchiveRef;
+
+// Flash kernels are either a single ELF file, or a TAR archive made by
+// artiq_compile for systems with subkernels, which holds `main.elf` and one
+// `<subkernel id> <destination>.elf` file per subkernel. Archives are checked
+// completely before anything is loaded or uploaded, so that a malformed file
+// is reported instead of panicking the runtime, and can be checked through
+// the management interface before it is written.
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "kernel is too short ({} bytes)", _0)]
+    TooShort(usize),
+    #[fail(display = "kernel is neither an ELF file nor a TAR archive")]
+    UnknownFormat,
+    #[fail(display = "{}", _0)]
+    Archive(#[cause] kernel_archive_proto::Error),
+    #[fail(display = "unexpected file {:?} in archive, expected main.elf \
+                      or \"<subkernel id> <destination>.elf\"", _0)]
+    InvalidFilename(String),
+    #[fail(display = "destination {} of subkernel {} is out of range", destination, id)]
+    DestinationOutOfRange { id: u32, destination: u32 },
+    #[fail(display = "subkernel {} appears more than once in archive", _0)]
+    DuplicateSubkernel(u32),
+    #[fail(display = "main.elf appears more than once in archive")]
+    DuplicateMain,
+    #[fail(display = "archive has no main.elf")]
+    MissingMain,
+    #[fail(display = "archive entry {:?} is not an ELF file", _0)]
+    NotElf(String),
+    #[fail(display = "subkernels are not supported in standalone systems")]
+    Unsupported,
+}
+
+impl From<kernel_archive_proto::Error> for Error {
+    fn from(value: kernel_archive_proto::Error) -> Error {
+        Error::Archive(value)
+    }
+}
+
+pub struct Subkernel<'a> {
+    pub id:          u32,
+    pub destination: u8,
+    pub library:     &'a [u8]
+}
+
+pub enum Kernel<'a> {
+    Elf(&'a [u8]),
+    Archive { main: &'a [u8], subkernels: Vec<Subkernel<'a>> }
+}
+
+fn is_elf(data: &[u8]) -> bool {
+    data.len() >= elf::SELFMAG && data[..elf::SELFMAG] == elf::ELFMAG[..elf::SELFMAG]
+}
+
+// The entries borrow from the archive rather than from its data; find them in
+// the data, so that they can be returned.
+fn within<'a>(data: &'a [u8], entry: &[u8]) -> &'a [u8] {
+    let start = entry.as_ptr() as usize - data.as_ptr() as usize;
+    &data[start..start + entry.len()]
+}
+
+fn parse_archive(data: &[u8]) -> Result<Kernel, Error> {
+    kernel_archive_proto::check(data)?;
+
+    let mut main = None;
+    let mut subkernels: Vec<Subkernel> = Vec::new();
+    let archive = TarArchiveRef::new(data);
+    for entry in archive.entries() {
+        let name = entry.filename();
+        let name = name.as_str();
+        let library = within(data, entry.data());
+        if name == "main.elf" {
+            if main.is_some() {
+                return Err(Error::DuplicateMain)
+            }
+            if !is_elf(library) {
+                return Err(Error::NotElf(String::from(name)))
+            }
+            main = Some(library);
+            continue
+        }
+
+        if cfg!(not(has_drtio)) {
+            return Err(Error::Unsupported)
+        }
+        let (id, destination) = kernel_archive_proto::parse_subkernel_filename(name)
+            .ok_or_else(|| Error::InvalidFilename(String::from(name)))?;
+        if destination as usize >= drtio_routing::DEST_COUNT {
+            return Err(Error::DestinationOutOfRange { id: id, destination: destination })
+        }
+        if subkernels.iter().any(|subkernel| subkernel.id == id) {
+            return Err(Error::DuplicateSubkernel(id))
+        }
+        if !is_elf(library) {
+            return Err(Error::NotElf(String::from(name)))
+        }
+        subkernels.push(Subkernel { id: id, destination: destination as u8, library: library });
+    }
+
+    match main {
+        Some(main) => Ok(Kernel::Archive { main: main, subkernels: subkernels }),
+        None => Err(Error::MissingMain)
+    }
+}
+
+/// Checks a flash kernel, and returns the libraries it contains.
+pub fn parse(data: &[u8]) -> Result<Kernel, Error> {
+    if data.len() < elf::SELFMAG {
+        return Err(Error::TooShort(data.len()))
+    }
+    if is_elf(data) {
+        return Ok(Kernel::Elf(data))
+    }
+    if !kernel_archive_proto::is_archive(data) {
+        return Err(Error::UnknownFormat)
+    }
+    parse_archive(data)
+}
@@ -25,15 +25,14 @@ extern crate board_artiq;
 extern crate logger_artiq;
 extern crate proto_artiq;
 extern crate riscv;
-#[cfg(has_drtio)]
 extern crate tar_no_std;
 
 use alloc::collections::BTreeMap;
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use core::convert::TryFrom;
-use smoltcp::wire::HardwareAddress;
+use smoltcp::wire::{HardwareAddress, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr};
 
-use board_misoc::{csr, ident, clock, spiflash, config, net_settings, pmp, boot};
+use board_misoc::{csr, ident, clock, spiflash, config, net_settings, pmp, boot, boot_control};
 #[cfg(has_ethmac)]
 use board_misoc::ethmac;
 use board_misoc::net_settings::{Ipv4AddrConfig};
@@ -41,7 +40,7 @@ use board_misoc::net_settings::{Ipv4AddrConfig};
 use board_artiq::drtioaux;
 use board_artiq::drtio_routing;
 use board_artiq::{mailbox, rpc_queue};
-use proto_artiq::{mgmt_proto, moninj_proto, rpc_proto, session_proto, kernel_proto};
+use proto_artiq::{auth_proto, dhcp_proto, kernel_archive_proto, mgmt_proto, moninj_proto, rpc_proto, session_proto, kernel_proto};
 #[cfg(has_drtio_eem)]
 use board_artiq::drtio_eem;
 #[cfg(has_rtio_analyzer)]
@@ -60,14 +59,24 @@ mod cache;
 mod rtio_dma;
 
 mod mgmt;
+mod config_schema;
+mod config_watch;
 mod kernel;
+mod kernel_archive;
 mod kern_hwreq;
 mod session;
 #[cfg(any(has_rtio_moninj, has_drtio))]
 mod moninj;
 #[cfg(has_rtio_analyzer)]
 mod analyzer;
+mod crash;
 mod dhcp;
+mod syslog;
+mod sntp;
+mod mdns;
+mod slaac;
+mod net_allow;
+mod auth;
 mod ip_addr_storage;
 
 #[cfg(has_grabber)]
@@ -83,9 +92,14 @@ fn setup_log_levels() {
         Ok(Ok(log_level_filter)) => {
             info!("log level set to {} by `log_level` config key",
                   log_level_filter);
-            log::set_max_level(log_level_filter);
+            logger_artiq::BufferLogger::with(|logger|
+                logger.set_log_level(log_level_filter));
+        }
+        _ => {
+            info!("log level set to INFO by default");
+            logger_artiq::BufferLogger::with(|logger|
+                logger.set_log_level(log::LevelFilter::Info));
         }
-        _ => info!("log level set to INFO by default")
     }
     match config::read_str("uart_log_level", |r| r.map(|s| s.parse())) {
         Ok(Ok(uart_log_level_filter)) => {
@@ -94,7 +108,100 @@ fn setup_log_levels() {
             logger_artiq::BufferLogger::with(|logger|
                 logger.set_uart_log_level(uart_log_level_filter));
         }
-        _ => info!("UART log level set to INFO by default")
+        _ => {
+            info!("UART log level set to INFO by default");
+            logger_artiq::BufferLogger::with(|logger|
+                logger.set_uart_log_level(log::LevelFilter::Info));
+        }
+    }
+    let log_filter = config_log_filter("log_filter");
+    logger_artiq::BufferLogger::with(|logger|
+        logger.set_log_filter(log_filter));
+    let uart_log_filter = config_log_filter("uart_log_filter");
+    logger_artiq::BufferLogger::with(|logger|
+        logger.set_uart_log_filter(uart_log_filter));
+}
+
+fn config_log_filter(key: &str) -> logger_artiq::LogFilter {
+    match config::read_str(key, |r| r.map(|s| s.parse::<logger_artiq::LogFilter>())) {
+        Ok(Ok(filter)) => {
+            info!("log filter set to \"{}\" by `{}` config key", filter, key);
+            filter
+        }
+        Ok(Err(err)) => {
+            warn!("ignoring `{}` config key: {}", key, err);
+            Default::default()
+        }
+        Err(_) => Default::default()
+    }
+}
+
+fn reconfigure_network(io: &sched::Io, dhcp: &Cell<Option<sched::ThreadHandle>>) {
+    let net_addresses = net_settings::get_adresses();
+    info!("network addresses changed: {}", net_addresses);
+    match net_addresses.ipv4_addr {
+        Ipv4AddrConfig::UseDhcp => {
+            if let Some(handle) = dhcp.take() {
+                // Already using DHCP, the other settings do not apply.
+                dhcp.set(Some(handle))
+            } else {
+                info!("Will try to acquire an IPv4 address with DHCP");
+                io.set_ipv4_address(&Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0));
+                io.remove_ipv4_default_route();
+                dhcp.set(Some(io.spawn(4096, dhcp::dhcp_thread)))
+            }
+        }
+        Ipv4AddrConfig::Static(ipv4) => {
+            if let Some(handle) = dhcp.take() {
+                handle.interrupt();
+                io.join(handle).expect("cannot join DHCP thread")
+            }
+            io.set_ipv4_address(&ipv4);
+            match net_addresses.ipv4_default_route {
+                Some(route) => { io.set_ipv4_default_route(route).unwrap(); }
+                None => { io.remove_ipv4_default_route(); }
+            }
+        }
+    }
+    io.set_ipv6_address(&net_addresses.ipv6_addr
+        .unwrap_or(Ipv6Cidr::new(Ipv6Address::UNSPECIFIED, 0)));
+    match net_addresses.ipv6_default_route {
+        Some(route) => { io.set_ipv6_default_route(route).unwrap(); }
+        None => { io.remove_ipv6_default_route(); }
+    }
+}
+
+// Restarts a thread that reads its config keys when it starts.
+fn restart_thread(io: &sched::Io, handle: &Cell<Option<sched::ThreadHandle>>, f: fn(sched::Io)) {
+    if let Some(handle) = handle.take() {
+        handle.interrupt();
+        io.join(handle).expect("cannot join thread")
+    }
+    handle.set(Some(io.spawn(4096, f)))
+}
+
+// Time the runtime has to run with all of its threads before firmware on
+// trial is confirmed.
+const BOOT_CONFIRM_DELAY_MS: u64 = 10_000;
+
+fn confirm_boot_thread(io: sched::Io) {
+    let state = boot_control::read();
+    if !state.trial {
+        return
+    }
+    if config::read_str("boot_confirm", |r| r == Ok("manual")) {
+        warn!("firmware in slot {} is on trial (attempt {} of {}), waiting for confirmation \
+               through the management interface",
+              state.active, state.attempts, boot_control::MAX_TRIAL_ATTEMPTS);
+        return
+    }
+    if io.sleep(BOOT_CONFIRM_DELAY_MS).is_err() {
+        return
+    }
+    // Unless new firmware was written in the meantime.
+    if boot_control::read() == state {
+        boot_control::confirm();
+        info!("firmware in slot {} confirmed", state.active);
     }
 }
 
@@ -105,6 +212,8 @@ fn startup() {
     info!("gateware ident {}", ident::read(&mut [0; 64]));
 
     setup_log_levels();
+    crash::log_previous();
+    let _ = config::health(|offset| warn!("skipping damaged config record at offset {}", offset));
     #[cfg(has_i2c)]
     board_misoc::i2c::init().expect("I2C initialization failed");
     #[cfg(all(soc_platform = "kasli", hw_rev = "v2.0"))]
@@ -166,6 +275,7 @@ fn startup() {
         .init_ip_addrs(&net_addresses)
         .neighbor_cache(neighbor_cache)
         .routes(Routes::new(BTreeMap::new()))
+        .ipv4_multicast_groups(BTreeMap::new())
         .finalize();
 
     if !use_dhcp {
@@ -196,10 +306,42 @@ fn startup() {
     let mut scheduler = sched::Scheduler::new(interface);
     let io = scheduler.io();
 
+    let dhcp = urc::Urc::new(Cell::new(None));
     if use_dhcp {
-        io.spawn(4096, dhcp::dhcp_thread);
+        dhcp.set(Some(io.spawn(4096, dhcp::dhcp_thread)));
     }
 
+    config_watch::subscribe(&["log_level", "uart_log_level", "log_filter", "uart_log_filter"],
+                            |_| setup_log_levels());
+    {
+        let dhcp = dhcp.clone();
+        config_watch::subscribe(&["hostname", "ip_fallback", "dhcp_timeout"], move |io| {
+            // Only restart the DHCP client if it is in use.
+            if let Some(handle) = dhcp.take() {
+                dhcp.set(Some(handle));
+                restart_thread(io, &dhcp, dhcp::dhcp_thread)
+            }
+        });
+    }
+    config_watch::subscribe(&["ip", "ipv4_default_route", "ip6", "ipv6_default_route"],
+        move |io| reconfigure_network(io, &dhcp));
+    let syslog = urc::Urc::new(Cell::new(Some(io.spawn(4096, syslog::thread))));
+    config_watch::subscribe(&["syslog_server", "syslog_level", "hostname"],
+        move |io| restart_thread(io, &syslog, syslog::thread));
+    let sntp = urc::Urc::new(Cell::new(Some(io.spawn(4096, sntp::thread))));
+    config_watch::subscribe(&["ntp_server"],
+        move |io| restart_thread(io, &sntp, sntp::thread));
+    let mdns = urc::Urc::new(Cell::new(Some(io.spawn(4096, mdns::thread))));
+    config_watch::subscribe(&["hostname", "mdns_instance"],
+        move |io| restart_thread(io, &mdns, mdns::thread));
+    let slaac = urc::Urc::new(Cell::new(Some(io.spawn(4096, slaac::thread))));
+    config_watch::subscribe(&["ip6_autoconf", "ipv6_default_route"],
+        move |io| restart_thread(io, &slaac, slaac::thread));
+    config_watch::subscribe(&["mac"], |_|
+        warn!("the new MAC address will be used after a reboot"));
+    auth::startup();
+    config_watch::subscribe(&["auth_key"], |_| auth::startup());
+
     rtio_mgt::startup(&io, &aux_mutex, &drtio_routing_table, &up_destinations, &ddma_mutex, &subkernel_mutex);
 
     io.spawn(4096, mgmt::thread);
@@ -232,6 +374,8 @@ fn startup() {
     #[cfg(has_grabber)]
     io.spawn(4096, grabber_thread);
 
+    io.spawn(4096, confirm_boot_thread);
+
     let mut net_stats = ethmac::EthernetStatistics::new();
     loop {
         scheduler.run();
@@ -332,6 +476,7 @@ pub extern fn exception(regs: *const TrapFrame) {
 
             hexdump(u32::try_from(pc).unwrap());
             let mtval = mtval::read();
+            crash::record_trap(unsafe { &*regs }, mcause::read().bits(), pc, mtval);
             panic!("exception {:?} at PC 0x{:x}, trap value 0x{:x}", e, u32::try_from(pc).unwrap(), mtval)
         }
     }
@@ -375,6 +520,8 @@ pub fn panic_impl(info: &core::panic::PanicInfo) -> ! {
         println!("{:#08x}", ip - 4);
     });
 
+    crash::save(info);
+
     if config::read_str("panic_reset", |r| r == Ok("1")) && 
         cfg!(any(soc_platform = "kasli", soc_platform = "kc705")) {
         println!("restarting...");
@@ -0,0 +1,459 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, BigEndian};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+use board_misoc::{csr, config, clock, net_settings};
+use sched::{Io, UdpSocket, Error as SchedError};
+
+// The mDNS responder (RFC 6762) answers for `<hostname>.local`, and advertises
+// the services of the core device with DNS-SD (RFC 6763), so that hosts can
+// find it when its address is assigned by DHCP. It only answers over IPv4,
+// with the A record of the current address.
+//
+// Names are not probed for conflicts before use; the records are announced at
+// startup and whenever the address changes, and answers from other devices
+// for the same host name are reported in the log.
+const PORT:              u16 = 5353;
+const GROUP:             Ipv4Address = Ipv4Address([224, 0, 0, 251]);
+const PACKET_SIZE:       usize = 1500;
+// How often the address is checked for changes.
+const POLL_INTERVAL_MS:  u64 = 1_000;
+const ANNOUNCEMENTS:     u32 = 2;
+// TTLs recommended by RFC 6762, for records that contain a host name or
+// address, and for the other ones; replies to legacy (one-shot) queries
+// must not be cached for long.
+const HOST_TTL:          u32 = 120;
+const OTHER_TTL:         u32 = 4500;
+const LEGACY_TTL:        u32 = 10;
+
+const TYPE_A:            u16 = 1;
+const TYPE_PTR:          u16 = 12;
+const TYPE_TXT:          u16 = 16;
+const TYPE_SRV:          u16 = 33;
+const TYPE_ANY:          u16 = 255;
+const CLASS_IN:          u16 = 1;
+const CLASS_ANY:         u16 = 255;
+// Top bit of the class: unicast response requested in questions, and
+// cache flush in records.
+const CLASS_FLAG:        u16 = 0x8000;
+
+const FLAG_RESPONSE:     u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const OPCODE_MASK:       u16 = 0x7800;
+
+const SERVICES_NAME: &[&str] = &["_services", "_dns-sd", "_udp", "local"];
+
+fn services() -> Vec<(&'static str, u16)> {
+    let mut services = vec![("_artiq-mgmt", 1380), ("_artiq-session", 1381)];
+    #[cfg(has_rtio_analyzer)]
+    services.push(("_artiq-analyzer", 1382));
+    #[cfg(any(has_rtio_moninj, has_drtio))]
+    services.push(("_artiq-moninj", 1383));
+    services
+}
+
+/// Longest instance name, in bytes.
+pub const INSTANCE_MAX_LEN: usize = 63;
+
+/// Parses a DNS-SD instance name, which is any text without control
+/// characters.
+pub fn parse_instance(value: &str) -> Option<&str> {
+    let valid = value.len() >= 1 && value.len() <= INSTANCE_MAX_LEN &&
+        !value.chars().any(|c| c.is_control());
+    if valid { Some(value) } else { None }
+}
+
+struct Settings {
+    hostname: String,
+    instance: String,
+    // Contents of the TXT records: a single `ident=...` string, with its
+    // length prefix.
+    txt:      Vec<u8>
+}
+
+fn settings() -> Settings {
+    let hardware_addr = net_settings::get_adresses().hardware_addr;
+    let hostname = net_settings::with_hostname(hardware_addr, String::from);
+    let instance = match config::read_str("mdns_instance", |r| r.map(|s| parse_instance(s)
+                                                                 .map(String::from))) {
+        Ok(Some(instance)) => instance,
+        Ok(None) => {
+            warn!("ignoring invalid `mdns_instance` config key");
+            hostname.clone()
+        }
+        Err(_) => hostname.clone()
+    };
+    let mut txt = Vec::new();
+    let ident = &csr::CONFIG_IDENTIFIER_STR.as_bytes()[..csr::CONFIG_IDENTIFIER_STR.len().min(249)];
+    txt.push((6 + ident.len()) as u8);
+    txt.extend_from_slice(b"ident=");
+    txt.extend_from_slice(ident);
+    Settings { hostname: hostname, instance: instance, txt: txt }
+}
+
+#[derive(PartialEq)]
+enum RecordData<'a> {
+    A(Ipv4Address),
+    Ptr(Vec<&'a str>),
+    Srv(u16, Vec<&'a str>),
+    Txt(&'a [u8])
+}
+
+#[derive(PartialEq)]
+struct Record<'a> {
+    name:   Vec<&'a str>,
+    unique: bool,
+    ttl:    u32,
+    data:   RecordData<'a>
+}
+
+impl<'a> Record<'a> {
+    fn rtype(&self) -> u16 {
+        match self.data {
+            RecordData::A(_) => TYPE_A,
+            RecordData::Ptr(_) => TYPE_PTR,
+            RecordData::Srv(..) => TYPE_SRV,
+            RecordData::Txt(_) => TYPE_TXT
+        }
+    }
+}
+
+// Makes the records of the device, with its current address.
+struct Records<'a> {
+    settings: &'a Settings,
+    address:  Ipv4Address
+}
+
+impl<'a> Records<'a> {
+    fn host_name(&self) -> Vec<&'a str> {
+        vec![self.settings.hostname.as_str(), "local"]
+    }
+
+    fn instance_name(&self, service: &'a str) -> Vec<&'a str> {
+        vec![self.settings.instance.as_str(), service, "_tcp", "local"]
+    }
+
+    fn address(&self) -> Record<'a> {
+        Record { name: self.host_name(), unique: true, ttl: HOST_TTL,
+                 data: RecordData::A(self.address) }
+    }
+
+    fn service_type(&self, service: &'a str) -> Record<'a> {
+        Record { name: SERVICES_NAME.to_vec(), unique: false, ttl: OTHER_TTL,
+                 data: RecordData::Ptr(vec![service, "_tcp", "local"]) }
+    }
+
+    fn instance(&self, service: &'a str) -> Record<'a> {
+        Record { name: vec![service, "_tcp", "local"], unique: false, ttl: OTHER_TTL,
+                 data: RecordData::Ptr(self.instance_name(service)) }
+    }
+
+    fn server(&self, service: &'a str, port: u16) -> Record<'a> {
+        Record { name: self.instance_name(service), unique: true, ttl: HOST_TTL,
+                 data: RecordData::Srv(port, self.host_name()) }
+    }
+
+    fn text(&self, service: &'a str) -> Record<'a> {
+        Record { name: self.instance_name(service), unique: true, ttl: OTHER_TTL,
+                 data: RecordData::Txt(&self.settings.txt) }
+    }
+}
+
+// A response, with the names compressed (RFC 1035 section 4.1.4), as the
+// announcement would not fit in one packet otherwise.
+struct Message {
+    data:  Vec<u8>,
+    // Offsets of the names and name suffixes written so far.
+    names: Vec<usize>
+}
+
+impl Message {
+    fn new(id: u16, question_count: u16, answer_count: usize, additional_count: usize)
+          -> Message {
+        let mut data = Vec::with_capacity(PACKET_SIZE);
+        for &field in &[id, FLAG_RESPONSE | FLAG_AUTHORITATIVE, question_count,
+                        answer_count as u16, 0, additional_count as u16] {
+            data.extend_from_slice(&field.to_be_bytes())
+        }
+        Message { data: data, names: Vec::new() }
+    }
+
+    fn write_name(&mut self, name: &[&str]) {
+        for i in 0..name.len() {
+            let pointer = {
+                let data = &self.data;
+                self.names.iter().cloned().find(|&offset| {
+                    read_name(data, offset)
+                        .map_or(false, |(labels, _)| name_matches(&labels, &name[i..]))
+                })
+            };
+            if let Some(offset) = pointer {
+                self.data.extend_from_slice(&(0xc000 | offset as u16).to_be_bytes());
+                return
+            }
+            if self.data.len() < 0x4000 {
+                self.names.push(self.data.len())
+            }
+            self.data.push(name[i].len() as u8);
+            self.data.extend_from_slice(name[i].as_bytes());
+        }
+        self.data.push(0)
+    }
+
+    fn write_record(&mut self, record: &Record, legacy: bool) {
+        self.write_name(&record.name);
+        self.data.extend_from_slice(&record.rtype().to_be_bytes());
+        let class = if record.unique && !legacy { CLASS_IN | CLASS_FLAG } else { CLASS_IN };
+        self.data.extend_from_slice(&class.to_be_bytes());
+        let ttl = if legacy { record.ttl.min(LEGACY_TTL) } else { record.ttl };
+        self.data.extend_from_slice(&ttl.to_be_bytes());
+
+        let length_offset = self.data.len();
+        self.data.extend_from_slice(&[0, 0]);
+        match record.data {
+            RecordData::A(address) => self.data.extend_from_slice(address.as_bytes()),
+            RecordData::Ptr(ref name) => self.write_name(name),
+            RecordData::Srv(port, ref target) => {
+                // Priority and weight.
+                self.data.extend_from_slice(&[0, 0, 0, 0]);
+                self.data.extend_from_slice(&port.to_be_bytes());
+                self.write_name(target)
+            }
+            RecordData::Txt(text) => self.data.extend_from_slice(text)
+        }
+        let length = (self.data.len() - length_offset - 2) as u16;
+        BigEndian::write_u16(&mut self.data[length_offset..], length)
+    }
+}
+
+// Legacy queries, sent from another port than 5353 by resolvers that do not
+// implement mDNS, get a unicast reply with the ID and questions of the query.
+fn write_response(id: u16, questions: &[u8], question_count: u16,
+                  answers: &[Record], additionals: &[Record], legacy: bool) -> Vec<u8> {
+    let mut message = Message::new(id, question_count, answers.len(), additionals.len());
+    message.data.extend_from_slice(questions);
+    for record in answers.iter().chain(additionals) {
+        message.write_record(record, legacy)
+    }
+    message.data
+}
+
+// Reads the name at `offset`, following compression pointers, and returns its
+// labels and the offset after it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(Vec<&[u8]>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let length = *packet.get(offset)? as usize;
+        if length & 0xc0 == 0xc0 {
+            // Each pointer must go further back, but limit them anyway.
+            pointers += 1;
+            if pointers > 16 {
+                return None
+            }
+            end = end.or(Some(offset + 2));
+            offset = ((length & 0x3f) << 8) | *packet.get(offset + 1)? as usize;
+        } else if length & 0xc0 != 0 {
+            return None
+        } else if length == 0 {
+            return Some((labels, end.unwrap_or(offset + 1)))
+        } else {
+            labels.push(packet.get(offset + 1..offset + 1 + length)?);
+            offset += 1 + length;
+        }
+    }
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    packet.get(offset..offset + 2).map(BigEndian::read_u16)
+}
+
+fn name_matches(labels: &[&[u8]], name: &[&str]) -> bool {
+    labels.len() == name.len() &&
+        labels.iter().zip(name).all(|(label, part)| label.eq_ignore_ascii_case(part.as_bytes()))
+}
+
+fn push_unique<'a>(records: &mut Vec<Record<'a>>, record: Record<'a>) {
+    if !records.contains(&record) {
+        records.push(record)
+    }
+}
+
+// Finds the records that answer a query, and the additional records that
+// the host will need next (RFC 6763 section 12).
+fn answer<'a>(records: &Records<'a>, services: &[(&'a str, u16)], packet: &[u8],
+              legacy: bool) -> Option<Vec<u8>> {
+    let id = read_u16(packet, 0)?;
+    let question_count = read_u16(packet, 4)?;
+    let mut answers = Vec::new();
+    let mut additionals = Vec::new();
+
+    let mut offset = 12;
+    for _ in 0..question_count {
+        let (name, end) = read_name(packet, offset)?;
+        let qtype = read_u16(packet, end)?;
+        let qclass = read_u16(packet, end + 2)? & !CLASS_FLAG;
+        offset = end + 4;
+        if qclass != CLASS_IN && qclass != CLASS_ANY {
+            continue
+        }
+        let wants = |rtype| qtype == rtype || qtype == TYPE_ANY;
+
+        if wants(TYPE_A) && name_matches(&name, &records.host_name()) {
+            push_unique(&mut answers, records.address());
+        }
+        for &(service, port) in services {
+            if wants(TYPE_PTR) && name_matches(&name, SERVICES_NAME) {
+                push_unique(&mut answers, records.service_type(service));
+            }
+            if wants(TYPE_PTR) && name_matches(&name, &[service, "_tcp", "local"]) {
+                push_unique(&mut answers, records.instance(service));
+                push_unique(&mut additionals, records.server(service, port));
+                push_unique(&mut additionals, records.text(service));
+                push_unique(&mut additionals, records.address());
+            }
+            if name_matches(&name, &records.instance_name(service)) {
+                if wants(TYPE_SRV) {
+                    push_unique(&mut answers, records.server(service, port));
+                    push_unique(&mut additionals, records.address());
+                }
+                if wants(TYPE_TXT) {
+                    push_unique(&mut answers, records.text(service));
+                }
+            }
+        }
+    }
+    if answers.is_empty() {
+        return None
+    }
+    additionals.retain(|record| !answers.contains(record));
+
+    if legacy {
+        Some(write_response(id, &packet[12..offset], question_count,
+                            &answers, &additionals, true))
+    } else {
+        Some(write_response(0, &[], 0, &answers, &additionals, false))
+    }
+}
+
+// Returns whether a response from another device has an address record for
+// our host name.
+fn is_conflict(records: &Records, packet: &[u8]) -> Option<bool> {
+    let question_count = read_u16(packet, 4)?;
+    let record_count = read_u16(packet, 6)? as u32 + read_u16(packet, 8)? as u32 +
+                       read_u16(packet, 10)? as u32;
+    let mut offset = 12;
+    for _ in 0..question_count {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+    for _ in 0..record_count {
+        let (name, end) = read_name(packet, offset)?;
+        let rtype = read_u16(packet, end)?;
+        let length = read_u16(packet, end + 8)? as usize;
+        if rtype == TYPE_A && name_matches(&name, &records.host_name()) &&
+                packet.get(end + 10..end + 10 + length)? != records.address.as_bytes() {
+            return Some(true)
+        }
+        offset = end + 10 + length;
+    }
+    Some(false)
+}
+
+fn announcement(records: &Records, services: &[(&str, u16)]) -> Vec<u8> {
+    let mut answers = vec![records.address()];
+    for &(service, port) in services {
+        answers.push(records.service_type(service));
+        answers.push(records.instance(service));
+        answers.push(records.server(service, port));
+        answers.push(records.text(service));
+    }
+    write_response(0, &[], 0, &answers, &[], false)
+}
+
+pub fn thread(io: Io) {
+    let settings = settings();
+    let services = services();
+    info!("answering mDNS queries for {}.local, as \"{}\"", settings.hostname, settings.instance);
+
+    let socket = UdpSocket::new(&io, 4096);
+    socket.bind(PORT).expect("mdns: cannot bind socket");
+    socket.set_hop_limit(Some(255));
+    socket.set_timeout(Some(POLL_INTERVAL_MS));
+    let group = IpEndpoint::new(IpAddress::Ipv4(GROUP), PORT);
+
+    let mut address = None;
+    let mut announcements = 0;
+    let mut next_announcement = 0;
+    let mut conflict = false;
+    let mut packet = vec![0; PACKET_SIZE];
+    loop {
+        if io.ipv4_address() != address {
+            address = io.ipv4_address();
+            if address.is_some() {
+                // Send an IGMP report from the new address.
+                let _ = io.leave_multicast_group(GROUP);
+                if let Err(err) = io.join_multicast_group(GROUP) {
+                    debug!("cannot join the mDNS group: {}", err);
+                }
+                announcements = 0;
+                next_announcement = clock::get_ms();
+                conflict = false;
+            }
+        }
+        let records = match address {
+            Some(address) => Some(Records { settings: &settings, address: address }),
+            None => None
+        };
+
+        if let Some(ref records) = records {
+            if announcements < ANNOUNCEMENTS && clock::get_ms() >= next_announcement {
+                match socket.send_to(&announcement(records, &services), group) {
+                    Ok(()) => (),
+                    Err(SchedError::Interrupted) => return,
+                    Err(err) => warn!("cannot send mDNS announcement: {}", err)
+                }
+                announcements += 1;
+                next_announcement = clock::get_ms() + 1000;
+            }
+        }
+
+        let (length, source) = match socket.recv_from(&mut packet) {
+            Ok(result) => result,
+            Err(SchedError::TimedOut) => continue,
+            // Interrupted when the settings change.
+            Err(SchedError::Interrupted) => return,
+            Err(err) => {
+                warn!("cannot receive mDNS queries: {}", err);
+                continue
+            }
+        };
+        let records = match records {
+            Some(records) => records,
+            None => continue
+        };
+        let packet = &packet[..length];
+        let flags = match read_u16(packet, 2) {
+            Some(flags) if flags & OPCODE_MASK == 0 => flags,
+            _ => continue
+        };
+
+        if flags & FLAG_RESPONSE != 0 {
+            if !conflict && is_conflict(&records, packet) == Some(true) {
+                warn!("{} also answers for {}.local", source.addr, settings.hostname);
+                conflict = true;
+            }
+            continue
+        }
+        let legacy = source.port != PORT;
+        if let Some(response) = answer(&records, &services, packet, legacy) {
+            let destination = if legacy { source } else { group };
+            match socket.send_to(&response, destination) {
+                Ok(()) => (),
+                Err(SchedError::Interrupted) => return,
+                Err(err) => debug!("cannot send mDNS response: {}", err)
+            }
+        }
+    }
+}
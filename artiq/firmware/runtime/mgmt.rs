@@ -1,10 +1,22 @@
-use log::{self, LevelFilter};
+use core::fmt::Write as FmtWrite;
+use alloc::{vec::Vec, string::String};
+use log::{self, Level, LevelFilter};
+use smoltcp::wire::IpCidr;
 
 use io::{Write, ProtoWrite, Error as IoError};
-use board_misoc::{config, spiflash};
-use logger_artiq::BufferLogger;
+use board_misoc::{clock, config, spiflash, flash_image, boot_control, kernel_store, crash_dump,
+                  net_settings};
+use board_misoc::net_settings::Ipv4AddrConfig;
+use logger_artiq::{BufferLogger, LogBufferRef, LogFilter, LogRecord};
 use mgmt_proto::*;
 use sched::{Io, TcpListener, TcpStream, Error as SchedError};
+use config_schema;
+use config_watch;
+use kernel_archive;
+use dhcp;
+use slaac;
+use net_allow;
+use auth;
 
 impl From<SchedError> for Error<SchedError> {
     fn from(value: SchedError) -> Error<SchedError> {
@@ -12,18 +24,137 @@ impl From<SchedError> for Error<SchedError> {
     }
 }
 
+fn log_text(buffer: &LogBufferRef) -> String {
+    let mut text = String::new();
+    for record in buffer.records() {
+        writeln!(text, "{}", record).unwrap();
+    }
+    text
+}
+
+// Sending records makes smoltcp log trace messages, which would be sent in
+// turn, forever; they are left out of the stream.
+fn is_network_trace(record: &LogRecord) -> bool {
+    record.level == Level::Trace && record.target.starts_with("smoltcp")
+}
+
+// Encodes the records starting at sequence number `from`, reporting the
+// records that were lost as gaps, and returns the sequence number to continue
+// from.
+fn write_log_records(buffer: &LogBufferRef, from: u64, batch: &mut Vec<u8>) -> u64 {
+    let mut next = from;
+    // After a restart of the core device, the host may ask for records that
+    // do not exist yet; send everything from the oldest record instead.
+    if next > buffer.next_sequence() {
+        next = 0
+    }
+    for record in buffer.records().filter(|record| record.sequence >= next) {
+        if record.sequence > next {
+            Reply::LogGap { from: next, to: record.sequence }.write_to(batch).unwrap();
+        }
+        next = record.sequence + 1;
+        if is_network_trace(&record) { continue }
+        Reply::LogRecord {
+            sequence:  record.sequence,
+            timestamp: record.timestamp,
+            level:     record.level,
+            target:    record.target,
+            message:   record.message
+        }.write_to(batch).unwrap();
+    }
+    if buffer.next_sequence() > next {
+        // The newest messages were dropped while the buffer was in use.
+        Reply::LogGap { from: next, to: buffer.next_sequence() }.write_to(batch).unwrap();
+    }
+    buffer.next_sequence()
+}
+
+fn write_network_status(io: &Io, stream: &mut TcpStream) -> Result<(), IoError<SchedError>> {
+    let ipv4_address = io.ip_addrs().iter()
+        .find(|cidr| match cidr { IpCidr::Ipv4(_) => true, _ => false })
+        .map(|cidr| format!("{}", cidr));
+    let net_addresses = net_settings::get_adresses();
+    let dhcp_status = match net_addresses.ipv4_addr {
+        Ipv4AddrConfig::UseDhcp => Some(dhcp::status()),
+        Ipv4AddrConfig::Static(_) => None
+    };
+    let ipv4_config = match dhcp_status {
+        None => Ipv4Config::Static,
+        Some(dhcp::Status::Fallback(_)) => Ipv4Config::Fallback,
+        Some(_) => Ipv4Config::Dhcp
+    };
+
+    let slaac_status = slaac::status();
+    let ipv6_addresses: Vec<String> = io.ip_addrs().iter()
+        .filter(|cidr| match cidr { IpCidr::Ipv6(_) => true, _ => false })
+        .map(|cidr| format!("{}", cidr))
+        .collect();
+    let ipv6_addresses: Vec<&str> = ipv6_addresses.iter().map(|address| address.as_str()).collect();
+    let ipv6_router = net_addresses.ipv6_default_route
+        .or(slaac_status.router)
+        .map(|router| format!("{}", router));
+    let ipv6_dns_servers: Vec<String> = slaac_status.dns_servers.iter()
+        .filter_map(|server| server.map(|server| format!("{}", server)))
+        .collect();
+    let ipv6_dns_servers: Vec<&str> = ipv6_dns_servers.iter().map(|server| server.as_str()).collect();
+    let ipv6 = Ipv6Status {
+        addresses: &ipv6_addresses,
+        router: ipv6_router.as_ref().map(|router| router.as_str()),
+        dns_servers: &ipv6_dns_servers
+    };
+
+    match dhcp_status {
+        Some(dhcp::Status::Bound(lease)) => {
+            let address = format!("{}", lease.address);
+            let server = format!("{}", lease.server);
+            let router = lease.router.map(|router| format!("{}", router));
+            let dns_servers: Vec<String> = lease.dns_servers.iter()
+                .filter_map(|server| server.map(|server| format!("{}", server)))
+                .collect();
+            let dns_servers: Vec<&str> = dns_servers.iter().map(|server| server.as_str()).collect();
+            let remaining = match lease.expires_at {
+                Some(expires_at) => (expires_at.saturating_sub(clock::get_ms()) / 1000) as u32,
+                None => u32::max_value()
+            };
+            Reply::NetworkStatus {
+                ipv4_config: ipv4_config,
+                ipv4_address: ipv4_address.as_ref().map(|address| address.as_str()),
+                lease: Some(DhcpLease {
+                    address: &address,
+                    server: &server,
+                    router: router.as_ref().map(|router| router.as_str()),
+                    dns_servers: &dns_servers,
+                    remaining: remaining
+                }),
+                ipv6: ipv6
+            }.write_to(stream)
+        }
+        _ => Reply::NetworkStatus {
+            ipv4_config: ipv4_config,
+            ipv4_address: ipv4_address.as_ref().map(|address| address.as_str()),
+            lease: None,
+            ipv6: ipv6
+        }.write_to(stream)
+    }
+}
+
 fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
     read_magic(stream)?;
+    if !auth::authenticate(stream)? {
+        return Ok(())
+    }
     Write::write_all(stream, "e".as_bytes())?;
     info!("new connection from {}", stream.remote_endpoint());
 
+    let mut flash: Option<flash_image::Writer> = None;
     loop {
         match Request::read_from(stream)? {
             Request::GetLog => {
-                BufferLogger::with(|logger| {
-                    let mut buffer = io.until_ok(|| logger.buffer())?;
-                    Reply::LogContent(buffer.extract()).write_to(stream)
+                let log = BufferLogger::with(|logger| -> Result<String, Error<SchedError>> {
+                    let buffer = io.until_ok(|| logger.buffer())?;
+                    Ok(log_text(&buffer))
                 })?;
+                Reply::LogContent(&log).write_to(stream)?;
             }
             Request::ClearLog => {
                 BufferLogger::with(|logger| -> Result<(), Error<SchedError>> {
@@ -36,14 +167,12 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
             Request::PullLog => {
                 BufferLogger::with(|logger| -> Result<(), Error<SchedError>> {
                     loop {
-                        // Do this *before* acquiring the buffer, since that sets the log level
-                        // to OFF.
                         let log_level = log::max_level();
 
                         let mut buffer = io.until_ok(|| logger.buffer())?;
                         if buffer.is_empty() { continue }
 
-                        stream.write_string(buffer.extract())?;
+                        stream.write_string(&log_text(&buffer))?;
 
                         if log_level == LevelFilter::Trace {
                             // Hold exclusive access over the logger until we get positive
@@ -62,9 +191,30 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
                     }
                 })?;
             }
+            Request::PullLogRecords { from } => {
+                BufferLogger::with(|logger| -> Result<(), Error<SchedError>> {
+                    let mut next = from;
+                    let mut batch = Vec::new();
+                    loop {
+                        let buffer = io.until_ok(|| logger.buffer())?;
+                        if buffer.next_sequence() == next { continue }
+                        next = write_log_records(&buffer, next, &mut batch);
+
+                        // Release the logger first, so that messages logged while the
+                        // records are sent are kept, and sent next; any dropped while the
+                        // buffer is in use are reported as a gap.
+                        drop(buffer);
+                        if !batch.is_empty() {
+                            stream.write_all(&batch)?;
+                            batch.clear();
+                        }
+                    }
+                })?;
+            }
             Request::SetLogFilter(level) => {
                 info!("changing log level to {}", level);
-                log::set_max_level(level);
+                BufferLogger::with(|logger|
+                    logger.set_log_level(level));
                 Reply::Success.write_to(stream)?;
             }
             Request::SetUartLogFilter(level) => {
@@ -73,6 +223,28 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
                     logger.set_uart_log_level(level));
                 Reply::Success.write_to(stream)?;
             }
+            Request::SetLogDirectives(ref directives) => {
+                match directives.parse::<LogFilter>() {
+                    Ok(filter) => {
+                        info!("changing log filter to \"{}\"", filter);
+                        BufferLogger::with(|logger|
+                            logger.set_log_filter(filter));
+                        Reply::Success.write_to(stream)
+                    }
+                    Err(err) => Reply::ErrorMessage(&format!("{}", err)).write_to(stream)
+                }?;
+            }
+            Request::SetUartLogDirectives(ref directives) => {
+                match directives.parse::<LogFilter>() {
+                    Ok(filter) => {
+                        info!("changing UART log filter to \"{}\"", filter);
+                        BufferLogger::with(|logger|
+                            logger.set_uart_log_filter(filter));
+                        Reply::Success.write_to(stream)
+                    }
+                    Err(err) => Reply::ErrorMessage(&format!("{}", err)).write_to(stream)
+                }?;
+            }
 
             Request::ConfigRead { ref key } => {
                 config::read(key, |result| {
@@ -83,22 +255,173 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
                 })?;
             }
             Request::ConfigWrite { ref key, ref value } => {
+                if let Err(message) = config_schema::check(key, value) {
+                    warn!("{}", message);
+                    Reply::ErrorMessage(&message).write_to(stream)?;
+                    continue
+                }
                 match config::write(key, value) {
-                    Ok(_)  => Reply::Success.write_to(stream),
-                    Err(_) => Reply::Error.write_to(stream)
-                }?;
+                    Ok(_)  => {
+                        Reply::Success.write_to(stream)?;
+                        config_watch::notify(io, &[key.as_str()])
+                    }
+                    Err(_) => Reply::Error.write_to(stream)?
+                }
             }
             Request::ConfigRemove { ref key } => {
                 match config::remove(key) {
-                    Ok(()) => Reply::Success.write_to(stream),
-                    Err(_) => Reply::Error.write_to(stream)
-                }?;
+                    Ok(()) => {
+                        Reply::Success.write_to(stream)?;
+                        config_watch::notify(io, &[key.as_str()])
+                    }
+                    Err(_) => Reply::Error.write_to(stream)?
+                }
 
             }
             Request::ConfigErase => {
                 match config::erase() {
+                    Ok(()) => {
+                        Reply::Success.write_to(stream)?;
+                        config_watch::notify_all(io)
+                    }
+                    Err(_) => Reply::Error.write_to(stream)?
+                }
+            }
+            Request::ConfigHealth => {
+                let mut damaged = Vec::new();
+                match config::health(|offset| damaged.push(offset as u32)) {
+                    Ok(health) => Reply::ConfigHealth {
+                        records:     health.records as u32,
+                        damaged:     &damaged,
+                        free:        health.free as u32,
+                        reclaimable: health.reclaimable as u32
+                    }.write_to(stream),
+                    Err(err) => Reply::ErrorMessage(&format!("{}", err)).write_to(stream)
+                }?;
+            }
+            Request::ConfigList { values } => {
+                let mut records: Vec<(String, u32, Option<Vec<u8>>)> = Vec::new();
+                let result = config::list(|key, value| {
+                    records.push((String::from_utf8_lossy(key).into_owned(),
+                                  value.len() as u32,
+                                  if values { Some(value.to_vec()) } else { None }))
+                });
+                match result {
+                    Ok(()) => {
+                        let entries: Vec<ConfigEntry> = records.iter()
+                            .map(|&(ref key, length, ref value)| ConfigEntry {
+                                key:    key,
+                                length: length,
+                                value:  value.as_ref().map(|value| &value[..])
+                            })
+                            .collect();
+                        Reply::ConfigList(&entries).write_to(stream)
+                    }
+                    Err(err) => Reply::ErrorMessage(&format!("{}", err)).write_to(stream)
+                }?;
+            }
+            Request::ConfigTransaction(ref changes) => {
+                let invalid = changes.iter()
+                    .filter_map(|change| match change {
+                        &ConfigChange::Write { ref key, ref value } =>
+                            config_schema::check(key, value).err(),
+                        &ConfigChange::Remove { .. } => None
+                    })
+                    .next();
+                if let Some(message) = invalid {
+                    warn!("{}", message);
+                    Reply::ErrorMessage(&message).write_to(stream)?;
+                    continue
+                }
+                let changes: Vec<config::Change> = changes.iter()
+                    .map(|change| match change {
+                        &ConfigChange::Write { ref key, ref value } =>
+                            config::Change::Write(key, value),
+                        &ConfigChange::Remove { ref key } =>
+                            config::Change::Remove(key)
+                    })
+                    .collect();
+                match config::transaction(&changes) {
+                    Ok(()) => {
+                        Reply::Success.write_to(stream)?;
+                        let keys: Vec<&str> = changes.iter()
+                            .map(|change| match change {
+                                &config::Change::Write(key, _) => key,
+                                &config::Change::Remove(key) => key
+                            })
+                            .collect();
+                        config_watch::notify(io, &keys)
+                    }
+                    Err(err) => Reply::ErrorMessage(&format!("{}", err)).write_to(stream)?
+                }
+            }
+            Request::ConfigExport => {
+                let mut backup = Vec::new();
+                match config::export(|part| backup.extend_from_slice(part)) {
+                    Ok(()) => Reply::ConfigData(&backup).write_to(stream),
+                    Err(err) => Reply::ErrorMessage(&format!("{}", err)).write_to(stream)
+                }?;
+            }
+            Request::ConfigImport { ref backup } => {
+                match config::import(backup) {
+                    Ok(()) => {
+                        info!("config restored from backup");
+                        Reply::Success.write_to(stream)?;
+                        config_watch::notify_all(io)
+                    }
+                    Err(err) => {
+                        error!("cannot restore config: {}", err);
+                        Reply::ErrorMessage(&format!("{}", err)).write_to(stream)?
+                    }
+                }
+            }
+
+            Request::FlashKernelWrite { ref name, ref data } => {
+                if let Err(err) = kernel_archive::parse(data) {
+                    warn!("rejected flash kernel {}: {}", name, err);
+                    Reply::ErrorMessage(&format!("invalid kernel: {}", err)).write_to(stream)?;
+                    continue
+                }
+                match kernel_store::write(name, data) {
+                    Ok(()) => {
+                        info!("flash kernel {} written ({} bytes)", name, data.len());
+                        Reply::Success.write_to(stream)
+                    }
+                    Err(err) => {
+                        error!("cannot write flash kernel {}: {}", name, err);
+                        Reply::ErrorMessage(&format!("{}", err)).write_to(stream)
+                    }
+                }?;
+            }
+            Request::FlashKernelList => {
+                let mut kernels: Vec<(String, u32)> = Vec::new();
+                let result = kernel_store::list(|name, length| {
+                    kernels.push((String::from(name), length as u32))
+                });
+                match result {
+                    Ok(free) => {
+                        let entries: Vec<FlashKernelEntry> = kernels.iter()
+                            .map(|&(ref name, length)| FlashKernelEntry {
+                                name:   name,
+                                length: length
+                            })
+                            .collect();
+                        Reply::FlashKernelList { kernels: &entries, free: free as u32 }
+                            .write_to(stream)
+                    }
+                    Err(err) => Reply::ErrorMessage(&format!("{}", err)).write_to(stream)
+                }?;
+            }
+            Request::FlashKernelRemove { ref name } => {
+                match kernel_store::remove(name) {
                     Ok(()) => Reply::Success.write_to(stream),
-                    Err(_) => Reply::Error.write_to(stream)
+                    Err(err) => Reply::ErrorMessage(&format!("{}", err)).write_to(stream)
+                }?;
+            }
+            Request::FlashKernelCheck { ref data } => {
+                match kernel_archive::parse(data) {
+                    Ok(_) => Reply::Success.write_to(stream),
+                    Err(err) => Reply::ErrorMessage(&format!("invalid kernel: {}", err)).write_to(stream)
                 }?;
             }
 
@@ -113,6 +436,102 @@ fn worker(io: &Io, stream: &mut TcpStream) -> Result<(), Error<SchedError>> {
 
             Request::DebugAllocator =>
                 unsafe { println!("{}", ::ALLOC) },
+
+            Request::FlashBegin { image, length } => {
+                let kind = match image {
+                    FlashImage::Firmware => flash_image::Kind::Firmware,
+                    FlashImage::Gateware => flash_image::Kind::Gateware
+                };
+                // Release the lock held by an unfinished previous image, if any.
+                flash = None;
+                match flash_image::Writer::new(kind, length as usize) {
+                    Ok(writer) => {
+                        info!("writing {} ({} bytes) to flash", kind, length);
+                        flash = Some(writer);
+                        Reply::Success.write_to(stream)
+                    }
+                    Err(err) => {
+                        error!("cannot write {} to flash: {}", kind, err);
+                        Reply::ErrorMessage(&format!("{}", err)).write_to(stream)
+                    }
+                }?;
+            }
+            Request::FlashData(ref data) => {
+                let result = match flash {
+                    Some(ref mut writer) => writer.write(data),
+                    None => {
+                        Reply::ErrorMessage("no flash write in progress").write_to(stream)?;
+                        continue
+                    }
+                };
+                match result {
+                    Ok(()) => {
+                        let writer = flash.as_ref().unwrap();
+                        Reply::FlashProgress {
+                            written: writer.written() as u32,
+                            length:  writer.length() as u32
+                        }.write_to(stream)
+                    }
+                    Err(err) => {
+                        error!("flash write failed: {}", err);
+                        flash = None;
+                        Reply::ErrorMessage(&format!("{}", err)).write_to(stream)
+                    }
+                }?;
+            }
+            Request::FlashEnd => {
+                match flash.take().map(|writer| (writer.kind(), writer.slot(), writer.finish())) {
+                    Some((kind, Some(slot), Ok(()))) => {
+                        info!("{} written to slot {} and verified, it will be tried on next boot",
+                              kind, slot);
+                        Reply::Success.write_to(stream)
+                    }
+                    Some((kind, None, Ok(()))) => {
+                        info!("{} written to flash and verified, reboot to use it", kind);
+                        Reply::Success.write_to(stream)
+                    }
+                    Some((kind, _, Err(err))) => {
+                        error!("{} verification failed: {}", kind, err);
+                        Reply::ErrorMessage(&format!("{}", err)).write_to(stream)
+                    }
+                    None => Reply::ErrorMessage("no flash write in progress").write_to(stream)
+                }?;
+            }
+
+            Request::GetBootStatus => {
+                let state = boot_control::read();
+                Reply::BootStatus {
+                    slot: match state.active {
+                        boot_control::Slot::A => 0,
+                        boot_control::Slot::B => 1
+                    },
+                    trial: state.trial,
+                    attempts: state.attempts
+                }.write_to(stream)?;
+            }
+            Request::ConfirmBoot => {
+                if let Some(slot) = boot_control::confirm() {
+                    info!("firmware in slot {} confirmed", slot);
+                }
+                Reply::Success.write_to(stream)?;
+            }
+
+            Request::GetCrashDump => {
+                // Copy the report, as the flash could be erased while it is sent.
+                let report = crash_dump::read(|report|
+                    report.map(|report| String::from_utf8_lossy(report).into_owned()));
+                match report {
+                    Some(report) => Reply::CrashDump(&report).write_to(stream),
+                    None => Reply::Unavailable.write_to(stream)
+                }?;
+            }
+            Request::ClearCrashDump => {
+                crash_dump::clear();
+                info!("crash report cleared");
+                Reply::Success.write_to(stream)?;
+            }
+
+            Request::GetNetworkStatus => write_network_status(io, stream)?,
         };
     }
 }
@@ -123,8 +542,15 @@ pub fn thread(io: Io) {
     info!("management interface active");
 
     loop {
-        let stream = listener.accept().expect("mgmt: cannot accept").into_handle();
-        io.spawn(4096, move |io| {
+        let stream = listener.accept().expect("mgmt: cannot accept");
+        if !net_allow::mgmt_allowed(&stream) {
+            stream.abort().expect("mgmt: cannot abort");
+            continue
+        }
+        let stream = stream.into_handle();
+        // Config changes run the config_watch handlers in this thread, which
+        // reconfigure the network interface and parse the device map.
+        io.spawn(16384, move |io| {
             let mut stream = TcpStream::from_handle(&io, stream);
             match worker(&io, &mut stream) {
                 Ok(()) => (),
@@ -5,6 +5,7 @@ use io::Error as IoError;
 use moninj_proto::*;
 use sched::{Io, Mutex, TcpListener, TcpStream, Error as SchedError};
 use urc::Urc;
+use net_allow;
 use board_misoc::clock;
 use board_artiq::drtio_routing;
 
@@ -221,7 +222,12 @@ pub fn thread(io: Io, aux_mutex: &Mutex, ddma_mutex: &Mutex, subkernel_mutex: &M
         let ddma_mutex = ddma_mutex.clone();
         let subkernel_mutex = subkernel_mutex.clone();
         let routing_table = routing_table.clone();
-        let stream = listener.accept().expect("moninj: cannot accept").into_handle();
+        let stream = listener.accept().expect("moninj: cannot accept");
+        if !net_allow::allowed(&stream) {
+            stream.abort().expect("moninj: cannot abort");
+            continue
+        }
+        let stream = stream.into_handle();
         io.spawn(16384, move |io| {
             let routing_table = routing_table.borrow();
             let mut stream = TcpStream::from_handle(&io, stream);
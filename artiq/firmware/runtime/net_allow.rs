@@ -0,0 +1,63 @@
+use alloc::vec::Vec;
+use smoltcp::wire::{IpAddress, IpCidr};
+
+use board_misoc::config;
+use sched::TcpStream;
+
+// Connections to the session, management, moninj and analyzer ports are only
+// accepted from the networks listed in the `net_allow` config key, and those
+// to the management port also have to come from the networks listed in
+// `mgmt_allow`. An unset key accepts connections from any host.
+
+/// Parses a list of networks, such as `10.0.0.0/16, fd00::/8`, separated by
+/// commas or spaces. A single address stands for a network of one host. An
+/// empty list is invalid, as it would refuse every connection.
+pub fn parse(value: &str) -> Option<Vec<IpCidr>> {
+    value.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse::<IpCidr>().ok().or_else(|| {
+                let address = item.parse::<IpAddress>().ok()?;
+                let prefix_len = match address {
+                    IpAddress::Ipv6(_) => 128,
+                    _ => 32
+                };
+                Some(IpCidr::new(address, prefix_len))
+            })
+        })
+        .collect::<Option<Vec<_>>>()
+        .filter(|networks| !networks.is_empty())
+}
+
+fn allows(key: &str, address: &IpAddress) -> bool {
+    config::read_str(key, |r| match r.map(parse) {
+        Ok(Some(networks)) => networks.iter().any(|network| network.contains_addr(address)),
+        // The key cannot be written with an invalid value through the
+        // management interface; do not let a damaged key open the device.
+        Ok(None) | Err(config::Error::Utf8Error(_)) => {
+            error!("invalid `{}` config key, refusing all connections", key);
+            false
+        }
+        Err(_) => true
+    })
+}
+
+fn check(stream: &TcpStream, keys: &[&str]) -> bool {
+    let remote = stream.remote_endpoint();
+    if keys.iter().all(|key| allows(key, &remote.addr)) {
+        true
+    } else {
+        warn!("refused connection from {}", remote);
+        false
+    }
+}
+
+/// Returns whether a connection may be accepted, logging it if not.
+pub fn allowed(stream: &TcpStream) -> bool {
+    check(stream, &["net_allow"])
+}
+
+/// Like `allowed`, for connections to the management interface.
+pub fn mgmt_allowed(stream: &TcpStream) -> bool {
+    check(stream, &["net_allow", "mgmt_allow"])
+}
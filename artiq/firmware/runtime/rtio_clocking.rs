@@ -15,19 +15,26 @@ pub enum RtioClock {
     Ext0_Synth0_125to125,
 }
 
+/// Parses a value of the `rtio_clock` config key, except for the legacy ones.
+pub fn parse_rtio_clock(value: &str) -> Option<RtioClock> {
+    match value {
+        "int_125" => Some(RtioClock::Int_125),
+        "int_100" => Some(RtioClock::Int_100),
+        "ext0_bypass" => Some(RtioClock::Ext0_Bypass),
+        "ext0_bypass_125" => Some(RtioClock::Ext0_Bypass),
+        "ext0_bypass_100" => Some(RtioClock::Ext0_Bypass),
+        "ext0_synth0_10to125" => Some(RtioClock::Ext0_Synth0_10to125),
+        "ext0_synth0_80to125" => Some(RtioClock::Ext0_Synth0_80to125),
+        "ext0_synth0_100to125" => Some(RtioClock::Ext0_Synth0_100to125),
+        "ext0_synth0_125to125" => Some(RtioClock::Ext0_Synth0_125to125),
+        _ => None
+    }
+}
+
 #[allow(unreachable_code)]
 fn get_rtio_clock_cfg() -> RtioClock {
     config::read_str("rtio_clock", |result| { 
         let res = match result {
-            Ok("int_125") => RtioClock::Int_125,
-            Ok("int_100") => RtioClock::Int_100,
-            Ok("ext0_bypass") => RtioClock::Ext0_Bypass,
-            Ok("ext0_bypass_125") => RtioClock::Ext0_Bypass,
-            Ok("ext0_bypass_100") => RtioClock::Ext0_Bypass,
-            Ok("ext0_synth0_10to125") => RtioClock::Ext0_Synth0_10to125,
-            Ok("ext0_synth0_80to125") => RtioClock::Ext0_Synth0_80to125,
-            Ok("ext0_synth0_100to125") => RtioClock::Ext0_Synth0_100to125,
-            Ok("ext0_synth0_125to125") => RtioClock::Ext0_Synth0_125to125,
             Ok("i") => {
                 warn!("Using legacy rtio_clock setting ('i'). Falling back to default. This will be deprecated.");
                 RtioClock::Default
@@ -36,9 +43,12 @@ fn get_rtio_clock_cfg() -> RtioClock {
                 warn!("Using legacy rtio_clock setting ('e'). This will be deprecated.");
                 RtioClock::Ext0_Bypass
             },
-            _ => {
-                warn!("rtio_clock setting not recognised. Falling back to default.");
-                RtioClock::Default
+            _ => match result.ok().and_then(parse_rtio_clock) {
+                Some(clock) => clock,
+                None => {
+                    warn!("rtio_clock setting not recognised. Falling back to default.");
+                    RtioClock::Default
+                }
             }
         };
         if res == RtioClock::Default {
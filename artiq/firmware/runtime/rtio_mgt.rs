@@ -8,6 +8,7 @@ use sched::Io;
 use sched::Mutex;
 use io::{Cursor, ProtoRead};
 use session_proto::{DeviceMap, resolve_channel_name, set_device_map};
+use config_watch;
 const ASYNC_ERROR_COLLISION: u8 = 1 << 0;
 const ASYNC_ERROR_BUSY: u8 = 1 << 1;
 const ASYNC_ERROR_SEQUENCE_ERROR: u8 = 1 << 2;
@@ -15,6 +16,7 @@ const ASYNC_ERROR_SEQUENCE_ERROR: u8 = 1 << 2;
 #[cfg(has_drtio)]
 pub mod drtio {
     use super::*;
+    use core::cell::Cell;
     use alloc::vec::Vec;
     use drtioaux;
     use proto_artiq::drtioaux_proto::{MASTER_PAYLOAD_MAX_SIZE, PayloadStatus};
@@ -65,9 +67,13 @@ pub mod drtio {
         let up_destinations = up_destinations.clone();
         let ddma_mutex = ddma_mutex.clone();
         let subkernel_mutex = subkernel_mutex.clone();
+        let reload = Urc::new(Cell::new(false));
+        {
+            let reload = reload.clone();
+            config_watch::subscribe(&["routing_table"], move |_| reload.set(true));
+        }
         io.spawn(16384, move |io| {
-            let routing_table = routing_table.borrow();
-            link_thread(io, &aux_mutex, &routing_table, &up_destinations, &ddma_mutex, &subkernel_mutex);
+            link_thread(io, &aux_mutex, &routing_table, &reload, &up_destinations, &ddma_mutex, &subkernel_mutex);
         });
     }
 
@@ -396,12 +402,57 @@ pub mod drtio {
         }
     }
 
+    fn reload_routing_table(io: &Io, aux_mutex: &Mutex,
+            routing_table: &Urc<RefCell<drtio_routing::RoutingTable>>,
+            up_links: &[bool],
+            up_destinations: &Urc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
+            ddma_mutex: &Mutex, subkernel_mutex: &Mutex) -> bool {
+        match routing_table.try_borrow_mut() {
+            Ok(mut routing_table) =>
+                *routing_table = drtio_routing::config_routing_table(csr::DRTIO.len()),
+            // Kernels, moninj and analyzer connections keep the table borrowed
+            // while they run; try again later.
+            Err(_) => return false
+        }
+        let routing_table = routing_table.borrow();
+        for linkno in 0..csr::DRTIO.len() {
+            if up_links[linkno] {
+                if let Err(e) = load_routing_table(io, aux_mutex, linkno as u8, &routing_table) {
+                    error!("[LINK#{}] failed to load routing table ({:?})", linkno, e);
+                }
+            }
+        }
+        // Destinations that remain reachable are routed through their new hops,
+        // the others are found again by the next survey.
+        for destination in 0..drtio_routing::DEST_COUNT {
+            let hop = routing_table.0[destination][0];
+            let destination = destination as u8;
+            if destination_up(up_destinations, destination) {
+                if hop == 0 || (hop as usize <= csr::DRTIO.len() && up_links[hop as usize - 1]) {
+                    drtio_routing::interconnect_enable(&routing_table, 0, destination);
+                } else {
+                    destination_set_up(&routing_table, up_destinations, destination, false);
+                    remote_dma::destination_changed(io, aux_mutex, ddma_mutex, subkernel_mutex, &routing_table, destination, false);
+                    subkernel::destination_changed(io, aux_mutex, ddma_mutex, subkernel_mutex, &routing_table, destination, false);
+                }
+            }
+        }
+        true
+    }
+
     pub fn link_thread(io: Io, aux_mutex: &Mutex,
-            routing_table: &drtio_routing::RoutingTable,
+            routing_table: &Urc<RefCell<drtio_routing::RoutingTable>>,
+            reload: &Cell<bool>,
             up_destinations: &Urc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
             ddma_mutex: &Mutex, subkernel_mutex: &Mutex) {
         let mut up_links = [false; csr::DRTIO.len()];
         loop {
+            if reload.get() && reload_routing_table(&io, aux_mutex, routing_table, &up_links,
+                                                    up_destinations, ddma_mutex, subkernel_mutex) {
+                reload.set(false);
+            }
+            let routing_table = routing_table.borrow();
+            let routing_table = &*routing_table;
             for linkno in 0..csr::DRTIO.len() {
                 let linkno = linkno as u8;
                 if up_links[linkno as usize] {
@@ -715,6 +766,7 @@ pub fn startup(io: &Io, aux_mutex: &Mutex,
         up_destinations: &Urc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
         ddma_mutex: &Mutex, subkernel_mutex: &Mutex) {
     set_device_map(read_device_map());
+    config_watch::subscribe(&["device_map"], |_| set_device_map(read_device_map()));
     drtio::startup(io, aux_mutex, routing_table, up_destinations, ddma_mutex, subkernel_mutex);
     unsafe {
         csr::rtio_core::reset_phy_write(1);
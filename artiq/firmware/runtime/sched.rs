@@ -2,13 +2,14 @@
 
 use core::mem;
 use core::result;
-use core::cell::{Cell, RefCell, RefMut};
+use core::cell::{Cell, RefCell};
 use alloc::vec::Vec;
 use fringe::OwnedStack;
 use fringe::generator::{Generator, Yielder, State as GeneratorState};
 use smoltcp::time::Duration;
 use smoltcp::Error as NetworkError;
-use smoltcp::wire::{IpEndpoint, Ipv4Address, Ipv4Cidr};
+use smoltcp::wire::{IpCidr, IpEndpoint, IpProtocol, IpVersion, Ipv4Address, Ipv4Cidr,
+                    Ipv6Address, Ipv6Cidr};
 use smoltcp::iface::{Interface, Route, SocketHandle};
 
 use io::{Read, Write};
@@ -250,10 +251,17 @@ impl<'a> Io<'a> {
         })
     }
 
-    pub fn until<F: FnMut() -> bool>(&self, mut f: F) -> Result<(), Error> {
+    pub fn until<F: FnMut() -> bool>(&self, f: F) -> Result<(), Error> {
+        self.until_timeout(None, f)
+    }
+
+    /// Like `until`, but fails with `Error::TimedOut` if `f` still returns false
+    /// after `timeout_ms` milliseconds.
+    pub fn until_timeout<F: FnMut() -> bool>(&self, timeout_ms: Option<u64>, mut f: F)
+                                            -> Result<(), Error> {
         let f = unsafe { mem::transmute::<&mut dyn FnMut() -> bool, *mut dyn FnMut() -> bool>(&mut f) };
         self.suspend(WaitRequest {
-            timeout: None,
+            timeout: timeout_ms.map(|timeout_ms| clock::get_ms() + timeout_ms),
             event:   Some(f)
         })
     }
@@ -286,6 +294,49 @@ impl<'a> Io<'a> {
     pub fn remove_ipv4_default_route(&self) -> Option<Route> {
         self.network.borrow_mut().routes_mut().remove_default_ipv4_route()
     }
+
+    pub fn set_ipv6_address(&self, addr: &Ipv6Cidr) {
+        self.network.borrow_mut().update_ipv6_addr(addr)
+    }
+
+    /// Sets the autoconfigured IPv6 addresses, which are kept apart from the
+    /// static one; `None` clears a slot.
+    pub fn set_ipv6_autoconf_addresses(&self, addrs: &[Option<Ipv6Cidr>]) {
+        self.network.borrow_mut().update_ipv6_autoconf_addrs(addrs)
+    }
+
+    pub fn set_ipv6_default_route(&self, addr: Ipv6Address) -> Result<Option<Route>, Error> {
+        Ok(self.network.borrow_mut().routes_mut().add_default_ipv6_route(addr)?)
+    }
+
+    pub fn remove_ipv6_default_route(&self) -> Option<Route> {
+        self.network.borrow_mut().routes_mut().remove_default_ipv6_route()
+    }
+
+    /// Returns the addresses assigned to the interface.
+    pub fn ip_addrs(&self) -> Vec<IpCidr> {
+        self.network.borrow().ip_addrs().iter()
+            .filter(|cidr| !cidr.address().is_unspecified())
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the IPv4 address of the interface, if one is assigned.
+    pub fn ipv4_address(&self) -> Option<Ipv4Address> {
+        self.network.borrow().ipv4_address().filter(|addr| !addr.is_unspecified())
+    }
+
+    /// Joins an IPv4 multicast group, and sends an IGMP report for it.
+    /// Returns false if the group was already joined.
+    pub fn join_multicast_group(&self, addr: Ipv4Address) -> Result<bool, Error> {
+        let timestamp = smoltcp::time::Instant::from_millis(clock::get_ms() as i64);
+        Ok(self.network.borrow_mut().join_multicast_group(addr, timestamp)?)
+    }
+
+    pub fn leave_multicast_group(&self, addr: Ipv4Address) -> Result<bool, Error> {
+        let timestamp = smoltcp::time::Instant::from_millis(clock::get_ms() as i64);
+        Ok(self.network.borrow_mut().leave_multicast_group(addr, timestamp)?)
+    }
 }
 
 #[derive(Clone)]
@@ -504,6 +555,13 @@ impl<'a> TcpStream<'a> {
         // then the last ACK will never be sent.
         self.io.relinquish()
     }
+
+    /// Resets the connection, without waiting for the remote end to close it.
+    pub fn abort(&self) -> Result<(), Error> {
+        self.with_lower(|s| s.abort());
+        // Give the reset a chance to be sent before the socket is dropped.
+        self.io.relinquish()
+    }
 }
 
 impl<'a> Read for TcpStream<'a> {
@@ -598,44 +656,203 @@ impl<'a> Drop for TcpStream<'a> {
     }
 }
 
-pub struct Dhcpv4Socket<'a> {
-    io: &'a Io<'a>,
-    handle: SocketHandle,
+type UdpSocketBuffer   = ::smoltcp::socket::UdpSocketBuffer<'static>;
+type UdpPacketMetadata = ::smoltcp::socket::UdpPacketMetadata;
+type UdpSocketLower    = ::smoltcp::socket::UdpSocket<'static>;
+
+// Number of datagrams that can be queued in each direction, as long as their
+// payloads fit in the buffer.
+const UDP_PACKET_COUNT: usize = 16;
+
+pub struct UdpSocket<'a> {
+    io:      &'a Io<'a>,
+    handle:  SocketHandle,
+    timeout: Cell<Option<u64>>
 }
 
-impl<'a> Dhcpv4Socket<'a> {
+impl<'a> UdpSocket<'a> {
+    pub fn new(io: &'a Io<'a>, buffer_size: usize) -> UdpSocket<'a> {
+        let rx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; UDP_PACKET_COUNT],
+                                             vec![0; buffer_size]);
+        let tx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; UDP_PACKET_COUNT],
+                                             vec![0; buffer_size]);
+        let handle = io.network
+            .borrow_mut()
+            .add_socket(UdpSocketLower::new(rx_buffer, tx_buffer));
+        UdpSocket {
+            io:      io,
+            handle:  handle,
+            timeout: Cell::new(None)
+        }
+    }
 
-    fn new_lower(io: &'a Io<'a>) -> SocketHandle {
-        let socket = smoltcp::socket::Dhcpv4Socket::new();
-        io.network.borrow_mut().add_socket(socket)
+    fn with_lower<F, R>(&self, f: F) -> R
+            where F: FnOnce(&mut UdpSocketLower) -> R {
+        let mut network = self.io.network.borrow_mut();
+        let result = f(network.get_socket(self.handle));
+        result
     }
 
-    pub fn new(io: &'a Io<'a>) -> Self {
-        Self {
-            io,
-            handle: Self::new_lower(io)
-        }
+    pub fn bind<T: Into<IpEndpoint>>(&self, endpoint: T) -> Result<(), Error> {
+        let endpoint = endpoint.into();
+        self.with_lower(|s| s.bind(endpoint)).map_err(|err| err.into())
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.with_lower(|s| s.is_open())
+    }
+
+    pub fn can_send(&self) -> bool {
+        self.with_lower(|s| s.can_send())
+    }
+
+    pub fn can_recv(&self) -> bool {
+        self.with_lower(|s| s.can_recv())
+    }
+
+    pub fn local_endpoint(&self) -> IpEndpoint {
+        self.with_lower(|s| s.endpoint())
+    }
+
+    pub fn set_hop_limit(&self, value: Option<u8>) {
+        self.with_lower(|s| s.set_hop_limit(value))
+    }
+
+    /// Timeout of `send_to` and `recv_from`, in milliseconds.
+    pub fn timeout(&self) -> Option<u64> {
+        self.timeout.get()
+    }
+
+    pub fn set_timeout(&self, value: Option<u64>) {
+        self.timeout.set(value)
     }
 
-    fn lower(&mut self) -> RefMut<smoltcp::socket::Dhcpv4Socket> {
-        RefMut::map(
-            self.io.network.borrow_mut(),
-            |network| network.get_socket::<smoltcp::socket::Dhcpv4Socket>(self.handle),
-        )
+    // Waits for `cond` until `deadline`, if any.
+    fn wait<F>(&self, deadline: Option<u64>, mut cond: F) -> Result<(), Error>
+            where F: FnMut(&mut UdpSocketLower) -> bool {
+        let (network, handle) = (self.io.network.clone(), self.handle);
+        let timeout = deadline.map(|deadline| deadline.saturating_sub(clock::get_ms()));
+        self.io.until_timeout(timeout, move || {
+            let mut network = network.borrow_mut();
+            cond(network.get_socket::<UdpSocketLower>(handle))
+        })
+    }
+
+    /// Queue a datagram for `endpoint`, waiting for room in the buffer.
+    pub fn send_to(&self, buf: &[u8], endpoint: IpEndpoint) -> Result<(), Error> {
+        let deadline = self.timeout.get().map(|timeout| clock::get_ms() + timeout);
+        loop {
+            match self.with_lower(|s| s.send_slice(buf, endpoint)) {
+                Ok(()) => return Ok(()),
+                // Either all the packets are queued, or there is not enough
+                // room left for this payload; wait until some are sent.
+                Err(NetworkError::Exhausted) =>
+                    self.wait(deadline, |s| s.can_send())?,
+                Err(err) => return Err(err.into())
+            }
+        }
     }
 
-    pub fn poll(&mut self) -> Option<smoltcp::socket::Dhcpv4Event> {
-        self.lower().poll()
+    /// Receive a datagram, waiting for one to arrive, and return its length
+    /// and source. The end of datagrams longer than `buf` is dropped.
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, IpEndpoint), Error> {
+        let deadline = self.timeout.get().map(|timeout| clock::get_ms() + timeout);
+        loop {
+            match self.with_lower(|s| s.recv_slice(buf)) {
+                Ok(result) => return Ok(result),
+                Err(NetworkError::Exhausted) =>
+                    self.wait(deadline, |s| s.can_recv())?,
+                Err(err) => return Err(err.into())
+            }
+        }
     }
 
-    pub fn reset(&mut self) {
-        self.lower().reset()
+    pub fn close(&self) {
+        self.with_lower(|s| s.close())
     }
 }
 
-impl<'a> Drop for Dhcpv4Socket<'a> {
+impl<'a> Drop for UdpSocket<'a> {
     fn drop(&mut self) {
+        self.with_lower(|s| s.close());
+        self.io.network.borrow_mut().remove_socket(self.handle);
+    }
+}
+
+type RawSocketBuffer   = ::smoltcp::socket::RawSocketBuffer<'static>;
+type RawPacketMetadata = ::smoltcp::socket::RawPacketMetadata;
+type RawSocketLower    = ::smoltcp::socket::RawSocket<'static>;
+
+// Number of packets that can be queued in each direction.
+const RAW_PACKET_COUNT: usize = 4;
+
+/// A socket that sends and receives whole IP packets, headers included, of
+/// one protocol. It receives a copy of the matching packets that smoltcp
+/// processes itself.
+pub struct RawSocket<'a> {
+    io:     &'a Io<'a>,
+    handle: SocketHandle
+}
+
+impl<'a> RawSocket<'a> {
+    pub fn new(io: &'a Io<'a>, ip_version: IpVersion, ip_protocol: IpProtocol,
+               buffer_size: usize) -> RawSocket<'a> {
+        let rx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; RAW_PACKET_COUNT],
+                                             vec![0; buffer_size]);
+        let tx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; RAW_PACKET_COUNT],
+                                             vec![0; buffer_size]);
+        let handle = io.network
+            .borrow_mut()
+            .add_socket(RawSocketLower::new(ip_version, ip_protocol, rx_buffer, tx_buffer));
+        RawSocket {
+            io:     io,
+            handle: handle
+        }
+    }
+
+    fn with_lower<F, R>(&self, f: F) -> R
+            where F: FnOnce(&mut RawSocketLower) -> R {
         let mut network = self.io.network.borrow_mut();
-        network.remove_socket(self.handle);
+        let result = f(network.get_socket(self.handle));
+        result
+    }
+
+    pub fn can_send(&self) -> bool {
+        self.with_lower(|s| s.can_send())
+    }
+
+    pub fn can_recv(&self) -> bool {
+        self.with_lower(|s| s.can_recv())
+    }
+
+    /// Queue a packet, waiting for room in the buffer.
+    pub fn send(&self, buf: &[u8]) -> Result<(), Error> {
+        loop {
+            match self.with_lower(|s| s.send_slice(buf)) {
+                Ok(()) => return Ok(()),
+                Err(NetworkError::Exhausted) =>
+                    until!(self, RawSocketLower, |s| s.can_send())?,
+                Err(err) => return Err(err.into())
+            }
+        }
+    }
+
+    /// Receive a packet, waiting for one to arrive, and return its length.
+    /// The end of packets longer than `buf` is dropped.
+    pub fn recv(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            match self.with_lower(|s| s.recv_slice(buf)) {
+                Ok(length) => return Ok(length),
+                Err(NetworkError::Exhausted) =>
+                    until!(self, RawSocketLower, |s| s.can_recv())?,
+                Err(err) => return Err(err.into())
+            }
+        }
+    }
+}
+
+impl<'a> Drop for RawSocket<'a> {
+    fn drop(&mut self) {
+        self.io.network.borrow_mut().remove_socket(self.handle);
     }
 }
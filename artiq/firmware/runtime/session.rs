@@ -2,15 +2,13 @@ use core::{mem, str, cell::{Cell, RefCell}, fmt::Write as FmtWrite};
 use alloc::{vec::Vec, string::{String, ToString}};
 use byteorder::{ByteOrder, NativeEndian};
 use cslice::CSlice;
-#[cfg(has_drtio)]
-use tar_no_std::TarArchiveRef;
 
-use dyld::elf;
 use io::{Read, Write, Error as IoError};
 #[cfg(has_drtio)]
 use io::Cursor;
-use board_misoc::{ident, cache, config};
+use board_misoc::{ident, cache, config, kernel_store};
 use {mailbox, rpc_queue, kernel};
+use kernel_archive;
 use urc::Urc;
 use sched::{ThreadHandle, Io, Mutex, TcpListener, TcpStream, Error as SchedError};
 use rtio_clocking;
@@ -24,6 +22,9 @@ use rtio_mgt::drtio;
 use rtio_mgt::get_async_errors;
 use cache::Cache;
 use kern_hwreq;
+use sntp;
+use net_allow;
+use auth;
 use board_artiq::drtio_routing;
 
 use rpc_proto as rpc;
@@ -36,6 +37,8 @@ pub enum Error<T> {
     Load(String),
     #[fail(display = "kernel not found")]
     KernelNotFound,
+    #[fail(display = "invalid kernel: {}", _0)]
+    InvalidKernel(#[cause] kernel_archive::Error),
     #[fail(display = "invalid kernel CPU pointer: {:#08x}", _0)]
     InvalidPointer(usize),
     #[fail(display = "RTIO clock failure")]
@@ -323,54 +326,29 @@ fn process_flash_kernel(io: &Io, _aux_mutex: &Mutex, _subkernel_mutex: &Mutex, _
                         session: &mut Session, kernel: &[u8]
 ) -> Result<(), Error<SchedError>> {
     // handle ELF and TAR files
-    if kernel[0] == elf::ELFMAG0 && kernel[1] == elf::ELFMAG1 &&
-       kernel[2] == elf::ELFMAG2 && kernel[3] == elf::ELFMAG3 {
-        // assume ELF file, proceed as before
-        unsafe {
-            // make a copy as kernel CPU cannot read SPI directly
-            kern_load(io, session, Vec::from(kernel).as_ref())
-        }
-    } else {
-        #[cfg(has_drtio)]
-        {  
-            let archive = TarArchiveRef::new(kernel);
-            let entries = archive.entries();
-            let mut main_lib: Option<&[u8]> = None;
-            for entry in entries {
-                if entry.filename().as_str() == "main.elf" {
-                    main_lib = Some(entry.data());
-                } else {
-                    // subkernel filename must be in format:
-                    // "<subkernel id> <destination>.elf"
-                    let filename = entry.filename();
-                    let mut iter = filename.as_str().split_whitespace();
-                    let sid: u32 = iter.next().unwrap()
-                                    .parse().unwrap();
-                    let dest: u8 = iter.next().unwrap()
-                                    .strip_suffix(".elf").unwrap()
-                                    .parse().unwrap();
-                    let up = {
-                        let up_destinations = _up_destinations.borrow();
-                        up_destinations[dest as usize]
-                    };
-                    if up {
-                        let subkernel_lib = entry.data().to_vec();
-                        subkernel::add_subkernel(io, _subkernel_mutex, sid, dest, subkernel_lib)?;
-                        subkernel::upload(io, _aux_mutex, _ddma_mutex, _subkernel_mutex, _routing_table, sid)?;
-                    } else {
-                        return Err(Error::DestinationDown);
-                    }
-                }
-            }
-            unsafe {
-                kern_load(io, session, Vec::from(main_lib.unwrap()).as_ref())
-            }
-        }
-        #[cfg(not(has_drtio))]
-        {
-            unexpected!("multi-kernel libraries are not supported in standalone systems")
+    let (main_lib, _subkernels) = match kernel_archive::parse(kernel) {
+        Ok(kernel_archive::Kernel::Elf(library)) => (library, Vec::new()),
+        Ok(kernel_archive::Kernel::Archive { main, subkernels }) => (main, subkernels),
+        Err(error) => return Err(Error::InvalidKernel(error))
+    };
+    #[cfg(has_drtio)]
+    for subkernel in _subkernels {
+        let up = {
+            let up_destinations = _up_destinations.borrow();
+            up_destinations[subkernel.destination as usize]
+        };
+        if up {
+            let subkernel_lib = subkernel.library.to_vec();
+            subkernel::add_subkernel(io, _subkernel_mutex, subkernel.id, subkernel.destination, subkernel_lib)?;
+            subkernel::upload(io, _aux_mutex, _ddma_mutex, _subkernel_mutex, _routing_table, subkernel.id)?;
+        } else {
+            return Err(Error::DestinationDown);
         }
     }
+    unsafe {
+        // make a copy as kernel CPU cannot read SPI directly
+        kern_load(io, session, Vec::from(main_lib).as_ref())
+    }
 }
 
 fn process_host_message(io: &Io, _aux_mutex: &Mutex, _ddma_mutex: &Mutex, _subkernel_mutex: &Mutex,
@@ -380,7 +358,8 @@ fn process_host_message(io: &Io, _aux_mutex: &Mutex, _ddma_mutex: &Mutex, _subke
         host::Request::SystemInfo => {
             host_write(stream, host::Reply::SystemInfo {
                 ident: ident::read(&mut [0; 64]),
-                finished_cleanly: session.congress.finished_cleanly.get()
+                finished_cleanly: session.congress.finished_cleanly.get(),
+                time_correlation: sntp::latest()
             })?;
             session.congress.finished_cleanly.set(true)
         }
@@ -841,25 +820,28 @@ fn flash_kernel_worker(io: &Io, aux_mutex: &Mutex,
                        routing_table: &drtio_routing::RoutingTable,
                        up_destinations: &Urc<RefCell<[bool; drtio_routing::DEST_COUNT]>>,
                        ddma_mutex: &Mutex, subkernel_mutex: &Mutex, congress: &mut Congress,
-                       config_key: &str) -> Result<(), Error<SchedError>> {
+                       name: &str) -> Result<(), Error<SchedError>> {
     let mut session = Session::new(congress);
 
-    config::read(config_key, |result| {
-        match result {
-            Ok(kernel) => {
-                // process .ELF or .TAR kernels
-                let res = process_flash_kernel(io, aux_mutex, subkernel_mutex, ddma_mutex, routing_table, up_destinations, &mut session, kernel);
-                #[cfg(has_drtio)]
-                match res {
-                    // wait to establish the DRTIO connection
-                    Err(Error::DestinationDown) => io.sleep(500)?,
-                    _ => ()
-                }
-                res
-            }
-            _ => Err(Error::KernelNotFound)
-        }
-    })?;
+    // Copy the kernel out of flash first, so that the kernel store and the
+    // config are not locked while it is loaded and sent to satellites.
+    // Kernels written with `artiq_coremgmt config` are stored in the config.
+    let kernel = kernel_store::read(name, |result| result.ok().map(|kernel| kernel.to_vec()))
+        .or_else(|| config::read(name, |result| result.ok().map(|kernel| kernel.to_vec())));
+    let kernel = match kernel {
+        Some(kernel) => kernel,
+        None => return Err(Error::KernelNotFound)
+    };
+
+    // process .ELF or .TAR kernels
+    let res = process_flash_kernel(io, aux_mutex, subkernel_mutex, ddma_mutex, routing_table, up_destinations, &mut session, &kernel);
+    #[cfg(has_drtio)]
+    match res {
+        // wait to establish the DRTIO connection
+        Err(Error::DestinationDown) => io.sleep(500)?,
+        _ => ()
+    }
+    res?;
     kern_run(&mut session)?;
 
     loop {
@@ -927,6 +909,10 @@ pub fn thread(io: Io, aux_mutex: &Mutex,
     loop {
         if listener.can_accept() {
             let mut stream = listener.accept().expect("session: cannot accept");
+            if !net_allow::allowed(&stream) {
+                stream.abort().expect("session: cannot abort");
+                continue
+            }
             stream.set_timeout(Some(2250));
             stream.set_keep_alive(Some(500));
 
@@ -938,6 +924,18 @@ pub fn thread(io: Io, aux_mutex: &Mutex,
                     continue
                 }
             }
+            match auth::authenticate(&mut stream) {
+                Ok(true) => (),
+                Ok(false) => {
+                    stream.close().expect("session: cannot close");
+                    continue
+                }
+                Err(err) => {
+                    warn!("cannot authenticate {}: {}", stream.remote_endpoint(), err);
+                    stream.close().expect("session: cannot close");
+                    continue
+                }
+            }
             match stream.write_all("e".as_bytes()) {
                 Ok(()) => (),
                 Err(_) => {
@@ -1014,6 +1012,11 @@ pub fn thread(io: Io, aux_mutex: &Mutex,
                         info!("no idle kernel found");
                         while io.relinquish().is_ok() {}
                     }
+                    Err(Error::InvalidKernel(err)) => {
+                        // retrying would only log the same error again
+                        error!("idle kernel is invalid: {}", err);
+                        while io.relinquish().is_ok() {}
+                    }
                     Err(err) => {
                         error!("idle kernel aborted: {}", err);
                         #[cfg(has_drtio)]
@@ -0,0 +1,543 @@
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, BigEndian};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpEndpoint, IpProtocol, IpVersion,
+                    Ipv6Address, Ipv6Cidr};
+
+use board_misoc::{clock, config, net_settings};
+use ip_addr_storage::IPV6_AUTOCONF_COUNT;
+use sched::{Io, RawSocket, UdpSocket, Error as SchedError};
+
+// IPv6 stateless address autoconfiguration (RFC 4862), from the router
+// advertisements that smoltcp ignores. For each prefix advertised as usable
+// for autoconfiguration, an address is formed from the prefix and the
+// interface identifier of the link-local address, which is derived from the
+// MAC address; duplicate address detection is therefore not done. The router
+// becomes the IPv6 default route, unless `ipv6_default_route` is set.
+//
+// When routers ask for it (managed or other configuration flag), the DNS
+// servers are requested with stateless DHCPv6 (RFC 8415); they are also taken
+// from the RDNSS option of advertisements (RFC 8106). Setting `ip6_autoconf`
+// to 0 disables all of this.
+const PACKET_SIZE:          usize = 1500;
+const IPV6_HEADER_LEN:      usize = 40;
+// Neighbor discovery messages are only accepted from the local link.
+const NDISC_HOP_LIMIT:      u8 = 255;
+
+const ICMPV6_ROUTER_SOLICIT: u8 = 133;
+const ICMPV6_ROUTER_ADVERT: u8 = 134;
+
+const NDISC_OPTION_SOURCE_LLADDR: u8 = 1;
+const NDISC_OPTION_PREFIX_INFO: u8 = 3;
+const NDISC_OPTION_RDNSS:   u8 = 25;
+
+const ROUTER_FLAG_MANAGED:  u8 = 0x80;
+const ROUTER_FLAG_OTHER:    u8 = 0x40;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+// RFC 4861 section 10.
+const MAX_RTR_SOLICITATION_DELAY_MS: u64 = 1_000;
+const RTR_SOLICITATION_INTERVAL_MS: u64 = 4_000;
+const MAX_RTR_SOLICITATIONS: u32 = 3;
+// Shortest valid lifetime that an unauthenticated advertisement can set for
+// an existing address (RFC 4862 section 5.5.3).
+const MIN_VALID_LIFETIME_MS: u64 = 2 * 3600 * 1000;
+
+const DHCPV6_CLIENT_PORT:   u16 = 546;
+const DHCPV6_SERVER_PORT:   u16 = 547;
+// RFC 8415 section 7.6; retransmissions are never given up.
+const DHCPV6_FIRST_RETRY_MS: u64 = 1_000;
+const DHCPV6_MAX_RETRY_MS:  u64 = 3_600_000;
+const DHCPV6_DEFAULT_REFRESH_S: u32 = 86_400;
+const DHCPV6_MIN_REFRESH_S: u32 = 600;
+
+const DHCPV6_REPLY:         u8 = 7;
+const DHCPV6_INFORMATION_REQUEST: u8 = 11;
+
+const DHCPV6_OPTION_CLIENT_ID: u16 = 1;
+const DHCPV6_OPTION_ORO:    u16 = 6;
+const DHCPV6_OPTION_ELAPSED_TIME: u16 = 8;
+const DHCPV6_OPTION_STATUS_CODE: u16 = 13;
+const DHCPV6_OPTION_DNS_SERVERS: u16 = 23;
+const DHCPV6_OPTION_REFRESH_TIME: u16 = 32;
+
+pub const MAX_DNS_SERVERS:  usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Address {
+    pub address:    Ipv6Cidr,
+    // Time in milliseconds since startup, or `None` for infinite lifetimes.
+    pub expires_at: Option<u64>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    pub addresses:     [Option<Address>; IPV6_AUTOCONF_COUNT],
+    pub router:        Option<Ipv6Address>,
+    router_expires_at: u64,
+    pub dns_servers:   [Option<Ipv6Address>; MAX_DNS_SERVERS],
+    dns_expires_at:    Option<u64>
+}
+
+const UNCONFIGURED: Status = Status {
+    addresses:         [None; IPV6_AUTOCONF_COUNT],
+    router:            None,
+    router_expires_at: 0,
+    dns_servers:       [None; MAX_DNS_SERVERS],
+    dns_expires_at:    None
+};
+
+static mut STATUS: Status = UNCONFIGURED;
+
+/// Returns the autoconfigured addresses, router and DNS servers.
+pub fn status() -> Status {
+    unsafe { STATUS }
+}
+
+struct Settings {
+    hardware_addr: EthernetAddress,
+    link_local:    Ipv6Address,
+    static_route:  bool
+}
+
+fn settings() -> Settings {
+    let net_addresses = net_settings::get_adresses();
+    let link_local = match net_addresses.ipv6_ll_addr.address() {
+        IpAddress::Ipv6(address) => address,
+        _ => unreachable!()
+    };
+    Settings {
+        hardware_addr: net_addresses.hardware_addr,
+        link_local:    link_local,
+        static_route:  net_addresses.ipv6_default_route.is_some()
+    }
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|chunk| match *chunk {
+            [high, low] => (high as u32) << 8 | low as u32,
+            [high] => (high as u32) << 8,
+            _ => unreachable!()
+        })
+        .sum()
+}
+
+// Returns the checksum of an ICMPv6 message; it is zero when computed over a
+// message with a valid checksum.
+fn icmpv6_checksum(src: &Ipv6Address, dst: &Ipv6Address, message: &[u8]) -> u16 {
+    let mut sum = checksum(src.as_bytes()) + checksum(dst.as_bytes()) +
+        checksum(&(message.len() as u32).to_be_bytes()) +
+        checksum(&[0, 0, 0, u8::from(IpProtocol::Icmpv6)]) + checksum(message);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16)
+    }
+    !(sum as u16)
+}
+
+fn write_router_solicit(settings: &Settings) -> Vec<u8> {
+    let dst = Ipv6Address::LINK_LOCAL_ALL_ROUTERS;
+    let mut message = vec![ICMPV6_ROUTER_SOLICIT, 0, 0, 0, 0, 0, 0, 0,
+                           NDISC_OPTION_SOURCE_LLADDR, 1];
+    message.extend_from_slice(settings.hardware_addr.as_bytes());
+    let checksum = icmpv6_checksum(&settings.link_local, &dst, &message);
+    BigEndian::write_u16(&mut message[2..], checksum);
+
+    // Raw sockets send the IP header too.
+    let mut packet = vec![0x60, 0, 0, 0, 0, 0, u8::from(IpProtocol::Icmpv6), NDISC_HOP_LIMIT];
+    BigEndian::write_u16(&mut packet[4..], message.len() as u16);
+    packet.extend_from_slice(settings.link_local.as_bytes());
+    packet.extend_from_slice(dst.as_bytes());
+    packet.extend_from_slice(&message);
+    packet
+}
+
+struct Prefix {
+    prefix:         Ipv6Address,
+    prefix_len:     u8,
+    autonomous:     bool,
+    valid_time:     u32,
+    preferred_time: u32
+}
+
+// The fields of a router advertisement that the client uses.
+struct Advert {
+    router:         Ipv6Address,
+    flags:          u8,
+    router_lifetime: u16,
+    prefixes:       Vec<Prefix>,
+    dns_servers:    [Option<Ipv6Address>; MAX_DNS_SERVERS],
+    dns_lifetime:   Option<u32>
+}
+
+fn read_advert(packet: &[u8]) -> Option<Advert> {
+    if packet.len() < IPV6_HEADER_LEN || packet[0] >> 4 != 6 ||
+            packet[6] != u8::from(IpProtocol::Icmpv6) || packet[7] != NDISC_HOP_LIMIT {
+        return None
+    }
+    let (src, dst) = (Ipv6Address::from_bytes(&packet[8..24]),
+                      Ipv6Address::from_bytes(&packet[24..40]));
+    let length = BigEndian::read_u16(&packet[4..]) as usize;
+    let message = packet.get(IPV6_HEADER_LEN..IPV6_HEADER_LEN + length)?;
+    if message.len() < 16 || message[0] != ICMPV6_ROUTER_ADVERT || message[1] != 0 ||
+            !src.is_link_local() || icmpv6_checksum(&src, &dst, message) != 0 {
+        return None
+    }
+    let mut advert = Advert {
+        router:          src,
+        flags:           message[5],
+        router_lifetime: BigEndian::read_u16(&message[6..]),
+        prefixes:        Vec::new(),
+        dns_servers:     [None; MAX_DNS_SERVERS],
+        dns_lifetime:    None
+    };
+
+    let mut options = &message[16..];
+    while options.len() >= 2 {
+        let length = options[1] as usize * 8;
+        if length == 0 {
+            return None
+        }
+        let data = options.get(..length)?;
+        options = &options[length..];
+        match data[0] {
+            NDISC_OPTION_PREFIX_INFO if length == 32 => {
+                advert.prefixes.push(Prefix {
+                    prefix:         Ipv6Address::from_bytes(&data[16..32]),
+                    prefix_len:     data[2],
+                    autonomous:     data[3] & PREFIX_FLAG_AUTONOMOUS != 0,
+                    valid_time:     BigEndian::read_u32(&data[4..]),
+                    preferred_time: BigEndian::read_u32(&data[8..])
+                })
+            }
+            NDISC_OPTION_RDNSS if length >= 24 => {
+                advert.dns_lifetime = Some(BigEndian::read_u32(&data[4..]));
+                for (server, data) in advert.dns_servers.iter_mut().zip(data[8..].chunks_exact(16)) {
+                    *server = Some(Ipv6Address::from_bytes(data))
+                }
+            }
+            _ => ()
+        }
+    }
+    Some(advert)
+}
+
+fn write_information_request(settings: &Settings, xid: u32, elapsed_ms: u64) -> Vec<u8> {
+    fn write_option(packet: &mut Vec<u8>, code: u16, data: &[u8]) {
+        packet.extend_from_slice(&code.to_be_bytes());
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+    }
+
+    let mut packet = vec![DHCPV6_INFORMATION_REQUEST];
+    packet.extend_from_slice(&xid.to_be_bytes()[1..]);
+    // DUID based on the link-layer address (type 3), of Ethernet (type 1).
+    let mut duid = vec![0, 3, 0, 1];
+    duid.extend_from_slice(settings.hardware_addr.as_bytes());
+    write_option(&mut packet, DHCPV6_OPTION_CLIENT_ID, &duid);
+    let mut requested = Vec::new();
+    requested.extend_from_slice(&DHCPV6_OPTION_DNS_SERVERS.to_be_bytes());
+    requested.extend_from_slice(&DHCPV6_OPTION_REFRESH_TIME.to_be_bytes());
+    write_option(&mut packet, DHCPV6_OPTION_ORO, &requested);
+    // In hundredths of a second.
+    let elapsed = (elapsed_ms / 10).min(0xffff) as u16;
+    write_option(&mut packet, DHCPV6_OPTION_ELAPSED_TIME, &elapsed.to_be_bytes());
+    packet
+}
+
+// The fields of a DHCPv6 reply that the client uses.
+struct Information {
+    dns_servers:  [Option<Ipv6Address>; MAX_DNS_SERVERS],
+    refresh_time: u32
+}
+
+fn read_information_reply(xid: u32, packet: &[u8]) -> Option<Information> {
+    if packet.len() < 4 || packet[0] != DHCPV6_REPLY || packet[1..4] != xid.to_be_bytes()[1..] {
+        return None
+    }
+    let mut information = Information {
+        dns_servers:  [None; MAX_DNS_SERVERS],
+        refresh_time: DHCPV6_DEFAULT_REFRESH_S
+    };
+
+    let mut options = &packet[4..];
+    while options.len() >= 4 {
+        let code = BigEndian::read_u16(options);
+        let length = BigEndian::read_u16(&options[2..]) as usize;
+        let data = options.get(4..4 + length)?;
+        options = &options[4 + length..];
+        match code {
+            DHCPV6_OPTION_STATUS_CODE if length >= 2 && BigEndian::read_u16(data) != 0 =>
+                return None,
+            DHCPV6_OPTION_DNS_SERVERS => {
+                for (server, data) in information.dns_servers.iter_mut().zip(data.chunks_exact(16)) {
+                    *server = Some(Ipv6Address::from_bytes(data))
+                }
+            }
+            DHCPV6_OPTION_REFRESH_TIME if length == 4 =>
+                information.refresh_time = BigEndian::read_u32(data).max(DHCPV6_MIN_REFRESH_S),
+            _ => ()
+        }
+    }
+    Some(information)
+}
+
+// Times are in seconds; all ones is infinite.
+fn expiry(time: u32, now: u64) -> Option<u64> {
+    match time {
+        0xffff_ffff => None,
+        time => Some(now + time as u64 * 1000)
+    }
+}
+
+// Returns the new expiry time of an existing address (RFC 4862 section
+// 5.5.3 e), which keeps spoofed advertisements from removing it quickly.
+fn update_expiry(expires_at: Option<u64>, valid_time: u32, now: u64) -> Option<u64> {
+    let received = expiry(valid_time, now);
+    let remaining = expires_at.map(|expires_at| expires_at.saturating_sub(now));
+    match (received, remaining) {
+        (None, _) => None,
+        (Some(received), _) if received - now > MIN_VALID_LIFETIME_MS => Some(received),
+        (Some(received), Some(remaining)) if received - now > remaining => Some(received),
+        (_, Some(remaining)) if remaining <= MIN_VALID_LIFETIME_MS => expires_at,
+        _ => Some(now + MIN_VALID_LIFETIME_MS)
+    }
+}
+
+fn update_prefix(status: &mut Status, settings: &Settings, prefix: &Prefix, now: u64) {
+    // Addresses are made of a 64-bit prefix and a 64-bit interface identifier.
+    if !prefix.autonomous || prefix.prefix.is_link_local() ||
+            prefix.preferred_time > prefix.valid_time {
+        return
+    }
+    if prefix.prefix_len != 64 {
+        debug!("ignoring IPv6 prefix {}/{}, of which the length is not 64",
+               prefix.prefix, prefix.prefix_len);
+        return
+    }
+    let mut address = [0; 16];
+    address[..8].copy_from_slice(&prefix.prefix.as_bytes()[..8]);
+    address[8..].copy_from_slice(&settings.link_local.as_bytes()[8..]);
+    let address = Ipv6Cidr::new(Ipv6Address(address), prefix.prefix_len);
+
+    if let Some(slot) = status.addresses.iter_mut().flatten().find(|slot| slot.address == address) {
+        slot.expires_at = update_expiry(slot.expires_at, prefix.valid_time, now);
+        return
+    }
+    if prefix.valid_time == 0 {
+        return
+    }
+    match status.addresses.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(Address {
+            address:    address,
+            expires_at: expiry(prefix.valid_time, now)
+        }),
+        None => warn!("ignoring IPv6 prefix {}/{}, only {} addresses can be autoconfigured",
+                      prefix.prefix, prefix.prefix_len, IPV6_AUTOCONF_COUNT)
+    }
+}
+
+fn update_advert(status: &mut Status, settings: &Settings, advert: &Advert, now: u64) {
+    for prefix in advert.prefixes.iter() {
+        update_prefix(status, settings, prefix, now)
+    }
+
+    // Follow the first router that advertises itself as a default router.
+    if status.router.is_none() || status.router == Some(advert.router) {
+        if advert.router_lifetime == 0 {
+            status.router = None
+        } else {
+            status.router = Some(advert.router);
+            status.router_expires_at = now + advert.router_lifetime as u64 * 1000
+        }
+    }
+
+    match advert.dns_lifetime {
+        Some(0) => {
+            status.dns_servers = [None; MAX_DNS_SERVERS];
+            status.dns_expires_at = None
+        }
+        Some(lifetime) => {
+            status.dns_servers = advert.dns_servers;
+            status.dns_expires_at = expiry(lifetime, now)
+        }
+        None => ()
+    }
+}
+
+fn expire(status: &mut Status, now: u64) {
+    for slot in status.addresses.iter_mut() {
+        if let Some(Address { expires_at: Some(expires_at), .. }) = *slot {
+            if now >= expires_at {
+                *slot = None
+            }
+        }
+    }
+    if now >= status.router_expires_at {
+        status.router = None
+    }
+    if status.dns_expires_at.map_or(false, |expires_at| now >= expires_at) {
+        status.dns_servers = [None; MAX_DNS_SERVERS];
+        status.dns_expires_at = None
+    }
+}
+
+fn next_expiry(status: &Status) -> Option<u64> {
+    status.addresses.iter()
+        .flatten()
+        .filter_map(|address| address.expires_at)
+        .chain(status.router.map(|_| status.router_expires_at))
+        .chain(status.dns_expires_at)
+        .min()
+}
+
+// Installs the addresses and the route of `new`, and makes it the status.
+fn configure(io: &Io, settings: &Settings, new: Status) {
+    let old = status();
+    for address in old.addresses.iter().flatten() {
+        if !new.addresses.iter().flatten().any(|new| new.address == address.address) {
+            info!("autoconfigured IPv6 address {} removed", address.address)
+        }
+    }
+    for address in new.addresses.iter().flatten() {
+        if !old.addresses.iter().flatten().any(|old| old.address == address.address) {
+            info!("autoconfigured IPv6 address {}", address.address)
+        }
+    }
+    let addresses = |status: &Status| {
+        let mut addresses = [None; IPV6_AUTOCONF_COUNT];
+        for (address, slot) in addresses.iter_mut().zip(status.addresses.iter()) {
+            *address = slot.map(|slot| slot.address)
+        }
+        addresses
+    };
+    if addresses(&old) != addresses(&new) {
+        io.set_ipv6_autoconf_addresses(&addresses(&new))
+    }
+
+    if old.router != new.router {
+        match new.router {
+            Some(router) => info!("IPv6 router {} advertised", router),
+            None => info!("IPv6 router {} removed", old.router.unwrap())
+        }
+        if !settings.static_route {
+            match new.router {
+                Some(router) => { io.set_ipv6_default_route(router).unwrap(); }
+                None => { io.remove_ipv6_default_route(); }
+            }
+        }
+    }
+    if old.dns_servers != new.dns_servers {
+        for server in new.dns_servers.iter().flatten() {
+            info!("IPv6 DNS server {}", server)
+        }
+    }
+    unsafe { STATUS = new }
+}
+
+fn retry_interval(attempt: u32) -> u64 {
+    (DHCPV6_FIRST_RETRY_MS << attempt.min(12)).min(DHCPV6_MAX_RETRY_MS)
+}
+
+// An exchange with the DHCPv6 servers, which is repeated until they reply.
+struct Exchange {
+    xid:        u32,
+    attempt:    u32,
+    started_at: u64
+}
+
+fn run(io: &Io, settings: &Settings) -> Result<(), SchedError> {
+    let icmp = RawSocket::new(io, IpVersion::Ipv6, IpProtocol::Icmpv6, 4096);
+    let dhcp = UdpSocket::new(io, 2048);
+    dhcp.bind(DHCPV6_CLIENT_PORT)?;
+    let dhcp_servers = IpEndpoint::new(IpAddress::v6(0xff02, 0, 0, 0, 0, 0, 1, 2),
+                                       DHCPV6_SERVER_PORT);
+
+    let mut status = UNCONFIGURED;
+    let mut solicitations = 0;
+    let mut solicit_at = Some(clock::get_ms() + MAX_RTR_SOLICITATION_DELAY_MS);
+    let mut exchange: Option<Exchange> = None;
+    let mut dhcp_at = None;
+    let mut packet = vec![0; PACKET_SIZE];
+    loop {
+        let now = clock::get_ms();
+        // Solicit advertisements until a router answers; later ones are
+        // sent periodically.
+        if solicit_at.map_or(false, |at| now >= at) {
+            icmp.send(&write_router_solicit(settings))?;
+            solicitations += 1;
+            solicit_at = if solicitations < MAX_RTR_SOLICITATIONS {
+                Some(now + RTR_SOLICITATION_INTERVAL_MS)
+            } else {
+                None
+            }
+        }
+        if dhcp_at.map_or(false, |at| now >= at) {
+            let current = exchange.take().unwrap_or(Exchange {
+                xid:        clock::get_us() as u32 & 0xff_ffff,
+                attempt:    0,
+                started_at: now
+            });
+            let request = write_information_request(settings, current.xid,
+                                                    now - current.started_at);
+            dhcp.send_to(&request, dhcp_servers)?;
+            dhcp_at = Some(now + retry_interval(current.attempt));
+            exchange = Some(Exchange { attempt: current.attempt + 1, ..current })
+        }
+
+        let deadline = [solicit_at, dhcp_at, next_expiry(&status)].iter().flatten().cloned().min();
+        match io.until_timeout(deadline.map(|deadline| deadline.saturating_sub(now)),
+                               || icmp.can_recv() || dhcp.can_recv()) {
+            Ok(()) | Err(SchedError::TimedOut) => (),
+            Err(err) => return Err(err)
+        }
+
+        let now = clock::get_ms();
+        while icmp.can_recv() {
+            let length = icmp.recv(&mut packet)?;
+            if let Some(advert) = read_advert(&packet[..length]) {
+                solicit_at = None;
+                update_advert(&mut status, settings, &advert, now);
+                if advert.flags & (ROUTER_FLAG_MANAGED | ROUTER_FLAG_OTHER) != 0 &&
+                        dhcp_at.is_none() {
+                    dhcp_at = Some(now)
+                }
+            }
+        }
+        while dhcp.can_recv() {
+            let (length, _) = dhcp.recv_from(&mut packet)?;
+            let information = exchange.as_ref()
+                .and_then(|exchange| read_information_reply(exchange.xid, &packet[..length]));
+            if let Some(information) = information {
+                if information.dns_servers.iter().any(|server| server.is_some()) {
+                    status.dns_servers = information.dns_servers;
+                    status.dns_expires_at = None
+                }
+                exchange = None;
+                dhcp_at = Some(now + information.refresh_time as u64 * 1000)
+            }
+        }
+        expire(&mut status, now);
+        if status != self::status() {
+            configure(io, settings, status)
+        }
+    }
+}
+
+pub fn thread(io: Io) {
+    if !config::read_str("ip6_autoconf", |r| r != Ok("0")) {
+        return
+    }
+    let settings = settings();
+    match run(&io, &settings) {
+        // Interrupted when the settings change.
+        Err(SchedError::Interrupted) => (),
+        Err(err) => error!("IPv6 autoconfiguration failed: {}", err),
+        Ok(()) => unreachable!()
+    }
+    // The default route may have been set statically in the meantime.
+    let settings = Settings {
+        static_route: net_settings::get_adresses().ipv6_default_route.is_some(),
+        ..settings
+    };
+    configure(&io, &settings, UNCONFIGURED)
+}
@@ -0,0 +1,155 @@
+use alloc::string::String;
+use byteorder::{ByteOrder, BigEndian};
+use smoltcp::wire::IpEndpoint;
+
+use board_misoc::{csr, config, net_settings};
+use session_proto::TimeCorrelation;
+use sched::{Io, UdpSocket, Error as SchedError};
+
+// The SNTP client (RFC 4330) measures the wall-clock time at values of the
+// RTIO counter, so that host tools can convert RTIO timestamps to UTC. Each
+// exchange with the server maps the middle of the exchange on the RTIO counter
+// to the middle of the processing of the request by the server; the error is
+// at most half of the round-trip time.
+const DEFAULT_PORT:      u16 = 123;
+const LOCAL_PORT:        u16 = 123;
+const PACKET_SIZE:       usize = 48;
+const TIMEOUT_MS:        u64 = 5_000;
+const POLL_INTERVAL_MS:  u64 = 64_000;
+const RETRY_INTERVAL_MS: u64 = 16_000;
+// Seconds from the NTP epoch (1900) to the Unix epoch (1970).
+const UNIX_EPOCH:        u64 = 2_208_988_800;
+
+#[derive(Fail, Debug)]
+enum Error {
+    #[fail(display = "{}", _0)]
+    Network(#[cause] SchedError),
+    #[fail(display = "invalid reply")]
+    InvalidReply,
+    #[fail(display = "server is not synchronized")]
+    Unsynchronized,
+    #[fail(display = "server refused the request ({})", _0)]
+    KissOfDeath(String)
+}
+
+impl From<SchedError> for Error {
+    fn from(value: SchedError) -> Error {
+        Error::Network(value)
+    }
+}
+
+static mut LATEST: Option<TimeCorrelation> = None;
+
+/// Returns the latest measurement, if any.
+pub fn latest() -> Option<TimeCorrelation> {
+    unsafe { LATEST }
+}
+
+/// Parses an NTP server address, with an optional port.
+pub fn parse_server(value: &str) -> Option<IpEndpoint> {
+    net_settings::parse_endpoint(value, DEFAULT_PORT)
+}
+
+fn rtio_counter() -> u64 {
+    unsafe {
+        csr::rtio_core::counter_update_write(1);
+        csr::rtio_core::counter_read()
+    }
+}
+
+// Converts an NTP timestamp to nanoseconds since the Unix epoch. Seconds with
+// the top bit clear are taken to be in the next era, which starts in 2036.
+fn unix_ns(timestamp: u64) -> u64 {
+    let mut seconds = timestamp >> 32;
+    if seconds & 0x8000_0000 == 0 {
+        seconds += 1 << 32
+    }
+    let nanoseconds = ((timestamp & 0xffff_ffff) * 1_000_000_000) >> 32;
+    (seconds - UNIX_EPOCH) * 1_000_000_000 + nanoseconds
+}
+
+fn query(socket: &UdpSocket, server: IpEndpoint) -> Result<TimeCorrelation, Error> {
+    let mut request = [0; PACKET_SIZE];
+    request[0] = (4 << 3) | 3; // version 4, client
+    // The server returns our transmit timestamp as the originate timestamp,
+    // which tells the reply apart from late replies to earlier requests.
+    let sent = rtio_counter();
+    BigEndian::write_u64(&mut request[40..], sent);
+    socket.send_to(&request, server)?;
+
+    loop {
+        let mut reply = [0; PACKET_SIZE];
+        let (length, source) = socket.recv_from(&mut reply)?;
+        let received = rtio_counter();
+        if source != server || length < PACKET_SIZE || BigEndian::read_u64(&reply[24..]) != sent {
+            continue
+        }
+
+        let (leap, mode, stratum) = (reply[0] >> 6, reply[0] & 7, reply[1]);
+        if mode != 4 {
+            return Err(Error::InvalidReply)
+        }
+        if stratum == 0 {
+            return Err(Error::KissOfDeath(String::from_utf8_lossy(&reply[12..16]).into_owned()))
+        }
+        if leap == 3 || stratum > 15 {
+            return Err(Error::Unsynchronized)
+        }
+        let (server_received, server_sent) = (BigEndian::read_u64(&reply[32..]),
+                                              BigEndian::read_u64(&reply[40..]));
+        if server_received == 0 || server_sent < server_received {
+            return Err(Error::InvalidReply)
+        }
+        let (server_received, server_sent) = (unix_ns(server_received), unix_ns(server_sent));
+        return Ok(TimeCorrelation {
+            wall_clock:   server_received + (server_sent - server_received) / 2,
+            rtio_counter: sent + (received - sent) / 2,
+            round_trip:   received - sent
+        })
+    }
+}
+
+pub fn thread(io: Io) {
+    let server = match config::read_str("ntp_server", |r| r.map(|s| parse_server(s))) {
+        Ok(Some(server)) => server,
+        Ok(None) => {
+            warn!("ignoring invalid `ntp_server` config key");
+            return
+        }
+        Err(_) => return
+    };
+
+    let socket = UdpSocket::new(&io, 1024);
+    socket.bind(LOCAL_PORT).expect("sntp: cannot bind socket");
+    socket.set_timeout(Some(TIMEOUT_MS));
+
+    // Only log changes, the server may be unreachable for a while.
+    let mut synchronized = None;
+    loop {
+        let interval = match query(&socket, server) {
+            Ok(correlation) => {
+                if synchronized != Some(true) {
+                    info!("wall clock synchronized with NTP server {}", server);
+                }
+                synchronized = Some(true);
+                debug!("{:?}", correlation);
+                unsafe { LATEST = Some(correlation) }
+                POLL_INTERVAL_MS
+            }
+            Err(Error::Network(SchedError::Interrupted)) => return,
+            Err(err) => {
+                if synchronized != Some(false) {
+                    warn!("cannot get the time from NTP server {}: {}", server, err);
+                } else {
+                    debug!("cannot get the time from NTP server {}: {}", server, err);
+                }
+                synchronized = Some(false);
+                RETRY_INTERVAL_MS
+            }
+        };
+        if io.sleep(interval).is_err() {
+            // Interrupted when the server changes.
+            return
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use core::fmt::Write;
+use alloc::string::String;
+use alloc::vec::Vec;
+use log::{Level, LevelFilter};
+use smoltcp::wire::IpEndpoint;
+
+use board_misoc::{clock, config, net_settings};
+use logger_artiq::{BufferLogger, LogRecord};
+use sched::{Io, UdpSocket, Error as SchedError};
+
+// Log messages are forwarded to a syslog server as RFC 5424 messages over UDP
+// (RFC 5426), so that they can be collected without a client connected to the
+// management interface. They are taken from the log buffer by sequence
+// number, so only messages kept in the buffer are forwarded, and messages
+// overwritten before they could be sent are reported instead.
+const DEFAULT_PORT: u16 = 514;
+const LOCAL_PORT:   u16 = 514;
+// Largest number of records copied out of the log buffer at once.
+const BATCH_SIZE:   usize = 16;
+// Facility of all messages ("user-level messages").
+const FACILITY:     u8 = 1;
+// Time to wait after the server could not be reached.
+const RETRY_INTERVAL_MS: u64 = 1000;
+
+struct Settings {
+    server:   IpEndpoint,
+    level:    LevelFilter,
+    hostname: String
+}
+
+/// Parses a syslog server address, with an optional port.
+pub fn parse_server(value: &str) -> Option<IpEndpoint> {
+    net_settings::parse_endpoint(value, DEFAULT_PORT)
+}
+
+fn settings() -> Option<Settings> {
+    let server = match config::read_str("syslog_server", |r| r.map(|s| parse_server(s))) {
+        Ok(Some(server)) => server,
+        Ok(None) => {
+            warn!("ignoring invalid `syslog_server` config key");
+            return None
+        }
+        Err(_) => return None
+    };
+    let level = match config::read_str("syslog_level", |r| r.map(|s| s.parse())) {
+        Ok(Ok(level)) => level,
+        _ => LevelFilter::Info
+    };
+    let hostname = net_settings::with_hostname(net_settings::get_adresses().hardware_addr,
+                                               String::from);
+    Some(Settings { server: server, level: level, hostname: hostname })
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn  => 4,
+        Level::Info  => 6,
+        Level::Debug | Level::Trace => 7
+    }
+}
+
+// Messages are formatted as:
+//   <PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID [meta ...] MSG
+// The core device has no wall clock, so the timestamp is left out, and the
+// time since startup is given as `sysUpTime` instead, in hundredths of a
+// second. `sequenceId` counts from 1 and wraps around at 2^31 - 1.
+fn header(hostname: &str, sequence: u64, timestamp: u64, level: Level) -> String {
+    let mut message = String::new();
+    write!(message, "<{}>1 - {} artiq - - [meta sequenceId=\"{}\" sysUpTime=\"{}\"] ",
+           FACILITY * 8 + severity(level), hostname,
+           sequence % 0x7fff_ffff + 1, timestamp / 10_000).unwrap();
+    message
+}
+
+fn format_record(hostname: &str, record: &LogRecord) -> Vec<u8> {
+    let mut message = header(hostname, record.sequence, record.timestamp, record.level);
+    write!(message, "{}: {}", record.target, record.message).unwrap();
+    message.into_bytes()
+}
+
+fn format_gap(hostname: &str, from: u64, to: u64, timestamp: u64) -> Vec<u8> {
+    let mut message = header(hostname, from, timestamp, Level::Warn);
+    write!(message, "{} log messages were not forwarded", to - from).unwrap();
+    message.into_bytes()
+}
+
+// Copies the records starting at sequence number `next` out of the log buffer,
+// and returns the sequence number to continue from.
+fn take_messages(settings: &Settings, next: Option<u64>, messages: &mut Vec<Vec<u8>>)
+                -> Option<u64> {
+    BufferLogger::with(|logger| {
+        let buffer = match logger.buffer() {
+            Ok(buffer) => buffer,
+            Err(()) => return next
+        };
+        // Start with the oldest record in the buffer.
+        let mut next = next.or_else(|| buffer.records().next().map(|record| record.sequence));
+        for record in buffer.records() {
+            if messages.len() == BATCH_SIZE {
+                return next
+            }
+            let from = next.unwrap_or(record.sequence);
+            if record.sequence < from {
+                continue
+            }
+            if record.sequence > from {
+                messages.push(format_gap(&settings.hostname, from, record.sequence,
+                                         record.timestamp));
+            }
+            if record.level <= settings.level {
+                messages.push(format_record(&settings.hostname, &record));
+            }
+            next = Some(record.sequence + 1);
+        }
+        if let Some(from) = next {
+            if buffer.next_sequence() > from {
+                // The newest messages were dropped while the buffer was in use,
+                // or the buffer was cleared.
+                messages.push(format_gap(&settings.hostname, from, buffer.next_sequence(),
+                                         clock::get_us()));
+            }
+        }
+        Some(buffer.next_sequence())
+    })
+}
+
+fn has_new_records(next: Option<u64>) -> bool {
+    BufferLogger::with(|logger| match logger.buffer() {
+        Ok(buffer) => match next {
+            Some(next) => buffer.next_sequence() > next,
+            None => !buffer.is_empty()
+        },
+        Err(()) => false
+    })
+}
+
+pub fn thread(io: Io) {
+    let settings = match settings() {
+        Some(settings) => settings,
+        None => return
+    };
+    info!("forwarding log messages to syslog server {} (level {})",
+          settings.server, settings.level);
+
+    let socket = UdpSocket::new(&io, 4096);
+    socket.bind(LOCAL_PORT).expect("syslog: cannot bind socket");
+
+    let mut next = None;
+    let mut messages = Vec::new();
+    let mut failing = false;
+    loop {
+        if io.until(|| has_new_records(next)).is_err() {
+            // Interrupted when the settings change.
+            return
+        }
+        next = take_messages(&settings, next, &mut messages);
+        for message in messages.drain(..) {
+            match socket.send_to(&message, settings.server) {
+                Ok(()) => failing = false,
+                Err(SchedError::Interrupted) => return,
+                Err(err) => {
+                    // Log once per outage, as the error is itself forwarded.
+                    if !failing {
+                        error!("cannot send log messages to syslog server {}: {}; \
+                                retrying, dropping messages meanwhile", settings.server, err);
+                        failing = true
+                    }
+                    if io.sleep(RETRY_INTERVAL_MS).is_err() {
+                        return
+                    }
+                    break
+                }
+            }
+        }
+    }
+}
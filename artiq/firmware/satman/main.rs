@@ -638,6 +638,14 @@ pub extern fn main() -> i32 {
     info!("software ident {}", csr::CONFIG_IDENTIFIER_STR);
     info!("gateware ident {}", ident::read(&mut [0; 64]));
 
+    // Satellites have no management interface, so reaching this point confirms a trial boot.
+    #[cfg(has_spiflash)]
+    {
+        if let Some(slot) = board_misoc::boot_control::confirm() {
+            info!("firmware in slot {} confirmed", slot);
+        }
+    }
+
     #[cfg(has_i2c)]
     i2c::init().expect("I2C initialization failed");
     #[cfg(all(soc_platform = "kasli", hw_rev = "v2.0"))]
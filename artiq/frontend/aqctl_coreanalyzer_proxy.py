@@ -9,7 +9,8 @@ from sipyco.asyncio_tools import AsyncioServer, SignalHandler, atexit_register_c
 from sipyco.pc_rpc import Server
 from sipyco import common_args
 
-from artiq.coredevice.comm_analyzer import get_analyzer_dump, ANALYZER_MAGIC
+from artiq.coredevice.comm_analyzer import (get_analyzer_dump, upgrade_dump,
+                                           ANALYZER_MAGIC)
 
 
 logger = logging.getLogger(__name__)
@@ -57,7 +58,8 @@ class ProxyControl:
 
     def trigger(self):
         try:
-            dump = get_analyzer_dump(self.core_addr, self.core_port)
+            dump = upgrade_dump(
+                get_analyzer_dump(self.core_addr, self.core_port))
             self.distribute_cb(dump)
         except:
             logger.warning("Trigger failed:", exc_info=True)
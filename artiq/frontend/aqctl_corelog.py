@@ -4,7 +4,6 @@ import argparse
 import asyncio
 import struct
 import logging
-import re
 
 from sipyco.pc_rpc import Server
 from sipyco import common_args
@@ -12,7 +11,8 @@ from sipyco.logging_tools import log_with_name
 from sipyco.asyncio_tools import SignalHandler
 from sipyco.keepalive import async_open_connection
 
-from artiq.coredevice.comm_mgmt import Request, Reply
+from artiq.coredevice.comm_mgmt import Request, Reply, LogLevel
+from artiq.coredevice import comm_auth
 
 logger = logging.getLogger(__name__)
 
@@ -23,6 +23,9 @@ def get_argparser():
     common_args.simple_network_args(parser, 1068)
     parser.add_argument("--simulation", action="store_true",
                         help="Simulation - does not connect to device")
+    parser.add_argument("--auth-key-file", default=None,
+                        help="file containing the authentication key of the "
+                             "core device")
     parser.add_argument("core_addr", metavar="CORE_ADDR",
                         help="hostname or IP address of the core device")
     return parser
@@ -39,7 +42,7 @@ async def get_logs_sim(host):
         log_with_name("firmware.simulation", logging.INFO, "hello " + host)
 
 
-async def get_logs(host):
+async def get_logs(host, auth_key):
     try:
         reader, writer = await async_open_connection(
             host,
@@ -50,35 +53,60 @@ async def get_logs(host):
         )
         writer.write(b"ARTIQ management\n")
         endian = await reader.readexactly(1)
+        if endian == comm_auth.CHALLENGE:
+            if auth_key is None:
+                raise IOError("Core device requires an authentication key")
+            nonce = await reader.readexactly(comm_auth.NONCE_LENGTH)
+            writer.write(comm_auth.answer(auth_key, nonce))
+            try:
+                endian = await reader.readexactly(1)
+            except asyncio.IncompleteReadError:
+                raise IOError("Core device refused the authentication key") from None
         if endian == b"e":
             endian = "<"
         elif endian == b"E":
             endian = ">"
         else:
             raise IOError("Incorrect reply from device: expected e/E.")
-        writer.write(struct.pack("B", Request.PullLog.value))
+        writer.write(struct.pack(endian + "BQ", Request.PullLogRecords.value, 0))
         await writer.drain()
 
-        while True:
+        async def read_int64():
+            value, = struct.unpack(endian + "Q", await reader.readexactly(8))
+            return value
+
+        async def read_string():
             length, = struct.unpack(endian + "l", await reader.readexactly(4))
-            log = await reader.readexactly(length)
-
-            for line in log.decode("utf-8").splitlines():
-                m = re.match(r"^\[.+?\] (TRACE|DEBUG| INFO| WARN|ERROR)\((.+?)\): (.+)$", line)
-                levelname = m.group(1)
-                if levelname == 'TRACE':
-                    level = logging.TRACE
-                elif levelname == 'DEBUG':
-                    level = logging.DEBUG
-                elif levelname == ' INFO':
-                    level = logging.INFO
-                elif levelname == ' WARN':
-                    level = logging.WARN
-                elif levelname == 'ERROR':
-                    level = logging.ERROR
-                name = 'firmware.' + m.group(2).replace('::', '.')
-                text = m.group(3)
-                log_with_name(name, level, text)
+            return (await reader.readexactly(length)).decode("utf-8")
+
+        levels = {
+            LogLevel.ERROR: logging.ERROR,
+            LogLevel.WARN: logging.WARN,
+            LogLevel.INFO: logging.INFO,
+            LogLevel.DEBUG: logging.DEBUG,
+            LogLevel.TRACE: logging.TRACE,
+        }
+        received = False
+        while True:
+            ty = Reply(*struct.unpack("B", await reader.readexactly(1)))
+            if ty == Reply.LogRecord:
+                await read_int64()  # sequence number
+                await read_int64()  # timestamp
+                level, = struct.unpack("B", await reader.readexactly(1))
+                target = await read_string()
+                message = await read_string()
+                name = "firmware." + target.replace("::", ".")
+                log_with_name(name, levels[LogLevel(level)], message)
+                received = True
+            elif ty == Reply.LogGap:
+                start = await read_int64()
+                end = await read_int64()
+                # Messages overwritten before we connected are not news.
+                if received:
+                    logger.warning("%d core device log messages were lost",
+                                   end - start)
+            else:
+                raise IOError("Incorrect reply from device: {}".format(ty))
     except asyncio.CancelledError:
         raise
     except:
@@ -88,6 +116,7 @@ async def get_logs(host):
 def main():
     args = get_argparser().parse_args()
     common_args.init_logger_from_args(args)
+    auth_key = comm_auth.read_key(args.auth_key_file)
 
     loop = asyncio.new_event_loop()
     asyncio.set_event_loop(loop)
@@ -96,7 +125,7 @@ def main():
         signal_handler.setup()
         try:
             get_logs_task = asyncio.ensure_future(
-                get_logs_sim(args.core_addr) if args.simulation else get_logs(args.core_addr),
+                get_logs_sim(args.core_addr) if args.simulation else get_logs(args.core_addr, auth_key),
                 loop=loop)
             try:
                 server = Server({"corelog": PingTarget()}, None, True)
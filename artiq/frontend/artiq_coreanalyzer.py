@@ -56,6 +56,7 @@ def main():
     if args.print_decoded:
         print("Log channel:", decoded_dump.log_channel)
         print("DDS one-hot:", decoded_dump.dds_onehot_sel)
+        print("Time correlation:", decoded_dump.time_correlation)
         for message in decoded_dump.messages:
             print(message)
     if args.write_vcd:
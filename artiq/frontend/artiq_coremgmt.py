@@ -1,14 +1,18 @@
 #!/usr/bin/env python3
 
 import argparse
+import io
 import struct
+import sys
 
 from sipyco import common_args
 
 from artiq import __version__ as artiq_version
 from artiq.master.databases import DeviceDB
 from artiq.coredevice.comm_kernel import CommKernel
-from artiq.coredevice.comm_mgmt import CommMgmt
+from artiq.coredevice.comm_mgmt import CommMgmt, FlashImage, Ipv4Config
+from artiq.coredevice.comm_auth import read_key
+from artiq.frontend.bit2bin import bit2bin
 
 
 def get_argparser():
@@ -24,6 +28,11 @@ def get_argparser():
     parser.add_argument("-D", "--device", default=None,
                         help="use specified core device address instead of "
                              "reading device database")
+    parser.add_argument("--auth-key-file", default=None,
+                        help="file containing the authentication key of the "
+                             "core device (default: the auth_key_file "
+                             "argument of the core device in the device "
+                             "database, if any)")
 
     tools = parser.add_subparsers(dest="tool")
     tools.required = True
@@ -48,6 +57,23 @@ def get_argparser():
     p_set_uart_level.add_argument("level", metavar="LEVEL", type=str,
                                   help="log level (one of: OFF ERROR WARN INFO DEBUG TRACE)")
 
+    p_set_filter = subparsers.add_parser("set_filter",
+                                         help="set levels of individual modules, "
+                                              "overriding the log level")
+    p_set_filter.add_argument("filter", metavar="FILTER", type=str,
+                              help="comma-separated MODULE=LEVEL directives, "
+                                   "such as runtime::rtio_mgt=TRACE "
+                                   "(empty to remove)")
+
+    p_set_uart_filter = subparsers.add_parser("set_uart_filter",
+                                              help="set levels of individual modules "
+                                                   "for UART, overriding the UART "
+                                                   "log level")
+    p_set_uart_filter.add_argument("filter", metavar="FILTER", type=str,
+                                   help="comma-separated MODULE=LEVEL directives, "
+                                        "such as runtime::rtio_mgt=TRACE "
+                                        "(empty to remove)")
+
     # configuration
     t_config = tools.add_parser("config",
                                 help="read and change core device configuration")
@@ -60,6 +86,11 @@ def get_argparser():
     p_read.add_argument("key", metavar="KEY", type=str,
                         help="key to be read from core device config")
 
+    p_list = subparsers.add_parser("list",
+                                   help="list keys in core device config")
+    p_list.add_argument("-v", "--values", default=False, action="store_true",
+                        help="also show the values")
+
     p_write = subparsers.add_parser("write",
                                     help="write key-value records to core "
                                          "device config")
@@ -72,6 +103,10 @@ def get_argparser():
                          metavar=("KEY", "FILENAME"),
                          help="key and file whose content to be written to "
                               "core device config")
+    p_write.add_argument("-a", "--atomic", default=False, action="store_true",
+                         help="write all records at once, so that none of "
+                              "them are written if the core device loses "
+                              "power")
 
     p_remove = subparsers.add_parser("remove",
                                      help="remove key from core device config")
@@ -81,10 +116,103 @@ def get_argparser():
 
     subparsers.add_parser("erase", help="fully erase core device config")
 
+    p_export = subparsers.add_parser("export",
+                                     help="save the whole core device config "
+                                          "to a file")
+    p_export.add_argument("file", metavar="FILE", type=str,
+                          help="backup file to write")
+
+    p_import = subparsers.add_parser("import",
+                                     help="replace the core device config "
+                                          "with a backup made by export")
+    p_import.add_argument("file", metavar="FILE", type=str,
+                          help="backup file to read")
+
+    subparsers.add_parser("health",
+                          help="check the core device config for damaged "
+                               "records")
+
+    # flash kernels
+    t_kernel = tools.add_parser("kernel",
+                                help="store startup and idle kernels in "
+                                     "the flash kernel partition")
+
+    subparsers = t_kernel.add_subparsers(dest="action")
+    subparsers.required = True
+
+    subparsers.add_parser("list", help="list stored kernels")
+
+    p_kernel_write = subparsers.add_parser("write",
+                                           help="store a kernel")
+    p_kernel_write.add_argument("name", metavar="NAME", type=str,
+                                help="kernel name, such as startup_kernel "
+                                     "or idle_kernel")
+    p_kernel_write.add_argument("filename", metavar="FILENAME", type=str,
+                                help="kernel (.elf or .tar) to store")
+
+    p_kernel_check = subparsers.add_parser("check",
+                                           help="check a kernel without "
+                                                "storing it")
+    p_kernel_check.add_argument("filename", metavar="FILENAME", type=str,
+                                help="kernel (.elf or .tar) to check")
+
+    p_kernel_remove = subparsers.add_parser("remove",
+                                            help="remove a stored kernel")
+    p_kernel_remove.add_argument("name", metavar="NAME", type=str,
+                                 help="name of the kernel to remove")
+
     # booting
     t_boot = tools.add_parser("reboot",
                               help="reboot the running system")
 
+    t_boot_slot = tools.add_parser("boot",
+                                   help="inspect and confirm the firmware "
+                                        "slot selection")
+
+    subparsers = t_boot_slot.add_subparsers(dest="action")
+    subparsers.required = True
+
+    subparsers.add_parser("status",
+                          help="show the active firmware slot")
+    subparsers.add_parser("confirm",
+                          help="mark the firmware on trial as good")
+
+    # crash reports
+    t_crash = tools.add_parser("crash",
+                               help="read the report of the last crash "
+                                    "of the runtime")
+
+    subparsers = t_crash.add_subparsers(dest="action")
+    subparsers.required = True
+
+    subparsers.add_parser("show", help="print the crash report")
+    subparsers.add_parser("clear", help="remove the crash report")
+
+    # network
+    tools.add_parser("network",
+                     help="show the network addresses of the core device, "
+                          "its DHCP lease and its IPv6 autoconfiguration")
+
+    # flashing
+    t_flash = tools.add_parser("flash",
+                               help="write a firmware or gateware image to "
+                                    "the core device flash")
+
+    subparsers = t_flash.add_subparsers(dest="action")
+    subparsers.required = True
+
+    p_firmware = subparsers.add_parser("firmware",
+                                       help="write firmware (.fbi) to flash")
+    p_firmware.add_argument("filename", metavar="FILENAME", type=str,
+                            help="firmware image, with the length/CRC header "
+                                 "produced by the build, signed or not")
+
+    p_gateware = subparsers.add_parser("gateware",
+                                       help="write slave FPGA gateware to "
+                                            "flash")
+    p_gateware.add_argument("filename", metavar="FILENAME", type=str,
+                            help="slave FPGA bitstream (.bit or .bin)")
+
     # misc debug
     t_debug = tools.add_parser("debug",
                                help="specialized debug functions")
@@ -102,18 +230,26 @@ def main():
     args = get_argparser().parse_args()
     common_args.init_logger_from_args(args)
 
+    auth_key_file = args.auth_key_file
     if args.device is None:
         ddb = DeviceDB(args.device_db)
-        core_addr = ddb.get("core", resolve_alias=True)["arguments"]["host"]
+        core_arguments = ddb.get("core", resolve_alias=True)["arguments"]
+        core_addr = core_arguments["host"]
+        if auth_key_file is None:
+            auth_key_file = core_arguments.get("auth_key_file")
     else:
         core_addr = args.device
-    mgmt = CommMgmt(core_addr)
+    mgmt = CommMgmt(core_addr, auth_key=read_key(auth_key_file))
 
     if args.tool == "log":
         if args.action == "set_level":
             mgmt.set_log_level(args.level)
         if args.action == "set_uart_level":
             mgmt.set_uart_log_level(args.level)
+        if args.action == "set_filter":
+            mgmt.set_log_filter(args.filter)
+        if args.action == "set_uart_filter":
+            mgmt.set_uart_log_filter(args.filter)
         if args.action == "clear":
             mgmt.clear_log()
         if args.action == None:
@@ -126,21 +262,123 @@ def main():
                 print("Key {} does not exist".format(args.key))
             else:
                 print(value)
+        if args.action == "list":
+            for key, length, value in mgmt.config_list(args.values):
+                if value is None:
+                    print("{} ({} bytes)".format(key, length))
+                else:
+                    print("{} ({} bytes): {}".format(key, length, value))
         if args.action == "write":
-            for key, value in args.string:
-                mgmt.config_write(key, value.encode("utf-8"))
+            records = [(key, value.encode("utf-8")) for key, value in args.string]
             for key, filename in args.file:
                 with open(filename, "rb") as fi:
-                    mgmt.config_write(key, fi.read())
+                    records.append((key, fi.read()))
+            if args.atomic:
+                mgmt.config_transaction(records)
+            else:
+                for key, value in records:
+                    mgmt.config_write(key, value)
         if args.action == "remove":
             for key in args.key:
                 mgmt.config_remove(key)
         if args.action == "erase":
             mgmt.config_erase()
+        if args.action == "export":
+            with open(args.file, "wb") as f:
+                f.write(mgmt.config_export())
+        if args.action == "import":
+            with open(args.file, "rb") as f:
+                mgmt.config_import(f.read())
+        if args.action == "health":
+            health = mgmt.config_health()
+            print("{} records, {} bytes free, {} bytes reclaimable".format(
+                health["records"], health["free"], health["reclaimable"]))
+            for offset in health["damaged"]:
+                print("Damaged record at offset {} (skipped)".format(offset))
+
+    if args.tool == "kernel":
+        if args.action == "list":
+            kernels, free = mgmt.flash_kernel_list()
+            for name, length in kernels:
+                print("{} ({} bytes)".format(name, length))
+            print("{} bytes free".format(free))
+        if args.action == "write":
+            with open(args.filename, "rb") as f:
+                mgmt.flash_kernel_write(args.name, f.read())
+        if args.action == "check":
+            with open(args.filename, "rb") as f:
+                mgmt.flash_kernel_check(f.read())
+        if args.action == "remove":
+            mgmt.flash_kernel_remove(args.name)
 
     if args.tool == "reboot":
         mgmt.reboot()
 
+    if args.tool == "boot":
+        if args.action == "status":
+            slot, trial, attempts = mgmt.boot_status()
+            if trial:
+                print("Slot {} (on trial, boot attempt {})".format(slot, attempts))
+            else:
+                print("Slot {}".format(slot))
+        if args.action == "confirm":
+            mgmt.confirm_boot()
+
+    if args.tool == "crash":
+        if args.action == "show":
+            report = mgmt.get_crash_dump()
+            if report is None:
+                print("No crash report")
+            else:
+                print(report, end="")
+        if args.action == "clear":
+            mgmt.clear_crash_dump()
+
+    if args.tool == "network":
+        status = mgmt.network_status()
+        print("IPv4 address: {} ({})".format(
+            status.ipv4_address or "none",
+            {Ipv4Config.Static: "static",
+             Ipv4Config.Dhcp: "DHCP",
+             Ipv4Config.Fallback: "fallback, no DHCP lease"}[status.ipv4_config]))
+        lease = status.lease
+        if lease is not None:
+            print("DHCP lease from {}: address {}, default route {}, "
+                  "DNS servers {}, {}".format(
+                lease.server, lease.address, lease.router or "none",
+                ", ".join(lease.dns_servers) or "none",
+                "no expiry" if lease.remaining is None
+                else "expires in {} s".format(lease.remaining)))
+        ipv6 = status.ipv6
+        print("IPv6 addresses: {}".format(", ".join(ipv6.addresses) or "none"))
+        print("IPv6 default route: {}, DNS servers {}".format(
+            ipv6.router or "none", ", ".join(ipv6.dns_servers) or "none"))
+
+    if args.tool == "flash":
+        def progress(written, total):
+            print("\r{}/{} bytes written".format(written, total),
+                  end="", file=sys.stderr, flush=True)
+
+        if args.action == "firmware":
+            with open(args.filename, "rb") as f:
+                image = f.read()
+            mgmt.flash(FlashImage.Firmware, image, progress=progress)
+        if args.action == "gateware":
+            with open(args.filename, "rb") as f:
+                if args.filename.endswith(".bit"):
+                    bitstream = io.BytesIO()
+                    bit2bin(f, bitstream)
+                    bitstream = bitstream.getvalue()
+                else:
+                    bitstream = f.read()
+            image = struct.pack("<II", 0x5352544d, len(bitstream)) + bitstream
+            mgmt.flash(FlashImage.Gateware, image, progress=progress)
+        print(file=sys.stderr)
+        print("Image written and verified; reboot the device to use it")
+        if args.action == "firmware":
+            print("The new firmware boots on trial and is kept once it starts; "
+                  "otherwise the device reverts to the previous one")
+
     if args.tool == "debug":
         if args.action == "allocator":
             mgmt.debug_allocator()
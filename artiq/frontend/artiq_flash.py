@@ -1,7 +1,9 @@
 #!/usr/bin/env python3
 
 import argparse
+import binascii
 import os
+import struct
 import subprocess
 import tempfile
 import shutil
@@ -27,7 +29,7 @@ Valid actions:
     * gateware: write main gateware bitstream to flash
     * bootloader: write bootloader to flash
     * storage: write storage image to flash
-    * firmware: write firmware to flash
+    * firmware: write firmware to flash (and select it for booting)
     * load: load main gateware bitstream into device (volatile but fast)
     * erase: erase flash memory
     * start: trigger the target to (re)load its gateware bitstream from flash
@@ -146,6 +148,15 @@ class Programmer:
                      "flash erase_sector {bankname} 0 last",
                      bankname=bankname)
 
+    def erase_sectors(self, bankname, address, length):
+        self.load_proxy()
+        add_commands(self._script,
+                     "flash probe {bankname}",
+                     "flash erase_sector {bankname} {firstsector} {lastsector}",
+                     bankname=bankname,
+                     firstsector=address // self._sector_size,
+                     lastsector=(address + length - 1) // self._sector_size)
+
     def load(self, bitfile, pld):
         os.stat(bitfile) # check for existence
 
@@ -255,32 +266,44 @@ def main():
 
     config = {
         "kasli": {
-            "programmer":   partial(ProgrammerXC7, board="kasli", proxy="bscan_spi_xc7a100t.bit"),
-            "gateware":     ("spi0", 0x000000),
-            "bootloader":   ("spi0", 0x400000),
-            "storage":      ("spi0", 0x440000),
-            "firmware":     ("spi0", 0x450000),
+            "programmer":         partial(ProgrammerXC7, board="kasli", proxy="bscan_spi_xc7a100t.bit"),
+            "gateware":           ("spi0", 0x000000),
+            "bootloader":         ("spi0", 0x400000),
+            "storage":            ("spi0", 0x440000),
+            "firmware":           ("spi0", 0x450000),
+            "boot_control":       ("spi0", 0x850000),
+            "boot_control_spare": ("spi0", 0x900000),
+            "storage_spare":      ("spi0", 0x860000),
         },
         "efc1v0": {
-            "programmer":   partial(ProgrammerXC7, board="efc", proxy="bscan_spi_xc7a100t.bit"),
-            "gateware":     ("spi0", 0x000000),
-            "bootloader":   ("spi0", 0x600000),
-            "storage":      ("spi0", 0x640000),
-            "firmware":     ("spi0", 0x650000),
+            "programmer":         partial(ProgrammerXC7, board="efc", proxy="bscan_spi_xc7a100t.bit"),
+            "gateware":           ("spi0", 0x000000),
+            "bootloader":         ("spi0", 0x600000),
+            "storage":            ("spi0", 0x640000),
+            "firmware":           ("spi0", 0x650000),
+            "boot_control":       ("spi0", 0xa50000),
+            "boot_control_spare": ("spi0", 0xb00000),
+            "storage_spare":      ("spi0", 0xa60000),
         },
         "efc1v1": {
-            "programmer":   partial(ProgrammerXC7, board="efc", proxy="bscan_spi_xc7a200t.bit"),
-            "gateware":     ("spi0", 0x000000),
-            "bootloader":   ("spi0", 0x600000),
-            "storage":      ("spi0", 0x640000),
-            "firmware":     ("spi0", 0x650000),
+            "programmer":         partial(ProgrammerXC7, board="efc", proxy="bscan_spi_xc7a200t.bit"),
+            "gateware":           ("spi0", 0x000000),
+            "bootloader":         ("spi0", 0x600000),
+            "storage":            ("spi0", 0x640000),
+            "firmware":           ("spi0", 0x650000),
+            "boot_control":       ("spi0", 0xa50000),
+            "boot_control_spare": ("spi0", 0xb00000),
+            "storage_spare":      ("spi0", 0xa60000),
         },
         "kc705": {
-            "programmer":   partial(ProgrammerXC7, board="kc705", proxy="bscan_spi_xc7k325t.bit"),
-            "gateware":     ("spi0", 0x000000),
-            "bootloader":   ("spi0", 0xaf0000),
-            "storage":      ("spi0", 0xb30000),
-            "firmware":     ("spi0", 0xb40000),
+            "programmer":         partial(ProgrammerXC7, board="kc705", proxy="bscan_spi_xc7k325t.bit"),
+            "gateware":           ("spi0", 0x000000),
+            "bootloader":         ("spi0", 0xaf0000),
+            "storage":            ("spi0", 0xb30000),
+            "firmware":           ("spi0", 0xb40000),
+            "boot_control":       ("spi0", 0xf40000),
+            "boot_control_spare": ("spi0", 0xff0000),
+            "storage_spare":      ("spi0", 0xf50000),
         },
     }[args.target]
 
@@ -318,6 +341,17 @@ def main():
         atexit.register(lambda: os.unlink(bin_filename))
         return bin_filename
 
+    def boot_control_record():
+        # Boot-control record selecting firmware slot A, which is where
+        # "firmware" writes to. See libboard_misoc/boot_control.rs.
+        record = struct.pack("<IBBBBI", 0x4c544342, 0, 0, 0, 0, 0)
+        record += struct.pack("<I", binascii.crc32(record))
+        handle, filename = tempfile.mkstemp(prefix="artiq_", suffix="_boot_control.bin")
+        with open(handle, "wb") as f:
+            f.write(record)
+        atexit.register(lambda: os.unlink(filename))
+        return filename
+
     for action in args.action:
         if action == "gateware":
             gateware_bin = convert_gateware(
@@ -329,6 +363,9 @@ def main():
         elif action == "storage":
             storage_img = args.storage
             programmer.write_binary(*config["storage"], storage_img)
+            # The core device alternates the storage between two sectors;
+            # erase the other one so that the image just written is used.
+            programmer.erase_sectors(*config["storage_spare"], 0x10000)
         elif action == "firmware":
             firmware_fbis = []
             for firmware in "satman", "runtime":
@@ -341,6 +378,9 @@ def main():
                 raise ValueError("more than one firmware file, please clean up your build directory. "
                    "Found firmware files: {}".format(" ".join(firmware_fbis)))
             programmer.write_binary(*config["firmware"], firmware_fbis[0])
+            programmer.write_binary(*config["boot_control"], boot_control_record())
+            # Records in the other boot-control sector may be newer.
+            programmer.erase_sectors(*config["boot_control_spare"], 0x10000)
         elif action == "load":
             gateware_bit = artifact_path(binary_dir, "gateware", "top.bit")
             programmer.load(gateware_bit, 0)
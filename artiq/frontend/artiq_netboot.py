@@ -0,0 +1,94 @@
+#!/usr/bin/env python3
+
+import argparse
+import io
+import socket
+import struct
+import sys
+
+from artiq.frontend.bit2bin import bit2bin
+
+
+def get_argparser():
+    parser = argparse.ArgumentParser(
+        description="ARTIQ netboot client, for core devices waiting in the "
+                    "bootloader")
+    parser.add_argument("hostname", metavar="HOSTNAME",
+                        help="hostname or IP address of the core device")
+    parser.add_argument("-p", "--port", default=4269, type=int,
+                        help="bootloader TCP port (default: %(default)s)")
+
+    group = parser.add_mutually_exclusive_group(required=True)
+    group.add_argument("-f", "--firmware",
+                       help="load firmware (.bin) into SDRAM and boot it")
+    group.add_argument("-g", "--gateware",
+                       help="load slave FPGA gateware (.bit or .bin)")
+    group.add_argument("--install-firmware", metavar="FILENAME",
+                       help="write firmware (.fbi) to flash")
+    group.add_argument("--install-gateware", metavar="FILENAME",
+                       help="write slave FPGA gateware (.bit or .bin) to "
+                            "flash")
+    return parser
+
+
+def read_gateware(filename):
+    with open(filename, "rb") as f:
+        if filename.endswith(".bit"):
+            bitstream = io.BytesIO()
+            bit2bin(f, bitstream)
+            return bitstream.getvalue()
+        else:
+            return f.read()
+
+
+def send_image(sock, command, data):
+    sock.sendall(command)
+    sock.sendall(struct.pack(">I", len(data)))
+    sock.sendall(data)
+    sock.sendall(b"OK")
+
+
+def read_status(sock):
+    status = b""
+    while not status.endswith(b"\n"):
+        data = sock.recv(128)
+        if not data:
+            raise ConnectionResetError("Connection closed by the device")
+        status += data
+    return status.decode().strip()
+
+
+def install(sock, command, data):
+    try:
+        send_image(sock, command, data)
+    except ConnectionError:
+        # The device gave up early; it reports why before closing.
+        pass
+    status = read_status(sock)
+    print(status)
+    if not status.startswith("OK"):
+        sys.exit(1)
+
+
+def main():
+    args = get_argparser().parse_args()
+
+    with socket.create_connection((args.hostname, args.port)) as sock:
+        if args.firmware is not None:
+            with open(args.firmware, "rb") as f:
+                send_image(sock, b"F", f.read())
+            sock.sendall(b"B")
+        if args.gateware is not None:
+            send_image(sock, b"G", read_gateware(args.gateware))
+
+        if args.install_firmware is not None:
+            with open(args.install_firmware, "rb") as f:
+                install(sock, b"IF", f.read())
+        if args.install_gateware is not None:
+            bitstream = read_gateware(args.install_gateware)
+            image = struct.pack("<II", 0x5352544d, len(bitstream)) + bitstream
+            install(sock, b"IG", image)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,83 @@
+#!/usr/bin/env python3
+
+import argparse
+import binascii
+import os
+import struct
+
+from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+
+
+SIGNATURE_MAGIC = 0x47495341  # "ASIG"
+
+
+def get_argparser():
+    parser = argparse.ArgumentParser(description="ARTIQ firmware signing tool")
+
+    subparsers = parser.add_subparsers(dest="action")
+    subparsers.required = True
+
+    p_keygen = subparsers.add_parser("keygen",
+                                     help="generate a signing key pair")
+    p_keygen.add_argument("key", metavar="KEY",
+                          help="private key file to create; the public key "
+                               "is written to KEY.pub")
+
+    p_sign = subparsers.add_parser("sign",
+                                   help="sign a firmware image")
+    p_sign.add_argument("-k", "--key", required=True,
+                        help="private key file")
+    p_sign.add_argument("input", metavar="INPUT",
+                        help="firmware to sign (.fbi for flashing, "
+                             ".bin for netboot)")
+    p_sign.add_argument("output", metavar="OUTPUT",
+                        help="signed firmware")
+
+    return parser
+
+
+def raw_bytes(key):
+    if isinstance(key, Ed25519PrivateKey):
+        return key.private_bytes(serialization.Encoding.Raw,
+                                 serialization.PrivateFormat.Raw,
+                                 serialization.NoEncryption())
+    else:
+        return key.public_bytes(serialization.Encoding.Raw,
+                                serialization.PublicFormat.Raw)
+
+
+def split_fbi(image):
+    # .fbi files start with the payload length and CRC32, see mscimg.
+    if len(image) >= 8:
+        length, crc = struct.unpack("<II", image[:8])
+        if length == len(image) - 8 and crc == binascii.crc32(image[8:]):
+            return image[:8], image[8:]
+    return b"", image
+
+
+def main():
+    args = get_argparser().parse_args()
+
+    if args.action == "keygen":
+        key = Ed25519PrivateKey.generate()
+        fd = os.open(args.key, os.O_WRONLY | os.O_CREAT | os.O_EXCL, 0o600)
+        with open(fd, "wb") as f:
+            f.write(raw_bytes(key))
+        with open(args.key + ".pub", "wb") as f:
+            f.write(raw_bytes(key.public_key()))
+
+    if args.action == "sign":
+        with open(args.key, "rb") as f:
+            key = Ed25519PrivateKey.from_private_bytes(f.read())
+        with open(args.input, "rb") as f:
+            header, firmware = split_fbi(f.read())
+        with open(args.output, "wb") as f:
+            f.write(header)
+            f.write(firmware)
+            f.write(struct.pack("<I", SIGNATURE_MAGIC))
+            f.write(key.sign(firmware))
+
+
+if __name__ == "__main__":
+    main()
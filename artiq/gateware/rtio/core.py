@@ -23,6 +23,10 @@ class Core(Module, AutoCSR):
         self.busy_channel = CSRStatus(16)
         self.sequence_error_channel = CSRStatus(16)
 
+        # RTIO counter, for the comms CPU
+        self.counter = CSRStatus(64)
+        self.counter_update = CSR()
+
         # Clocking/Reset
         # Create rio and rio_phy domains based on sys
         # with reset controlled by CSR.
@@ -57,6 +61,8 @@ class Core(Module, AutoCSR):
                                      for channel in channels))
         assert tsc.glbl_fine_ts_width >= chan_fine_ts_width
 
+        self.sync += If(self.counter_update.re, self.counter.status.eq(tsc.full_ts_cri))
+
         # Outputs/Inputs
         quash_channels = [n for n, c in enumerate(channels) if isinstance(c, LogChannel)]
 